@@ -0,0 +1,410 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info};
+
+use crate::image::CropRegion;
+use crate::magick_tiler::{BaseMagickTiler, MagickTiler, TilingError};
+use crate::progress::ProgressTracker;
+use crate::tile_set_info::TileSetInfo;
+
+/// A tiler that implements the Microsoft Deep Zoom (DZI) tiling scheme, as
+/// used by OpenSeadragon.
+///
+/// The Deep Zoom tiling scheme arranges tiles in the following folder/file
+/// structure:
+/// /tileset-root/[name].dzi
+/// /tileset-root/[name]_files/[level]/[column]_[row].jpg (or .png, .webp, .avif)
+///
+/// Level 0 is a single pixel; each following level doubles the resolution up
+/// to the full image size at the highest level. Column/row numbering of tiles
+/// starts top/left, counting direction is right/downwards.
+///
+/// Tiles on the border may be irregularly sized. Every tile is extended by
+/// `overlap` pixels on each side that has a neighbouring tile.
+pub struct DeepZoomTiler {
+    base: BaseMagickTiler,
+
+    /// Number of pixels each tile overlaps with its neighbours
+    overlap: i32,
+}
+
+pub const DESCRIPTOR_EXTENSION: &str = "dzi";
+pub const FILES_DIR_SUFFIX: &str = "_files";
+const DESCRIPTOR_TEMPLATE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Image xmlns="http://schemas.microsoft.com/deepzoom/2008" Format="@ext@" Overlap="@overlap@" TileSize="@tilesize@">
+  <Size Width="@width@" Height="@height@"/>
+</Image>
+"#;
+
+/// Returns the highest Deep Zoom level for an image of the given size. The
+/// image has this number plus one levels in total.
+pub fn max_level(width: i32, height: i32) -> i32 {
+    let max_dim = width.max(height).max(1);
+    (max_dim as f64).log2().ceil() as i32
+}
+
+/// Returns the image dimensions at the given Deep Zoom level.
+pub fn level_dimensions(width: i32, height: i32, level: i32) -> (i32, i32) {
+    let factor = 2_f64.powi(max_level(width, height) - level);
+    (
+        ((width as f64 / factor).ceil() as i32).max(1),
+        ((height as f64 / factor).ceil() as i32).max(1),
+    )
+}
+
+/// Returns the number of tiles in all levels of a Deep Zoom pyramid.
+pub fn total_tiles(width: i32, height: i32, tile_size: i32) -> i32 {
+    (0..=max_level(width, height))
+        .map(|level| {
+            let (w, h) = level_dimensions(width, height, level);
+            let columns = (w as f64 / tile_size as f64).ceil() as i32;
+            let rows = (h as f64 / tile_size as f64).ceil() as i32;
+            columns * rows
+        })
+        .sum()
+}
+
+/// Returns the pixel region (x, y, width, height) covered by a tile, including
+/// the overlap with its neighbours.
+pub fn tile_region(
+    level_width: i32,
+    level_height: i32,
+    tile_size: i32,
+    overlap: i32,
+    column: i32,
+    row: i32,
+) -> (i32, i32, i32, i32) {
+    let x = column * tile_size - if column > 0 { overlap } else { 0 };
+    let y = row * tile_size - if row > 0 { overlap } else { 0 };
+    let x_end = ((column + 1) * tile_size + overlap).min(level_width);
+    let y_end = ((row + 1) * tile_size + overlap).min(level_height);
+    (x, y, x_end - x, y_end - y)
+}
+
+impl DeepZoomTiler {
+    pub fn new() -> Self {
+        let mut base = BaseMagickTiler::new();
+        base.set_tile_size(254);
+        Self { base, overlap: 1 }
+    }
+
+    pub fn overlap(&self) -> i32 {
+        self.overlap
+    }
+
+    /// Sets the number of pixels each tile overlaps with its neighbours
+    /// (default: 1).
+    pub fn set_overlap(&mut self, overlap: i32) {
+        self.overlap = overlap.max(0);
+    }
+
+    pub fn set_tile_size(&mut self, size: i32) {
+        self.base.set_tile_size(size);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn generate_level_tiles(
+        &self,
+        level_image: &Path,
+        level_width: i32,
+        level_height: i32,
+        level: i32,
+        info: &TileSetInfo,
+        target_dir: &Path,
+        progress: &ProgressTracker,
+    ) -> Result<(), TilingError> {
+        let tile_size = info.tile_width();
+        let ext = info.tile_format().extension();
+        let columns = (level_width as f64 / tile_size as f64).ceil() as i32;
+        let rows = (level_height as f64 / tile_size as f64).ceil() as i32;
+
+        if self.overlap == 0 {
+            // Without overlap, the level can be cut in one go
+            let filename_pattern = target_dir.join(format!("tmp-%d.{}", ext));
+            self.base
+                .processor()
+                .crop(level_image, &filename_pattern, tile_size, tile_size)?;
+
+            for i in 0..(columns * rows) {
+                let old_name = target_dir.join(format!("tmp-{}.{}", i, ext));
+                let new_name = target_dir.join(format!("{}_{}.{}", i % columns, i / columns, ext));
+                fs::rename(&old_name, &new_name).map_err(TilingError::not_writable(&new_name))?;
+            }
+            progress.tiles_done(columns * rows, level, rows - 1);
+            return Ok(());
+        }
+
+        // Every tile overlaps its neighbours, so the tiles are cut as regions
+        // of the level image, which is decoded only once
+        let regions: Vec<CropRegion> = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .map(|(column, row)| {
+                let (x, y, width, height) = tile_region(
+                    level_width,
+                    level_height,
+                    tile_size,
+                    self.overlap,
+                    column,
+                    row,
+                );
+                CropRegion {
+                    target: target_dir.join(format!("{}_{}.{}", column, row, ext)),
+                    x,
+                    y,
+                    width,
+                    height,
+                }
+            })
+            .collect();
+        self.base.processor().crop_regions(level_image, &regions)?;
+        progress.tiles_done(columns * rows, level, rows - 1);
+
+        Ok(())
+    }
+
+    /// Tiles all levels, starting with the original image at the highest
+    /// level. Each level is computed from the one above it; `level_image` holds
+    /// the most recent level image, so it can be removed if tiling fails.
+    fn generate_levels(
+        &self,
+        image: &Path,
+        info: &TileSetInfo,
+        files_dir: &Path,
+        name: &str,
+        level_image: &mut PathBuf,
+    ) -> Result<(), TilingError> {
+        let max_level = max_level(info.image_width(), info.image_height());
+        let progress = self.base.progress_tracker(total_tiles(
+            info.image_width(),
+            info.image_height(),
+            info.tile_width(),
+        ));
+
+        for level in (0..=max_level).rev() {
+            self.base.check_cancelled()?;
+            debug!("Tiling level {}", level);
+            let (width, height) = level_dimensions(info.image_width(), info.image_height(), level);
+
+            if level < max_level {
+                let next_image = self
+                    .base
+                    .working_directory()
+                    .join(format!("{}-dzi-{}.tif", name, level));
+                self.base
+                    .processor()
+                    .scale(level_image, &next_image, width, height)?;
+                if level_image != image {
+                    fs::remove_file(&*level_image).map_err(TilingError::io(&*level_image))?;
+                }
+                *level_image = next_image;
+            }
+
+            let level_dir = files_dir.join(level.to_string());
+            fs::create_dir_all(&level_dir).map_err(TilingError::not_writable(&level_dir))?;
+            self.generate_level_tiles(
+                level_image,
+                width,
+                height,
+                level,
+                info,
+                &level_dir,
+                &progress,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn generate_descriptor(&self, info: &TileSetInfo, name: &str) -> Result<(), TilingError> {
+        let descriptor = DESCRIPTOR_TEMPLATE
+            .replace("@ext@", info.tile_format().extension())
+            .replace("@overlap@", &self.overlap.to_string())
+            .replace("@tilesize@", &info.tile_width().to_string())
+            .replace("@width@", &info.image_width().to_string())
+            .replace("@height@", &info.image_height().to_string());
+
+        if let Some(root_dir) = self.base.tileset_root_dir() {
+            let descriptor_path = root_dir.join(format!("{}.{}", name, DESCRIPTOR_EXTENSION));
+            let mut file = File::create(&descriptor_path)
+                .map_err(TilingError::not_writable(&descriptor_path))?;
+
+            file.write_all(descriptor.as_bytes()).map_err(|e| {
+                error!("Error writing DZI descriptor: {}", e);
+                TilingError::not_writable(&descriptor_path)(e)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn generate_preview(&self, info: &TileSetInfo, name: &str) -> Result<(), TilingError> {
+        let template = include_str!("dzi-template.html");
+        let html = template
+            .replace(
+                "@title@",
+                &info.image_file().file_name().unwrap().to_string_lossy(),
+            )
+            .replace(
+                "@descriptor@",
+                &format!("{}.{}", name, DESCRIPTOR_EXTENSION),
+            );
+
+        self.base.write_html_preview(&html)
+    }
+}
+
+impl Default for DeepZoomTiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MagickTiler for DeepZoomTiler {
+    fn base(&self) -> &BaseMagickTiler {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut BaseMagickTiler {
+        &mut self.base
+    }
+
+    fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError> {
+        let target = self.base.default_target();
+        self.convert_to(image, &target)
+    }
+
+    fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
+        let info = self.base.prepare(image, target)?;
+        self.convert_internal(image, info)
+    }
+
+    fn convert_internal(
+        &mut self,
+        image: &Path,
+        info: TileSetInfo,
+    ) -> Result<TileSetInfo, TilingError> {
+        let start_time = std::time::Instant::now();
+        let max_level = max_level(info.image_width(), info.image_height());
+        info!(
+            "Generating Deep Zoom tiles for file {}: {}x{}, {} levels, tile size {}, overlap {}",
+            image.file_name().unwrap().to_string_lossy(),
+            info.image_width(),
+            info.image_height(),
+            max_level + 1,
+            info.tile_width(),
+            self.overlap
+        );
+
+        let name = image.file_stem().unwrap().to_string_lossy().into_owned();
+        let files_dir = self
+            .base
+            .tileset_root_dir()
+            .unwrap()
+            .join(format!("{}{}", name, FILES_DIR_SUFFIX));
+
+        let mut level_image: PathBuf = image.to_path_buf();
+        let result = self.generate_levels(image, &info, &files_dir, &name, &mut level_image);
+        let cleanup = if level_image != image {
+            fs::remove_file(&level_image).map_err(TilingError::io(&level_image))
+        } else {
+            Ok(())
+        };
+        result?;
+        cleanup?;
+
+        // Generate the .dzi descriptor
+        self.generate_descriptor(&info, &name)?;
+
+        // Optionally generate an OpenSeadragon preview
+        if self.base.generate_preview() {
+            self.generate_preview(&info, &name)?;
+        }
+
+        info!("Took {} ms", start_time.elapsed().as_millis());
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+
+    #[test]
+    fn levels_halve_down_to_a_single_pixel() {
+        assert_eq!(max_level(300, 200), 9);
+        assert_eq!(max_level(256, 256), 8);
+        assert_eq!(max_level(1, 1), 0);
+
+        assert_eq!(level_dimensions(300, 200, 9), (300, 200));
+        assert_eq!(level_dimensions(300, 200, 8), (150, 100));
+        assert_eq!(level_dimensions(300, 200, 7), (75, 50));
+        assert_eq!(level_dimensions(300, 200, 1), (2, 1));
+        assert_eq!(level_dimensions(300, 200, 0), (1, 1));
+
+        // Two tiles at full size, a single one on every other level
+        assert_eq!(total_tiles(300, 200, 254), 11);
+    }
+
+    #[test]
+    fn tiles_without_overlap_are_cut_from_the_grid() {
+        assert_eq!(tile_region(600, 500, 254, 0, 0, 0), (0, 0, 254, 254));
+        assert_eq!(tile_region(600, 500, 254, 0, 1, 0), (254, 0, 254, 254));
+        assert_eq!(tile_region(600, 500, 254, 0, 2, 1), (508, 254, 92, 246));
+    }
+
+    #[test]
+    fn tiles_overlap_only_towards_their_neighbours() {
+        assert_eq!(tile_region(600, 500, 254, 1, 0, 0), (0, 0, 255, 255));
+        assert_eq!(tile_region(600, 500, 254, 1, 1, 0), (253, 0, 256, 255));
+        assert_eq!(tile_region(600, 500, 254, 1, 1, 1), (253, 253, 256, 247));
+        assert_eq!(tile_region(600, 500, 254, 1, 2, 1), (507, 253, 93, 247));
+        assert_eq!(tile_region(600, 500, 254, 4, 2, 1), (504, 250, 96, 250));
+    }
+
+    #[test]
+    fn overlapping_tiles_are_cut_from_the_level_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.png");
+        let source = image::RgbImage::from_fn(300, 200, |x, y| {
+            image::Rgb([x as u8, y as u8, (x * y % 251) as u8])
+        });
+        source.save(&image).unwrap();
+
+        let mut tiler = DeepZoomTiler::new();
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_format(ImageFormat::PNG);
+        tiler.base_mut().set_working_directory(dir.path());
+        tiler.base_mut().set_generate_preview_html(false);
+        tiler.set_tile_size(128);
+        tiler.set_overlap(2);
+        let target = dir.path().join("tiles");
+        tiler.convert_to(&image, &target).unwrap();
+
+        let level_dir = target.join("image_files").join("9");
+        for row in 0..2 {
+            for column in 0..3 {
+                let (x, y, width, height) = tile_region(300, 200, 128, 2, column, row);
+                let tile = image::open(level_dir.join(format!("{}_{}.png", column, row)))
+                    .unwrap()
+                    .to_rgb8();
+                let expected = image::imageops::crop_imm(
+                    &source,
+                    x as u32,
+                    y as u32,
+                    width as u32,
+                    height as u32,
+                )
+                .to_image();
+                assert_eq!(tile, expected, "tile {}_{}", column, row);
+            }
+        }
+        assert!(!level_dir.join("3_0.png").exists());
+        assert!(target.join("image_files/0/0_0.png").exists());
+        assert!(target.join("image.dzi").exists());
+    }
+}
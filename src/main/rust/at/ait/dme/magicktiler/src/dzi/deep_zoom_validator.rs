@@ -0,0 +1,335 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::Rgba;
+
+use super::deep_zoom_tiler::{
+    level_dimensions, max_level, tile_region, DESCRIPTOR_EXTENSION, FILES_DIR_SUFFIX,
+};
+use crate::tiling_scheme::{tile_format, TilesetMetadata};
+use crate::validation_failed_exception::ValidationFailedError;
+use crate::validation_report::{Finding, FindingCategory, ValidationReport};
+use crate::validator::{check_tile_content, Validator, DEFAULT_BACKGROUND};
+
+/// Validator for the Deep Zoom (DZI) tiling scheme.
+pub struct DeepZoomValidator {
+    /// Decode every tile and check its dimensions, including the overlap
+    deep: bool,
+
+    /// The background colour blank tiles are made of
+    background: Rgba<u8>,
+}
+
+/// The tileset parameters declared in a .dzi descriptor
+struct Descriptor {
+    format: String,
+    overlap: i32,
+    tile_size: i32,
+    width: i32,
+    height: i32,
+}
+
+impl DeepZoomValidator {
+    pub fn new() -> Self {
+        Self {
+            deep: false,
+            background: DEFAULT_BACKGROUND,
+        }
+    }
+
+    fn find_descriptor(&self, dir: &Path) -> Option<PathBuf> {
+        fs::read_dir(dir)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|p| {
+                p.is_file()
+                    && p.extension()
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case(DESCRIPTOR_EXTENSION))
+            })
+    }
+
+    fn parse_descriptor(&self, xml: &str) -> Result<Descriptor, ValidationFailedError> {
+        // Helper function to extract an attribute value between quotes
+        let extract_value = |attr: &str| -> Result<String, ValidationFailedError> {
+            let start = xml
+                .find(&format!("{}=\"", attr))
+                .ok_or_else(|| ValidationFailedError::new(format!("Missing attribute {}", attr)))?
+                + attr.len()
+                + 2;
+            let end = xml[start..]
+                .find('"')
+                .ok_or_else(|| ValidationFailedError::new("Missing closing quote"))?;
+            Ok(xml[start..start + end].to_string())
+        };
+        let extract_number = |attr: &str| -> Result<i32, ValidationFailedError> {
+            extract_value(attr)?.parse::<i32>().map_err(|e| {
+                ValidationFailedError::new(format!("Invalid number for {}: {}", attr, e))
+            })
+        };
+
+        let descriptor = Descriptor {
+            format: extract_value("Format")?,
+            overlap: extract_number("Overlap")?,
+            tile_size: extract_number("TileSize")?,
+            width: extract_number("Width")?,
+            height: extract_number("Height")?,
+        };
+
+        if descriptor.tile_size <= 0 || descriptor.width <= 0 || descriptor.height <= 0 {
+            return Err(ValidationFailedError::new(
+                "Invalid tile size or image dimensions in DZI descriptor",
+            ));
+        }
+
+        Ok(descriptor)
+    }
+
+    fn check_levels(
+        &self,
+        descriptor: &Descriptor,
+        files_dir: &Path,
+        report: &mut ValidationReport,
+    ) {
+        let max_level = max_level(descriptor.width, descriptor.height);
+        let files_dir_name = PathBuf::from(files_dir.file_name().unwrap());
+
+        for level in 0..=max_level {
+            let level_dir = files_dir.join(level.to_string());
+            if !level_dir.is_dir() {
+                report.add(
+                    Finding::error(
+                        FindingCategory::MissingTile,
+                        format!("Missing directory for level {}", level),
+                    )
+                    .at_zoom(level)
+                    .with_path(files_dir_name.join(level.to_string())),
+                );
+                continue;
+            }
+
+            let (width, height) = level_dimensions(descriptor.width, descriptor.height, level);
+            let columns = (width as f64 / descriptor.tile_size as f64).ceil() as i32;
+            let rows = (height as f64 / descriptor.tile_size as f64).ceil() as i32;
+
+            for row in 0..rows {
+                for column in 0..columns {
+                    let tile = format!("{}_{}.{}", column, row, descriptor.format);
+                    if !level_dir.join(&tile).exists() {
+                        report.add(
+                            Finding::error(
+                                FindingCategory::MissingTile,
+                                format!("Missing tile: {}/{}", level, tile),
+                            )
+                            .at_tile(level, column, row)
+                            .with_path(files_dir_name.join(level.to_string()).join(tile)),
+                        );
+                    } else if self.deep {
+                        let (_, _, tile_width, tile_height) = tile_region(
+                            width,
+                            height,
+                            descriptor.tile_size,
+                            descriptor.overlap,
+                            column,
+                            row,
+                        );
+                        check_tile_content(
+                            files_dir.parent().unwrap(),
+                            &files_dir_name.join(level.to_string()).join(&tile),
+                            tile_width,
+                            tile_height,
+                            0,
+                            None,
+                            self.background,
+                            Some((level, column, row)),
+                            report,
+                        );
+                    }
+                }
+            }
+        }
+
+        if files_dir.join((max_level + 1).to_string()).exists() {
+            report.add(
+                Finding::error(
+                    FindingCategory::ExtraFile,
+                    format!(
+                        "Unexpected level {} - the tileset has only {} levels",
+                        max_level + 1,
+                        max_level + 1
+                    ),
+                )
+                .at_zoom(max_level + 1)
+                .with_path(files_dir_name.join((max_level + 1).to_string())),
+            );
+        }
+    }
+
+    fn check(
+        &self,
+        dir: &Path,
+        report: &mut ValidationReport,
+    ) -> Result<(), ValidationFailedError> {
+        let descriptor_path = self.find_descriptor(dir).ok_or_else(|| {
+            ValidationFailedError::new("Not a Deep Zoom tileset - missing .dzi descriptor")
+        })?;
+
+        let xml = fs::read_to_string(&descriptor_path)?;
+        let descriptor = self.parse_descriptor(&xml)?;
+
+        let name = descriptor_path
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let files_dir = dir.join(format!("{}{}", name, FILES_DIR_SUFFIX));
+        if !files_dir.is_dir() {
+            return Err(ValidationFailedError::new(format!(
+                "Missing tile directory {}{}",
+                name, FILES_DIR_SUFFIX
+            )));
+        }
+
+        if descriptor.overlap < 0 {
+            report.add(
+                Finding::error(FindingCategory::MetadataMismatch, "Negative tile overlap")
+                    .with_path(descriptor_path.file_name().unwrap()),
+            );
+        }
+
+        self.check_levels(&descriptor, &files_dir, report);
+        Ok(())
+    }
+}
+
+impl Default for DeepZoomValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Validator for DeepZoomValidator {
+    fn is_tileset_dir(&self, dir: &Path) -> bool {
+        dir.is_dir() && self.find_descriptor(dir).is_some()
+    }
+
+    fn read_metadata(&self, dir: &Path) -> Result<TilesetMetadata, ValidationFailedError> {
+        let descriptor_path = self.find_descriptor(dir).ok_or_else(|| {
+            ValidationFailedError::new("Not a Deep Zoom tileset - missing .dzi descriptor")
+        })?;
+        let descriptor = self.parse_descriptor(&fs::read_to_string(descriptor_path)?)?;
+        Ok(TilesetMetadata::new(
+            descriptor.width,
+            descriptor.height,
+            descriptor.tile_size,
+            descriptor.tile_size,
+            max_level(descriptor.width, descriptor.height) + 1,
+            tile_format(&descriptor.format)?,
+        ))
+    }
+
+    fn set_deep(&mut self, deep: bool) {
+        self.deep = deep;
+    }
+
+    fn set_background_color(&mut self, color: Rgba<u8>) {
+        self.background = color;
+    }
+
+    fn report(&self, dir: &Path) -> ValidationReport {
+        let mut report = ValidationReport::new(dir);
+        if let Err(e) = self.check(dir, &mut report) {
+            report.add(Finding::error(
+                FindingCategory::InvalidTileset,
+                e.to_string(),
+            ));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dzi::DeepZoomTiler;
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+    use crate::MagickTiler;
+
+    /// Tiles a 300x200 image with 128 pixel tiles and an overlap of 1
+    fn tileset(dir: &Path) -> PathBuf {
+        let image = dir.join("image.png");
+        image::RgbImage::from_fn(300, 200, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(&image)
+            .unwrap();
+        let mut tiler = DeepZoomTiler::new();
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_format(ImageFormat::PNG);
+        tiler.base_mut().set_working_directory(dir);
+        tiler.base_mut().set_generate_preview_html(false);
+        tiler.set_tile_size(128);
+        let target = dir.join("tiles");
+        tiler.convert_to(&image, &target).unwrap();
+        target
+    }
+
+    fn deep_validator() -> DeepZoomValidator {
+        let mut validator = DeepZoomValidator::new();
+        validator.set_deep(true);
+        validator
+    }
+
+    #[test]
+    fn tiled_images_are_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        let report = deep_validator().report(&tiles);
+        assert!(report.is_valid(), "{:?}", report.findings());
+
+        let metadata = DeepZoomValidator::new().read_metadata(&tiles).unwrap();
+        assert_eq!(
+            metadata,
+            TilesetMetadata::new(300, 200, 128, 128, 10, ImageFormat::PNG)
+        );
+    }
+
+    #[test]
+    fn missing_tiles_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        fs::remove_file(tiles.join("image_files/9/1_1.png")).unwrap();
+
+        let report = DeepZoomValidator::new().report(&tiles);
+        let findings = report.findings();
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        assert_eq!(findings[0].category(), FindingCategory::MissingTile);
+        assert_eq!(
+            (findings[0].zoom(), findings[0].column(), findings[0].row()),
+            (Some(9), Some(1), Some(1))
+        );
+        assert_eq!(findings[0].path(), Some(Path::new("image_files/9/1_1.png")));
+    }
+
+    #[test]
+    fn wrongly_sized_tiles_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        // Without the overlap towards its neighbours
+        image::RgbImage::from_fn(128, 128, |x, y| image::Rgb([x as u8, y as u8, 1]))
+            .save(tiles.join("image_files/9/1_0.png"))
+            .unwrap();
+
+        // Only deep validation decodes the tiles
+        assert!(DeepZoomValidator::new().report(&tiles).is_valid());
+        let report = deep_validator().report(&tiles);
+        let errors: Vec<_> = report.errors().collect();
+        assert_eq!(errors.len(), 1, "{:?}", report.findings());
+        assert_eq!(errors[0].category(), FindingCategory::WrongDimensions);
+        assert_eq!(
+            (errors[0].zoom(), errors[0].column(), errors[0].row()),
+            (Some(9), Some(1), Some(0))
+        );
+        assert!(errors[0].message().contains("128x128 instead of 130x129"));
+    }
+}
@@ -0,0 +1,5 @@
+mod deep_zoom_tiler;
+mod deep_zoom_validator;
+
+pub use deep_zoom_tiler::DeepZoomTiler;
+pub use deep_zoom_validator::DeepZoomValidator;
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+
+use crate::magick_tiler::TilingError;
+
+/// A geographical bounding box in WGS84 degrees.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    north: f64,
+    south: f64,
+    east: f64,
+    west: f64,
+}
+
+impl BoundingBox {
+    /// Creates a bounding box. Fails if the latitudes are out of range or
+    /// north lies south of south.
+    pub fn new(north: f64, south: f64, east: f64, west: f64) -> Result<Self, TilingError> {
+        if !(-90.0..=90.0).contains(&north) || !(-90.0..=90.0).contains(&south) {
+            return Err(TilingError::general(format!(
+                "Latitudes must be within [-90, 90], got north={} south={}",
+                north, south
+            )));
+        }
+        if !(-180.0..=180.0).contains(&east) || !(-180.0..=180.0).contains(&west) {
+            return Err(TilingError::general(format!(
+                "Longitudes must be within [-180, 180], got east={} west={}",
+                east, west
+            )));
+        }
+        if north <= south {
+            return Err(TilingError::general(format!(
+                "North ({}) must be greater than south ({})",
+                north, south
+            )));
+        }
+        if east == west {
+            return Err(TilingError::general(
+                "Bounding box has no longitudinal extent".to_string(),
+            ));
+        }
+        Ok(Self {
+            north,
+            south,
+            east,
+            west,
+        })
+    }
+
+    pub fn north(&self) -> f64 {
+        self.north
+    }
+
+    pub fn south(&self) -> f64 {
+        self.south
+    }
+
+    pub fn east(&self) -> f64 {
+        self.east
+    }
+
+    pub fn west(&self) -> f64 {
+        self.west
+    }
+
+    pub fn lat_extent(&self) -> f64 {
+        self.north - self.south
+    }
+
+    /// The longitudinal extent, measured eastwards from west to east. Boxes
+    /// crossing the antimeridian (east < west) are handled correctly.
+    pub fn lon_extent(&self) -> f64 {
+        if self.east > self.west {
+            self.east - self.west
+        } else {
+            self.east - self.west + 360.0
+        }
+    }
+
+    /// Returns the part of this box covered by a pixel rectangle of an image
+    /// of `width` x `height` pixels that spans the whole box. Pixel rows count
+    /// from the top. The rectangle may extend beyond the image, e.g. for
+    /// padded border tiles; latitudes beyond the poles are then clamped to
+    /// [-90, 90].
+    pub fn pixel_region(
+        &self,
+        width: i32,
+        height: i32,
+        x: f64,
+        y: f64,
+        region_width: f64,
+        region_height: f64,
+    ) -> BoundingBox {
+        let lon_per_pixel = self.lon_extent() / width as f64;
+        let lat_per_pixel = self.lat_extent() / height as f64;
+        let west = normalize_longitude(self.west + x * lon_per_pixel);
+        let east = normalize_longitude(self.west + (x + region_width) * lon_per_pixel);
+        BoundingBox {
+            north: (self.north - y * lat_per_pixel).min(90.0),
+            south: (self.north - (y + region_height) * lat_per_pixel).max(-90.0),
+            east,
+            west,
+        }
+    }
+}
+
+/// Wraps a longitude into the range [-180, 180].
+fn normalize_longitude(lon: f64) -> f64 {
+    if lon > 180.0 {
+        lon - 360.0
+    } else if lon < -180.0 {
+        lon + 360.0
+    } else {
+        lon
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_region_of_a_padded_tile_is_clamped_to_the_poles() {
+        let bbox = BoundingBox::new(80.0, -80.0, 180.0, -180.0).unwrap();
+        // 100 x 100 pixel image, tile reaching 50 pixels beyond the top
+        let region = bbox.pixel_region(100, 100, 0.0, -50.0, 50.0, 100.0);
+        assert_eq!(region.north(), 90.0);
+        assert_eq!(region.south(), 0.0);
+        assert_eq!(region.west(), -180.0);
+        assert_eq!(region.east(), 0.0);
+
+        let region = bbox.pixel_region(100, 100, 0.0, 50.0, 50.0, 100.0);
+        assert_eq!(region.north(), 0.0);
+        assert_eq!(region.south(), -90.0);
+    }
+
+    #[test]
+    fn pixel_region_within_the_image() {
+        let bbox = BoundingBox::new(50.0, 40.0, 20.0, 10.0).unwrap();
+        let region = bbox.pixel_region(200, 100, 100.0, 50.0, 50.0, 50.0);
+        assert_eq!(
+            (region.north(), region.south(), region.east(), region.west()),
+            (45.0, 40.0, 17.5, 15.0)
+        );
+    }
+}
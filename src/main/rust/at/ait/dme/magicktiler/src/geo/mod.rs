@@ -0,0 +1,3 @@
+mod bounding_box;
+
+pub use bounding_box::BoundingBox;
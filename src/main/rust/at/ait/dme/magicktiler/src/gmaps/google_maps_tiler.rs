@@ -1,27 +1,35 @@
-use std::fs::{self, File};
-use std::io::{self, Read, Write};
-use std::path::{Path, PathBuf};
+use std::fs::{self};
+use std::path::Path;
+use std::sync::Arc;
 
 use log::{debug, error, info};
 
-use crate::image::ImageProcessor;
 use crate::magick_tiler::{BaseMagickTiler, MagickTiler, TilingError};
+use crate::pyramid::build_stripe_pyramid;
+use crate::sink::{TileKey, TileSink};
 use crate::stripe::{Orientation, Stripe};
 use crate::tile_set_info::TileSetInfo;
+use crate::tiling_scheme::TilingScheme;
 
 /// A tiler that implements the Google Maps tiling scheme.
 ///
 /// The Google Maps tiling scheme arranges tiles in the following folder/file structure:
-/// /tileset-root/[zoomlevel]-[column]-[row].jpg (or .png)
+/// /tileset-root/[zoomlevel]-[column]-[row].jpg (or .png, .webp, .avif)
 ///
 /// The highest-resolution zoom level has the highest number. Column/row
 /// numbering of tiles starts top/left, counting direction is right/downwards.
+///
+/// The tileset is written through the configured `TileSink`, by default to
+/// the target directory.
 pub struct GoogleMapsTiler {
     base: BaseMagickTiler,
 }
 
 pub const METADATA_FILE: &str = "gmap_tileset.info";
 
+/// Name of the base image resized to a power of two, without extension
+const BASE_IMAGE: &str = "gmapbase";
+
 impl GoogleMapsTiler {
     pub fn new() -> Self {
         Self {
@@ -70,7 +78,7 @@ impl GoogleMapsTiler {
                 )
             };
 
-        self.base.stripe_image(
+        self.base.stripe_image_with_canvas(
             info.image_file(),
             orientation,
             stripes,
@@ -83,39 +91,79 @@ impl GoogleMapsTiler {
         )
     }
 
-    fn create_stripes_for_next_zoom_level(
+    fn merge_stripes(
         &self,
-        stripes: &[Stripe],
+        stripe1: &Stripe,
+        stripe2: Option<&Stripe>,
         base_file_name: &str,
         z: i32,
-    ) -> Result<Vec<Stripe>, TilingError> {
-        let base_name = self
+        idx: usize,
+    ) -> Result<Option<Stripe>, TilingError> {
+        let target_stripe = self
             .base
             .working_directory()
             .join(base_file_name)
-            .with_extension("");
-
-        let mut next_level = Vec::new();
-        for i in 0..((stripes.len() as f64 / 2.0).ceil() as usize) {
-            let target_stripe = base_name.with_extension(format!("{}-{}.tif", z, i));
-            let stripe1 = &stripes[i * 2];
-            let stripe2 = if i * 2 + 1 < stripes.len() {
-                Some(&stripes[i * 2 + 1])
+            .with_extension("")
+            .with_extension(format!("{}-{}.tif", z, idx));
+
+        // we should always have an even number of stripes
+        match stripe2 {
+            Some(s2) => Ok(Some(stripe1.merge(
+                s2,
+                &target_stripe,
+                self.base.processor(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Tiles a stripe into the working directory and puts the tiles into the
+    /// sink.
+    fn generate_gmap_tiles(
+        &self,
+        stripe: &Stripe,
+        sink: &dyn TileSink,
+        s: usize,
+        z: i32,
+        base_name: &str,
+    ) -> Result<(), TilingError> {
+        let format = self.base.processor().get_image_format();
+        let ext = format.extension();
+
+        // Stripes may be tiled concurrently, so the temporary files are named
+        // after the stripe
+        let tmp_prefix = format!("{}-tmp-{}-{}-", base_name, z, s);
+        let filename_pattern = self
+            .base
+            .working_directory()
+            .join(format!("{}%d.{}", tmp_prefix, ext));
+
+        self.base.processor().crop(
+            stripe.image_file(),
+            &filename_pattern,
+            self.base.tile_width(),
+            self.base.tile_height(),
+        )?;
+
+        let tiles = if stripe.orientation() == Orientation::Horizontal {
+            stripe.width() / self.base.tile_width()
+        } else {
+            stripe.height() / self.base.tile_height()
+        };
+
+        for t in 0..tiles {
+            let (column, row) = if stripe.orientation() == Orientation::Horizontal {
+                (t, s as i32)
             } else {
-                None
+                (s as i32, t)
             };
 
-            // we should always have an even number of stripes
-            if let Some(s2) = stripe2 {
-                let result = stripe1.merge(
-                    s2,
-                    &target_stripe,
-                    self.base.processor().get_image_processing_system(),
-                )?;
-                next_level.push(result);
-            }
+            let tile = filename_pattern.with_file_name(format!("{}{}.{}", tmp_prefix, t, ext));
+            let key = TileKey::new(TilingScheme::GoogleMaps, z, column, row, format);
+            sink.put_tile_file(&key, &tile)?;
         }
-        Ok(next_level)
+
+        Ok(())
     }
 
     fn resize_base_image(
@@ -166,7 +214,7 @@ impl GoogleMapsTiler {
         )
     }
 
-    fn generate_preview(&self, info: &TileSetInfo) -> Result<(), TilingError> {
+    fn generate_preview(&self, info: &TileSetInfo, sink: &dyn TileSink) -> Result<(), TilingError> {
         let template = include_str!("gmaps-template.html");
         let html = template
             .replace(
@@ -184,19 +232,40 @@ impl GoogleMapsTiler {
                     .to_string_lossy()
                     .replace('\\', "/"),
             )
-            .replace("@ext@", &info.tile_format().extension());
+            .replace("@ext@", info.tile_format().extension());
 
-        self.base.write_html_preview(&html)
+        sink.put_metadata("preview.html", html.as_bytes())
+    }
+}
+
+impl Default for GoogleMapsTiler {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl MagickTiler for GoogleMapsTiler {
+    fn base(&self) -> &BaseMagickTiler {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut BaseMagickTiler {
+        &mut self.base
+    }
+
+    fn set_tile_sink(&mut self, sink: Arc<dyn TileSink>) -> Result<(), TilingError> {
+        self.base.set_tile_sink(sink);
+        Ok(())
+    }
+
     fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError> {
-        self.base.convert(image)
+        let target = self.base.default_target();
+        self.convert_to(image, &target)
     }
 
     fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
-        self.base.convert_to(image, target)
+        let info = self.base.prepare(image, target)?;
+        self.convert_internal(image, info)
     }
 
     fn convert_internal(
@@ -210,103 +279,71 @@ impl MagickTiler for GoogleMapsTiler {
             image.file_name().unwrap().to_string_lossy()
         );
 
-        let mut all_stripes = Vec::new();
         let mut info = info;
+        let base_file_name = image.file_name().unwrap().to_string_lossy();
+        let base_name = image.file_stem().unwrap().to_string_lossy().into_owned();
+        let sink = self.base.tile_sink();
 
         debug!("Resizing base image");
         // Step 1: resize to the closest 256*n^2
-        let src = self.base.tileset_root_dir().unwrap().join(format!(
-            "gmapbase.{}",
+        let base_image = format!(
+            "{}.{}",
+            BASE_IMAGE,
             self.base.processor().get_image_format().extension()
-        ));
+        );
+        let src = self
+            .base
+            .working_directory()
+            .join(format!("{}-{}", base_name, base_image));
         info = self.resize_base_image(image, &info, &src)?;
 
+        self.base.check_cancelled()?;
         debug!("Striping base image");
         // Step 2: cut the image into stripes, thereby creating a squared result image
-        let mut stripes = self.stripe_base_image(&mut info)?;
-        all_stripes.extend(stripes.clone());
-
-        for z in (0..info.zoom_levels()).rev() {
-            debug!("Tiling level {}", z);
-            // Step 3: create the tiles for this zoom level
-            let tile_base = self.base.tileset_root_dir().unwrap().join(z.to_string());
-
-            for (s, stripe) in stripes.iter().enumerate() {
-                let filename_pattern = tile_base.with_extension(format!(
-                    "_%d.{}",
-                    self.base.processor().get_image_format().extension()
-                ));
-
-                self.base.processor().crop(
-                    stripe.image_file(),
-                    &filename_pattern,
-                    self.base.tile_width(),
-                    self.base.tile_height(),
-                )?;
-
-                let tiles = if stripe.orientation() == Orientation::Horizontal {
-                    stripe.width() / self.base.tile_width()
-                } else {
-                    stripe.height() / self.base.tile_height()
-                };
-
-                for t in 0..tiles {
-                    let (column, row) = if stripe.orientation() == Orientation::Horizontal {
-                        (t, s as i32)
-                    } else {
-                        (s as i32, t)
-                    };
-
-                    let old_name = filename_pattern.with_extension(format!(
-                        "_{}.{}",
-                        t,
-                        self.base.processor().get_image_format().extension()
-                    ));
-                    let new_name = tile_base.with_extension(format!(
-                        "_{}_{}_.{}",
-                        column,
-                        row,
-                        self.base.processor().get_image_format().extension()
-                    ));
-
-                    fs::rename(&old_name, &new_name).map_err(|e| {
-                        TilingError::General(format!(
-                            "Failed to rename file {}: {}",
-                            old_name.display(),
-                            e
-                        ))
-                    })?;
+        let stripes = self.stripe_base_image(&mut info)?;
+
+        // Step 3: create the tiles for each zoom level, merging stripes for the
+        // next level as soon as both of them are available
+        let progress = self.base.progress_tracker(info.total_number_of_tiles());
+        build_stripe_pyramid(
+            self.base.worker_threads(),
+            self.base.cancellation_token(),
+            stripes,
+            info.zoom_levels(),
+            |level, i, stripe1, stripe2| {
+                self.merge_stripes(stripe1, stripe2, &base_file_name, level, i)
+            },
+            |level, s, stripe| {
+                let z = info.zoom_levels() - level - 1;
+                if s == 0 {
+                    debug!("Tiling level {}", z);
                 }
-            }
-
-            stripes = self.create_stripes_for_next_zoom_level(
-                &stripes,
-                &image.file_name().unwrap().to_string_lossy(),
-                info.zoom_levels() - z,
-            )?;
-            all_stripes.extend(stripes.clone());
-        }
+                self.generate_gmap_tiles(stripe, sink.as_ref(), s, z, &base_name)?;
+                // The image was squared, so every stripe spans a full row or column
+                progress.tiles_done(info.number_of_x_tiles(level), z, s as i32);
+                Ok(())
+            },
+        )?;
 
         // Step 4: optionally create the preview.html
         if self.base.generate_preview() {
-            self.generate_preview(&info)?;
+            self.generate_preview(&info, sink.as_ref())?;
         }
 
-        // Step 5: write the metadata file
-        let metadata_path = self.base.tileset_root_dir().unwrap().join(METADATA_FILE);
-        let metadata = serde_json::to_string(&info)?;
-        fs::write(&metadata_path, metadata)?;
-
-        // Clean up
-        for stripe in all_stripes {
-            if let Err(e) = stripe.delete() {
-                error!(
-                    "Could not delete stripe {}: {}",
-                    stripe.image_file().display(),
-                    e
-                );
-            }
+        // Step 5: write the metadata file and the resized base image
+        info.set_image_file(&self.base.default_target().join(&base_image));
+        let metadata = serde_json::to_string(&info)
+            .map_err(|e| TilingError::general_at(METADATA_FILE, e.to_string()))?;
+        sink.put_metadata(METADATA_FILE, metadata.as_bytes())?;
+        let data = fs::read(&src).map_err(|source| TilingError::InputNotReadable {
+            path: src.clone(),
+            source,
+        })?;
+        sink.put_metadata(&base_image, &data)?;
+        if let Err(e) = fs::remove_file(&src) {
+            error!("Could not delete {}: {}", src.display(), e);
         }
+        sink.finalize()?;
 
         info!("Took {} ms", start_time.elapsed().as_millis());
         Ok(info)
@@ -1,23 +1,35 @@
 use std::fs::{self, File};
-use std::io::{self, Read};
-use std::path::{Path, PathBuf};
+use std::io::Read;
+use std::path::Path;
 
+use image::Rgba;
 use log::error;
 
 use super::google_maps_tiler::METADATA_FILE;
 use crate::tile_set_info::TileSetInfo;
+use crate::tiling_scheme::TilesetMetadata;
 use crate::validation_failed_exception::ValidationFailedError;
-use crate::validator::Validator;
+use crate::validation_report::{Finding, FindingCategory, ValidationReport};
+use crate::validator::{check_tile_content, Validator, DEFAULT_BACKGROUND};
 
 /// Validator for the Google Maps tiling scheme.
-pub struct GoogleMapsValidator;
+pub struct GoogleMapsValidator {
+    /// Decode every tile and check that it has the full tile size
+    deep: bool,
+
+    /// The background colour blank tiles are made of
+    background: Rgba<u8>,
+}
 
 impl GoogleMapsValidator {
     pub fn new() -> Self {
-        Self
+        Self {
+            deep: false,
+            background: DEFAULT_BACKGROUND,
+        }
     }
 
-    fn read_metadata(&self, dir: &Path) -> Result<TileSetInfo, ValidationFailedError> {
+    fn read_tileset_info(&self, dir: &Path) -> Result<TileSetInfo, ValidationFailedError> {
         let metadata_path = dir.join(METADATA_FILE);
         let mut metadata = String::new();
         File::open(&metadata_path)
@@ -35,51 +47,173 @@ impl GoogleMapsValidator {
     }
 }
 
+impl Default for GoogleMapsValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Validator for GoogleMapsValidator {
-    fn is_tileset_dir<P: AsRef<Path>>(&self, dir: P) -> bool {
-        if !dir.as_ref().is_dir() {
+    fn is_tileset_dir(&self, dir: &Path) -> bool {
+        if !dir.is_dir() {
             return false;
         }
 
-        fs::read_dir(dir.as_ref()).ok().map_or(false, |entries| {
+        fs::read_dir(dir).is_ok_and(|entries| {
             entries
                 .filter_map(|e| e.ok())
                 .any(|e| e.file_name() == METADATA_FILE)
         })
     }
 
-    fn validate<P: AsRef<Path>>(&self, dir: P) -> Result<(), ValidationFailedError> {
-        let dir = dir.as_ref();
+    fn read_metadata(&self, dir: &Path) -> Result<TilesetMetadata, ValidationFailedError> {
+        let info = self.read_tileset_info(dir)?;
+        Ok(TilesetMetadata::new(
+            info.image_width(),
+            info.image_height(),
+            info.tile_width(),
+            info.tile_height(),
+            info.zoom_levels(),
+            info.tile_format(),
+        )
+        .with_encoder_options(info.encoder_options().cloned()))
+    }
+
+    fn set_deep(&mut self, deep: bool) {
+        self.deep = deep;
+    }
+
+    fn set_background_color(&mut self, color: Rgba<u8>) {
+        self.background = color;
+    }
+
+    fn report(&self, dir: &Path) -> ValidationReport {
+        let mut report = ValidationReport::new(dir);
         if !self.is_tileset_dir(dir) {
-            return Err(ValidationFailedError::new(
+            report.add(Finding::error(
+                FindingCategory::InvalidTileset,
                 "Not a MagickTiler Google Maps tileset, validation cannot be continued.",
             ));
+            return report;
         }
 
-        let info = self.read_metadata(dir)?;
-        let mut files_verified = 0;
+        let info = match self.read_tileset_info(dir) {
+            Ok(info) => info,
+            Err(e) => {
+                report.add(
+                    Finding::error(FindingCategory::InvalidTileset, e.to_string())
+                        .with_path(METADATA_FILE),
+                );
+                return report;
+            }
+        };
 
         for z in 0..info.zoom_levels() {
             for x in 0..info.number_of_x_tiles(info.zoom_levels() - 1 - z) {
                 for y in 0..info.number_of_y_tiles(info.zoom_levels() - 1 - z) {
-                    let tile = format!("{}_{}_{}_{}", z, x, y, info.tile_format().extension());
+                    let tile = format!("{}_{}_{}.{}", z, x, y, info.tile_format().extension());
                     if !dir.join(&tile).exists() {
-                        return Err(ValidationFailedError::new(format!(
-                            "Files missing for zoom level {}",
-                            z
-                        )));
+                        report.add(
+                            Finding::error(
+                                FindingCategory::MissingTile,
+                                format!("Missing tile {} for zoom level {}", tile, z),
+                            )
+                            .at_tile(z, x, y)
+                            .with_path(tile),
+                        );
+                    } else if self.deep {
+                        // Google Maps tiles are always full-sized. The
+                        // metadata records only the squared image, so the
+                        // padding cannot be told apart from blank image areas.
+                        check_tile_content(
+                            dir,
+                            Path::new(&tile),
+                            info.tile_width(),
+                            info.tile_height(),
+                            0,
+                            Some((0, 0, 0, 0)),
+                            self.background,
+                            Some((z, x, y)),
+                            &mut report,
+                        );
                     }
-                    files_verified += 1;
                 }
             }
         }
 
-        if files_verified != info.total_number_of_tiles() {
-            return Err(ValidationFailedError::new(
-                "Not enough files generated for Tileset!",
-            ));
-        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::gmaps::GoogleMapsTiler;
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+    use crate::MagickTiler;
+
+    /// Tiles a 256x128 image, squared to 256x256, with 64 pixel tiles
+    fn tileset(dir: &Path) -> PathBuf {
+        let image = dir.join("image.png");
+        image::RgbImage::from_fn(256, 128, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(&image)
+            .unwrap();
+        let mut tiler = GoogleMapsTiler::new();
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_format(ImageFormat::PNG);
+        tiler.base_mut().set_tile_size(64);
+        tiler.base_mut().set_working_directory(dir);
+        tiler.base_mut().set_generate_preview_html(false);
+        let target = dir.join("tiles");
+        tiler.convert_to(&image, &target).unwrap();
+        target
+    }
+
+    #[test]
+    fn tiled_images_are_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        let mut validator = GoogleMapsValidator::new();
+        validator.set_deep(true);
+        let report = validator.report(&tiles);
+        // The padding above and below the image is not reported as blank
+        assert!(report.findings().is_empty(), "{:?}", report.findings());
+    }
+
+    #[test]
+    fn all_missing_tiles_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        fs::remove_file(tiles.join("0_0_0.png")).unwrap();
+        fs::remove_file(tiles.join("2_3_1.png")).unwrap();
+
+        let report = GoogleMapsValidator::new().report(&tiles);
+        let findings: Vec<_> = report
+            .findings()
+            .iter()
+            .map(|f| (f.category(), f.zoom(), f.column(), f.row()))
+            .collect();
+        assert_eq!(
+            findings,
+            [
+                (FindingCategory::MissingTile, Some(0), Some(0), Some(0)),
+                (FindingCategory::MissingTile, Some(2), Some(3), Some(1)),
+            ]
+        );
+    }
 
-        Ok(())
+    #[test]
+    fn unreadable_metadata_stops_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        fs::write(tiles.join(METADATA_FILE), "{").unwrap();
+        let report = GoogleMapsValidator::new().report(&tiles);
+        let categories: Vec<_> = report.findings().iter().map(|f| f.category()).collect();
+        assert_eq!(categories, [FindingCategory::InvalidTileset]);
+        assert_eq!(report.findings()[0].path(), Some(Path::new(METADATA_FILE)));
     }
 }
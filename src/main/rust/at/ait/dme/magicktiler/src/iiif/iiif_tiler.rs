@@ -0,0 +1,578 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+use serde_json::{json, Value};
+
+use crate::image::ImageFormat;
+use crate::magick_tiler::{BaseMagickTiler, MagickTiler, TilingError};
+use crate::pyramid::build_stripe_pyramid;
+use crate::streaming::LevelLayout;
+use crate::stripe::{scaled_size, Orientation, Stripe};
+use crate::tile_set_info::TileSetInfo;
+
+/// A tiler that generates a static IIIF Image API level-0 tileset, i.e. a
+/// layout that any web server can serve without an image server.
+///
+/// The tileset has the following folder/file structure:
+/// /tileset-root/info.json
+/// /tileset-root/[region]/[size]/0/default.jpg (or .png, .webp)
+/// /tileset-root/full/[size]/0/default.jpg (or .png, .webp)
+///
+/// where [region] is 'x,y,w,h' in full-resolution pixels and [size] is
+/// 'w,h' (API 3.0) or 'w,' (API 2.1). Tiles on the border may be irregularly
+/// sized. Only the smallest pyramid levels, which fit on a single tile, are
+/// additionally published as full-image sizes.
+pub struct IIIFTiler {
+    base: BaseMagickTiler,
+
+    /// The Image API version of the generated tileset
+    version: ImageApiVersion,
+
+    /// The URI the tileset will be published under
+    base_uri: Option<String>,
+}
+
+/// Supported IIIF Image API versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageApiVersion {
+    V2_1,
+    V3,
+}
+
+impl ImageApiVersion {
+    pub fn context(&self) -> &'static str {
+        match self {
+            ImageApiVersion::V2_1 => "http://iiif.io/api/image/2/context.json",
+            ImageApiVersion::V3 => "http://iiif.io/api/image/3/context.json",
+        }
+    }
+
+    /// Returns the API version declared by an info.json context URI.
+    pub fn from_context(context: &str) -> Option<Self> {
+        if context == ImageApiVersion::V2_1.context() {
+            Some(ImageApiVersion::V2_1)
+        } else if context == ImageApiVersion::V3.context() {
+            Some(ImageApiVersion::V3)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size path segment for an image of the given dimensions.
+    pub fn size(&self, width: i32, height: i32) -> String {
+        match self {
+            ImageApiVersion::V2_1 => format!("{},", width),
+            ImageApiVersion::V3 => format!("{},{}", width, height),
+        }
+    }
+}
+
+pub const INFO_JSON: &str = "info.json";
+pub const PROTOCOL: &str = "http://iiif.io/api/image";
+pub const FULL_REGION: &str = "full";
+
+/// The tile formats defined by the IIIF Image API
+const TILE_FORMATS: &[ImageFormat] = &[
+    ImageFormat::JPEG,
+    ImageFormat::PNG,
+    ImageFormat::TIFF,
+    ImageFormat::WebP,
+    ImageFormat::WebPLossless,
+];
+
+/// Returns the path of a tile (relative to the tileset root), given the image
+/// dimensions, tile size, scale factor and the tile's column and row.
+#[allow(clippy::too_many_arguments)]
+pub fn tile_path(
+    version: ImageApiVersion,
+    width: i32,
+    height: i32,
+    tile_width: i32,
+    tile_height: i32,
+    scale_factor: i32,
+    column: i32,
+    row: i32,
+    ext: &str,
+) -> PathBuf {
+    let x = column * tile_width * scale_factor;
+    let y = row * tile_height * scale_factor;
+    let w = (tile_width * scale_factor).min(width - x);
+    let h = (tile_height * scale_factor).min(height - y);
+    let (scaled_w, scaled_h) = tile_dimensions(
+        width,
+        height,
+        tile_width,
+        tile_height,
+        scale_factor,
+        column,
+        row,
+    );
+
+    image_path(
+        version,
+        &format!("{},{},{},{}", x, y, w, h),
+        scaled_w,
+        scaled_h,
+        ext,
+    )
+}
+
+/// Returns the size in pixels of a tile, given the same parameters as
+/// `tile_path`. Border tiles are cut from the scaled-down image, so their
+/// size follows the rounding of the pyramid rather than of the region.
+pub fn tile_dimensions(
+    width: i32,
+    height: i32,
+    tile_width: i32,
+    tile_height: i32,
+    scale_factor: i32,
+    column: i32,
+    row: i32,
+) -> (i32, i32) {
+    (
+        tile_width.min(scaled_size(width, scale_factor) - column * tile_width),
+        tile_height.min(scaled_size(height, scale_factor) - row * tile_height),
+    )
+}
+
+/// Returns the path of a full-image size (relative to the tileset root).
+pub fn full_image_path(version: ImageApiVersion, width: i32, height: i32, ext: &str) -> PathBuf {
+    image_path(version, FULL_REGION, width, height, ext)
+}
+
+fn image_path(
+    version: ImageApiVersion,
+    region: &str,
+    width: i32,
+    height: i32,
+    ext: &str,
+) -> PathBuf {
+    Path::new(region)
+        .join(version.size(width, height))
+        .join("0")
+        .join(format!("default.{}", ext))
+}
+
+impl IIIFTiler {
+    pub fn new() -> Self {
+        Self {
+            base: BaseMagickTiler::new(),
+            version: ImageApiVersion::V3,
+            base_uri: None,
+        }
+    }
+
+    pub fn api_version(&self) -> ImageApiVersion {
+        self.version
+    }
+
+    /// Sets the IIIF Image API version of the generated tileset (default: 3.0).
+    pub fn set_api_version(&mut self, version: ImageApiVersion) {
+        self.version = version;
+    }
+
+    /// Sets the URI under which the tileset will be published. It is used as
+    /// the image identifier in info.json. Defaults to the tileset root
+    /// directory.
+    pub fn set_base_uri<S: Into<String>>(&mut self, base_uri: S) {
+        self.base_uri = Some(base_uri.into());
+    }
+
+    fn generate_iiif_tiles(
+        &self,
+        stripe: &Stripe,
+        info: &TileSetInfo,
+        zoom_level: i32,
+        row: i32,
+    ) -> Result<(), TilingError> {
+        let root_dir = self.base.tileset_root_dir().unwrap();
+        let ext = info.tile_format().extension();
+        // Stripes may be tiled concurrently, so the temporary files are named
+        // after the stripe
+        let tmp_prefix = format!("tmp-{}-{}-", zoom_level, row);
+        let filename_pattern = root_dir.join(format!("{}%d.{}", tmp_prefix, ext));
+
+        self.base.processor().crop(
+            stripe.image_file(),
+            &filename_pattern,
+            info.tile_width(),
+            info.tile_height(),
+        )?;
+
+        // Move result files into place
+        let scale_factor = 2_i32.pow(zoom_level as u32);
+        for column in 0..info.number_of_x_tiles(zoom_level) {
+            let old_name = root_dir.join(format!("{}{}.{}", tmp_prefix, column, ext));
+            let new_name = root_dir.join(tile_path(
+                self.version,
+                info.image_width(),
+                info.image_height(),
+                info.tile_width(),
+                info.tile_height(),
+                scale_factor,
+                column,
+                row,
+                ext,
+            ));
+            let dir = new_name.parent().unwrap();
+            fs::create_dir_all(dir).map_err(TilingError::not_writable(dir))?;
+
+            fs::rename(&old_name, &new_name).map_err(TilingError::not_writable(&new_name))?;
+            self.publish_full_image(info, zoom_level, &new_name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Levels that fit on a single tile are also published as full-image size
+    fn publish_full_image(
+        &self,
+        info: &TileSetInfo,
+        zoom_level: i32,
+        tile: &Path,
+    ) -> Result<(), TilingError> {
+        if info.number_of_x_tiles(zoom_level) == 1 && info.number_of_y_tiles(zoom_level) == 1 {
+            let (width, height) = scaled_dimensions(info, zoom_level);
+            let ext = info.tile_format().extension();
+            let root_dir = self.base.tileset_root_dir().unwrap();
+            let full_image = root_dir.join(full_image_path(self.version, width, height, ext));
+            let dir = full_image.parent().unwrap();
+            fs::create_dir_all(dir).map_err(TilingError::not_writable(dir))?;
+            fs::copy(tile, &full_image).map_err(TilingError::not_writable(&full_image))?;
+        }
+        Ok(())
+    }
+
+    /// Computes the pyramid in-process while streaming the image.
+    fn stream_iiif_tiles(&self, image: &Path, info: &TileSetInfo) -> Result<(), TilingError> {
+        let root_dir = self.base.tileset_root_dir().unwrap();
+        let progress = self.base.progress_tracker(info.total_number_of_tiles());
+        let layouts = LevelLayout::unpadded(
+            info.image_width() as u32,
+            info.image_height() as u32,
+            info.zoom_levels() as usize,
+        );
+        self.base
+            .stream_pyramid(image, layouts, |level, column, row, tile| {
+                let zoom_level = level as i32;
+                let target = root_dir.join(tile_path(
+                    self.version,
+                    info.image_width(),
+                    info.image_height(),
+                    info.tile_width(),
+                    info.tile_height(),
+                    2_i32.pow(level as u32),
+                    column as i32,
+                    row as i32,
+                    info.tile_format().extension(),
+                ));
+                let dir = target.parent().unwrap();
+                fs::create_dir_all(dir).map_err(TilingError::not_writable(dir))?;
+                self.base.write_tile(tile, &target)?;
+                self.publish_full_image(info, zoom_level, &target)?;
+                progress.tiles_done(1, zoom_level, row as i32);
+                Ok(())
+            })
+    }
+
+    fn merge_stripes(
+        &self,
+        stripe1: &Stripe,
+        stripe2: Option<&Stripe>,
+        target_file: &Path,
+    ) -> Result<Stripe, TilingError> {
+        match stripe2 {
+            None => Ok(stripe1.shrink(target_file, self.base.processor())?),
+            Some(s2) => Ok(stripe1.merge(s2, target_file, self.base.processor())?),
+        }
+    }
+
+    fn generate_info_json(&self, info: &TileSetInfo) -> Result<(), TilingError> {
+        let root_dir = self.base.tileset_root_dir().unwrap();
+        let id = self
+            .base_uri
+            .clone()
+            .unwrap_or_else(|| root_dir.to_string_lossy().replace('\\', "/"));
+
+        let scale_factors: Vec<i32> = (0..info.zoom_levels())
+            .map(|z| 2_i32.pow(z as u32))
+            .collect();
+        let sizes: Vec<Value> = (0..info.zoom_levels())
+            .rev()
+            .filter(|z| info.number_of_x_tiles(*z) == 1 && info.number_of_y_tiles(*z) == 1)
+            .map(|z| {
+                let (width, height) = scaled_dimensions(info, z);
+                json!({ "width": width, "height": height })
+            })
+            .collect();
+        let tiles = json!([{
+            "width": info.tile_width(),
+            "height": info.tile_height(),
+            "scaleFactors": scale_factors,
+        }]);
+
+        // Level 0 only mandates JPEG, other formats must be declared
+        let extra_formats: Vec<&str> = match info.tile_format() {
+            ImageFormat::JPEG => vec![],
+            format => vec![format.extension()],
+        };
+
+        let info_json = match self.version {
+            ImageApiVersion::V2_1 => {
+                let mut profile = vec![json!("http://iiif.io/api/image/2/level0.json")];
+                if !extra_formats.is_empty() {
+                    profile.push(json!({ "formats": extra_formats }));
+                }
+                json!({
+                    "@context": self.version.context(),
+                    "@id": id,
+                    "protocol": PROTOCOL,
+                    "width": info.image_width(),
+                    "height": info.image_height(),
+                    "sizes": sizes,
+                    "tiles": tiles,
+                    "profile": profile,
+                })
+            }
+            ImageApiVersion::V3 => {
+                let mut info_json = json!({
+                    "@context": self.version.context(),
+                    "id": id,
+                    "type": "ImageService3",
+                    "protocol": PROTOCOL,
+                    "profile": "level0",
+                    "width": info.image_width(),
+                    "height": info.image_height(),
+                    "sizes": sizes,
+                    "tiles": tiles,
+                });
+                if !extra_formats.is_empty() {
+                    info_json["preferredFormats"] = json!(extra_formats);
+                    info_json["extraFormats"] = json!(extra_formats);
+                }
+                info_json
+            }
+        };
+
+        let info_json_path = root_dir.join(INFO_JSON);
+        let info_json = serde_json::to_string_pretty(&info_json)
+            .map_err(|e| TilingError::general_at(&info_json_path, e.to_string()))?;
+        fs::write(&info_json_path, info_json)
+            .map_err(TilingError::not_writable(&info_json_path))?;
+        Ok(())
+    }
+
+    fn generate_preview(&self, info: &TileSetInfo) -> Result<(), TilingError> {
+        let template = include_str!("iiif-template.html");
+        let html = template
+            .replace(
+                "@title@",
+                &info.image_file().file_name().unwrap().to_string_lossy(),
+            )
+            .replace("@infojson@", INFO_JSON);
+
+        self.base.write_html_preview(&html)
+    }
+}
+
+impl Default for IIIFTiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Image dimensions at the given zoom level (0 = full resolution).
+fn scaled_dimensions(info: &TileSetInfo, zoom_level: i32) -> (i32, i32) {
+    let factor = 2_i32.pow(zoom_level as u32);
+    (
+        scaled_size(info.image_width(), factor),
+        scaled_size(info.image_height(), factor),
+    )
+}
+
+impl MagickTiler for IIIFTiler {
+    fn base(&self) -> &BaseMagickTiler {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut BaseMagickTiler {
+        &mut self.base
+    }
+
+    fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError> {
+        let target = self.base.default_target();
+        self.convert_to(image, &target)
+    }
+
+    fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
+        self.base.check_tile_format(image, "IIIF", TILE_FORMATS)?;
+        let info = self.base.prepare(image, target)?;
+        self.convert_internal(image, info)
+    }
+
+    fn convert_internal(
+        &mut self,
+        image: &Path,
+        info: TileSetInfo,
+    ) -> Result<TileSetInfo, TilingError> {
+        let start_time = std::time::Instant::now();
+        info!(
+            "Generating IIIF tiles for file {}: {}x{}, {}x{} basetiles, {} zoom levels, {} tiles total",
+            image.file_name().unwrap().to_string_lossy(),
+            info.image_width(),
+            info.image_height(),
+            info.number_of_x_tiles(0),
+            info.number_of_y_tiles(0),
+            info.zoom_levels(),
+            info.total_number_of_tiles()
+        );
+
+        let base_name = image.file_stem().unwrap().to_string_lossy().into_owned();
+
+        if self.base.streaming() {
+            // Steps 1 and 2 - stream the image through the pyramid
+            self.stream_iiif_tiles(image, &info)?;
+        } else {
+            // Step 1 - stripe the base image
+            self.base.check_cancelled()?;
+            debug!("Striping base image");
+            let base_stripes = self.base.stripe_image(
+                image,
+                Orientation::Horizontal,
+                info.number_of_y_tiles(0),
+                info.image_width(),
+                self.base.tile_height(),
+                &format!("{}-0-", base_name),
+            )?;
+
+            // Step 2 - tile base image stripes and compute the pyramid
+            let progress = self.base.progress_tracker(info.total_number_of_tiles());
+            build_stripe_pyramid(
+                self.base.worker_threads(),
+                self.base.cancellation_token(),
+                base_stripes,
+                info.zoom_levels(),
+                |level, j, stripe1, stripe2| {
+                    let target_file = self
+                        .base
+                        .working_directory()
+                        .join(format!("{}-{}-{}.tif", base_name, level, j));
+                    Ok(Some(self.merge_stripes(stripe1, stripe2, &target_file)?))
+                },
+                |level, j, stripe| {
+                    if j == 0 {
+                        debug!("Tiling level {}", level + 1);
+                    }
+                    self.generate_iiif_tiles(stripe, &info, level, j as i32)?;
+                    progress.tiles_done(info.number_of_x_tiles(level), level, j as i32);
+                    Ok(())
+                },
+            )?;
+        }
+
+        // Step 3 - generate info.json
+        self.generate_info_json(&info)?;
+
+        // Step 4 (optional) - generate OpenSeadragon preview
+        if self.base.generate_preview() {
+            self.generate_preview(&info)?;
+        }
+
+        info!("Took {} ms", start_time.elapsed().as_millis());
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageProcessingSystem;
+
+    /// Tiles an odd-sized image and checks every tile against the size in
+    /// its path, and the full-image sizes against info.json.
+    fn check_odd_sized_image(streaming: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("odd.png");
+        image::RgbImage::from_fn(301, 177, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(&image)
+            .unwrap();
+
+        let mut tiler = IIIFTiler::new();
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_format(ImageFormat::PNG);
+        tiler.base_mut().set_tile_size(64);
+        tiler.base_mut().set_working_directory(dir.path());
+        tiler.base_mut().set_generate_preview_html(false);
+        tiler.base_mut().set_streaming(streaming);
+        let root = dir.path().join("tiles");
+        let info = tiler.convert_to(&image, &root).unwrap();
+        assert_eq!(info.zoom_levels(), 4);
+
+        let mut widths = vec![];
+        for zoom_level in 0..info.zoom_levels() {
+            let scale_factor = 2_i32.pow(zoom_level as u32);
+            let mut width = 0;
+            for row in 0..info.number_of_y_tiles(zoom_level) {
+                for column in 0..info.number_of_x_tiles(zoom_level) {
+                    let path = tile_path(
+                        ImageApiVersion::V3,
+                        301,
+                        177,
+                        64,
+                        64,
+                        scale_factor,
+                        column,
+                        row,
+                        "png",
+                    );
+                    let size = path.iter().nth(1).unwrap().to_string_lossy().into_owned();
+                    let tile = image::open(root.join(&path)).unwrap();
+                    assert_eq!(format!("{},{}", tile.width(), tile.height()), size);
+                    if row == 0 {
+                        width += tile.width();
+                    }
+                }
+            }
+            widths.push(width);
+        }
+        assert_eq!(widths, [301, 151, 76, 38]);
+
+        let info_json: Value =
+            serde_json::from_slice(&fs::read(root.join(INFO_JSON)).unwrap()).unwrap();
+        for size in info_json["sizes"].as_array().unwrap() {
+            let (width, height) = (
+                size["width"].as_i64().unwrap(),
+                size["height"].as_i64().unwrap(),
+            );
+            let full = root.join(full_image_path(
+                ImageApiVersion::V3,
+                width as i32,
+                height as i32,
+                "png",
+            ));
+            let full = image::open(full).unwrap();
+            assert_eq!((full.width() as i64, full.height() as i64), (width, height));
+        }
+    }
+
+    #[test]
+    fn odd_sized_image_with_stripes() {
+        check_odd_sized_image(false);
+    }
+
+    #[test]
+    fn odd_sized_image_streaming() {
+        check_odd_sized_image(true);
+    }
+
+    #[test]
+    fn tile_dimensions_follow_the_pyramid() {
+        // 301 -> 151 -> 76 -> 38 pixels
+        assert_eq!(scaled_size(301, 8), 38);
+        assert_eq!(tile_dimensions(301, 177, 64, 64, 1, 4, 2), (45, 49));
+        assert_eq!(tile_dimensions(301, 177, 64, 64, 4, 1, 0), (12, 45));
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use image::Rgba;
+use log::error;
+use serde_json::Value;
+
+use super::iiif_tiler::{
+    full_image_path, tile_dimensions, tile_path, ImageApiVersion, FULL_REGION, INFO_JSON,
+};
+use crate::tiling_scheme::{tile_format, TilesetMetadata};
+use crate::validation_failed_exception::ValidationFailedError;
+use crate::validation_report::{Finding, FindingCategory, ValidationReport};
+use crate::validator::{check_tile_content, Validator, DEFAULT_BACKGROUND};
+
+/// Validator for static IIIF Image API level-0 tilesets. Checks the tiles
+/// and sizes declared in info.json against the files on disk.
+pub struct IIIFValidator {
+    /// Decode every tile and full-image size and check its dimensions
+    deep: bool,
+
+    /// The background colour blank tiles are made of
+    background: Rgba<u8>,
+}
+
+/// The tileset parameters declared in info.json
+struct ImageInformation {
+    version: ImageApiVersion,
+    width: i32,
+    height: i32,
+    tile_width: i32,
+    tile_height: i32,
+    scale_factors: Vec<i32>,
+    sizes: Vec<(i32, i32)>,
+}
+
+impl IIIFValidator {
+    pub fn new() -> Self {
+        Self {
+            deep: false,
+            background: DEFAULT_BACKGROUND,
+        }
+    }
+
+    fn read_info_json(&self, dir: &Path) -> Result<ImageInformation, ValidationFailedError> {
+        let json = fs::read_to_string(dir.join(INFO_JSON))
+            .map_err(|_| ValidationFailedError::new("info.json not found!"))?;
+        let json: Value = serde_json::from_str(&json).map_err(|e| {
+            error!("Could not parse info.json: {}", e);
+            ValidationFailedError::new("Failed to parse info.json")
+        })?;
+
+        let number = |value: &Value, field: &str| -> Result<i32, ValidationFailedError> {
+            value[field]
+                .as_i64()
+                .map(|n| n as i32)
+                .filter(|n| *n > 0)
+                .ok_or_else(|| {
+                    ValidationFailedError::new(format!(
+                        "Missing or invalid '{}' in info.json",
+                        field
+                    ))
+                })
+        };
+
+        let version = json["@context"]
+            .as_str()
+            .and_then(ImageApiVersion::from_context)
+            .ok_or_else(|| ValidationFailedError::new("Unsupported IIIF Image API context"))?;
+
+        let tiles = json["tiles"]
+            .as_array()
+            .and_then(|tiles| tiles.first())
+            .ok_or_else(|| ValidationFailedError::new("No tiles declared in info.json"))?;
+        let tile_width = number(tiles, "width")?;
+        let tile_height = match tiles.get("height") {
+            Some(_) => number(tiles, "height")?,
+            None => tile_width,
+        };
+
+        let scale_factors = tiles["scaleFactors"]
+            .as_array()
+            .ok_or_else(|| ValidationFailedError::new("No scaleFactors declared in info.json"))?
+            .iter()
+            .map(|s| {
+                s.as_i64()
+                    .map(|s| s as i32)
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| ValidationFailedError::new("Invalid scale factor"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let sizes = json["sizes"]
+            .as_array()
+            .map(|sizes| {
+                sizes
+                    .iter()
+                    .map(|size| Ok((number(size, "width")?, number(size, "height")?)))
+                    .collect::<Result<Vec<_>, ValidationFailedError>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(ImageInformation {
+            version,
+            width: number(&json, "width")?,
+            height: number(&json, "height")?,
+            tile_width,
+            tile_height,
+            scale_factors,
+            sizes,
+        })
+    }
+
+    fn tile_extension(&self, dir: &Path) -> Result<String, ValidationFailedError> {
+        // The format is not part of info.json, so take it from the smallest full-image size
+        let full_dir = dir.join(FULL_REGION);
+        for entry in fs::read_dir(&full_dir)
+            .map_err(|_| ValidationFailedError::new("Missing 'full' region directory"))?
+        {
+            let quality_dir = entry?.path().join("0");
+            if let Ok(files) = fs::read_dir(&quality_dir) {
+                for file in files {
+                    let name = file?.file_name().to_string_lossy().into_owned();
+                    if let Some(ext) = name.strip_prefix("default.") {
+                        return Ok(ext.to_string());
+                    }
+                }
+            }
+        }
+        Err(ValidationFailedError::new(
+            "Could not determine the tile format - no full-image sizes found",
+        ))
+    }
+
+    fn check(
+        &self,
+        dir: &Path,
+        report: &mut ValidationReport,
+    ) -> Result<(), ValidationFailedError> {
+        if !self.is_tileset_dir(dir) {
+            return Err(ValidationFailedError::new(
+                "Not a IIIF tileset, validation cannot be continued.",
+            ));
+        }
+
+        let info = self.read_info_json(dir)?;
+        let ext = self.tile_extension(dir)?;
+        let mut regions = HashSet::new();
+        regions.insert(FULL_REGION.to_string());
+
+        for &scale_factor in &info.scale_factors {
+            let region_width = info.tile_width * scale_factor;
+            let region_height = info.tile_height * scale_factor;
+            let columns = (info.width as f64 / region_width as f64).ceil() as i32;
+            let rows = (info.height as f64 / region_height as f64).ceil() as i32;
+
+            for row in 0..rows {
+                for column in 0..columns {
+                    let tile = tile_path(
+                        info.version,
+                        info.width,
+                        info.height,
+                        info.tile_width,
+                        info.tile_height,
+                        scale_factor,
+                        column,
+                        row,
+                        &ext,
+                    );
+                    if !dir.join(&tile).is_file() {
+                        report.add(
+                            Finding::error(
+                                FindingCategory::MissingTile,
+                                format!(
+                                    "Missing tile for scale factor {}: {}",
+                                    scale_factor,
+                                    tile.display()
+                                ),
+                            )
+                            .at_tile(scale_factor, column, row)
+                            .with_path(&tile),
+                        );
+                    } else if self.deep {
+                        let (width, height) = tile_dimensions(
+                            info.width,
+                            info.height,
+                            info.tile_width,
+                            info.tile_height,
+                            scale_factor,
+                            column,
+                            row,
+                        );
+                        check_tile_content(
+                            dir,
+                            &tile,
+                            width,
+                            height,
+                            0,
+                            None,
+                            self.background,
+                            Some((scale_factor, column, row)),
+                            report,
+                        );
+                    }
+                    regions.insert(
+                        tile.components()
+                            .next()
+                            .unwrap()
+                            .as_os_str()
+                            .to_string_lossy()
+                            .into_owned(),
+                    );
+                }
+            }
+        }
+
+        for &(width, height) in &info.sizes {
+            let image = full_image_path(info.version, width, height, &ext);
+            if !dir.join(&image).is_file() {
+                report.add(
+                    Finding::error(
+                        FindingCategory::MissingTile,
+                        format!(
+                            "Missing full-image size {}x{}: {}",
+                            width,
+                            height,
+                            image.display()
+                        ),
+                    )
+                    .with_path(image),
+                );
+            } else if self.deep {
+                check_tile_content(
+                    dir,
+                    &image,
+                    width,
+                    height,
+                    0,
+                    None,
+                    self.background,
+                    None,
+                    report,
+                );
+            }
+        }
+
+        // Every region directory on disk must be accounted for by info.json
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.path().is_dir() && !regions.contains(&name) {
+                report.add(
+                    Finding::error(
+                        FindingCategory::ExtraFile,
+                        format!("Region {} is not declared in info.json", name),
+                    )
+                    .with_path(name),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for IIIFValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Validator for IIIFValidator {
+    fn is_tileset_dir(&self, dir: &Path) -> bool {
+        fs::read_to_string(dir.join(INFO_JSON)).is_ok_and(|json| {
+            serde_json::from_str::<Value>(&json).is_ok_and(|json| {
+                json["@context"]
+                    .as_str()
+                    .and_then(ImageApiVersion::from_context)
+                    .is_some()
+            })
+        })
+    }
+
+    fn read_metadata(&self, dir: &Path) -> Result<TilesetMetadata, ValidationFailedError> {
+        let info = self.read_info_json(dir)?;
+        Ok(TilesetMetadata::new(
+            info.width,
+            info.height,
+            info.tile_width,
+            info.tile_height,
+            info.scale_factors.len() as i32,
+            tile_format(&self.tile_extension(dir)?)?,
+        ))
+    }
+
+    fn set_deep(&mut self, deep: bool) {
+        self.deep = deep;
+    }
+
+    fn set_background_color(&mut self, color: Rgba<u8>) {
+        self.background = color;
+    }
+
+    fn report(&self, dir: &Path) -> ValidationReport {
+        let mut report = ValidationReport::new(dir);
+        if let Err(e) = self.check(dir, &mut report) {
+            report.add(Finding::error(
+                FindingCategory::InvalidTileset,
+                e.to_string(),
+            ));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::iiif::IIIFTiler;
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+    use crate::MagickTiler;
+
+    /// Tiles a 300x200 image with 128 pixel tiles
+    fn tileset(dir: &Path) -> PathBuf {
+        let image = dir.join("image.png");
+        image::RgbImage::from_fn(300, 200, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(&image)
+            .unwrap();
+        let mut tiler = IIIFTiler::new();
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_format(ImageFormat::PNG);
+        tiler.base_mut().set_tile_size(128);
+        tiler.base_mut().set_working_directory(dir);
+        tiler.base_mut().set_generate_preview_html(false);
+        let target = dir.join("tiles");
+        tiler.convert_to(&image, &target).unwrap();
+        target
+    }
+
+    #[test]
+    fn tiled_images_are_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        let mut validator = IIIFValidator::new();
+        validator.set_deep(true);
+        let report = validator.report(&tiles);
+        assert!(report.findings().is_empty(), "{:?}", report.findings());
+    }
+
+    #[test]
+    fn all_problems_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        let tile = tile_path(ImageApiVersion::V3, 300, 200, 128, 128, 1, 2, 1, "png");
+        fs::remove_file(tiles.join(&tile)).unwrap();
+        let other = tile_path(ImageApiVersion::V3, 300, 200, 128, 128, 2, 1, 0, "png");
+        fs::remove_file(tiles.join(&other)).unwrap();
+        fs::create_dir(tiles.join("0,0,1,1")).unwrap();
+
+        let report = IIIFValidator::new().report(&tiles);
+        let findings: Vec<_> = report
+            .findings()
+            .iter()
+            .map(|f| (f.category(), f.path().unwrap().to_path_buf()))
+            .collect();
+        assert_eq!(
+            findings,
+            [
+                (FindingCategory::MissingTile, tile),
+                (FindingCategory::MissingTile, other),
+                (FindingCategory::ExtraFile, PathBuf::from("0,0,1,1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_info_json_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        let mut info: Value =
+            serde_json::from_slice(&fs::read(tiles.join(INFO_JSON)).unwrap()).unwrap();
+        info["tiles"][0]["scaleFactors"] = serde_json::json!([1, 0]);
+        fs::write(tiles.join(INFO_JSON), info.to_string()).unwrap();
+
+        let report = IIIFValidator::new().report(&tiles);
+        let categories: Vec<_> = report.findings().iter().map(|f| f.category()).collect();
+        assert_eq!(categories, [FindingCategory::InvalidTileset]);
+        assert!(report.findings()[0]
+            .message()
+            .contains("Invalid scale factor"));
+    }
+}
@@ -0,0 +1,5 @@
+mod iiif_tiler;
+mod iiif_validator;
+
+pub use iiif_tiler::{IIIFTiler, ImageApiVersion};
+pub use iiif_validator::IIIFValidator;
@@ -0,0 +1,233 @@
+use serde::{Deserialize, Serialize};
+
+use super::image_format::ImageFormat;
+use super::tiled_tiff_writer::TiffCompression;
+
+/// Chroma subsampling of JPEG tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    /// Full colour resolution
+    #[serde(rename = "4:4:4")]
+    YCbCr444,
+    /// Half the horizontal colour resolution
+    #[serde(rename = "4:2:2")]
+    YCbCr422,
+    /// Half the horizontal and vertical colour resolution
+    #[default]
+    #[serde(rename = "4:2:0")]
+    YCbCr420,
+}
+
+impl ChromaSubsampling {
+    /// The value of the `-sampling-factor` option of GraphicsMagick and
+    /// ImageMagick
+    pub fn command_line_name(&self) -> &'static str {
+        match self {
+            ChromaSubsampling::YCbCr444 => "1x1",
+            ChromaSubsampling::YCbCr422 => "2x1",
+            ChromaSubsampling::YCbCr420 => "2x2",
+        }
+    }
+}
+
+impl std::fmt::Display for ChromaSubsampling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ChromaSubsampling::YCbCr444 => "4:4:4",
+            ChromaSubsampling::YCbCr422 => "4:2:2",
+            ChromaSubsampling::YCbCr420 => "4:2:0",
+        })
+    }
+}
+
+/// The settings tiles are encoded with. Each image processor applies the
+/// settings of the tile format it writes and ignores the others. Tilers
+/// record the settings in the `TileSetInfo` of a tileset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncoderOptions {
+    /// JPEG compression quality from 0 (bad quality) to 100 (maximum
+    /// quality), default=75
+    pub jpeg_quality: i32,
+
+    /// Write progressive instead of baseline JPEGs, default=false
+    pub jpeg_progressive: bool,
+
+    /// Chroma subsampling of JPEG tiles, default=4:2:0
+    pub chroma_subsampling: ChromaSubsampling,
+
+    /// zlib compression level of PNG tiles from 0 (fastest) to 9 (smallest
+    /// files), default=6
+    pub png_compression_level: i32,
+
+    /// Quantise PNG tiles to a palette of at most 256 colours,
+    /// default=false
+    pub png_palette: bool,
+
+    /// Bits per channel of PNG tiles, 8 or 16, default=8. Palette tiles
+    /// always use 8 bits.
+    pub png_bit_depth: u8,
+
+    /// Compression of TIFF tiles, default=None (uncompressed)
+    pub tiff_compression: Option<TiffCompression>,
+
+    /// Compression quality of lossy WebP tiles from 0 (bad quality) to 100
+    /// (maximum quality), default=80
+    pub webp_quality: i32,
+
+    /// Effort spent on compressing WebP tiles from 0 (fastest) to 6
+    /// (smallest files), default=4
+    pub webp_effort: i32,
+
+    /// Compression quality of AVIF tiles from 0 (bad quality) to 100
+    /// (maximum quality), default=60
+    pub avif_quality: i32,
+
+    /// Effort spent on compressing AVIF tiles from 0 (fastest) to 9
+    /// (smallest files), default=4
+    pub avif_effort: i32,
+}
+
+impl EncoderOptions {
+    /// Returns the options with every setting clamped to its valid range
+    pub fn clamped(self) -> Self {
+        Self {
+            jpeg_quality: self.jpeg_quality.clamp(0, 100),
+            png_compression_level: self.png_compression_level.clamp(0, 9),
+            png_bit_depth: if self.png_bit_depth > 8 { 16 } else { 8 },
+            webp_quality: self.webp_quality.clamp(0, 100),
+            webp_effort: self.webp_effort.clamp(0, 6),
+            avif_quality: self.avif_quality.clamp(0, 100),
+            avif_effort: self.avif_effort.clamp(0, 9),
+            ..self
+        }
+    }
+
+    /// A short description of the settings that apply to tiles of the given
+    /// format, e.g. "quality 75, 4:2:0, baseline"
+    pub fn describe(&self, format: ImageFormat) -> String {
+        match format {
+            ImageFormat::JPEG => format!(
+                "quality {}, {}, {}",
+                self.jpeg_quality,
+                self.chroma_subsampling,
+                if self.jpeg_progressive {
+                    "progressive"
+                } else {
+                    "baseline"
+                }
+            ),
+            ImageFormat::PNG if self.png_palette => {
+                format!("compression level {}, palette", self.png_compression_level)
+            }
+            ImageFormat::PNG => format!(
+                "compression level {}, {}-bit",
+                self.png_compression_level, self.png_bit_depth
+            ),
+            ImageFormat::TIFF => match self.tiff_compression {
+                Some(compression) => format!("{:?} compression", compression),
+                None => "uncompressed".to_string(),
+            },
+            ImageFormat::WebP => {
+                format!("quality {}, effort {}", self.webp_quality, self.webp_effort)
+            }
+            ImageFormat::WebPLossless => format!("lossless, effort {}", self.webp_effort),
+            ImageFormat::AVIF => {
+                format!("quality {}, effort {}", self.avif_quality, self.avif_effort)
+            }
+        }
+    }
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        Self {
+            jpeg_quality: 75,
+            jpeg_progressive: false,
+            chroma_subsampling: ChromaSubsampling::default(),
+            png_compression_level: 6,
+            png_palette: false,
+            png_bit_depth: 8,
+            tiff_compression: None,
+            webp_quality: 80,
+            webp_effort: 4,
+            avif_quality: 60,
+            avif_effort: 4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_range_settings_are_clamped() {
+        let options = EncoderOptions {
+            jpeg_quality: 120,
+            png_compression_level: -1,
+            png_bit_depth: 12,
+            webp_quality: -5,
+            webp_effort: 7,
+            avif_quality: 101,
+            avif_effort: 10,
+            ..EncoderOptions::default()
+        }
+        .clamped();
+        assert_eq!(
+            options,
+            EncoderOptions {
+                jpeg_quality: 100,
+                png_compression_level: 0,
+                png_bit_depth: 16,
+                webp_quality: 0,
+                webp_effort: 6,
+                avif_quality: 100,
+                avif_effort: 9,
+                ..EncoderOptions::default()
+            }
+        );
+        let defaults = EncoderOptions::default();
+        assert_eq!(defaults.clone().clamped(), defaults);
+    }
+
+    #[test]
+    fn settings_are_described_per_format() {
+        let options = EncoderOptions {
+            jpeg_progressive: true,
+            tiff_compression: Some(TiffCompression::Deflate),
+            ..EncoderOptions::default()
+        };
+        assert_eq!(
+            options.describe(ImageFormat::JPEG),
+            "quality 75, 4:2:0, progressive"
+        );
+        assert_eq!(
+            options.describe(ImageFormat::PNG),
+            "compression level 6, 8-bit"
+        );
+        assert_eq!(options.describe(ImageFormat::TIFF), "Deflate compression");
+        assert_eq!(options.describe(ImageFormat::WebP), "quality 80, effort 4");
+        assert_eq!(
+            options.describe(ImageFormat::WebPLossless),
+            "lossless, effort 4"
+        );
+        assert_eq!(options.describe(ImageFormat::AVIF), "quality 60, effort 4");
+    }
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        let options: EncoderOptions =
+            serde_json::from_str(r#"{"jpeg_quality": 90, "chroma_subsampling": "4:4:4"}"#).unwrap();
+        assert_eq!(
+            options,
+            EncoderOptions {
+                jpeg_quality: 90,
+                chroma_subsampling: ChromaSubsampling::YCbCr444,
+                ..EncoderOptions::default()
+            }
+        );
+        let json = serde_json::to_value(&options).unwrap();
+        assert_eq!(json["chroma_subsampling"], "4:4:4");
+    }
+}
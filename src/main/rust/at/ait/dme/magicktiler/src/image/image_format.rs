@@ -10,6 +10,12 @@ pub enum ImageFormat {
     PNG,
     /// TIFF format (image/tiff, .tif)
     TIFF,
+    /// Lossy WebP format (image/webp, .webp)
+    WebP,
+    /// Lossless WebP format (image/webp, .webp)
+    WebPLossless,
+    /// AVIF format (image/avif, .avif)
+    AVIF,
 }
 
 impl ImageFormat {
@@ -18,6 +24,8 @@ impl ImageFormat {
             ImageFormat::JPEG => "image/jpeg",
             ImageFormat::PNG => "image/png",
             ImageFormat::TIFF => "image/tiff",
+            ImageFormat::WebP | ImageFormat::WebPLossless => "image/webp",
+            ImageFormat::AVIF => "image/avif",
         }
     }
 
@@ -26,6 +34,21 @@ impl ImageFormat {
             ImageFormat::JPEG => "jpg",
             ImageFormat::PNG => "png",
             ImageFormat::TIFF => "tif",
+            ImageFormat::WebP | ImageFormat::WebPLossless => "webp",
+            ImageFormat::AVIF => "avif",
+        }
+    }
+
+    /// Returns the format for a file extension (case-insensitive), if it is
+    /// supported. WebP files are reported as lossy WebP.
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(ImageFormat::JPEG),
+            "png" => Some(ImageFormat::PNG),
+            "tif" | "tiff" => Some(ImageFormat::TIFF),
+            "webp" => Some(ImageFormat::WebP),
+            "avif" => Some(ImageFormat::AVIF),
+            _ => None,
         }
     }
 }
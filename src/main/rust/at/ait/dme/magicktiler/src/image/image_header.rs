@@ -0,0 +1,535 @@
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// The colour space of the pixel data stored in an image file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorSpace {
+    Gray,
+    RGB,
+    CMYK,
+    /// Palette images (PNG colour type 3, TIFF palette colour)
+    Indexed,
+    /// CIE L*a*b*
+    Lab,
+}
+
+/// Facts about an image read from its file header. Fields the format does not
+/// record, or that could not be parsed, are `None`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct ImageHeader {
+    pub bit_depth: Option<u8>,
+    pub channels: Option<u8>,
+    pub has_alpha: Option<bool>,
+    pub color_space: Option<ColorSpace>,
+    pub dpi: Option<(f64, f64)>,
+    pub orientation: Option<u16>,
+}
+
+// TIFF (and EXIF) tags
+const BITS_PER_SAMPLE: u16 = 258;
+const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+const ORIENTATION: u16 = 274;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const X_RESOLUTION: u16 = 282;
+const Y_RESOLUTION: u16 = 283;
+const RESOLUTION_UNIT: u16 = 296;
+const EXTRA_SAMPLES: u16 = 338;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Reads the header of a JPEG, PNG or TIFF file without decoding any pixel
+/// data. Other formats yield an empty header.
+pub(crate) fn read_header(path: &Path) -> io::Result<ImageHeader> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    let read = read_up_to(&mut reader, &mut magic)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    match &magic[..read] {
+        [0xFF, 0xD8, ..] => read_jpeg_header(&mut reader),
+        m if m == PNG_SIGNATURE => read_png_header(&mut reader),
+        [b'I', b'I', 42, 0, ..] | [b'M', b'M', 0, 42, ..] => read_tiff_header(&mut reader),
+        _ => Ok(ImageHeader::default()),
+    }
+}
+
+/// Reads the dimensions of an AVIF image from the first `ispe` (image
+/// spatial extents) property in its `meta` box, without decoding it.
+pub(crate) fn read_avif_dimensions(path: &Path) -> io::Result<(u32, u32)> {
+    // The meta box precedes the media data
+    let mut data = Vec::new();
+    File::open(path)?.take(64 * 1024).read_to_end(&mut data)?;
+    avif_dimensions(&data)
+}
+
+/// Reads the dimensions of an AVIF image held in memory, see
+/// `read_avif_dimensions`.
+pub(crate) fn avif_dimensions(data: &[u8]) -> io::Result<(u32, u32)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "No image extents in AVIF file");
+    // meta and ispe are full boxes, starting with version and flags
+    let ispe = find_box(data, b"meta")
+        .and_then(|meta| meta.get(4..))
+        .and_then(|meta| find_box(meta, b"iprp"))
+        .and_then(|iprp| find_box(iprp, b"ipco"))
+        .and_then(|ipco| find_box(ipco, b"ispe"))
+        .filter(|ispe| ispe.len() >= 12)
+        .ok_or_else(invalid)?;
+    let width = u32::from_be_bytes(ispe[4..8].try_into().unwrap());
+    let height = u32::from_be_bytes(ispe[8..12].try_into().unwrap());
+    Ok((width, height))
+}
+
+/// Returns the content of the first ISO base media file format box of the
+/// given type in a sequence of boxes.
+fn find_box<'a>(mut data: &'a [u8], box_type: &[u8; 4]) -> Option<&'a [u8]> {
+    while data.len() >= 8 {
+        let (header, size) = match u32::from_be_bytes(data[..4].try_into().unwrap()) {
+            // A 64-bit size follows the type
+            1 => (
+                16,
+                u64::from_be_bytes(data.get(8..16)?.try_into().unwrap()) as usize,
+            ),
+            // The box extends to the end of the data
+            0 => (8, data.len()),
+            size => (8, size as usize),
+        };
+        if size < header || size > data.len() {
+            return None;
+        }
+        if &data[4..8] == box_type {
+            return Some(&data[header..size]);
+        }
+        data = &data[size..];
+    }
+    None
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16_be<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32_be<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Walks the JPEG markers up to the start of the scan data.
+fn read_jpeg_header<R: Read + Seek>(reader: &mut R) -> io::Result<ImageHeader> {
+    let mut header = ImageHeader {
+        has_alpha: Some(false),
+        ..ImageHeader::default()
+    };
+    let mut jfif_dpi = None;
+    let mut exif = None;
+    let mut adobe = false;
+
+    reader.seek(SeekFrom::Start(2))?;
+    loop {
+        if read_u8(reader)? != 0xFF {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid JPEG marker",
+            ));
+        }
+        let mut marker = read_u8(reader)?;
+        while marker == 0xFF {
+            marker = read_u8(reader)?;
+        }
+        match marker {
+            // Start of scan or end of image: no more header segments
+            0xDA | 0xD9 => break,
+            // Standalone markers without a length
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+
+        let length = read_u16_be(reader)? as usize;
+        if length < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid JPEG segment length",
+            ));
+        }
+        let mut segment = vec![0u8; length - 2];
+        reader.read_exact(&mut segment)?;
+
+        match marker {
+            // Start of frame, except DHT (C4), JPG (C8) and DAC (CC)
+            0xC0..=0xCF
+                if marker != 0xC4 && marker != 0xC8 && marker != 0xCC && segment.len() >= 6 =>
+            {
+                let components = segment[5];
+                header.bit_depth = Some(segment[0]);
+                header.channels = Some(components);
+                header.color_space = match components {
+                    1 => Some(ColorSpace::Gray),
+                    3 => Some(ColorSpace::RGB),
+                    4 => Some(ColorSpace::CMYK),
+                    _ => None,
+                };
+            }
+            // APP0: JFIF density
+            0xE0 if segment.len() >= 12 && segment.starts_with(b"JFIF\0") => {
+                let x = u16::from_be_bytes([segment[8], segment[9]]) as f64;
+                let y = u16::from_be_bytes([segment[10], segment[11]]) as f64;
+                jfif_dpi = match segment[7] {
+                    1 => Some((x, y)),
+                    2 => Some((x * 2.54, y * 2.54)),
+                    // Aspect ratio only
+                    _ => None,
+                };
+            }
+            // APP1: EXIF
+            0xE1 if segment.starts_with(b"Exif\0\0") => {
+                exif = Some(segment[6..].to_vec());
+            }
+            // APP14: Adobe, which marks 4-component images as (inverted) CMYK
+            0xEE if segment.starts_with(b"Adobe") => {
+                adobe = true;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(exif) = exif {
+        // A broken EXIF block should not hide the frame information
+        if let Ok(tags) = read_tiff_header(&mut Cursor::new(exif)) {
+            header.orientation = tags.orientation;
+            header.dpi = tags.dpi;
+        }
+    }
+    if jfif_dpi.is_some() {
+        header.dpi = jfif_dpi;
+    }
+    if adobe && header.channels == Some(4) {
+        header.color_space = Some(ColorSpace::CMYK);
+    }
+
+    Ok(header)
+}
+
+/// Reads the PNG chunks up to the first image data chunk.
+fn read_png_header<R: Read + Seek>(reader: &mut R) -> io::Result<ImageHeader> {
+    let mut header = ImageHeader::default();
+    let mut color_type = None;
+
+    reader.seek(SeekFrom::Start(PNG_SIGNATURE.len() as u64))?;
+    loop {
+        let length = read_u32_be(reader)?;
+        let mut chunk_type = [0u8; 4];
+        reader.read_exact(&mut chunk_type)?;
+
+        match &chunk_type {
+            b"IHDR" => {
+                let mut data = [0u8; 13];
+                reader.read_exact(&mut data)?;
+                reader.seek(SeekFrom::Current(length as i64 - 13))?;
+                header.bit_depth = Some(data[8]);
+                color_type = Some(data[9]);
+                let (channels, has_alpha, color_space) = match data[9] {
+                    0 => (1, false, ColorSpace::Gray),
+                    2 => (3, false, ColorSpace::RGB),
+                    3 => (3, false, ColorSpace::Indexed),
+                    4 => (2, true, ColorSpace::Gray),
+                    6 => (4, true, ColorSpace::RGB),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Invalid PNG colour type",
+                        ))
+                    }
+                };
+                header.channels = Some(channels);
+                header.has_alpha = Some(has_alpha);
+                header.color_space = Some(color_space);
+            }
+            // Transparency for images without an alpha channel
+            b"tRNS" if matches!(color_type, Some(0 | 2 | 3)) => {
+                header.has_alpha = Some(true);
+                header.channels = header.channels.map(|c| c + 1);
+                reader.seek(SeekFrom::Current(length as i64))?;
+            }
+            b"pHYs" if length >= 9 => {
+                let x = read_u32_be(reader)? as f64;
+                let y = read_u32_be(reader)? as f64;
+                // Unit 1 is pixels per metre, 0 is aspect ratio only
+                if read_u8(reader)? == 1 {
+                    header.dpi = Some((x * 0.0254, y * 0.0254));
+                }
+                reader.seek(SeekFrom::Current(length as i64 - 9))?;
+            }
+            b"eXIf" => {
+                let mut exif = vec![0u8; length as usize];
+                reader.read_exact(&mut exif)?;
+                if let Ok(tags) = read_tiff_header(&mut Cursor::new(exif)) {
+                    header.orientation = tags.orientation;
+                }
+            }
+            b"IDAT" | b"IEND" => break,
+            _ => {
+                reader.seek(SeekFrom::Current(length as i64))?;
+            }
+        }
+        // Skip the CRC
+        reader.seek(SeekFrom::Current(4))?;
+    }
+
+    Ok(header)
+}
+
+/// A TIFF byte order
+#[derive(Clone, Copy)]
+enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrder {
+    fn u16(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            ByteOrder::LittleEndian => u16::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            ByteOrder::LittleEndian => u32::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn read_u16<R: Read>(self, reader: &mut R) -> io::Result<u16> {
+        let mut buf = [0u8; 2];
+        reader.read_exact(&mut buf)?;
+        Ok(self.u16(buf))
+    }
+
+    fn read_u32<R: Read>(self, reader: &mut R) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        Ok(self.u32(buf))
+    }
+}
+
+/// A directory entry of the first TIFF IFD
+struct IfdEntry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    /// The raw value field, holding either the value or its offset
+    value: [u8; 4],
+}
+
+// TIFF field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+
+impl IfdEntry {
+    /// The first value of a SHORT or LONG field
+    fn first_value<R: Read + Seek>(&self, reader: &mut R, order: ByteOrder) -> io::Result<u32> {
+        match self.field_type {
+            SHORT if self.count <= 2 => Ok(order.u16([self.value[0], self.value[1]]) as u32),
+            SHORT => {
+                reader.seek(SeekFrom::Start(order.u32(self.value) as u64))?;
+                Ok(order.read_u16(reader)? as u32)
+            }
+            LONG if self.count <= 1 => Ok(order.u32(self.value)),
+            LONG => {
+                reader.seek(SeekFrom::Start(order.u32(self.value) as u64))?;
+                order.read_u32(reader)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected TIFF field type",
+            )),
+        }
+    }
+
+    /// The value of a RATIONAL field, which is always stored at an offset
+    fn rational<R: Read + Seek>(&self, reader: &mut R, order: ByteOrder) -> io::Result<f64> {
+        if self.field_type != RATIONAL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected TIFF field type",
+            ));
+        }
+        reader.seek(SeekFrom::Start(order.u32(self.value) as u64))?;
+        let numerator = order.read_u32(reader)? as f64;
+        let denominator = order.read_u32(reader)? as f64;
+        Ok(if denominator == 0.0 {
+            0.0
+        } else {
+            numerator / denominator
+        })
+    }
+}
+
+/// Reads the tags of the first IFD of a TIFF file or EXIF block. Offsets are
+/// relative to the start of `reader`.
+fn read_tiff_header<R: Read + Seek>(reader: &mut R) -> io::Result<ImageHeader> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut byte_order = [0u8; 2];
+    reader.read_exact(&mut byte_order)?;
+    let order = match &byte_order {
+        b"II" => ByteOrder::LittleEndian,
+        b"MM" => ByteOrder::BigEndian,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid TIFF byte order",
+            ))
+        }
+    };
+    if order.read_u16(reader)? != 42 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid TIFF magic number",
+        ));
+    }
+
+    let ifd_offset = order.read_u32(reader)?;
+    reader.seek(SeekFrom::Start(ifd_offset as u64))?;
+    let entry_count = order.read_u16(reader)?;
+    let mut entries = Vec::with_capacity(entry_count as usize);
+    for _ in 0..entry_count {
+        let tag = order.read_u16(reader)?;
+        let field_type = order.read_u16(reader)?;
+        let count = order.read_u32(reader)?;
+        let mut value = [0u8; 4];
+        reader.read_exact(&mut value)?;
+        entries.push(IfdEntry {
+            tag,
+            field_type,
+            count,
+            value,
+        });
+    }
+
+    let mut header = ImageHeader::default();
+    let mut photometric = None;
+    let mut resolution = (None, None);
+    let mut resolution_unit = 2;
+    for entry in &entries {
+        match entry.tag {
+            BITS_PER_SAMPLE => {
+                header.bit_depth = Some(entry.first_value(reader, order)? as u8);
+            }
+            SAMPLES_PER_PIXEL => {
+                header.channels = Some(entry.first_value(reader, order)? as u8);
+            }
+            PHOTOMETRIC_INTERPRETATION => {
+                photometric = Some(entry.first_value(reader, order)?);
+            }
+            ORIENTATION => {
+                header.orientation = Some(entry.first_value(reader, order)? as u16);
+            }
+            EXTRA_SAMPLES => {
+                // 1 = associated alpha, 2 = unassociated alpha
+                let extra = entry.first_value(reader, order)?;
+                header.has_alpha = Some(extra == 1 || extra == 2);
+            }
+            X_RESOLUTION => resolution.0 = Some(entry.rational(reader, order)?),
+            Y_RESOLUTION => resolution.1 = Some(entry.rational(reader, order)?),
+            RESOLUTION_UNIT => resolution_unit = entry.first_value(reader, order)?,
+            _ => {}
+        }
+    }
+
+    if let Some(photometric) = photometric {
+        header.color_space = match photometric {
+            0 | 1 => Some(ColorSpace::Gray),
+            2 | 6 => Some(ColorSpace::RGB),
+            3 => Some(ColorSpace::Indexed),
+            5 => Some(ColorSpace::CMYK),
+            8 => Some(ColorSpace::Lab),
+            _ => None,
+        };
+        header.channels = header.channels.or(Some(1));
+        header.bit_depth = header.bit_depth.or(Some(1));
+        // Some writers omit ExtraSamples, so treat any sample beyond the
+        // colour channels as alpha, like libtiff does
+        if header.has_alpha.is_none() {
+            let color_channels = match header.color_space {
+                Some(ColorSpace::RGB) | Some(ColorSpace::Lab) => 3,
+                Some(ColorSpace::CMYK) => 4,
+                _ => 1,
+            };
+            header.has_alpha = header.channels.map(|c| c > color_channels);
+        }
+    }
+
+    // ResolutionUnit 1 means no absolute unit, 2 is inch and 3 is centimetre
+    if let (Some(x), Some(y)) = resolution {
+        header.dpi = match resolution_unit {
+            2 => Some((x, y)),
+            3 => Some((x * 2.54, y * 2.54)),
+            _ => None,
+        };
+    }
+
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ISO base media file format box
+    fn iso_box(box_type: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut data = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(content);
+        data
+    }
+
+    #[test]
+    fn avif_dimensions_are_read_from_the_image_extents() {
+        let mut ispe = vec![0; 4];
+        ispe.extend_from_slice(&640u32.to_be_bytes());
+        ispe.extend_from_slice(&480u32.to_be_bytes());
+        let iprp = iso_box(b"iprp", &iso_box(b"ipco", &iso_box(b"ispe", &ispe)));
+        let mut meta = vec![0; 4];
+        meta.extend(iso_box(b"hdlr", &[0; 24]));
+        meta.extend(iprp);
+
+        let mut data = iso_box(b"ftyp", b"avifmif1");
+        data.extend(iso_box(b"meta", &meta));
+        assert_eq!(avif_dimensions(&data).unwrap(), (640, 480));
+
+        // Truncated inside the meta box
+        assert!(avif_dimensions(&data[..data.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn unknown_formats_have_an_empty_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("image.gif");
+        std::fs::write(&file, b"GIF89a").unwrap();
+        assert_eq!(read_header(&file).unwrap(), ImageHeader::default());
+    }
+}
@@ -1,27 +1,102 @@
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
+use super::image_header::{read_header, ColorSpace, ImageHeader};
+use super::image_processor::ImageProcessor;
+use crate::tiling_exception::TilingError;
+
 /// Information about an image file
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageInfo {
     file: PathBuf,
     width: i32,
     height: i32,
+
+    /// Bits per channel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bit_depth: Option<u8>,
+
+    /// Number of channels, including alpha
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    channels: Option<u8>,
+
+    /// Whether the image has an alpha channel or transparency information
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    has_alpha: Option<bool>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color_space: Option<ColorSpace>,
+
+    /// Horizontal and vertical resolution in dots per inch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dpi: Option<(f64, f64)>,
+
+    /// EXIF orientation (1-8, 1 = not rotated or mirrored)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    orientation: Option<u16>,
 }
 
 impl ImageInfo {
-    pub fn new(file: &Path, system: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Reads the information of an image file. The dimensions are probed with
+    /// the given image processor; bit depth, channels, alpha, colour space,
+    /// DPI and orientation are read from the header of JPEG, PNG and TIFF
+    /// files and left unknown for other formats.
+    ///
+    /// # Errors
+    /// Returns an TilingError if the image dimensions cannot be read
+    pub fn new(file: &Path, processor: &dyn ImageProcessor) -> Result<Self, TilingError> {
+        let (width, height) = processor.get_dimensions(file)?;
+        if width <= 0 || height <= 0 {
+            return Err(TilingError::InvalidDimensions {
+                path: file.to_path_buf(),
+                width,
+                height,
+            });
+        }
+
+        let header = read_header(file).unwrap_or_else(|e| {
+            warn!("Could not read image header of {}: {}", file.display(), e);
+            ImageHeader::default()
+        });
+
         Ok(Self {
             file: file.to_path_buf(),
-            width: 0,
-            height: 0,
+            width,
+            height,
+            bit_depth: header.bit_depth,
+            channels: header.channels,
+            has_alpha: header.has_alpha,
+            color_space: header.color_space,
+            dpi: header.dpi,
+            orientation: header.orientation,
         })
     }
 
+    /// Creates the information for an image whose dimensions are already
+    /// known, e.g. from tileset metadata, without reading the file.
+    pub fn from_dimensions(file: &Path, width: i32, height: i32) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            width,
+            height,
+            bit_depth: None,
+            channels: None,
+            has_alpha: None,
+            color_space: None,
+            dpi: None,
+            orientation: None,
+        }
+    }
+
     pub fn file(&self) -> &Path {
         &self.file
     }
 
+    pub fn set_file(&mut self, file: &Path) {
+        self.file = file.to_path_buf();
+    }
+
     pub fn width(&self) -> i32 {
         self.width
     }
@@ -37,16 +112,154 @@ impl ImageInfo {
     pub fn set_height(&mut self, height: i32) {
         self.height = height;
     }
+
+    pub fn bit_depth(&self) -> Option<u8> {
+        self.bit_depth
+    }
+
+    pub fn channels(&self) -> Option<u8> {
+        self.channels
+    }
+
+    pub fn has_alpha(&self) -> Option<bool> {
+        self.has_alpha
+    }
+
+    pub fn color_space(&self) -> Option<ColorSpace> {
+        self.color_space
+    }
+
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        self.dpi
+    }
+
+    pub fn orientation(&self) -> Option<u16> {
+        self.orientation
+    }
 }
 
 impl std::fmt::Display for ImageInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "ImageInfo [file={}, width={}, height={}]",
+            "ImageInfo [file={}, width={}, height={}",
             self.file.display(),
             self.width,
             self.height
-        )
+        )?;
+        if let Some(bit_depth) = self.bit_depth {
+            write!(f, ", bitDepth={}", bit_depth)?;
+        }
+        if let Some(channels) = self.channels {
+            write!(f, ", channels={}", channels)?;
+        }
+        if let Some(has_alpha) = self.has_alpha {
+            write!(f, ", alpha={}", has_alpha)?;
+        }
+        if let Some(color_space) = self.color_space {
+            write!(f, ", colorSpace={:?}", color_space)?;
+        }
+        if let Some((x, y)) = self.dpi {
+            write!(f, ", dpi={}x{}", x, y)?;
+        }
+        if let Some(orientation) = self.orientation {
+            write!(f, ", orientation={}", orientation)?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use super::*;
+    use crate::image::NativeImageProcessor;
+
+    fn read_info(file: &Path) -> ImageInfo {
+        ImageInfo::new(file, &NativeImageProcessor::new()).unwrap()
+    }
+
+    #[test]
+    fn png_headers_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let rgba = dir.path().join("rgba.png");
+        image::RgbaImage::new(30, 20).save(&rgba).unwrap();
+        let info = read_info(&rgba);
+        assert_eq!((info.width(), info.height()), (30, 20));
+        assert_eq!(info.bit_depth(), Some(8));
+        assert_eq!(info.channels(), Some(4));
+        assert_eq!(info.has_alpha(), Some(true));
+        assert_eq!(info.color_space(), Some(ColorSpace::RGB));
+
+        let gray = dir.path().join("gray.png");
+        image::ImageBuffer::<image::Luma<u16>, Vec<u16>>::new(7, 9)
+            .save(&gray)
+            .unwrap();
+        let info = read_info(&gray);
+        assert_eq!((info.width(), info.height()), (7, 9));
+        assert_eq!(info.bit_depth(), Some(16));
+        assert_eq!(info.channels(), Some(1));
+        assert_eq!(info.has_alpha(), Some(false));
+        assert_eq!(info.color_space(), Some(ColorSpace::Gray));
+    }
+
+    #[test]
+    fn jpeg_headers_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let rgb = dir.path().join("rgb.jpg");
+        let mut encoder = jpeg_encoder::Encoder::new_file(&rgb, 90).unwrap();
+        encoder.set_density(jpeg_encoder::Density::Inch { x: 300, y: 150 });
+        encoder
+            .encode(&[128; 40 * 25 * 3], 40, 25, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        let info = read_info(&rgb);
+        assert_eq!((info.width(), info.height()), (40, 25));
+        assert_eq!(info.bit_depth(), Some(8));
+        assert_eq!(info.channels(), Some(3));
+        assert_eq!(info.has_alpha(), Some(false));
+        assert_eq!(info.color_space(), Some(ColorSpace::RGB));
+        assert_eq!(info.dpi(), Some((300.0, 150.0)));
+
+        let cmyk = dir.path().join("cmyk.jpg");
+        jpeg_encoder::Encoder::new_file(&cmyk, 90)
+            .unwrap()
+            .encode(&[0; 16 * 8 * 4], 16, 8, jpeg_encoder::ColorType::Cmyk)
+            .unwrap();
+        let info = read_info(&cmyk);
+        assert_eq!((info.width(), info.height()), (16, 8));
+        assert_eq!(info.channels(), Some(4));
+        assert_eq!(info.color_space(), Some(ColorSpace::CMYK));
+    }
+
+    #[test]
+    fn tiff_headers_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let rgb = dir.path().join("rgb.tif");
+        let mut encoder = tiff::encoder::TiffEncoder::new(File::create(&rgb).unwrap()).unwrap();
+        let mut image = encoder
+            .new_image::<tiff::encoder::colortype::RGB8>(50, 10)
+            .unwrap();
+        image.resolution(
+            tiff::tags::ResolutionUnit::Inch,
+            tiff::encoder::Rational { n: 72, d: 1 },
+        );
+        image.write_data(&[0; 50 * 10 * 3]).unwrap();
+
+        let info = read_info(&rgb);
+        assert_eq!((info.width(), info.height()), (50, 10));
+        assert_eq!(info.bit_depth(), Some(8));
+        assert_eq!(info.channels(), Some(3));
+        assert_eq!(info.has_alpha(), Some(false));
+        assert_eq!(info.color_space(), Some(ColorSpace::RGB));
+        assert_eq!(info.dpi(), Some((72.0, 72.0)));
+    }
+
+    #[test]
+    fn unreadable_images_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("image.png");
+        fs::write(&text, "not an image").unwrap();
+        assert!(ImageInfo::new(&text, &NativeImageProcessor::new()).is_err());
     }
 }
@@ -1,8 +1,21 @@
-use crate::image::ImageFormat;
-use std::path::Path;
+use crate::image::{EncoderOptions, ImageFormat, ResamplingFilter, TiffCompression};
+use crate::tiling_exception::TilingError;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-/// Trait for image processing operations
-pub trait ImageProcessor {
+/// A region to crop out of an image and the file it is written to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CropRegion {
+    pub target: PathBuf,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Trait for image processing operations. Processors are shared between the
+/// worker threads of a tiler, so they must be thread-safe.
+pub trait ImageProcessor: Send + Sync {
     /// Get the image processing system being used (e.g., "ImageMagick")
     fn get_image_processing_system(&self) -> &str;
 
@@ -12,32 +25,119 @@ pub trait ImageProcessor {
     /// Set the image format to use
     fn set_image_format(&mut self, format: ImageFormat);
 
+    /// Get the settings tiles are encoded with
+    fn get_encoder_options(&self) -> &EncoderOptions;
+
+    /// Set the settings tiles are encoded with. Out-of-range values are
+    /// clamped to their valid range.
+    fn set_encoder_options(&mut self, options: EncoderOptions);
+
+    /// Whether this processor can write images in the given format with its
+    /// encoder options
+    fn can_write(&self, format: ImageFormat) -> bool;
+
+    /// Get the background color used for canvas and montage operations
+    fn get_background_color(&self) -> Option<&str>;
+
+    /// Set the background color used for canvas and montage operations
+    fn set_background_color(&mut self, color: Option<String>);
+
+    /// Get the filter used by resize, scale and montage operations
+    fn get_resampling_filter(&self) -> ResamplingFilter;
+
+    /// Set the filter used by resize, scale and montage operations,
+    /// default=Lanczos3
+    fn set_resampling_filter(&mut self, filter: ResamplingFilter);
+
+    /// Get the maximum time a single external command may run
+    fn get_timeout(&self) -> Option<Duration>;
+
+    /// Set the maximum time a single external command may run before it is
+    /// killed and the operation fails with `TilingError::ProcessTimedOut`.
+    /// `None` (the default) waits indefinitely. Processors that run no
+    /// external commands ignore it.
+    fn set_timeout(&mut self, timeout: Option<Duration>);
+
     /// Resize an image to the specified dimensions
-    fn resize(
+    fn resize(&self, src: &Path, target: &Path, width: i32, height: i32)
+        -> Result<(), TilingError>;
+
+    /// Scale an image to exactly the specified dimensions
+    fn scale(&self, src: &Path, target: &Path, width: i32, height: i32) -> Result<(), TilingError>;
+
+    /// Crop an image into tiles
+    fn crop(&self, src: &Path, target: &Path, width: i32, height: i32) -> Result<(), TilingError>;
+
+    /// Crop a single region out of an image
+    #[allow(clippy::too_many_arguments)]
+    fn crop_region(
         &self,
         src: &Path,
         target: &Path,
+        x: i32,
+        y: i32,
         width: i32,
         height: i32,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<(), TilingError>;
 
-    /// Crop an image into tiles
-    fn crop(
+    /// Crop several regions out of an image, decoding it only once. Use this
+    /// instead of repeated `crop_region` calls on the same image.
+    fn crop_regions(&self, src: &Path, regions: &[CropRegion]) -> Result<(), TilingError>;
+
+    /// Crop an image into tiles and place each tile on a canvas of the
+    /// specified size, positioned according to the gravity
+    #[allow(clippy::too_many_arguments)]
+    fn crop_with_canvas(
         &self,
         src: &Path,
         target: &Path,
         width: i32,
         height: i32,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+        canvas_width: i32,
+        canvas_height: i32,
+        gravity: &str,
+    ) -> Result<(), TilingError>;
 
     /// Merge two images side by side
-    fn merge(
+    fn merge(&self, src1: &Path, src2: &Path, target: &Path) -> Result<(), TilingError>;
+
+    /// Join images edge to edge on a grid of `x_tiles` x `y_tiles` (a single
+    /// row or column), aligned to the top/left, and scale the result down by
+    /// 50%, rounding odd dimensions up
+    fn montage(
+        &self,
+        srcs: &[&Path],
+        target: &Path,
+        x_tiles: i32,
+        y_tiles: i32,
+    ) -> Result<(), TilingError>;
+
+    /// Arrange images on a grid of `x_tiles` x `y_tiles` cells of the specified
+    /// size. Each image is fitted into its cell and positioned according to the
+    /// gravity; a `None` source leaves its cell empty.
+    #[allow(clippy::too_many_arguments)]
+    fn montage_with_canvas(
+        &self,
+        srcs: &[Option<&Path>],
+        target: &Path,
+        x_tiles: i32,
+        y_tiles: i32,
+        width: i32,
+        height: i32,
+        background_color: &str,
+        gravity: &str,
+    ) -> Result<(), TilingError>;
+
+    /// Combine the resolution levels of a pyramid, largest first, into a
+    /// single tiled multi-resolution TIFF
+    fn pyramid_tiff(
         &self,
-        src1: &Path,
-        src2: &Path,
+        levels: &[&Path],
         target: &Path,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+        tile_size: i32,
+        compression: TiffCompression,
+    ) -> Result<(), TilingError>;
 
     /// Get the dimensions of an image
-    fn get_dimensions(&self, image: &Path) -> Result<(i32, i32), Box<dyn std::error::Error>>;
+    fn get_dimensions(&self, image: &Path) -> Result<(i32, i32), TilingError>;
 }
@@ -1,15 +1,73 @@
-use std::collections::HashMap;
-use std::path::Path;
-use std::process::Command;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use log::error;
+
+use super::encoder_options::EncoderOptions;
 use super::image_format::ImageFormat;
-use super::image_processor::ImageProcessor;
+use super::image_processor::{CropRegion, ImageProcessor};
+use super::native_image_processor::NativeImageProcessor;
+use super::resampling_filter::ResamplingFilter;
+use super::tiled_tiff_writer::TiffCompression;
+use crate::tiling_exception::TilingError;
+
+/// The number of regions `crop_regions` cuts with a single ImageMagick command
+const REGIONS_PER_COMMAND: usize = 64;
+
+/// Numbers the pixel caches of concurrent `crop_regions` calls
+static PIXEL_CACHE_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
-/// Supported image processing systems: GraphicsMagick or ImageMagick.
+/// Supported image processing systems: GraphicsMagick, ImageMagick or the
+/// pure-Rust `image` crate backend.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ImageProcessingSystem {
     GraphicsMagick,
     ImageMagick,
+    Native,
+}
+
+impl ImageProcessingSystem {
+    /// Picks the first image processing system found on the PATH, preferring
+    /// GraphicsMagick over ImageMagick. Falls back to the pure-Rust backend if
+    /// neither binary is installed.
+    pub fn detect() -> Self {
+        if is_on_path("gm") {
+            ImageProcessingSystem::GraphicsMagick
+        } else if is_on_path("convert") && is_on_path("montage") {
+            ImageProcessingSystem::ImageMagick
+        } else {
+            ImageProcessingSystem::Native
+        }
+    }
+
+    /// Creates an image processor backed by this image processing system.
+    pub fn create_processor(self) -> Box<dyn ImageProcessor> {
+        match self {
+            ImageProcessingSystem::Native => Box::new(NativeImageProcessor::new()),
+            system => Box::new(ImageProcessorImpl::new(system)),
+        }
+    }
+}
+
+impl Default for ImageProcessingSystem {
+    fn default() -> Self {
+        Self::detect()
+    }
+}
+
+fn is_on_path(binary: &str) -> bool {
+    let Some(paths) = env::var_os("PATH") else {
+        return false;
+    };
+    env::split_paths(&paths)
+        .any(|dir| dir.join(binary).is_file() || dir.join(format!("{}.exe", binary)).is_file())
 }
 
 /// A concrete implementation of the ImageProcessor trait
@@ -21,11 +79,18 @@ pub struct ImageProcessorImpl {
     /// The image format this processor will produce as output
     format: ImageFormat,
 
-    /// JPEG compression quality (in case of JPEG image format), default=75
-    jpeg_quality: i32,
+    /// The settings tiles are encoded with
+    encoder: EncoderOptions,
 
     /// The default background color for montage operations
     background_color: Option<String>,
+
+    /// The filter for resize, scale and montage operations, default=Lanczos3
+    filter: ResamplingFilter,
+
+    /// Maximum run time of a single GraphicsMagick/ImageMagick invocation,
+    /// default=no limit
+    timeout: Option<Duration>,
 }
 
 impl ImageProcessorImpl {
@@ -36,8 +101,10 @@ impl ImageProcessorImpl {
         Self {
             processing_system,
             format: ImageFormat::JPEG,
-            jpeg_quality: 75,
+            encoder: EncoderOptions::default(),
             background_color: None,
+            filter: ResamplingFilter::default(),
+            timeout: None,
         }
     }
 
@@ -45,8 +112,10 @@ impl ImageProcessorImpl {
         Self {
             processing_system,
             format,
-            jpeg_quality: 75,
+            encoder: EncoderOptions::default(),
             background_color: None,
+            filter: ResamplingFilter::default(),
+            timeout: None,
         }
     }
 
@@ -58,8 +127,10 @@ impl ImageProcessorImpl {
         Self {
             processing_system,
             format,
-            jpeg_quality: 75,
+            encoder: EncoderOptions::default(),
             background_color: Some(background_color),
+            filter: ResamplingFilter::default(),
+            timeout: None,
         }
     }
 
@@ -72,8 +143,14 @@ impl ImageProcessorImpl {
         Self {
             processing_system,
             format,
-            jpeg_quality,
+            encoder: EncoderOptions {
+                jpeg_quality,
+                ..EncoderOptions::default()
+            }
+            .clamped(),
             background_color,
+            filter: ResamplingFilter::default(),
+            timeout: None,
         }
     }
 
@@ -88,8 +165,184 @@ impl ImageProcessorImpl {
         if self.processing_system == ImageProcessingSystem::GraphicsMagick {
             cmd.arg("convert");
         }
+        self.add_encoder_options(&mut cmd);
+        cmd.arg("-filter").arg(self.filter.magick_name());
         cmd
     }
+
+    fn create_montage_command(&self) -> Command {
+        let mut cmd = if self.processing_system == ImageProcessingSystem::GraphicsMagick {
+            let mut cmd = Command::new("gm");
+            cmd.arg("montage");
+            cmd
+        } else {
+            Command::new("montage")
+        };
+        self.add_encoder_options(&mut cmd);
+        cmd.arg("-filter").arg(self.filter.magick_name());
+        cmd
+    }
+
+    /// Runs a GraphicsMagick/ImageMagick command. `path` is the file the
+    /// command works on, reported if it fails. The command fails if it exits
+    /// with a non-zero status or runs longer than the configured timeout; its
+    /// stderr output is included in the error.
+    fn run(&self, mut cmd: Command, path: &Path) -> Result<Output, TilingError> {
+        let program = PathBuf::from(cmd.get_program());
+        let failed = |status: Option<i32>, stderr: String| TilingError::ProcessFailed {
+            program: program.clone(),
+            path: path.to_path_buf(),
+            status,
+            stderr,
+        };
+
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                if e.kind() == io::ErrorKind::NotFound {
+                    TilingError::ProcessorNotFound {
+                        program: program.clone(),
+                    }
+                } else {
+                    failed(None, e.to_string())
+                }
+            })?;
+
+        // Drain the pipes while waiting, so the process cannot block on a
+        // full pipe buffer
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+
+        let status = match self.timeout {
+            None => child.wait().map(Some),
+            Some(timeout) => wait_with_timeout(&mut child, timeout),
+        }
+        .map_err(|e| failed(None, e.to_string()))?;
+
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = String::from_utf8_lossy(&stderr.join().unwrap_or_default())
+            .trim()
+            .to_string();
+
+        match status {
+            None => Err(TilingError::ProcessTimedOut {
+                program,
+                path: path.to_path_buf(),
+                timeout: self.timeout.unwrap_or_default(),
+            }),
+            Some(status) if !status.success() => Err(failed(status.code(), stderr)),
+            Some(status) => Ok(Output {
+                status,
+                stdout,
+                stderr: stderr.into_bytes(),
+            }),
+        }
+    }
+
+    /// Adds the encoder options of the tile format
+    fn add_encoder_options(&self, cmd: &mut Command) {
+        let options = &self.encoder;
+        match self.format {
+            ImageFormat::JPEG => {
+                cmd.arg("-quality")
+                    .arg(options.jpeg_quality.to_string())
+                    .arg("-interlace")
+                    .arg(if options.jpeg_progressive {
+                        "Plane"
+                    } else {
+                        "None"
+                    })
+                    .arg("-sampling-factor")
+                    .arg(options.chroma_subsampling.command_line_name());
+            }
+            ImageFormat::PNG => {
+                // The tens digit of the PNG quality is the zlib compression
+                // level, the ones digit the filter (5 = adaptive)
+                cmd.arg("-quality")
+                    .arg((options.png_compression_level * 10 + 5).to_string());
+                if !options.png_palette {
+                    cmd.arg("-depth").arg(options.png_bit_depth.to_string());
+                }
+            }
+            ImageFormat::TIFF => {
+                cmd.arg("-compress").arg(
+                    options
+                        .tiff_compression
+                        .map_or("None", |c| c.command_line_name()),
+                );
+                if options.tiff_compression == Some(TiffCompression::JPEG) {
+                    cmd.arg("-quality").arg(options.jpeg_quality.to_string());
+                }
+            }
+            ImageFormat::WebP => {
+                cmd.arg("-quality")
+                    .arg(options.webp_quality.to_string())
+                    .arg("-define")
+                    .arg(format!("webp:method={}", options.webp_effort));
+            }
+            ImageFormat::WebPLossless => {
+                cmd.arg("-define")
+                    .arg("webp:lossless=true")
+                    .arg("-define")
+                    .arg(format!("webp:method={}", options.webp_effort));
+            }
+            ImageFormat::AVIF => {
+                // The AV1 encoder's speed runs the other way, from 9 (fastest)
+                // to 0 (smallest files)
+                cmd.arg("-quality")
+                    .arg(options.avif_quality.to_string())
+                    .arg("-define")
+                    .arg(format!("heic:speed={}", 9 - options.avif_effort));
+            }
+        }
+    }
+
+    /// The output file argument for `target`. PNG tiles quantised to a
+    /// palette are written with the PNG8 coder; all other files are encoded
+    /// according to their extension.
+    fn output(&self, target: &Path) -> OsString {
+        let png = target
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("png"));
+        if png && self.format == ImageFormat::PNG && self.encoder.png_palette {
+            let mut output = OsString::from("PNG8:");
+            output.push(target);
+            output
+        } else {
+            target.into()
+        }
+    }
+}
+
+/// Reads a child process pipe to the end on a separate thread.
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Waits for the child process to exit. Kills it and returns `None` if it is
+/// still running after `timeout`.
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
 }
 
 impl ImageProcessor for ImageProcessorImpl {
@@ -97,6 +350,7 @@ impl ImageProcessor for ImageProcessorImpl {
         match self.processing_system {
             ImageProcessingSystem::GraphicsMagick => "GraphicsMagick",
             ImageProcessingSystem::ImageMagick => "ImageMagick",
+            ImageProcessingSystem::Native => "Native",
         }
     }
 
@@ -108,80 +362,516 @@ impl ImageProcessor for ImageProcessorImpl {
         self.format = format;
     }
 
+    fn get_encoder_options(&self) -> &EncoderOptions {
+        &self.encoder
+    }
+
+    fn set_encoder_options(&mut self, options: EncoderOptions) {
+        self.encoder = options.clamped();
+    }
+
+    fn can_write(&self, format: ImageFormat) -> bool {
+        // GraphicsMagick reads AVIF, but cannot write it
+        !(self.processing_system == ImageProcessingSystem::GraphicsMagick
+            && format == ImageFormat::AVIF)
+    }
+
+    fn get_background_color(&self) -> Option<&str> {
+        self.background_color.as_deref()
+    }
+
+    fn set_background_color(&mut self, color: Option<String>) {
+        self.background_color = color;
+    }
+
+    fn get_resampling_filter(&self) -> ResamplingFilter {
+        self.filter
+    }
+
+    fn set_resampling_filter(&mut self, filter: ResamplingFilter) {
+        self.filter = filter;
+    }
+
+    fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
     fn resize(
         &self,
         src: &Path,
         target: &Path,
         width: i32,
         height: i32,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), TilingError> {
         let mut cmd = self.create_convert_command();
         cmd.arg(src)
             .arg("-resize")
             .arg(format!("{}x{}", width, height))
-            .arg(target);
+            .arg(self.output(target));
+
+        self.run(cmd, target).map(|_| ())
+    }
+
+    fn scale(&self, src: &Path, target: &Path, width: i32, height: i32) -> Result<(), TilingError> {
+        // '-scale' ignores the filter setting, so this resizes without
+        // preserving the aspect ratio instead
+        let mut cmd = self.create_convert_command();
+        cmd.arg(src)
+            .arg("-resize")
+            .arg(format!("{}x{}!", width, height))
+            .arg(self.output(target));
 
-        cmd.output().map(|_| ()).map_err(|e| e.into())
+        self.run(cmd, target).map(|_| ())
     }
 
-    fn crop(
+    fn crop(&self, src: &Path, target: &Path, width: i32, height: i32) -> Result<(), TilingError> {
+        let mut cmd = self.create_convert_command();
+        cmd.arg(src)
+            .arg("-crop")
+            .arg(format!("{}x{}", width, height))
+            .arg("+adjoin")
+            .arg(self.output(target));
+
+        self.run(cmd, target).map(|_| ())
+    }
+
+    fn crop_region(
         &self,
         src: &Path,
         target: &Path,
+        x: i32,
+        y: i32,
         width: i32,
         height: i32,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), TilingError> {
         let mut cmd = self.create_convert_command();
         cmd.arg(src)
             .arg("-crop")
+            .arg(format!("{}x{}+{}+{}", width, height, x, y))
+            .arg("+repage")
+            .arg(self.output(target));
+
+        self.run(cmd, target).map(|_| ())
+    }
+
+    fn crop_regions(&self, src: &Path, regions: &[CropRegion]) -> Result<(), TilingError> {
+        // The source is decoded once into a memory-mapped pixel cache, which
+        // every crop then reads without decoding it again
+        let cache = env::temp_dir().join(format!(
+            "magicktiler-{}-{}.mpc",
+            process::id(),
+            PIXEL_CACHE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut cmd = self.create_convert_command();
+        cmd.arg(src).arg(&cache);
+        let result = self.run(cmd, src).and_then(|_| {
+            if self.processing_system == ImageProcessingSystem::GraphicsMagick {
+                // GraphicsMagick cannot clone images within a command
+                return regions.iter().try_for_each(|r| {
+                    self.crop_region(&cache, &r.target, r.x, r.y, r.width, r.height)
+                });
+            }
+            for chunk in regions.chunks(REGIONS_PER_COMMAND) {
+                let mut cmd = self.create_convert_command();
+                cmd.arg(&cache);
+                for region in chunk {
+                    cmd.arg("(")
+                        .arg("+clone")
+                        .arg("-crop")
+                        .arg(format!(
+                            "{}x{}+{}+{}",
+                            region.width, region.height, region.x, region.y
+                        ))
+                        .arg("+repage")
+                        .arg("-write")
+                        .arg(self.output(&region.target))
+                        .arg("+delete")
+                        .arg(")");
+                }
+                cmd.arg("null:");
+                self.run(cmd, &chunk[0].target)?;
+            }
+            Ok(())
+        });
+
+        for file in [cache.with_extension("mpc"), cache.with_extension("cache")] {
+            match fs::remove_file(&file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    error!("Could not delete {}: {}", file.display(), e)
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+
+    fn crop_with_canvas(
+        &self,
+        src: &Path,
+        target: &Path,
+        width: i32,
+        height: i32,
+        canvas_width: i32,
+        canvas_height: i32,
+        gravity: &str,
+    ) -> Result<(), TilingError> {
+        let mut cmd = self.create_convert_command();
+        if let Some(bg) = &self.background_color {
+            cmd.arg("-background").arg(bg);
+        }
+        cmd.arg("-crop")
             .arg(format!("{}x{}", width, height))
             .arg("+adjoin")
-            .arg(target);
+            .arg(src)
+            .arg("-gravity")
+            .arg(gravity)
+            .arg("-extent")
+            .arg(format!("{}x{}", canvas_width, canvas_height))
+            .arg(self.output(target));
 
-        cmd.output().map(|_| ()).map_err(|e| e.into())
+        self.run(cmd, target).map(|_| ())
     }
 
-    fn merge(
+    fn merge(&self, src1: &Path, src2: &Path, target: &Path) -> Result<(), TilingError> {
+        let mut cmd = self.create_convert_command();
+        if let Some(bg) = &self.background_color {
+            cmd.arg("-background").arg(bg);
+        }
+        cmd.arg(src1)
+            .arg(src2)
+            .arg("+append")
+            .arg(self.output(target));
+
+        self.run(cmd, target).map(|_| ())
+    }
+
+    fn montage(
         &self,
-        src1: &Path,
-        src2: &Path,
+        srcs: &[&Path],
         target: &Path,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        x_tiles: i32,
+        y_tiles: i32,
+    ) -> Result<(), TilingError> {
+        // 'montage -geometry +0+0' would pad smaller images to the size of
+        // the largest one, so the images are appended instead
         let mut cmd = self.create_convert_command();
         if let Some(bg) = &self.background_color {
             cmd.arg("-background").arg(bg);
         }
-        cmd.arg(src1).arg(src2).arg("+append").arg(target);
+        cmd.arg("-gravity")
+            .arg("NorthWest")
+            .args(srcs)
+            .arg(if y_tiles > 1 && x_tiles == 1 {
+                "-append"
+            } else {
+                "+append"
+            })
+            // Percentage geometries are rounded to the nearest pixel, so odd
+            // dimensions are rounded up like `stripe::half_size` does
+            .arg("-resize")
+            .arg("50%x50%")
+            .arg(self.output(target));
 
-        cmd.output().map(|_| ()).map_err(|e| e.into())
+        self.run(cmd, target).map(|_| ())
     }
 
-    fn get_dimensions(&self, image: &Path) -> Result<(i32, i32), Box<dyn std::error::Error>> {
-        let mut cmd = Command::new(
-            if self.processing_system == ImageProcessingSystem::GraphicsMagick {
-                "gm"
-            } else {
-                "identify"
-            },
-        );
-        cmd.arg("identify").arg(image);
+    fn montage_with_canvas(
+        &self,
+        srcs: &[Option<&Path>],
+        target: &Path,
+        x_tiles: i32,
+        y_tiles: i32,
+        width: i32,
+        height: i32,
+        background_color: &str,
+        gravity: &str,
+    ) -> Result<(), TilingError> {
+        let mut cmd = self.create_montage_command();
+        cmd.arg("-tile")
+            .arg(format!("{}x{}", x_tiles, y_tiles))
+            .arg("-gravity")
+            .arg(gravity)
+            .arg("-background")
+            .arg(background_color)
+            .arg("-geometry")
+            .arg(format!("{}x{}", width, height));
+        for src in srcs {
+            match src {
+                Some(src) => cmd.arg(src),
+                None => cmd.arg("null:"),
+            };
+        }
+        cmd.arg(self.output(target));
+
+        self.run(cmd, target).map(|_| ())
+    }
 
-        let output = cmd.output()?;
+    fn pyramid_tiff(
+        &self,
+        levels: &[&Path],
+        target: &Path,
+        tile_size: i32,
+        compression: TiffCompression,
+    ) -> Result<(), TilingError> {
+        let mut cmd = self.create_convert_command();
+        cmd.arg("-define")
+            .arg(format!("tiff:tile-geometry={}x{}", tile_size, tile_size))
+            .arg("-compress")
+            .arg(compression.command_line_name());
+        if compression == TiffCompression::JPEG {
+            cmd.arg("-quality")
+                .arg(self.encoder.jpeg_quality.to_string());
+        }
+        cmd.arg("-adjoin").args(levels).arg(self.output(target));
+
+        self.run(cmd, target).map(|_| ())
+    }
+
+    fn get_dimensions(&self, image: &Path) -> Result<(i32, i32), TilingError> {
+        let mut cmd = if self.processing_system == ImageProcessingSystem::GraphicsMagick {
+            let mut cmd = Command::new("gm");
+            cmd.arg("identify");
+            cmd
+        } else {
+            Command::new("identify")
+        };
+        // Print the size of each frame, without reading the pixels
+        cmd.arg("-ping").arg("-format").arg("%w %h\n").arg(image);
+
+        let output = self.run(cmd, image)?;
         let output_str = String::from_utf8_lossy(&output.stdout);
 
-        // Parse dimensions from output (format: "image.jpg JPEG 1920x1080+0+0")
-        let parts: Vec<&str> = output_str.split_whitespace().collect();
-        if parts.len() >= 3 {
-            if let Some(dimensions) = parts[2].split('x').next() {
-                let dims: Vec<&str> = dimensions.split('x').collect();
-                if dims.len() == 2 {
-                    let width = dims[0].parse::<i32>()?;
-                    let height = dims[1].parse::<i32>()?;
+        // Parse dimensions from output (format: "1920 1080", one line per frame)
+        if let Some(line) = output_str.lines().next() {
+            let dims: Vec<&str> = line.split_whitespace().collect();
+            if dims.len() == 2 {
+                if let (Ok(width), Ok(height)) = (dims[0].parse(), dims[1].parse()) {
                     return Ok((width, height));
                 }
             }
         }
 
-        Err("Failed to parse image dimensions".into())
+        Err(TilingError::UnsupportedFormat {
+            path: image.to_path_buf(),
+            reason: "Failed to parse image dimensions".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ChromaSubsampling;
+    use crate::image::NativeImageProcessor;
+
+    #[test]
+    fn slow_commands_are_killed() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let start = Instant::now();
+        let status = wait_with_timeout(&mut child, Duration::from_millis(100)).unwrap();
+        assert!(status.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let mut child = Command::new("true").spawn().unwrap();
+        let status = wait_with_timeout(&mut child, Duration::from_secs(5)).unwrap();
+        assert!(status.unwrap().success());
+    }
+
+    #[test]
+    fn timeout_is_set_through_the_trait() {
+        let mut processors: Vec<Box<dyn ImageProcessor>> = vec![
+            Box::new(ImageProcessorImpl::new(
+                ImageProcessingSystem::GraphicsMagick,
+            )),
+            Box::new(NativeImageProcessor::new()),
+        ];
+        for processor in &mut processors {
+            assert_eq!(processor.get_timeout(), None);
+            processor.set_timeout(Some(Duration::from_secs(30)));
+            assert_eq!(processor.get_timeout(), Some(Duration::from_secs(30)));
+        }
+    }
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn filters_are_passed_to_both_backends() {
+        for system in [
+            ImageProcessingSystem::GraphicsMagick,
+            ImageProcessingSystem::ImageMagick,
+        ] {
+            let mut processor = ImageProcessorImpl::new(system);
+            for (filter, name) in [
+                (ResamplingFilter::Box, "Box"),
+                (ResamplingFilter::Triangle, "Triangle"),
+                (ResamplingFilter::CatmullRom, "Catrom"),
+                (ResamplingFilter::Lanczos3, "Lanczos"),
+                (ResamplingFilter::Mitchell, "Mitchell"),
+            ] {
+                processor.set_resampling_filter(filter);
+                assert_eq!(processor.get_resampling_filter(), filter);
+                for cmd in [
+                    processor.create_convert_command(),
+                    processor.create_montage_command(),
+                ] {
+                    let args = args(&cmd);
+                    let i = args.iter().position(|a| a == "-filter").unwrap();
+                    assert_eq!(args[i + 1], name, "{:?}", system);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn commands_match_the_backend() {
+        let gm = ImageProcessorImpl::new(ImageProcessingSystem::GraphicsMagick);
+        assert_eq!(gm.create_convert_command().get_program(), "gm");
+        assert_eq!(args(&gm.create_convert_command())[0], "convert");
+        assert_eq!(args(&gm.create_montage_command())[0], "montage");
+
+        let im = ImageProcessorImpl::new(ImageProcessingSystem::ImageMagick);
+        assert_eq!(im.create_convert_command().get_program(), "convert");
+        assert_eq!(im.create_montage_command().get_program(), "montage");
+    }
+
+    /// The encoder arguments of a convert command writing the given format
+    fn encoder_args(
+        system: ImageProcessingSystem,
+        format: ImageFormat,
+        options: EncoderOptions,
+    ) -> Vec<String> {
+        let mut processor = ImageProcessorImpl::with_format(system, format);
+        processor.set_encoder_options(options);
+        let args = args(&processor.create_convert_command());
+        let start = args.iter().position(|a| a != "convert").unwrap();
+        let end = args.iter().position(|a| a == "-filter").unwrap();
+        args[start..end].to_vec()
+    }
+
+    #[test]
+    fn encoder_options_are_passed_to_both_backends() {
+        let options = EncoderOptions {
+            jpeg_quality: 90,
+            jpeg_progressive: true,
+            chroma_subsampling: ChromaSubsampling::YCbCr444,
+            png_compression_level: 9,
+            png_bit_depth: 16,
+            tiff_compression: Some(TiffCompression::JPEG),
+            webp_quality: 70,
+            webp_effort: 6,
+            avif_quality: 50,
+            avif_effort: 2,
+            ..EncoderOptions::default()
+        };
+        for system in [
+            ImageProcessingSystem::GraphicsMagick,
+            ImageProcessingSystem::ImageMagick,
+        ] {
+            let args = |format| encoder_args(system, format, options.clone());
+            assert_eq!(
+                args(ImageFormat::JPEG),
+                [
+                    "-quality",
+                    "90",
+                    "-interlace",
+                    "Plane",
+                    "-sampling-factor",
+                    "1x1"
+                ]
+            );
+            assert_eq!(args(ImageFormat::PNG), ["-quality", "95", "-depth", "16"]);
+            assert_eq!(
+                args(ImageFormat::TIFF),
+                ["-compress", "JPEG", "-quality", "90"]
+            );
+            assert_eq!(
+                args(ImageFormat::WebP),
+                ["-quality", "70", "-define", "webp:method=6"]
+            );
+            assert_eq!(
+                args(ImageFormat::WebPLossless),
+                ["-define", "webp:lossless=true", "-define", "webp:method=6"]
+            );
+            assert_eq!(
+                args(ImageFormat::AVIF),
+                ["-quality", "50", "-define", "heic:speed=7"]
+            );
+        }
+    }
+
+    #[test]
+    fn default_encoder_options_are_passed_on() {
+        let args = |format| {
+            encoder_args(
+                ImageProcessingSystem::GraphicsMagick,
+                format,
+                EncoderOptions::default(),
+            )
+        };
+        assert_eq!(
+            args(ImageFormat::JPEG),
+            [
+                "-quality",
+                "75",
+                "-interlace",
+                "None",
+                "-sampling-factor",
+                "2x2"
+            ]
+        );
+        assert_eq!(args(ImageFormat::PNG), ["-quality", "65", "-depth", "8"]);
+        assert_eq!(args(ImageFormat::TIFF), ["-compress", "None"]);
+    }
+
+    #[test]
+    fn palette_pngs_are_written_with_the_png8_coder() {
+        let mut processor =
+            ImageProcessorImpl::with_format(ImageProcessingSystem::ImageMagick, ImageFormat::PNG);
+        processor.set_encoder_options(EncoderOptions {
+            png_palette: true,
+            ..EncoderOptions::default()
+        });
+        assert_eq!(
+            processor.output(Path::new("tile.png")),
+            OsString::from("PNG8:tile.png")
+        );
+        assert_eq!(
+            processor.output(Path::new("tile.jpg")),
+            OsString::from("tile.jpg")
+        );
+        // Without -depth, which would override the palette
+        assert_eq!(
+            encoder_args(
+                ImageProcessingSystem::ImageMagick,
+                ImageFormat::PNG,
+                processor.get_encoder_options().clone()
+            ),
+            ["-quality", "65"]
+        );
+    }
+
+    #[test]
+    fn encoder_options_are_clamped() {
+        let mut processor = ImageProcessorImpl::new(ImageProcessingSystem::GraphicsMagick);
+        processor.set_encoder_options(EncoderOptions {
+            jpeg_quality: 150,
+            ..EncoderOptions::default()
+        });
+        assert_eq!(processor.get_encoder_options().jpeg_quality, 100);
+        let processor = ImageProcessorImpl::with_quality(
+            ImageProcessingSystem::ImageMagick,
+            ImageFormat::JPEG,
+            None,
+            -10,
+        );
+        assert_eq!(processor.get_encoder_options().jpeg_quality, 0);
     }
 }
@@ -1,9 +1,22 @@
+mod encoder_options;
 mod image_format;
+mod image_header;
 mod image_info;
 mod image_processor;
 mod image_processor_imp;
+mod named_colors;
+mod native_image_processor;
+mod resampling_filter;
+mod tiled_tiff_writer;
 
+pub use encoder_options::{ChromaSubsampling, EncoderOptions};
 pub use image_format::ImageFormat;
+pub use image_header::ColorSpace;
+pub(crate) use image_header::{avif_dimensions, read_avif_dimensions};
 pub use image_info::ImageInfo;
-pub use image_processor::ImageProcessor;
+pub use image_processor::{CropRegion, ImageProcessor};
 pub use image_processor_imp::{ImageProcessingSystem, ImageProcessorImpl};
+pub use native_image_processor::{parse_color, NativeImageProcessor};
+pub use resampling_filter::ResamplingFilter;
+pub(crate) use resampling_filter::{to_u8, Taps};
+pub use tiled_tiff_writer::{TiffCompression, TiledTiffWriter};
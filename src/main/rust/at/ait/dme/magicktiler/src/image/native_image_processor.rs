@@ -0,0 +1,858 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::Duration;
+
+use color_quant::NeuQuant;
+use image::codecs::webp::WebPEncoder;
+use image::imageops;
+use image::io::Reader;
+use image::{ColorType, DynamicImage, GenericImageView, Rgba, RgbaImage};
+use jpeg_encoder::SamplingFactor;
+
+use super::encoder_options::{ChromaSubsampling, EncoderOptions};
+use super::image_format::ImageFormat;
+use super::image_processor::{CropRegion, ImageProcessor};
+use super::named_colors::named_color;
+use super::resampling_filter::{fit_dimensions, resample, ResamplingFilter};
+use super::tiled_tiff_writer::{TiffCompression, TiledTiffWriter};
+use crate::tiling_exception::TilingError;
+
+/// An ImageProcessor that runs entirely in-process on top of the `image`
+/// crate, so no GraphicsMagick or ImageMagick installation is required.
+///
+/// Operations mirror the behaviour of the corresponding GraphicsMagick
+/// commands used by `ImageProcessorImpl`. Output files are encoded according
+/// to their file extension, with the encoder options of that format. WebP is
+/// always written lossless; lossy WebP, AVIF and JPEG-compressed TIFF require
+/// GraphicsMagick or ImageMagick.
+#[derive(Debug)]
+pub struct NativeImageProcessor {
+    /// The image format this processor will produce as output
+    format: ImageFormat,
+
+    /// The settings tiles are encoded with
+    encoder: EncoderOptions,
+
+    /// The default background color for canvas operations
+    background_color: Option<String>,
+
+    /// The filter for resize, scale and montage operations, default=Lanczos3
+    filter: ResamplingFilter,
+
+    /// Kept for `get_timeout` only, as no external commands are run
+    timeout: Option<Duration>,
+}
+
+impl NativeImageProcessor {
+    pub fn new() -> Self {
+        Self {
+            format: ImageFormat::JPEG,
+            encoder: EncoderOptions::default(),
+            background_color: None,
+            filter: ResamplingFilter::default(),
+            timeout: None,
+        }
+    }
+
+    pub fn with_format(format: ImageFormat) -> Self {
+        Self {
+            format,
+            ..Self::new()
+        }
+    }
+
+    pub fn with_quality(
+        format: ImageFormat,
+        background_color: Option<String>,
+        jpeg_quality: i32,
+    ) -> Self {
+        Self {
+            format,
+            encoder: EncoderOptions {
+                jpeg_quality,
+                ..EncoderOptions::default()
+            }
+            .clamped(),
+            background_color,
+            ..Self::new()
+        }
+    }
+
+    fn open(&self, src: &Path) -> Result<DynamicImage, TilingError> {
+        let read_error = |source| TilingError::InputNotReadable {
+            path: src.to_path_buf(),
+            source,
+        };
+        let mut reader = Reader::open(src)
+            .map_err(read_error)?
+            .with_guessed_format()
+            .map_err(read_error)?;
+        // Tiling sources are routinely larger than the decoder's default limits
+        reader.no_limits();
+        reader.decode().map_err(|e| TilingError::read(src, e))
+    }
+
+    /// Encodes an image according to the extension of the target file, with
+    /// the encoder options of its format
+    pub(crate) fn save(&self, img: &DynamicImage, target: &Path) -> Result<(), TilingError> {
+        let extension = target
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "jpg" | "jpeg" => self.save_jpeg(img, target),
+            "png" => self.save_png(img, target),
+            "tif" | "tiff" => self.save_tiff(img, target),
+            "webp" => self.save_webp(img, target),
+            "avif" => Err(self.unsupported(target)),
+            _ => img.save(target).map_err(|e| TilingError::write(target, e)),
+        }
+    }
+
+    fn save_jpeg(&self, img: &DynamicImage, target: &Path) -> Result<(), TilingError> {
+        let (width, height) = jpeg_dimensions(img, target)?;
+        let mut encoder = jpeg_encoder::Encoder::new(
+            create(target)?,
+            self.encoder.jpeg_quality.clamp(1, 100) as u8,
+        );
+        encoder.set_progressive(self.encoder.jpeg_progressive);
+        encoder.set_sampling_factor(match self.encoder.chroma_subsampling {
+            ChromaSubsampling::YCbCr444 => SamplingFactor::R_4_4_4,
+            ChromaSubsampling::YCbCr422 => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::YCbCr420 => SamplingFactor::R_4_2_0,
+        });
+        encoder
+            .encode(
+                img.to_rgb8().as_raw(),
+                width,
+                height,
+                jpeg_encoder::ColorType::Rgb,
+            )
+            .map_err(|e| match e {
+                jpeg_encoder::EncodingError::IoError(source) => TilingError::OutputNotWritable {
+                    path: target.to_path_buf(),
+                    source,
+                },
+                e => TilingError::UnsupportedFormat {
+                    path: target.to_path_buf(),
+                    reason: e.to_string(),
+                },
+            })
+    }
+
+    /// Writes a PNG, optionally quantised to a palette. The png crate only
+    /// offers fast, default and best compression, so the compression level
+    /// is mapped to the closest of these.
+    fn save_png(&self, img: &DynamicImage, target: &Path) -> Result<(), TilingError> {
+        let alpha = img.color().has_alpha();
+        let mut encoder = png::Encoder::new(create(target)?, img.width(), img.height());
+        encoder.set_compression(match self.encoder.png_compression_level {
+            ..=2 => png::Compression::Fast,
+            3..=6 => png::Compression::Default,
+            _ => png::Compression::Best,
+        });
+
+        let data = if self.encoder.png_palette {
+            let (palette, indices) = quantize(&img.to_rgba8());
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_palette(
+                palette
+                    .iter()
+                    .flat_map(|p| [p[0], p[1], p[2]])
+                    .collect::<Vec<_>>(),
+            );
+            if alpha {
+                encoder.set_trns(palette.iter().map(|p| p[3]).collect::<Vec<_>>());
+            }
+            indices
+        } else if self.encoder.png_bit_depth == 16 {
+            encoder.set_depth(png::BitDepth::Sixteen);
+            let samples = if alpha {
+                encoder.set_color(png::ColorType::Rgba);
+                img.to_rgba16().into_raw()
+            } else {
+                encoder.set_color(png::ColorType::Rgb);
+                img.to_rgb16().into_raw()
+            };
+            // PNG stores 16-bit samples big-endian
+            samples.iter().flat_map(|s| s.to_be_bytes()).collect()
+        } else {
+            encoder.set_depth(png::BitDepth::Eight);
+            if alpha {
+                encoder.set_color(png::ColorType::Rgba);
+                img.to_rgba8().into_raw()
+            } else {
+                encoder.set_color(png::ColorType::Rgb);
+                img.to_rgb8().into_raw()
+            }
+        };
+
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&data))
+            .map_err(|e| match e {
+                png::EncodingError::IoError(source) => TilingError::OutputNotWritable {
+                    path: target.to_path_buf(),
+                    source,
+                },
+                e => TilingError::UnsupportedFormat {
+                    path: target.to_path_buf(),
+                    reason: e.to_string(),
+                },
+            })
+    }
+
+    /// Writes a TIFF. The TIFF compression only applies while the tile format
+    /// is TIFF; compressed TIFFs are written tiled, as 8-bit RGB.
+    fn save_tiff(&self, img: &DynamicImage, target: &Path) -> Result<(), TilingError> {
+        let compression = self
+            .encoder
+            .tiff_compression
+            .filter(|_| self.format == ImageFormat::TIFF);
+        let Some(compression) = compression else {
+            return img.save(target).map_err(|e| TilingError::write(target, e));
+        };
+        if !self.can_write(self.format) {
+            return Err(self.unsupported(target));
+        }
+        let write_error = |source| TilingError::OutputNotWritable {
+            path: target.to_path_buf(),
+            source,
+        };
+
+        // Small images, like tiles, fit into a single TIFF tile
+        let tile_size = img.width().max(img.height()).next_multiple_of(16).min(256);
+        let mut writer = TiledTiffWriter::new(
+            create(target)?,
+            tile_size,
+            compression,
+            self.encoder.jpeg_quality.clamp(1, 100) as u8,
+        )
+        .map_err(write_error)?;
+        writer.write_level(img).map_err(write_error)?;
+        writer.finish().map_err(write_error)?;
+        Ok(())
+    }
+
+    fn save_webp(&self, img: &DynamicImage, target: &Path) -> Result<(), TilingError> {
+        if !self.can_write(self.format) {
+            return Err(self.unsupported(target));
+        }
+        let encoder = WebPEncoder::new_lossless(create(target)?);
+        let result = if img.color().has_alpha() {
+            let pixels = img.to_rgba8();
+            encoder.encode(&pixels, img.width(), img.height(), ColorType::Rgba8)
+        } else {
+            let pixels = img.to_rgb8();
+            encoder.encode(&pixels, img.width(), img.height(), ColorType::Rgb8)
+        };
+        result.map_err(|e| TilingError::write(target, e))
+    }
+
+    fn unsupported(&self, target: &Path) -> TilingError {
+        TilingError::UnsupportedFormat {
+            path: target.to_path_buf(),
+            reason: format!(
+                "The native image processor cannot write {:?} ({}); use GraphicsMagick or ImageMagick",
+                self.format,
+                self.encoder.describe(self.format)
+            ),
+        }
+    }
+
+    /// The given background color, or the default one. White if neither is
+    /// set. `target` is the file the color is used for.
+    fn background(&self, color: Option<&str>, target: &Path) -> Result<Rgba<u8>, TilingError> {
+        match color.or(self.background_color.as_deref()) {
+            Some(color) => parse_color(color).ok_or_else(|| TilingError::InvalidColor {
+                path: target.to_path_buf(),
+                color: color.to_string(),
+            }),
+            None => Ok(Rgba([255, 255, 255, 255])),
+        }
+    }
+
+    /// Cuts the image into width x height pieces, row by row, passing each
+    /// piece and its sequence number to the callback.
+    fn for_each_tile<F>(
+        &self,
+        src: &Path,
+        width: i32,
+        height: i32,
+        mut f: F,
+    ) -> Result<(), TilingError>
+    where
+        F: FnMut(usize, DynamicImage) -> Result<(), TilingError>,
+    {
+        if width <= 0 || height <= 0 {
+            return Err(TilingError::InvalidDimensions {
+                path: src.to_path_buf(),
+                width,
+                height,
+            });
+        }
+
+        let img = self.open(src)?;
+        let (tile_width, tile_height) = (width as u32, height as u32);
+        let mut idx = 0;
+        for y in (0..img.height()).step_by(tile_height as usize) {
+            for x in (0..img.width()).step_by(tile_width as usize) {
+                let w = tile_width.min(img.width() - x);
+                let h = tile_height.min(img.height() - y);
+                f(idx, img.crop_imm(x, y, w, h))?;
+                idx += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for NativeImageProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageProcessor for NativeImageProcessor {
+    fn get_image_processing_system(&self) -> &str {
+        "Native"
+    }
+
+    fn get_image_format(&self) -> ImageFormat {
+        self.format
+    }
+
+    fn set_image_format(&mut self, format: ImageFormat) {
+        self.format = format;
+    }
+
+    fn get_encoder_options(&self) -> &EncoderOptions {
+        &self.encoder
+    }
+
+    fn set_encoder_options(&mut self, options: EncoderOptions) {
+        self.encoder = options.clamped();
+    }
+
+    fn can_write(&self, format: ImageFormat) -> bool {
+        match format {
+            ImageFormat::WebP | ImageFormat::AVIF => false,
+            // Writing works, but the image crate cannot read the tiles back
+            ImageFormat::TIFF => self.encoder.tiff_compression != Some(TiffCompression::JPEG),
+            _ => true,
+        }
+    }
+
+    fn get_background_color(&self) -> Option<&str> {
+        self.background_color.as_deref()
+    }
+
+    fn set_background_color(&mut self, color: Option<String>) {
+        self.background_color = color;
+    }
+
+    fn get_resampling_filter(&self) -> ResamplingFilter {
+        self.filter
+    }
+
+    fn set_resampling_filter(&mut self, filter: ResamplingFilter) {
+        self.filter = filter;
+    }
+
+    fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn resize(
+        &self,
+        src: &Path,
+        target: &Path,
+        width: i32,
+        height: i32,
+    ) -> Result<(), TilingError> {
+        // Like GM's -resize, this preserves the aspect ratio
+        let img = self.open(src)?;
+        let (width, height) = fit_dimensions(img.dimensions(), (width as u32, height as u32));
+        let resized = resample(&img, width, height, self.filter);
+        self.save(&resized, target)
+    }
+
+    fn scale(&self, src: &Path, target: &Path, width: i32, height: i32) -> Result<(), TilingError> {
+        let img = self.open(src)?;
+        let scaled = resample(&img, width as u32, height as u32, self.filter);
+        self.save(&scaled, target)
+    }
+
+    fn crop(&self, src: &Path, target: &Path, width: i32, height: i32) -> Result<(), TilingError> {
+        self.for_each_tile(src, width, height, |idx, tile| {
+            self.save(&tile, &numbered(target, idx))
+        })
+    }
+
+    fn crop_region(
+        &self,
+        src: &Path,
+        target: &Path,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    ) -> Result<(), TilingError> {
+        let img = self.open(src)?;
+        let region = img.crop_imm(x as u32, y as u32, width as u32, height as u32);
+        self.save(&region, target)
+    }
+
+    fn crop_regions(&self, src: &Path, regions: &[CropRegion]) -> Result<(), TilingError> {
+        let img = self.open(src)?;
+        for region in regions {
+            let tile = img.crop_imm(
+                region.x as u32,
+                region.y as u32,
+                region.width as u32,
+                region.height as u32,
+            );
+            self.save(&tile, &region.target)?;
+        }
+        Ok(())
+    }
+
+    fn crop_with_canvas(
+        &self,
+        src: &Path,
+        target: &Path,
+        width: i32,
+        height: i32,
+        canvas_width: i32,
+        canvas_height: i32,
+        gravity: &str,
+    ) -> Result<(), TilingError> {
+        let background = self.background(None, target)?;
+        self.for_each_tile(src, width, height, |idx, tile| {
+            let mut canvas =
+                RgbaImage::from_pixel(canvas_width as u32, canvas_height as u32, background);
+            let (x, y) = position(
+                gravity,
+                (canvas.width(), canvas.height()),
+                tile.dimensions(),
+            );
+            imageops::overlay(&mut canvas, &tile.to_rgba8(), x, y);
+            self.save(&DynamicImage::ImageRgba8(canvas), &numbered(target, idx))
+        })
+    }
+
+    fn merge(&self, src1: &Path, src2: &Path, target: &Path) -> Result<(), TilingError> {
+        let left = self.open(src1)?;
+        let right = self.open(src2)?;
+
+        let mut canvas = RgbaImage::from_pixel(
+            left.width() + right.width(),
+            left.height().max(right.height()),
+            self.background(None, target)?,
+        );
+        imageops::overlay(&mut canvas, &left.to_rgba8(), 0, 0);
+        imageops::overlay(&mut canvas, &right.to_rgba8(), left.width() as i64, 0);
+        self.save(&DynamicImage::ImageRgba8(canvas), target)
+    }
+
+    fn montage(
+        &self,
+        srcs: &[&Path],
+        target: &Path,
+        x_tiles: i32,
+        y_tiles: i32,
+    ) -> Result<(), TilingError> {
+        let images = srcs
+            .iter()
+            .map(|src| self.open(src))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let vertical = y_tiles > 1 && x_tiles == 1;
+        let (width, height) = if vertical {
+            (
+                images.iter().map(|i| i.width()).max().unwrap_or(0),
+                images.iter().map(|i| i.height()).sum(),
+            )
+        } else {
+            (
+                images.iter().map(|i| i.width()).sum(),
+                images.iter().map(|i| i.height()).max().unwrap_or(0),
+            )
+        };
+
+        let mut canvas = RgbaImage::from_pixel(width, height, self.background(None, target)?);
+        let mut offset = 0;
+        for img in &images {
+            if vertical {
+                imageops::overlay(&mut canvas, &img.to_rgba8(), 0, offset);
+                offset += img.height() as i64;
+            } else {
+                imageops::overlay(&mut canvas, &img.to_rgba8(), offset, 0);
+                offset += img.width() as i64;
+            }
+        }
+
+        let merged = DynamicImage::ImageRgba8(canvas);
+        let resized = resample(
+            &merged,
+            merged.width().div_ceil(2),
+            merged.height().div_ceil(2),
+            self.filter,
+        );
+        self.save(&resized, target)
+    }
+
+    fn montage_with_canvas(
+        &self,
+        srcs: &[Option<&Path>],
+        target: &Path,
+        x_tiles: i32,
+        y_tiles: i32,
+        width: i32,
+        height: i32,
+        background_color: &str,
+        gravity: &str,
+    ) -> Result<(), TilingError> {
+        let (cell_width, cell_height) = (width as u32, height as u32);
+        let mut canvas = RgbaImage::from_pixel(
+            cell_width * x_tiles as u32,
+            cell_height * y_tiles as u32,
+            self.background(Some(background_color), target)?,
+        );
+
+        for (idx, src) in srcs.iter().enumerate() {
+            let Some(src) = src else {
+                continue;
+            };
+            let img = self.open(src)?;
+            let fitted = if img.dimensions() == (cell_width, cell_height) {
+                img
+            } else {
+                let (width, height) = fit_dimensions(img.dimensions(), (cell_width, cell_height));
+                resample(&img, width, height, self.filter)
+            };
+
+            let (cell_x, cell_y) = cell_origin(idx, x_tiles, (cell_width, cell_height));
+            let (x, y) = position(gravity, (cell_width, cell_height), fitted.dimensions());
+            imageops::overlay(&mut canvas, &fitted.to_rgba8(), cell_x + x, cell_y + y);
+        }
+
+        self.save(&DynamicImage::ImageRgba8(canvas), target)
+    }
+
+    fn pyramid_tiff(
+        &self,
+        levels: &[&Path],
+        target: &Path,
+        tile_size: i32,
+        compression: TiffCompression,
+    ) -> Result<(), TilingError> {
+        let write_error = |source| TilingError::OutputNotWritable {
+            path: target.to_path_buf(),
+            source,
+        };
+        if tile_size <= 0 || tile_size % 16 != 0 {
+            return Err(TilingError::InvalidDimensions {
+                path: target.to_path_buf(),
+                width: tile_size,
+                height: tile_size,
+            });
+        }
+
+        let file = BufWriter::new(File::create(target).map_err(write_error)?);
+        let mut writer = TiledTiffWriter::new(
+            file,
+            tile_size as u32,
+            compression,
+            self.encoder.jpeg_quality.clamp(1, 100) as u8,
+        )
+        .map_err(write_error)?;
+        for level in levels {
+            writer
+                .write_level(&self.open(level)?)
+                .map_err(write_error)?;
+        }
+        writer.finish().map_err(write_error)?;
+        Ok(())
+    }
+
+    fn get_dimensions(&self, image: &Path) -> Result<(i32, i32), TilingError> {
+        let read_error = |source| TilingError::InputNotReadable {
+            path: image.to_path_buf(),
+            source,
+        };
+        let (width, height) = Reader::open(image)
+            .map_err(read_error)?
+            .with_guessed_format()
+            .map_err(read_error)?
+            .into_dimensions()
+            .map_err(|e| TilingError::read(image, e))?;
+        Ok((width as i32, height as i32))
+    }
+}
+
+/// Creates an output file.
+fn create(target: &Path) -> Result<BufWriter<File>, TilingError> {
+    File::create(target)
+        .map(BufWriter::new)
+        .map_err(|source| TilingError::OutputNotWritable {
+            path: target.to_path_buf(),
+            source,
+        })
+}
+
+/// The dimensions of an image as JPEG allows them, at most 65535 pixels.
+fn jpeg_dimensions(img: &DynamicImage, target: &Path) -> Result<(u16, u16), TilingError> {
+    match (u16::try_from(img.width()), u16::try_from(img.height())) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(TilingError::InvalidDimensions {
+            path: target.to_path_buf(),
+            width: img.width() as i32,
+            height: img.height() as i32,
+        }),
+    }
+}
+
+/// Reduces an image to a palette of at most 256 colours. Returns the palette
+/// and the palette index of every pixel. Images with no more than 256
+/// distinct colours keep their exact colours; all others are quantised with
+/// NeuQuant.
+fn quantize(img: &RgbaImage) -> (Vec<[u8; 4]>, Vec<u8>) {
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+    let exact = img.pixels().all(|p| {
+        if lookup.contains_key(&p.0) {
+            return true;
+        }
+        if palette.len() == 256 {
+            return false;
+        }
+        lookup.insert(p.0, palette.len() as u8);
+        palette.push(p.0);
+        true
+    });
+    if exact {
+        let indices = img.pixels().map(|p| lookup[&p.0]).collect();
+        return (palette, indices);
+    }
+
+    let quantizer = NeuQuant::new(10, 256, img.as_raw());
+    let palette = quantizer
+        .color_map_rgba()
+        .chunks_exact(4)
+        .map(|c| [c[0], c[1], c[2], c[3]])
+        .collect();
+    let indices = img
+        .pixels()
+        .map(|p| quantizer.index_of(&p.0) as u8)
+        .collect();
+    (palette, indices)
+}
+
+/// Expands the `%d` placeholder in a GM-style output file pattern.
+fn numbered(pattern: &Path, idx: usize) -> std::path::PathBuf {
+    pattern
+        .to_string_lossy()
+        .replace("%d", &idx.to_string())
+        .into()
+}
+
+/// Top-left corner of the cell with the given index on a montage grid.
+fn cell_origin(idx: usize, x_tiles: i32, cell: (u32, u32)) -> (i64, i64) {
+    let col = idx as i64 % x_tiles as i64;
+    let row = idx as i64 / x_tiles as i64;
+    (col * cell.0 as i64, row * cell.1 as i64)
+}
+
+/// Offset of an image of size `inner` inside an area of size `outer`, as
+/// defined by a GM gravity name (e.g. "SouthWest").
+fn position(gravity: &str, outer: (u32, u32), inner: (u32, u32)) -> (i64, i64) {
+    let free_x = outer.0 as i64 - inner.0 as i64;
+    let free_y = outer.1 as i64 - inner.1 as i64;
+    let gravity = gravity.to_lowercase();
+
+    let x = if gravity.ends_with("west") {
+        0
+    } else if gravity.ends_with("east") {
+        free_x
+    } else {
+        free_x / 2
+    };
+    let y = if gravity.starts_with("north") {
+        0
+    } else if gravity.starts_with("south") {
+        free_y
+    } else {
+        free_y / 2
+    };
+    (x, y)
+}
+
+/// Parses a `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa` color string, or a
+/// color name (see `named_color`).
+pub fn parse_color(color: &str) -> Option<Rgba<u8>> {
+    let Some(hex) = color.strip_prefix('#') else {
+        return named_color(color).map(Rgba);
+    };
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+
+    match hex.len() {
+        3 | 4 => {
+            let mut rgba = [255u8; 4];
+            for (i, c) in hex.chars().enumerate() {
+                let v = c.to_digit(16)? as u8;
+                rgba[i] = v * 16 + v;
+            }
+            Some(Rgba(rgba))
+        }
+        6 => Some(Rgba([channel(0)?, channel(2)?, channel(4)?, 255])),
+        8 => Some(Rgba([channel(0)?, channel(2)?, channel(4)?, channel(6)?])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse_color("#f00"), Some(Rgba([255, 0, 0, 255])));
+        assert_eq!(parse_color("#f008"), Some(Rgba([255, 0, 0, 136])));
+        assert_eq!(parse_color("#102030"), Some(Rgba([16, 32, 48, 255])));
+        assert_eq!(parse_color("#10203040"), Some(Rgba([16, 32, 48, 64])));
+        assert_eq!(parse_color("#12345"), None);
+        assert_eq!(parse_color("#ggg"), None);
+    }
+
+    #[test]
+    fn parses_color_names() {
+        assert_eq!(parse_color("black"), Some(Rgba([0, 0, 0, 255])));
+        assert_eq!(parse_color("none"), Some(Rgba([0, 0, 0, 0])));
+        assert_eq!(parse_color("blak"), None);
+    }
+
+    #[test]
+    fn unknown_background_color_is_an_error() {
+        let mut processor = NativeImageProcessor::new();
+        processor.set_background_color(Some("blak".to_string()));
+        let err = processor
+            .background(None, Path::new("tile.png"))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            TilingError::InvalidColor { ref color, .. } if color == "blak"
+        ));
+        assert_eq!(
+            processor
+                .background(Some("black"), Path::new("tile.png"))
+                .unwrap(),
+            Rgba([0, 0, 0, 255])
+        );
+    }
+
+    #[test]
+    fn scaling_uses_the_resampling_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.png");
+        image::RgbImage::from_fn(4, 1, |x, _| image::Rgb([[0, 100, 200, 250][x as usize]; 3]))
+            .save(&src)
+            .unwrap();
+
+        let mut processor = NativeImageProcessor::with_format(ImageFormat::PNG);
+        let mut scaled = |filter| {
+            processor.set_resampling_filter(filter);
+            let target = dir.path().join(format!("{}.png", filter));
+            processor.scale(&src, &target, 2, 1).unwrap();
+            let pixels = image::open(&target).unwrap().to_rgb8();
+            (pixels.get_pixel(0, 0)[0], pixels.get_pixel(1, 0)[0])
+        };
+        assert_eq!(scaled(ResamplingFilter::Box), (50, 225));
+        // The wider Lanczos kernel also takes in the neighbouring pixels
+        assert_eq!(scaled(ResamplingFilter::Lanczos3), (57, 223));
+    }
+
+    /// Writes a gradient tile with the given options and returns its bytes
+    fn encode(format: ImageFormat, options: EncoderOptions, ext: &str) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join(format!("tile.{}", ext));
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([x as u8 * 8, y as u8 * 8, 128])
+        }));
+        let mut processor = NativeImageProcessor::with_format(format);
+        processor.set_encoder_options(options);
+        processor.save(&img, &target).unwrap();
+        std::fs::read(target).unwrap()
+    }
+
+    #[test]
+    fn jpeg_options_are_applied() {
+        let jpeg = |options| encode(ImageFormat::JPEG, options, "jpg");
+        let baseline = jpeg(EncoderOptions::default());
+        // Start of frame: baseline (C0) or progressive (C2)
+        let has_marker = |data: &[u8], marker: u8| data.windows(2).any(|w| w == [0xFF, marker]);
+        assert!(has_marker(&baseline, 0xC0) && !has_marker(&baseline, 0xC2));
+        let progressive = jpeg(EncoderOptions {
+            jpeg_progressive: true,
+            ..EncoderOptions::default()
+        });
+        assert!(has_marker(&progressive, 0xC2));
+
+        let low = jpeg(EncoderOptions {
+            jpeg_quality: 10,
+            ..EncoderOptions::default()
+        });
+        let high = jpeg(EncoderOptions {
+            jpeg_quality: 100,
+            chroma_subsampling: ChromaSubsampling::YCbCr444,
+            ..EncoderOptions::default()
+        });
+        assert!(low.len() < baseline.len() && baseline.len() < high.len());
+    }
+
+    #[test]
+    fn png_options_are_applied() {
+        let png = |options| {
+            let data = encode(ImageFormat::PNG, options, "png");
+            // Bit depth and colour type of the IHDR chunk
+            (data[24], data[25])
+        };
+        assert_eq!(png(EncoderOptions::default()), (8, 2));
+        assert_eq!(
+            png(EncoderOptions {
+                png_bit_depth: 16,
+                ..EncoderOptions::default()
+            }),
+            (16, 2)
+        );
+        assert_eq!(
+            png(EncoderOptions {
+                png_palette: true,
+                png_bit_depth: 16,
+                ..EncoderOptions::default()
+            }),
+            (8, 3)
+        );
+    }
+
+    #[test]
+    fn writable_formats_depend_on_the_options() {
+        let mut processor = NativeImageProcessor::new();
+        assert!(processor.can_write(ImageFormat::TIFF));
+        assert!(!processor.can_write(ImageFormat::WebP));
+        processor.set_encoder_options(EncoderOptions {
+            tiff_compression: Some(TiffCompression::JPEG),
+            ..EncoderOptions::default()
+        });
+        assert!(!processor.can_write(ImageFormat::TIFF));
+        assert!(processor.can_write(ImageFormat::WebPLossless));
+    }
+}
@@ -0,0 +1,299 @@
+use std::f64::consts::PI;
+
+use image::{DynamicImage, GenericImageView, RgbImage, RgbaImage};
+
+/// The filters available for scaling images, e.g. down to the next level of
+/// a pyramid. Sharper filters preserve fine detail such as text better, but
+/// may produce ringing around hard edges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResamplingFilter {
+    /// Averages the source pixels covered by a target pixel
+    Box,
+    /// Linear interpolation (a tent filter)
+    Triangle,
+    /// The Catmull-Rom cubic spline, sharp with little ringing
+    CatmullRom,
+    /// Windowed sinc with 3 lobes, the sharpest filter
+    #[default]
+    Lanczos3,
+    /// The Mitchell-Netravali cubic (B = C = 1/3), a balance between blurring
+    /// and ringing
+    Mitchell,
+}
+
+/// The source pixels contributing to a target pixel, starting at `start`,
+/// and their normalized weights
+#[derive(Debug, Clone)]
+pub(crate) struct Taps {
+    pub start: usize,
+    pub weights: Vec<f32>,
+}
+
+impl ResamplingFilter {
+    /// The name of the filter in the `-filter` option of GraphicsMagick and
+    /// ImageMagick
+    pub fn magick_name(&self) -> &'static str {
+        match self {
+            ResamplingFilter::Box => "Box",
+            ResamplingFilter::Triangle => "Triangle",
+            ResamplingFilter::CatmullRom => "Catrom",
+            ResamplingFilter::Lanczos3 => "Lanczos",
+            ResamplingFilter::Mitchell => "Mitchell",
+        }
+    }
+
+    /// The radius of the filter kernel, in target pixels
+    pub(crate) fn support(&self) -> f64 {
+        match self {
+            ResamplingFilter::Box => 0.5,
+            ResamplingFilter::Triangle => 1.0,
+            ResamplingFilter::CatmullRom | ResamplingFilter::Mitchell => 2.0,
+            ResamplingFilter::Lanczos3 => 3.0,
+        }
+    }
+
+    /// The (unnormalized) kernel weight at distance `x`, in target pixels
+    fn weight(&self, x: f64) -> f64 {
+        match self {
+            // Half-open, so that a source pixel centered exactly between two
+            // target pixels counts only once
+            ResamplingFilter::Box if (-0.5..0.5).contains(&x) => 1.0,
+            ResamplingFilter::Box => 0.0,
+            ResamplingFilter::Triangle => (1.0 - x.abs()).max(0.0),
+            ResamplingFilter::CatmullRom => cubic(x, 0.0, 0.5),
+            ResamplingFilter::Mitchell => cubic(x, 1.0 / 3.0, 1.0 / 3.0),
+            ResamplingFilter::Lanczos3 if x.abs() < 3.0 => sinc(x) * sinc(x / 3.0),
+            ResamplingFilter::Lanczos3 => 0.0,
+        }
+    }
+
+    /// Computes the taps of target pixel `target` for a source of
+    /// `source_len` pixels, where one target pixel covers `scale` source
+    /// pixels. Source pixels beyond the edges are left out and the remaining
+    /// weights renormalized.
+    pub(crate) fn taps(&self, target: usize, source_len: usize, scale: f64) -> Taps {
+        // When scaling down, the kernel is stretched to cover all source
+        // pixels of a target pixel
+        let stretch = scale.max(1.0);
+        let support = self.support() * stretch;
+        let center = (target as f64 + 0.5) * scale;
+        let start = ((center - support).floor().max(0.0) as usize).min(source_len);
+        let end = ((center + support).ceil().max(0.0) as usize).min(source_len);
+
+        let weights: Vec<f64> = (start..end)
+            .map(|j| self.weight((j as f64 + 0.5 - center) / stretch))
+            .collect();
+        let sum: f64 = weights.iter().sum();
+        if sum.abs() < f64::EPSILON {
+            // Only possible if the target pixel lies outside the source;
+            // fall back to the nearest source pixel
+            let nearest = (center as usize).min(source_len.saturating_sub(1));
+            return Taps {
+                start: nearest,
+                weights: vec![1.0],
+            };
+        }
+        Taps {
+            start,
+            weights: weights.iter().map(|w| (w / sum) as f32).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for ResamplingFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.magick_name())
+    }
+}
+
+/// Keys' family of cubic filters, with the parameters B and C
+fn cubic(x: f64, b: f64, c: f64) -> f64 {
+    let x = x.abs();
+    let w = if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+            + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+            + (6.0 - 2.0 * b)
+    } else if x < 2.0 {
+        (-b - 6.0 * c) * x.powi(3)
+            + (6.0 * b + 30.0 * c) * x.powi(2)
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    } else {
+        0.0
+    };
+    w / 6.0
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Converts a filtered channel value back to 8 bits
+pub(crate) fn to_u8(value: f32) -> u8 {
+    (value + 0.5).clamp(0.0, 255.0) as u8
+}
+
+/// Scales an image to exactly the given dimensions with a filter. Images
+/// with an alpha channel are scaled as RGBA, all others as RGB.
+pub(crate) fn resample(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: ResamplingFilter,
+) -> DynamicImage {
+    let (width, height) = (width.max(1), height.max(1));
+    let source = img.dimensions();
+    if source == (width, height) {
+        return img.clone();
+    }
+
+    if img.color().has_alpha() {
+        let pixels = resample_pixels(img.to_rgba8().as_raw(), 4, source, (width, height), filter);
+        DynamicImage::ImageRgba8(
+            RgbaImage::from_raw(width, height, pixels).expect("buffer matches its dimensions"),
+        )
+    } else {
+        let pixels = resample_pixels(img.to_rgb8().as_raw(), 3, source, (width, height), filter);
+        DynamicImage::ImageRgb8(
+            RgbImage::from_raw(width, height, pixels).expect("buffer matches its dimensions"),
+        )
+    }
+}
+
+/// Scales interleaved 8-bit pixels, first horizontally, then vertically
+fn resample_pixels(
+    pixels: &[u8],
+    channels: usize,
+    (source_width, source_height): (u32, u32),
+    (width, height): (u32, u32),
+    filter: ResamplingFilter,
+) -> Vec<u8> {
+    let (source_width, source_height) = (source_width as usize, source_height as usize);
+    let (width, height) = (width as usize, height as usize);
+
+    let columns: Vec<Taps> = (0..width)
+        .map(|x| filter.taps(x, source_width, source_width as f64 / width as f64))
+        .collect();
+    let mut horizontal = vec![0f32; width * source_height * channels];
+    for (y, row) in horizontal.chunks_exact_mut(width * channels).enumerate() {
+        let source_row = &pixels[y * source_width * channels..(y + 1) * source_width * channels];
+        for (pixel, taps) in row.chunks_exact_mut(channels).zip(&columns) {
+            for (i, weight) in taps.weights.iter().enumerate() {
+                let offset = (taps.start + i) * channels;
+                for (c, value) in pixel.iter_mut().enumerate() {
+                    *value += source_row[offset + c] as f32 * weight;
+                }
+            }
+        }
+    }
+
+    let stride = width * channels;
+    let mut scaled = vec![0u8; width * height * channels];
+    for (y, row) in scaled.chunks_exact_mut(stride).enumerate() {
+        let taps = filter.taps(y, source_height, source_height as f64 / height as f64);
+        let mut sums = vec![0f32; stride];
+        for (i, weight) in taps.weights.iter().enumerate() {
+            let source_row = &horizontal[(taps.start + i) * stride..(taps.start + i + 1) * stride];
+            for (sum, value) in sums.iter_mut().zip(source_row) {
+                *sum += value * weight;
+            }
+        }
+        for (target, sum) in row.iter_mut().zip(sums) {
+            *target = to_u8(sum);
+        }
+    }
+    scaled
+}
+
+/// The largest dimensions with the aspect ratio of `source` that fit into
+/// `bounds`, like GraphicsMagick's `-resize WxH`
+pub(crate) fn fit_dimensions(source: (u32, u32), bounds: (u32, u32)) -> (u32, u32) {
+    let scale =
+        (bounds.0 as f64 / source.0.max(1) as f64).min(bounds.1 as f64 / source.1.max(1) as f64);
+    (
+        ((source.0 as f64 * scale).round() as u32).clamp(1, bounds.0.max(1)),
+        ((source.1 as f64 * scale).round() as u32).clamp(1, bounds.1.max(1)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [ResamplingFilter; 5] = [
+        ResamplingFilter::Box,
+        ResamplingFilter::Triangle,
+        ResamplingFilter::CatmullRom,
+        ResamplingFilter::Lanczos3,
+        ResamplingFilter::Mitchell,
+    ];
+
+    #[test]
+    fn filters_have_magick_names() {
+        let names: Vec<_> = FILTERS.iter().map(|f| f.magick_name()).collect();
+        assert_eq!(names, ["Box", "Triangle", "Catrom", "Lanczos", "Mitchell"]);
+        assert_eq!(ResamplingFilter::default(), ResamplingFilter::Lanczos3);
+        assert_eq!(ResamplingFilter::CatmullRom.to_string(), "Catrom");
+    }
+
+    #[test]
+    fn taps_are_normalized() {
+        for filter in FILTERS {
+            for (source_len, scale) in [(100, 2.0), (100, 0.5), (7, 7.0 / 3.0)] {
+                for target in [0, 1, (source_len as f64 / scale) as usize - 1] {
+                    let taps = filter.taps(target, source_len, scale);
+                    let sum: f32 = taps.weights.iter().sum();
+                    assert!(
+                        (sum - 1.0).abs() < 1e-5,
+                        "{} at {}: {}",
+                        filter,
+                        target,
+                        sum
+                    );
+                    assert!(taps.start + taps.weights.len() <= source_len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn box_filter_averages_the_covered_pixels() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(4, 1, |x, _| {
+            image::Rgb([[0, 100, 200, 250][x as usize]; 3])
+        }));
+        let scaled = resample(&img, 2, 1, ResamplingFilter::Box).to_rgb8();
+        assert_eq!(scaled.get_pixel(0, 0).0, [50; 3]);
+        assert_eq!(scaled.get_pixel(1, 0).0, [225; 3]);
+    }
+
+    #[test]
+    fn uniform_images_stay_uniform() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            9,
+            5,
+            image::Rgba([10, 120, 240, 128]),
+        ));
+        for filter in FILTERS {
+            for (width, height) in [(4, 2), (20, 11)] {
+                let scaled = resample(&img, width, height, filter).to_rgba8();
+                assert_eq!(scaled.dimensions(), (width, height));
+                assert!(
+                    scaled.pixels().all(|p| p.0 == [10, 120, 240, 128]),
+                    "{}",
+                    filter
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fitted_dimensions_keep_the_aspect_ratio() {
+        assert_eq!(fit_dimensions((300, 200), (150, 150)), (150, 100));
+        assert_eq!(fit_dimensions((200, 300), (150, 150)), (100, 150));
+        assert_eq!(fit_dimensions((1000, 1), (10, 10)), (10, 1));
+    }
+}
@@ -0,0 +1,419 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+use flate2::write::ZlibEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, GenericImageView, RgbImage};
+use serde::{Deserialize, Serialize};
+
+/// Compression schemes for tiled TIFF output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TiffCompression {
+    /// JPEG compression (lossy)
+    JPEG,
+    /// Adobe Deflate (zlib) compression (lossless)
+    Deflate,
+    /// LZW compression (lossless)
+    LZW,
+}
+
+impl TiffCompression {
+    /// The value of the TIFF Compression tag
+    pub fn tag_value(&self) -> u16 {
+        match self {
+            TiffCompression::JPEG => 7,
+            TiffCompression::Deflate => 8,
+            TiffCompression::LZW => 5,
+        }
+    }
+
+    /// The name of the compression type on the GM/IM command line
+    pub fn command_line_name(&self) -> &'static str {
+        match self {
+            TiffCompression::JPEG => "JPEG",
+            TiffCompression::Deflate => "Zip",
+            TiffCompression::LZW => "LZW",
+        }
+    }
+}
+
+// TIFF tags
+const NEW_SUBFILE_TYPE: u16 = 254;
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const PLANAR_CONFIGURATION: u16 = 284;
+const TILE_WIDTH: u16 = 322;
+const TILE_LENGTH: u16 = 323;
+const TILE_OFFSETS: u16 = 324;
+const TILE_BYTE_COUNTS: u16 = 325;
+const YCBCR_SUBSAMPLING: u16 = 530;
+
+// TIFF field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+const PHOTOMETRIC_RGB: u16 = 2;
+const PHOTOMETRIC_YCBCR: u16 = 6;
+
+/// A single directory entry. Values that don't fit into the entry itself are
+/// written to the file before the directory.
+struct Entry {
+    tag: u16,
+    field_type: u16,
+    values: Vec<u32>,
+}
+
+impl Entry {
+    fn short(tag: u16, values: &[u16]) -> Self {
+        Self {
+            tag,
+            field_type: SHORT,
+            values: values.iter().map(|v| *v as u32).collect(),
+        }
+    }
+
+    fn long(tag: u16, values: &[u32]) -> Self {
+        Self {
+            tag,
+            field_type: LONG,
+            values: values.to_vec(),
+        }
+    }
+
+    fn value_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for v in &self.values {
+            if self.field_type == SHORT {
+                bytes.extend_from_slice(&(*v as u16).to_le_bytes());
+            } else {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+/// Writes a tiled, multi-resolution (pyramid) TIFF, one resolution level
+/// at a time, starting with the full-resolution image. Each level is stored
+/// in its own image file directory, with all but the first flagged as
+/// reduced-resolution versions of the first.
+///
+/// Images are stored as 8-bit RGB. JPEG-compressed tiles are stored as
+/// YCbCr without chroma subsampling.
+pub struct TiledTiffWriter<W: Write + Seek> {
+    writer: W,
+    tile_size: u32,
+    compression: TiffCompression,
+    jpeg_quality: u8,
+    /// Tag entries of all levels written so far
+    directories: Vec<Vec<Entry>>,
+}
+
+impl<W: Write + Seek> TiledTiffWriter<W> {
+    pub fn new(
+        mut writer: W,
+        tile_size: u32,
+        compression: TiffCompression,
+        jpeg_quality: u8,
+    ) -> io::Result<Self> {
+        if tile_size == 0 || !tile_size.is_multiple_of(16) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TIFF tile size must be a multiple of 16",
+            ));
+        }
+
+        // Little-endian header; the offset of the first IFD is filled in by finish()
+        writer.write_all(b"II")?;
+        writer.write_all(&42u16.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            tile_size,
+            compression,
+            jpeg_quality: jpeg_quality.clamp(1, 100),
+            directories: Vec::new(),
+        })
+    }
+
+    /// Writes the tiles of the next resolution level.
+    pub fn write_level(&mut self, img: &DynamicImage) -> io::Result<()> {
+        let rgb = img.to_rgb8();
+        let (width, height) = rgb.dimensions();
+        let columns = width.div_ceil(self.tile_size);
+        let rows = height.div_ceil(self.tile_size);
+
+        let mut offsets = Vec::with_capacity((columns * rows) as usize);
+        let mut byte_counts = Vec::with_capacity((columns * rows) as usize);
+        for row in 0..rows {
+            for column in 0..columns {
+                let tile = self.extract_tile(&rgb, column, row);
+                let data = self.compress(tile)?;
+                offsets.push(self.position()?);
+                byte_counts.push(data.len() as u32);
+                self.writer.write_all(&data)?;
+                self.word_align()?;
+            }
+        }
+
+        let photometric = match self.compression {
+            TiffCompression::JPEG => PHOTOMETRIC_YCBCR,
+            _ => PHOTOMETRIC_RGB,
+        };
+        let subfile_type = if self.directories.is_empty() { 0 } else { 1 };
+
+        let mut entries = vec![
+            Entry::long(NEW_SUBFILE_TYPE, &[subfile_type]),
+            Entry::long(IMAGE_WIDTH, &[width]),
+            Entry::long(IMAGE_LENGTH, &[height]),
+            Entry::short(BITS_PER_SAMPLE, &[8, 8, 8]),
+            Entry::short(COMPRESSION, &[self.compression.tag_value()]),
+            Entry::short(PHOTOMETRIC_INTERPRETATION, &[photometric]),
+            Entry::short(SAMPLES_PER_PIXEL, &[3]),
+            Entry::short(PLANAR_CONFIGURATION, &[1]),
+            Entry::long(TILE_WIDTH, &[self.tile_size]),
+            Entry::long(TILE_LENGTH, &[self.tile_size]),
+            Entry::long(TILE_OFFSETS, &offsets),
+            Entry::long(TILE_BYTE_COUNTS, &byte_counts),
+        ];
+        if self.compression == TiffCompression::JPEG {
+            entries.push(Entry::short(YCBCR_SUBSAMPLING, &[1, 1]));
+        }
+        self.directories.push(entries);
+        Ok(())
+    }
+
+    /// Writes the image file directories and links them into a chain.
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut previous_next_pointer = 4;
+        for entries in std::mem::take(&mut self.directories) {
+            // Out-of-line values go before the directory
+            let mut value_offsets = Vec::with_capacity(entries.len());
+            for entry in &entries {
+                let bytes = entry.value_bytes();
+                if bytes.len() > 4 {
+                    value_offsets.push(Some(self.position()?));
+                    self.writer.write_all(&bytes)?;
+                    self.word_align()?;
+                } else {
+                    value_offsets.push(None);
+                }
+            }
+
+            let directory_offset = self.position()?;
+            self.patch(previous_next_pointer, directory_offset)?;
+
+            self.writer
+                .write_all(&(entries.len() as u16).to_le_bytes())?;
+            for (entry, value_offset) in entries.iter().zip(value_offsets) {
+                self.writer.write_all(&entry.tag.to_le_bytes())?;
+                self.writer.write_all(&entry.field_type.to_le_bytes())?;
+                self.writer
+                    .write_all(&(entry.values.len() as u32).to_le_bytes())?;
+                match value_offset {
+                    Some(offset) => self.writer.write_all(&offset.to_le_bytes())?,
+                    None => {
+                        let mut inline = entry.value_bytes();
+                        inline.resize(4, 0);
+                        self.writer.write_all(&inline)?;
+                    }
+                }
+            }
+
+            previous_next_pointer = self.position()?;
+            self.writer.write_all(&0u32.to_le_bytes())?;
+        }
+
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn extract_tile(&self, img: &RgbImage, column: u32, row: u32) -> RgbImage {
+        let x = column * self.tile_size;
+        let y = row * self.tile_size;
+        let view = img.view(
+            x,
+            y,
+            self.tile_size.min(img.width() - x),
+            self.tile_size.min(img.height() - y),
+        );
+
+        // TIFF tiles always have the full tile size, border tiles are padded
+        let mut tile = RgbImage::new(self.tile_size, self.tile_size);
+        image::imageops::replace(&mut tile, &*view, 0, 0);
+        tile
+    }
+
+    fn compress(&self, tile: RgbImage) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match self.compression {
+            TiffCompression::JPEG => {
+                JpegEncoder::new_with_quality(&mut data, self.jpeg_quality)
+                    .encode_image(&tile)
+                    .map_err(io::Error::other)?;
+            }
+            TiffCompression::Deflate => {
+                let mut encoder = ZlibEncoder::new(&mut data, flate2::Compression::default());
+                encoder.write_all(tile.as_raw())?;
+                encoder.finish()?;
+            }
+            TiffCompression::LZW => {
+                data = weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8)
+                    .encode(tile.as_raw())
+                    .map_err(io::Error::other)?;
+            }
+        }
+        Ok(data)
+    }
+
+    fn position(&mut self) -> io::Result<u32> {
+        let position = self.writer.stream_position()?;
+        u32::try_from(position).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Pyramid TIFF exceeds the 4 GB limit of classic TIFF",
+            )
+        })
+    }
+
+    fn word_align(&mut self) -> io::Result<()> {
+        if !self.position()?.is_multiple_of(2) {
+            self.writer.write_all(&[0])?;
+        }
+        Ok(())
+    }
+
+    fn patch(&mut self, pointer: u32, value: u32) -> io::Result<()> {
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(pointer as u64))?;
+        self.writer.write_all(&value.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Reads the (tag, values) entries of the directory at `offset`, and the
+    /// offset of the next directory.
+    fn read_directory(data: &[u8], offset: usize) -> (Vec<(u16, Vec<u32>)>, u32) {
+        let count = u16_at(data, offset) as usize;
+        let mut entries = Vec::new();
+        for i in 0..count {
+            let entry = offset + 2 + i * 12;
+            let field_type = u16_at(data, entry + 2);
+            let n = u32_at(data, entry + 4) as usize;
+            let size = if field_type == SHORT { 2 } else { 4 };
+            let values_at = if n * size > 4 {
+                u32_at(data, entry + 8) as usize
+            } else {
+                entry + 8
+            };
+            let values = (0..n)
+                .map(|i| match field_type {
+                    SHORT => u16_at(data, values_at + i * 2) as u32,
+                    _ => u32_at(data, values_at + i * 4),
+                })
+                .collect();
+            entries.push((u16_at(data, entry), values));
+        }
+        (entries, u32_at(data, offset + 2 + count * 12))
+    }
+
+    fn value(entries: &[(u16, Vec<u32>)], tag: u16) -> &[u32] {
+        &entries.iter().find(|(t, _)| *t == tag).unwrap().1
+    }
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([x as u8, y as u8, (x + y) as u8])
+        }))
+    }
+
+    #[test]
+    fn writes_one_directory_per_level() {
+        let mut writer =
+            TiledTiffWriter::new(Cursor::new(Vec::new()), 32, TiffCompression::Deflate, 90)
+                .unwrap();
+        writer.write_level(&gradient(100, 40)).unwrap();
+        writer.write_level(&gradient(50, 20)).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(&data[..4], b"II\x2a\x00");
+        let (first, next) = read_directory(&data, u32_at(&data, 4) as usize);
+        let (second, last) = read_directory(&data, next as usize);
+        assert_eq!(last, 0);
+
+        for (entries, (width, height), subfile_type, tiles) in
+            [(&first, (100, 40), 0, 4 * 2), (&second, (50, 20), 1, 2)]
+        {
+            // Tags must be sorted in ascending order
+            assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+            assert_eq!(value(entries, NEW_SUBFILE_TYPE), [subfile_type]);
+            assert_eq!(value(entries, IMAGE_WIDTH), [width]);
+            assert_eq!(value(entries, IMAGE_LENGTH), [height]);
+            assert_eq!(value(entries, BITS_PER_SAMPLE), [8, 8, 8]);
+            assert_eq!(value(entries, COMPRESSION), [8]);
+            assert_eq!(value(entries, PHOTOMETRIC_INTERPRETATION), [2]);
+            assert_eq!(value(entries, TILE_WIDTH), [32]);
+            assert_eq!(value(entries, TILE_LENGTH), [32]);
+
+            let offsets = value(entries, TILE_OFFSETS);
+            let byte_counts = value(entries, TILE_BYTE_COUNTS);
+            assert_eq!(offsets.len(), tiles);
+            assert_eq!(byte_counts.len(), tiles);
+            for (offset, count) in offsets.iter().zip(byte_counts) {
+                let compressed = &data[*offset as usize..(*offset + *count) as usize];
+                let mut tile = Vec::new();
+                flate2::read::ZlibDecoder::new(compressed)
+                    .read_to_end(&mut tile)
+                    .unwrap();
+                assert_eq!(tile.len(), 32 * 32 * 3);
+            }
+        }
+    }
+
+    #[test]
+    fn levels_decode_with_the_tiff_crate() {
+        let mut writer =
+            TiledTiffWriter::new(Cursor::new(Vec::new()), 16, TiffCompression::LZW, 90).unwrap();
+        writer.write_level(&gradient(40, 24)).unwrap();
+        writer.write_level(&gradient(20, 12)).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        let mut decoder = tiff::decoder::Decoder::new(Cursor::new(data)).unwrap();
+        for (width, height) in [(40, 24), (20, 12)] {
+            assert_eq!(decoder.dimensions().unwrap(), (width, height));
+            let tiff::decoder::DecodingResult::U8(pixels) = decoder.read_image().unwrap() else {
+                panic!("Expected 8-bit samples");
+            };
+            assert_eq!(pixels, gradient(width, height).to_rgb8().into_raw());
+            if width == 40 {
+                decoder.next_image().unwrap();
+            }
+        }
+        assert!(!decoder.more_images());
+    }
+
+    #[test]
+    fn rejects_tile_sizes_that_are_not_multiples_of_16() {
+        assert!(
+            TiledTiffWriter::new(Cursor::new(Vec::new()), 24, TiffCompression::LZW, 90).is_err()
+        );
+    }
+}
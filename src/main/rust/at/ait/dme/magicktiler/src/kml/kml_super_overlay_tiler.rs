@@ -0,0 +1,455 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{debug, error, info};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::geo::BoundingBox;
+use crate::image::ImageFormat;
+use crate::magick_tiler::{BaseMagickTiler, MagickTiler, TilingError};
+use crate::sink::TileSink;
+use crate::tile_set_info::TileSetInfo;
+use crate::tms::TMSTiler;
+
+/// A tiler that generates a KML Super-Overlay for Google Earth.
+///
+/// Tiles are generated in the TMS layout (see `TMSTiler`), i.e.
+/// /tileset-root/[zoomlevel]/[column]/[row].jpg, with a KML file next to each
+/// tile:
+/// /tileset-root/[zoomlevel]/[column]/[row].kml
+///
+/// Each tile KML holds a `Region` limiting it to the tile's extent, a
+/// `GroundOverlay` draping the tile image over that extent and `NetworkLink`s
+/// to the (up to four) tiles of the next zoom level covering the same area.
+/// Google Earth thereby loads finer tiles only as the user zooms in. The
+/// entry point is /tileset-root/doc.kml.
+///
+/// The image is expected to be in a geographic (equirectangular) projection
+/// and to span the configured bounding box exactly.
+pub struct KMLSuperOverlayTiler {
+    tms: TMSTiler,
+
+    /// Geographical bounding box of the image
+    bbox: Option<BoundingBox>,
+
+    /// Whether to package the super-overlay into a single KMZ file
+    kmz: bool,
+}
+
+pub const ROOT_KML: &str = "doc.kml";
+
+/// The image formats Google Earth can overlay
+const TILE_FORMATS: &[ImageFormat] = &[ImageFormat::JPEG, ImageFormat::PNG, ImageFormat::TIFF];
+
+const ROOT_KML_TEMPLATE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2">
+  <Document>
+    <name>@name@</name>
+@network.links@  </Document>
+</kml>
+"#;
+
+const TILE_KML_TEMPLATE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2">
+  <Document>
+    <name>@name@</name>
+    <Region>
+      <Lod>
+        <minLodPixels>@minlod@</minLodPixels>
+        <maxLodPixels>-1</maxLodPixels>
+      </Lod>
+      <LatLonAltBox>
+        <north>@north@</north>
+        <south>@south@</south>
+        <east>@east@</east>
+        <west>@west@</west>
+      </LatLonAltBox>
+    </Region>
+@network.links@    <GroundOverlay>
+      <drawOrder>@draworder@</drawOrder>
+      <Icon>
+        <href>@img.href@</href>
+      </Icon>
+      <LatLonBox>
+        <north>@north@</north>
+        <south>@south@</south>
+        <east>@east@</east>
+        <west>@west@</west>
+      </LatLonBox>
+    </GroundOverlay>
+  </Document>
+</kml>
+"#;
+
+const NETWORK_LINK_TEMPLATE: &str = r#"    <NetworkLink>
+      <name>@name@</name>
+      <Region>
+        <Lod>
+          <minLodPixels>@minlod@</minLodPixels>
+          <maxLodPixels>-1</maxLodPixels>
+        </Lod>
+        <LatLonAltBox>
+          <north>@north@</north>
+          <south>@south@</south>
+          <east>@east@</east>
+          <west>@west@</west>
+        </LatLonAltBox>
+      </Region>
+      <Link>
+        <href>@href@</href>
+        <viewRefreshMode>onRegion</viewRefreshMode>
+      </Link>
+    </NetworkLink>
+"#;
+
+impl KMLSuperOverlayTiler {
+    pub fn new() -> Self {
+        let mut tms = TMSTiler::new();
+        // The OpenLayers preview of the TMS tiler is of no use here
+        tms.base_mut().set_generate_preview_html(false);
+        Self {
+            tms,
+            bbox: None,
+            kmz: false,
+        }
+    }
+
+    pub fn bounding_box(&self) -> Option<&BoundingBox> {
+        self.bbox.as_ref()
+    }
+
+    /// Sets the geographical bounding box of the image. Required.
+    pub fn set_bounding_box(&mut self, bbox: BoundingBox) {
+        self.bbox = Some(bbox);
+    }
+
+    /// Sets whether `convert_to` packages the super-overlay into a single
+    /// KMZ file instead of a directory (default: false).
+    pub fn set_kmz(&mut self, kmz: bool) {
+        self.kmz = kmz;
+    }
+
+    pub fn set_tile_size(&mut self, size: i32) {
+        self.tms.base_mut().set_tile_size(size);
+    }
+
+    /// Returns the geographical extent of a TMS tile. Rows count from the
+    /// bottom; border tiles include the background buffer TMS adds at the
+    /// top and right of the image.
+    fn tile_bounds(
+        &self,
+        bbox: &BoundingBox,
+        info: &TileSetInfo,
+        zoomlevel: i32,
+        col: i32,
+        row: i32,
+    ) -> BoundingBox {
+        let factor = 2_f64.powi(info.zoom_levels() - zoomlevel - 1);
+        let tile_width = info.tile_width() as f64 * factor;
+        let tile_height = info.tile_height() as f64 * factor;
+        bbox.pixel_region(
+            info.image_width(),
+            info.image_height(),
+            col as f64 * tile_width,
+            info.image_height() as f64 - (row + 1) as f64 * tile_height,
+            tile_width,
+            tile_height,
+        )
+    }
+
+    /// Returns the number of columns and rows of a TMS zoom level.
+    fn level_tiles(info: &TileSetInfo, zoomlevel: i32) -> (i32, i32) {
+        let level = info.zoom_levels() - zoomlevel - 1;
+        (info.number_of_x_tiles(level), info.number_of_y_tiles(level))
+    }
+
+    fn network_link(&self, name: &str, bounds: &BoundingBox, href: &str, min_lod: i32) -> String {
+        NETWORK_LINK_TEMPLATE
+            .replace("@name@", name)
+            .replace("@minlod@", &min_lod.to_string())
+            .replace("@north@", &bounds.north().to_string())
+            .replace("@south@", &bounds.south().to_string())
+            .replace("@east@", &bounds.east().to_string())
+            .replace("@west@", &bounds.west().to_string())
+            .replace("@href@", href)
+    }
+
+    fn generate_tile_kml(
+        &self,
+        bbox: &BoundingBox,
+        info: &TileSetInfo,
+        zoomlevel: i32,
+        col: i32,
+        row: i32,
+    ) -> Result<(), TilingError> {
+        let min_lod = info.tile_width().min(info.tile_height()) / 2;
+        let bounds = self.tile_bounds(bbox, info, zoomlevel, col, row);
+
+        let mut network_links = String::new();
+        if zoomlevel + 1 < info.zoom_levels() {
+            let (columns, rows) = Self::level_tiles(info, zoomlevel + 1);
+            for x in 0..2 {
+                for y in 0..2 {
+                    let (child_col, child_row) = (col * 2 + x, row * 2 + y);
+                    if child_col >= columns || child_row >= rows {
+                        continue;
+                    }
+                    let child = self.tile_bounds(bbox, info, zoomlevel + 1, child_col, child_row);
+                    network_links.push_str(&self.network_link(
+                        &format!("{}/{}/{}", zoomlevel + 1, child_col, child_row),
+                        &child,
+                        &format!("../../{}/{}/{}.kml", zoomlevel + 1, child_col, child_row),
+                        min_lod,
+                    ));
+                }
+            }
+        }
+
+        let kml = TILE_KML_TEMPLATE
+            .replace("@name@", &format!("{}/{}/{}", zoomlevel, col, row))
+            .replace("@minlod@", &min_lod.to_string())
+            .replace("@north@", &bounds.north().to_string())
+            .replace("@south@", &bounds.south().to_string())
+            .replace("@east@", &bounds.east().to_string())
+            .replace("@west@", &bounds.west().to_string())
+            .replace("@network.links@", &network_links)
+            .replace("@draworder@", &(zoomlevel + 1).to_string())
+            .replace(
+                "@img.href@",
+                &format!("{}.{}", row, info.tile_format().extension()),
+            );
+
+        let kml_file = self
+            .tms
+            .base()
+            .tileset_root_dir()
+            .unwrap()
+            .join(zoomlevel.to_string())
+            .join(col.to_string())
+            .join(format!("{}.kml", row));
+        fs::write(&kml_file, kml).map_err(TilingError::not_writable(&kml_file))
+    }
+
+    fn generate_root_kml(&self, bbox: &BoundingBox, info: &TileSetInfo) -> Result<(), TilingError> {
+        let name = info.image_file().file_stem().unwrap().to_string_lossy();
+        let network_link = self.network_link(
+            &name,
+            &self.tile_bounds(bbox, info, 0, 0, 0),
+            "0/0/0.kml",
+            info.tile_width().min(info.tile_height()) / 2,
+        );
+        let kml = ROOT_KML_TEMPLATE
+            .replace("@name@", &name)
+            .replace("@network.links@", &network_link);
+
+        let root_file = self.tms.base().tileset_root_dir().unwrap().join(ROOT_KML);
+        fs::write(&root_file, kml).map_err(TilingError::not_writable(&root_file))
+    }
+
+    /// Packages the super-overlay in `dir` into a KMZ file. doc.kml is
+    /// stored as the first entry, as Google Earth expects.
+    fn write_kmz(&self, dir: &Path, kmz_file: &Path) -> Result<(), TilingError> {
+        Self::package_kmz(dir, kmz_file).map_err(TilingError::not_writable(kmz_file))
+    }
+
+    fn package_kmz(dir: &Path, kmz_file: &Path) -> io::Result<()> {
+        let mut zip = ZipWriter::new(File::create(kmz_file)?);
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        // Tile images are compressed already
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+        zip.start_file(ROOT_KML, deflated)
+            .map_err(io::Error::from)?;
+        zip.write_all(&fs::read(dir.join(ROOT_KML))?)?;
+
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(current) = dirs.pop() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(&current)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<_, _>>()?;
+            entries.sort();
+            for path in entries {
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let name = path
+                    .strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                if name == ROOT_KML {
+                    continue;
+                }
+                let options = if name.ends_with(".kml") || name.ends_with(".xml") {
+                    deflated
+                } else {
+                    stored
+                };
+                zip.start_file(name, options).map_err(io::Error::from)?;
+                zip.write_all(&fs::read(&path)?)?;
+            }
+        }
+
+        zip.finish().map_err(io::Error::from)?;
+        Ok(())
+    }
+}
+
+impl Default for KMLSuperOverlayTiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the KMZ file name, adding a .kmz extension if necessary.
+fn kmz_target(target: &Path) -> PathBuf {
+    if target
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("kmz"))
+    {
+        target.to_path_buf()
+    } else {
+        PathBuf::from(format!("{}.kmz", target.to_string_lossy()))
+    }
+}
+
+impl MagickTiler for KMLSuperOverlayTiler {
+    fn base(&self) -> &BaseMagickTiler {
+        self.tms.base()
+    }
+
+    fn base_mut(&mut self) -> &mut BaseMagickTiler {
+        self.tms.base_mut()
+    }
+
+    /// Not supported: the KML files are generated from the tiles in the
+    /// target directory.
+    fn set_tile_sink(&mut self, _sink: Arc<dyn TileSink>) -> Result<(), TilingError> {
+        Err(TilingError::general(
+            "KML super-overlays cannot be written through a tile sink, use --kmz to package them"
+                .to_string(),
+        ))
+    }
+
+    fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError> {
+        let target = self.tms.base().default_target();
+        self.convert_to(image, &target)
+    }
+
+    fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
+        self.tms
+            .base()
+            .check_tile_format(image, "KML super-overlays", TILE_FORMATS)?;
+        if !self.kmz {
+            let info = self.tms.base_mut().prepare(image, target)?;
+            return self.convert_internal(image, info);
+        }
+
+        // Build the super-overlay in the working directory, then package it
+        let kmz_file = kmz_target(target);
+        let temp_dir = self.tms.base().working_directory().join(format!(
+            "{}-kml",
+            image.file_stem().unwrap().to_string_lossy()
+        ));
+        let info = self.tms.base_mut().prepare(image, &temp_dir)?;
+        let info = match self.convert_internal(image, info) {
+            Ok(info) => info,
+            Err(e) => {
+                if let Err(e) = fs::remove_dir_all(&temp_dir) {
+                    error!("Could not delete {}: {}", temp_dir.display(), e);
+                }
+                return Err(e);
+            }
+        };
+
+        debug!("Packaging KMZ");
+        if let Some(parent) = kmz_file.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(TilingError::not_writable(parent))?;
+        }
+        self.write_kmz(&temp_dir, &kmz_file)?;
+        fs::remove_dir_all(&temp_dir).map_err(TilingError::io(&temp_dir))?;
+
+        Ok(info)
+    }
+
+    fn convert_internal(
+        &mut self,
+        image: &Path,
+        info: TileSetInfo,
+    ) -> Result<TileSetInfo, TilingError> {
+        let bbox = self
+            .bbox
+            .ok_or_else(|| TilingError::general("No bounding box set!".to_string()))?;
+
+        let start_time = std::time::Instant::now();
+        info!(
+            "Generating KML Super-Overlay for file {}: N {} S {} E {} W {}",
+            image.file_name().unwrap().to_string_lossy(),
+            bbox.north(),
+            bbox.south(),
+            bbox.east(),
+            bbox.west()
+        );
+
+        // Step 1 - generate the TMS tiles
+        let info = self.tms.convert_internal(image, info)?;
+
+        // Step 2 - generate a KML file for each tile
+        for zoomlevel in 0..info.zoom_levels() {
+            self.tms.base().check_cancelled()?;
+            debug!("Generating KML for level {}", zoomlevel);
+            let (columns, rows) = Self::level_tiles(&info, zoomlevel);
+            for col in 0..columns {
+                for row in 0..rows {
+                    self.generate_tile_kml(&bbox, &info, zoomlevel, col, row)?;
+                }
+            }
+        }
+
+        // Step 3 - generate the root KML file
+        self.generate_root_kml(&bbox, &info)?;
+
+        info!("Took {} ms", start_time.elapsed().as_millis());
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::MemorySink;
+    use crate::tiling_scheme::TilesetMetadata;
+
+    #[test]
+    fn tile_sinks_are_rejected() {
+        let mut tiler = KMLSuperOverlayTiler::new();
+        let err = tiler
+            .set_tile_sink(Arc::new(MemorySink::new()))
+            .unwrap_err();
+        assert!(err.to_string().contains("KML"));
+    }
+
+    #[test]
+    fn padded_top_row_stays_within_the_poles() {
+        let tiler = KMLSuperOverlayTiler::new();
+        let bbox = BoundingBox::new(85.0, -85.0, 180.0, -180.0).unwrap();
+        // The top row of tiles reaches 68 pixels (16.5 degrees) beyond the
+        // image
+        let metadata = TilesetMetadata::new(1000, 700, 256, 256, 3, ImageFormat::JPEG);
+        let info = TileSetInfo::from_metadata(Path::new("tiles"), &metadata);
+        for zoomlevel in 0..info.zoom_levels() {
+            let (columns, rows) = KMLSuperOverlayTiler::level_tiles(&info, zoomlevel);
+            for col in 0..columns {
+                for row in 0..rows {
+                    let bounds = tiler.tile_bounds(&bbox, &info, zoomlevel, col, row);
+                    assert!(bounds.north() <= 90.0 && bounds.south() >= -90.0);
+                    assert!(bounds.north() > bounds.south());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,3 @@
+mod kml_super_overlay_tiler;
+
+pub use kml_super_overlay_tiler::KMLSuperOverlayTiler;
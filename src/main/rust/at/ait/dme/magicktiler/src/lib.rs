@@ -1,14 +1,32 @@
+pub mod dzi;
+pub mod geo;
 pub mod gmaps;
+pub mod iiif;
 pub mod image;
+pub mod kml;
 pub mod magick_tiler;
+pub mod pmtiles;
+pub mod progress;
+pub mod ptif;
+pub mod pyramid;
+pub mod serve;
+pub mod sink;
+pub mod streaming;
 pub mod stripe;
 pub mod tile_set_info;
+pub mod tiling_exception;
+pub mod tiling_scheme;
 pub mod tms;
 pub mod validation_failed_exception;
+pub mod validation_report;
 pub mod validator;
 pub mod zoomify;
 
 pub use magick_tiler::MagickTiler;
+pub use progress::{CancellationToken, Progress, ProgressListener};
 pub use tile_set_info::TileSetInfo;
+pub use tiling_exception::TilingError;
+pub use tiling_scheme::{detect_scheme, DetectedTileset, TilesetMetadata, TilingScheme};
 pub use validation_failed_exception::ValidationFailedError;
+pub use validation_report::{Finding, FindingCategory, Severity, ValidationReport};
 pub use validator::Validator;
@@ -1,31 +1,150 @@
 use std::fs;
 use std::path::{Path, PathBuf};
-use thiserror::Error;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::image::{ImageFormat, ImageProcessingSystem, ImageProcessor, ImageProcessorImpl};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+use crate::image::{
+    parse_color, EncoderOptions, ImageFormat, ImageProcessingSystem, ImageProcessor,
+    NativeImageProcessor, ResamplingFilter,
+};
+use crate::progress::{CancellationToken, ProgressListener, ProgressTracker};
+use crate::sink::{FileSystemSink, TileKey, TileSink};
+use crate::streaming::{open_row_source, LevelLayout, StreamingPyramid, DEFAULT_MEMORY_BUDGET};
+use crate::stripe::{Orientation, Stripe};
 use crate::tile_set_info::TileSetInfo;
+pub use crate::tiling_exception::TilingError;
 
-#[derive(Debug, Error)]
-pub enum TilingError {
-    #[error("IO error: {0}")]
-    IO(#[from] std::io::Error),
-    #[error("General error: {0}")]
-    General(String),
-}
+/// Tilers are `Send + Sync`, so that a `TileServer` can cut tiles on demand
+/// from its connection threads.
+pub trait MagickTiler: Send + Sync {
+    /// The settings shared by all tilers
+    fn base(&self) -> &BaseMagickTiler;
+    fn base_mut(&mut self) -> &mut BaseMagickTiler;
 
-impl From<Box<dyn std::error::Error>> for TilingError {
-    fn from(err: Box<dyn std::error::Error>) -> Self {
-        Self::General(err.to_string())
+    fn set_tile_format(&mut self, format: ImageFormat) {
+        self.base_mut().set_tile_format(format);
     }
-}
 
-impl From<serde_json::Error> for TilingError {
-    fn from(err: serde_json::Error) -> Self {
-        Self::General(err.to_string())
+    /// Sets the JPEG compression quality from 0 (bad quality) to 100
+    /// (maximum quality), default=75
+    fn set_jpeg_quality(&mut self, quality: i32) {
+        self.base_mut().set_jpeg_quality(quality);
+    }
+
+    /// Sets the compression quality of lossy WebP tiles from 0 (bad quality)
+    /// to 100 (maximum quality), default=80
+    fn set_webp_quality(&mut self, quality: i32) {
+        self.base_mut().set_webp_quality(quality);
+    }
+
+    /// Sets the effort spent on compressing WebP tiles from 0 (fastest) to 6
+    /// (smallest files), default=4
+    fn set_webp_effort(&mut self, effort: i32) {
+        self.base_mut().set_webp_effort(effort);
+    }
+
+    /// Sets the compression quality of AVIF tiles from 0 (bad quality) to
+    /// 100 (maximum quality), default=60
+    fn set_avif_quality(&mut self, quality: i32) {
+        self.base_mut().set_avif_quality(quality);
+    }
+
+    /// Sets the effort spent on compressing AVIF tiles from 0 (fastest) to 9
+    /// (smallest files), default=4
+    fn set_avif_effort(&mut self, effort: i32) {
+        self.base_mut().set_avif_effort(effort);
+    }
+
+    /// Sets all settings tiles are encoded with at once. Values out of range
+    /// are clamped.
+    fn set_encoder_options(&mut self, options: EncoderOptions) {
+        self.base_mut().set_encoder_options(options);
+    }
+
+    fn set_background_color(&mut self, color: String) {
+        self.base_mut().set_background_color(color);
+    }
+
+    /// Sets the filter used to scale the image down to the lower resolution
+    /// levels, by every tiling scheme and in streaming mode, default=Lanczos3
+    fn set_resampling_filter(&mut self, filter: ResamplingFilter) {
+        self.base_mut().set_resampling_filter(filter);
+    }
+
+    /// Sets the maximum time a single GraphicsMagick or ImageMagick command
+    /// may run before it is killed, default=no limit
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.base_mut().set_timeout(timeout);
+    }
+
+    fn set_generate_preview_html(&mut self, generate_preview: bool) {
+        self.base_mut().set_generate_preview_html(generate_preview);
+    }
+
+    fn set_working_directory(&mut self, working_directory: &Path) {
+        self.base_mut().set_working_directory(working_directory);
+    }
+
+    /// Sets the number of threads used to tile stripes and compute the
+    /// pyramid, default=1
+    fn set_worker_threads(&mut self, worker_threads: usize) {
+        self.base_mut().set_worker_threads(worker_threads);
+    }
+
+    /// Sets the listener that is notified as tiles are written
+    fn set_progress_listener(&mut self, listener: Arc<dyn ProgressListener>) {
+        self.base_mut().set_progress_listener(listener);
+    }
+
+    /// Sets the token used to cancel a running conversion
+    fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.base_mut().set_cancellation_token(token);
+    }
+
+    /// Reads the source row by row and computes the pyramid in-process instead
+    /// of striping it with the image processor, so that images larger than
+    /// the available memory can be tiled. Supported by the TMS, Zoomify and
+    /// IIIF tilers; other tilers ignore this setting. Default: disabled.
+    fn set_streaming(&mut self, streaming: bool) {
+        self.base_mut().set_streaming(streaming);
+    }
+
+    /// Sets the approximate upper limit (in bytes) for the memory used by
+    /// streaming tiling, default=512 MiB
+    fn set_memory_budget(&mut self, memory_budget: usize) {
+        self.base_mut().set_memory_budget(memory_budget);
+    }
+
+    /// Sets the sink tiles and metadata files are written to instead of the
+    /// target directory, e.g. a `ZipSink` or a `MemorySink`. The tiler
+    /// finalizes the sink at the end of the conversion. Supported by the TMS,
+    /// Zoomify and Google Maps tilers.
+    fn set_tile_sink(&mut self, _sink: Arc<dyn TileSink>) -> Result<(), TilingError> {
+        Err(TilingError::general(
+            "Cannot write this tiling scheme through a tile sink".to_string(),
+        ))
+    }
+
+    /// Writes the descriptor files (and the preview, if enabled) of the
+    /// tileset for an image to `target` without cutting any tiles, so that
+    /// the tiles can be generated when they are requested (see
+    /// `OnDemandTiles`). Supported by the TMS and Zoomify tilers.
+    fn write_descriptors(
+        &mut self,
+        image: &Path,
+        _target: &Path,
+    ) -> Result<TileSetInfo, TilingError> {
+        Err(TilingError::general_at(
+            image,
+            format!(
+                "Cannot tile {} on demand with this tiling scheme",
+                image.display()
+            ),
+        ))
     }
-}
 
-pub trait MagickTiler {
     fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError>;
     fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError>;
     fn convert_internal(
@@ -40,21 +159,37 @@ pub struct BaseMagickTiler {
     pub tile_width: i32,
     pub tile_height: i32,
     pub generate_preview: bool,
-    pub working_directory: Option<PathBuf>,
+    pub working_directory: PathBuf,
     pub tileset_root_dir: Option<PathBuf>,
+    pub worker_threads: usize,
+    pub progress_listener: Option<Arc<dyn ProgressListener>>,
+    pub cancellation_token: CancellationToken,
+    pub streaming: bool,
+    pub memory_budget: usize,
+    pub tile_sink: Option<Arc<dyn TileSink>>,
 }
 
 impl BaseMagickTiler {
     pub fn new() -> Self {
+        Self::with_image_processing_system(ImageProcessingSystem::default())
+    }
+
+    pub fn with_image_processing_system(system: ImageProcessingSystem) -> Self {
+        let mut processor = system.create_processor();
+        processor.set_background_color(Some("#ffffffff".to_string()));
         Self {
-            processor: Box::new(ImageProcessorImpl::new(
-                ImageProcessingSystem::GraphicsMagick,
-            )),
+            processor,
             tile_width: 256,
             tile_height: 256,
             generate_preview: true,
-            working_directory: None,
+            working_directory: PathBuf::from("."),
             tileset_root_dir: None,
+            worker_threads: 1,
+            progress_listener: None,
+            cancellation_token: CancellationToken::new(),
+            streaming: false,
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            tile_sink: None,
         }
     }
 
@@ -74,21 +209,144 @@ impl BaseMagickTiler {
         self.generate_preview
     }
 
-    pub fn working_directory(&self) -> Option<&Path> {
-        self.working_directory.as_deref()
+    pub fn working_directory(&self) -> &Path {
+        &self.working_directory
     }
 
     pub fn tileset_root_dir(&self) -> Option<&Path> {
         self.tileset_root_dir.as_deref()
     }
 
+    pub fn worker_threads(&self) -> usize {
+        self.worker_threads
+    }
+
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget
+    }
+
+    /// The sink the tileset is written to: the configured one, or a
+    /// `FileSystemSink` writing to the tileset root directory.
+    pub fn tile_sink(&self) -> Arc<dyn TileSink> {
+        match &self.tile_sink {
+            Some(sink) => sink.clone(),
+            None => Arc::new(FileSystemSink::new(self.default_target())),
+        }
+    }
+
     pub fn set_tile_size(&mut self, size: i32) {
         self.tile_width = size;
         self.tile_height = size;
     }
 
     pub fn set_working_directory<P: AsRef<Path>>(&mut self, working_directory: P) {
-        self.working_directory = Some(working_directory.as_ref().to_path_buf());
+        self.working_directory = working_directory.as_ref().to_path_buf();
+    }
+
+    /// Sets the number of threads used for tiling. Values below 1 are treated
+    /// as 1 (single-threaded).
+    pub fn set_worker_threads(&mut self, worker_threads: usize) {
+        self.worker_threads = worker_threads.max(1);
+    }
+
+    pub fn set_progress_listener(&mut self, listener: Arc<dyn ProgressListener>) {
+        self.progress_listener = Some(listener);
+    }
+
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation_token = token;
+    }
+
+    pub fn set_tile_sink(&mut self, sink: Arc<dyn TileSink>) {
+        self.tile_sink = Some(sink);
+    }
+
+    pub fn set_streaming(&mut self, streaming: bool) {
+        self.streaming = streaming;
+    }
+
+    pub fn set_memory_budget(&mut self, memory_budget: usize) {
+        self.memory_budget = memory_budget;
+    }
+
+    /// Returns `TilingError::Cancelled` if the conversion was cancelled.
+    /// Tilers call this between their tiling steps.
+    pub fn check_cancelled(&self) -> Result<(), TilingError> {
+        self.cancellation_token.check()
+    }
+
+    /// Creates the tracker a tiler reports its written tiles to during one
+    /// conversion.
+    pub fn progress_tracker(&self, total_tiles: i32) -> ProgressTracker {
+        ProgressTracker::new(self.progress_listener.clone(), total_tiles)
+    }
+
+    /// Switches to a different image processing system, keeping the configured
+    /// tile format and encoder settings, background color, resampling filter
+    /// and timeout.
+    pub fn set_image_processing_system(&mut self, system: ImageProcessingSystem) {
+        let mut processor = system.create_processor();
+        processor.set_image_format(self.processor.get_image_format());
+        processor.set_encoder_options(self.processor.get_encoder_options().clone());
+        processor.set_background_color(self.processor.get_background_color().map(String::from));
+        processor.set_resampling_filter(self.processor.get_resampling_filter());
+        processor.set_timeout(self.processor.get_timeout());
+        self.processor = processor;
+    }
+
+    pub fn set_tile_format(&mut self, format: ImageFormat) {
+        self.processor.set_image_format(format);
+    }
+
+    pub fn set_encoder_options(&mut self, options: EncoderOptions) {
+        self.processor.set_encoder_options(options);
+    }
+
+    /// Changes a single setting of the encoder options
+    fn update_encoder_options<F: FnOnce(&mut EncoderOptions)>(&mut self, update: F) {
+        let mut options = self.processor.get_encoder_options().clone();
+        update(&mut options);
+        self.processor.set_encoder_options(options);
+    }
+
+    pub fn set_jpeg_quality(&mut self, quality: i32) {
+        self.update_encoder_options(|o| o.jpeg_quality = quality);
+    }
+
+    pub fn set_webp_quality(&mut self, quality: i32) {
+        self.update_encoder_options(|o| o.webp_quality = quality);
+    }
+
+    pub fn set_webp_effort(&mut self, effort: i32) {
+        self.update_encoder_options(|o| o.webp_effort = effort);
+    }
+
+    pub fn set_avif_quality(&mut self, quality: i32) {
+        self.update_encoder_options(|o| o.avif_quality = quality);
+    }
+
+    pub fn set_avif_effort(&mut self, effort: i32) {
+        self.update_encoder_options(|o| o.avif_effort = effort);
+    }
+
+    pub fn set_background_color(&mut self, color: String) {
+        self.processor.set_background_color(Some(color));
+    }
+
+    pub fn set_resampling_filter(&mut self, filter: ResamplingFilter) {
+        self.processor.set_resampling_filter(filter);
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.processor.set_timeout(timeout);
     }
 
     pub fn set_tileset_root_dir<P: AsRef<Path>>(&mut self, tileset_root_dir: P) {
@@ -102,33 +360,225 @@ impl BaseMagickTiler {
     pub fn write_html_preview(&self, html: &str) -> Result<(), TilingError> {
         if let Some(dir) = &self.tileset_root_dir {
             let preview = dir.join("preview.html");
-            fs::write(preview, html)?;
+            fs::write(&preview, html).map_err(TilingError::not_writable(&preview))?;
         }
         Ok(())
     }
 
-    pub fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError> {
-        self.convert_to(
-            image,
-            self.tileset_root_dir.as_deref().unwrap_or(Path::new(".")),
-        )
+    /// The target directory used by `MagickTiler::convert`: the configured
+    /// tileset root directory, or the current directory if none is set.
+    pub fn default_target(&self) -> PathBuf {
+        self.tileset_root_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("."))
     }
 
-    pub fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
-        if !target.exists() {
-            fs::create_dir_all(target)?;
+    /// Checks that the configured tile format is one of the formats the
+    /// viewers of a tiling scheme can display.
+    pub fn check_tile_format(
+        &self,
+        image: &Path,
+        scheme: &str,
+        supported: &[ImageFormat],
+    ) -> Result<(), TilingError> {
+        let format = self.processor.get_image_format();
+        if supported.contains(&format) {
+            Ok(())
+        } else {
+            Err(TilingError::UnsupportedFormat {
+                path: image.to_path_buf(),
+                reason: format!("{:?} tiles are not supported by {}", format, scheme),
+            })
+        }
+    }
+
+    /// Creates the target directory (unless a tile sink is configured), makes
+    /// it the tileset root and collects the tileset information for the
+    /// image. Tilers call this before running their `convert_internal`.
+    pub fn prepare(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
+        // Streamed tiles are always encoded in-process
+        let format = self.processor.get_image_format();
+        let native = self.native_encoder();
+        let encoder: &dyn ImageProcessor = if self.streaming {
+            &native
+        } else {
+            self.processor()
+        };
+        if !encoder.can_write(format) {
+            return Err(TilingError::UnsupportedFormat {
+                path: image.to_path_buf(),
+                reason: format!(
+                    "{:?} tiles ({}) cannot be written by the {} image processor",
+                    format,
+                    encoder.get_encoder_options().describe(format),
+                    encoder.get_image_processing_system()
+                ),
+            });
+        }
+
+        if !self.working_directory.exists() {
+            fs::create_dir_all(&self.working_directory)
+                .map_err(TilingError::not_writable(&self.working_directory))?;
+        }
+        if self.tile_sink.is_none() && !target.exists() {
+            fs::create_dir_all(target).map_err(TilingError::not_writable(target))?;
         }
         self.set_tileset_root_dir(target);
 
-        let info = TileSetInfo::new(image, self.tile_width, self.tile_height, self.processor())?;
-        self.convert_internal(image, info)
+        if self.tile_width <= 0 || self.tile_height <= 0 {
+            return Err(TilingError::InvalidDimensions {
+                path: image.to_path_buf(),
+                width: self.tile_width,
+                height: self.tile_height,
+            });
+        }
+
+        TileSetInfo::new(image, self.tile_width, self.tile_height, self.processor())
     }
 
-    pub fn convert_internal(
-        &mut self,
+    /// Streams an image through a pyramid with the given level layouts and
+    /// passes every tile with its level, column and row to `tile`. See
+    /// `StreamingPyramid::run`.
+    pub fn stream_pyramid<F>(
+        &self,
         image: &Path,
-        info: TileSetInfo,
-    ) -> Result<TileSetInfo, TilingError> {
-        Ok(info)
+        layouts: Vec<LevelLayout>,
+        tile: F,
+    ) -> Result<(), TilingError>
+    where
+        F: Fn(usize, u32, u32, RgbaImage) -> Result<(), TilingError> + Sync,
+    {
+        let mut source = open_row_source(
+            image,
+            self.processor(),
+            &self.working_directory,
+            self.memory_budget,
+        )?;
+        let mut pyramid =
+            StreamingPyramid::new(layouts, self.tile_width as u32, self.tile_height as u32);
+        let background = match self.processor.get_background_color() {
+            Some(color) => parse_color(color).ok_or_else(|| TilingError::InvalidColor {
+                path: image.to_path_buf(),
+                color: color.to_string(),
+            })?,
+            None => Rgba([255, 255, 255, 255]),
+        };
+        pyramid.set_background(background);
+        pyramid.set_filter(self.processor.get_resampling_filter());
+        pyramid.set_memory_budget(self.memory_budget);
+        pyramid.set_worker_threads(self.worker_threads);
+        pyramid.set_working_directory(&self.working_directory);
+        pyramid.run(source.as_mut(), &self.cancellation_token, tile)
+    }
+
+    /// Encodes a tile computed in-process in the configured tile format.
+    pub fn write_tile(&self, tile: RgbaImage, target: &Path) -> Result<(), TilingError> {
+        self.native_encoder()
+            .save(&DynamicImage::ImageRgba8(tile), target)
+    }
+
+    /// Encodes a tile computed in-process and puts it into a sink. The tile
+    /// is encoded into a temporary file in the working directory, named after
+    /// the image and the tile position.
+    pub fn put_tile(
+        &self,
+        sink: &dyn TileSink,
+        key: &TileKey,
+        tile: RgbaImage,
+        base_name: &str,
+    ) -> Result<(), TilingError> {
+        let file = self.working_directory.join(format!(
+            "{}-{}",
+            base_name,
+            key.required_path()?.replace('/', "-")
+        ));
+        self.write_tile(tile, &file)?;
+        sink.put_tile_file(key, &file)
+    }
+
+    /// The in-process encoder for tiles in the configured tile format
+    fn native_encoder(&self) -> NativeImageProcessor {
+        let mut encoder = NativeImageProcessor::with_format(self.processor.get_image_format());
+        encoder.set_encoder_options(self.processor.get_encoder_options().clone());
+        encoder
+    }
+
+    /// Stripes an image.
+    pub fn stripe_image(
+        &self,
+        image: &Path,
+        orientation: Orientation,
+        stripes: i32,
+        width: i32,
+        height: i32,
+        outfile_prefix: &str,
+    ) -> Result<Vec<Stripe>, TilingError> {
+        self.stripe_image_with_canvas(
+            image,
+            orientation,
+            stripes,
+            width,
+            height,
+            width,
+            height,
+            "",
+            outfile_prefix,
+        )
+    }
+
+    /// Stripes an image, placing each stripe on a canvas of the specified size.
+    /// The gravity specifies the location of the stripe on the canvas.
+    #[allow(clippy::too_many_arguments)]
+    pub fn stripe_image_with_canvas(
+        &self,
+        image: &Path,
+        orientation: Orientation,
+        stripes: i32,
+        width: i32,
+        height: i32,
+        canvas_width: i32,
+        canvas_height: i32,
+        gravity: &str,
+        outfile_prefix: &str,
+    ) -> Result<Vec<Stripe>, TilingError> {
+        let target_pattern = self
+            .working_directory
+            .join(format!("{}%d.tif", outfile_prefix));
+        if canvas_height == height && canvas_width == width {
+            self.processor.crop(image, &target_pattern, width, height)?;
+        } else {
+            self.processor.crop_with_canvas(
+                image,
+                &target_pattern,
+                width,
+                height,
+                canvas_width,
+                canvas_height,
+                gravity,
+            )?;
+        }
+
+        // Assemble the list of stripes
+        let mut result_stripes = Vec::new();
+        let mut w = canvas_width;
+        let mut h = canvas_height;
+        for i in 0..stripes {
+            let file = self
+                .working_directory
+                .join(format!("{}{}.tif", outfile_prefix, i));
+
+            // in case the last stripe has a different width or height
+            if i == stripes - 1 {
+                (w, h) = self.processor.get_dimensions(&file)?;
+            }
+            result_stripes.push(Stripe::new(file, w, h, orientation));
+        }
+        Ok(result_stripes)
+    }
+}
+
+impl Default for BaseMagickTiler {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -0,0 +1,1107 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::TcpListener;
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use clap::{Parser, ValueEnum};
+use log::{info, Level, LevelFilter, Log, Metadata, Record};
+
+use magicktiler::dzi::DeepZoomTiler;
+use magicktiler::geo::BoundingBox;
+use magicktiler::gmaps::GoogleMapsTiler;
+use magicktiler::iiif::IIIFTiler;
+use magicktiler::image::{
+    parse_color, ChromaSubsampling, EncoderOptions, ImageFormat, ResamplingFilter, TiffCompression,
+};
+use magicktiler::kml::KMLSuperOverlayTiler;
+use magicktiler::pmtiles::{PMTilesTiler, PMTilesValidator};
+use magicktiler::ptif::PTIFConverter;
+use magicktiler::serve::{OnDemandTiles, TileServer};
+use magicktiler::sink::{
+    DefaultHttpTransport, S3Config, S3Credentials, S3Sink, TarSink, TileSink, ZipSink,
+};
+use magicktiler::tms::TMSTiler;
+use magicktiler::zoomify::ZoomifyTiler;
+use magicktiler::{
+    detect_scheme, MagickTiler, TileSetInfo, TilingError, TilingScheme, ValidationReport, Validator,
+};
+
+const LOG_FILE: &str = "log.txt";
+
+/// Converts images into tilesets for publishing them as high-resolution,
+/// zoomable Web images.
+#[derive(Parser)]
+#[command(
+    name = "magicktiler",
+    version,
+    after_help = "Example: magicktiler -s tms -f jpeg -i image.tif -p"
+)]
+struct Options {
+    /// Tiling scheme (detected automatically when validating or inspecting, if
+    /// omitted)
+    #[arg(
+        short = 's',
+        long = "scheme",
+        value_enum,
+        required_unless_present_any = ["validate", "inspect", "serve"]
+    )]
+    scheme: Option<Scheme>,
+
+    /// Input file or directory
+    #[arg(short = 'i', long = "input")]
+    input: PathBuf,
+
+    /// Output directory (for tilesets) or file (for PTIF, KMZ, MBTiles and
+    /// PMTiles; tilesets written with --archive get a .zip or .tar extension,
+    /// with --s3-bucket this is the key prefix in the bucket)
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+
+    /// Tile format
+    #[arg(short = 'f', long = "format", value_enum, default_value = "jpeg")]
+    format: Format,
+
+    /// Compression quality (0 - 100) of JPEG, lossy WebP and AVIF tiles and
+    /// JPEG-compressed TIFF tiles, default=75, 80 and 60 respectively
+    #[arg(
+        short = 'q',
+        long = "quality",
+        value_parser = clap::value_parser!(i32).range(0..=100)
+    )]
+    quality: Option<i32>,
+
+    /// Compression effort of WebP (0 - 6, default=4) and AVIF (0 - 9,
+    /// default=4) tiles; higher values produce smaller files more slowly
+    #[arg(long = "effort", value_parser = clap::value_parser!(i32).range(0..=9))]
+    effort: Option<i32>,
+
+    /// Write progressive JPEG tiles
+    #[arg(long = "progressive")]
+    progressive: bool,
+
+    /// Chroma subsampling of JPEG tiles
+    #[arg(long = "subsampling", value_enum, default_value = "420")]
+    subsampling: Subsampling,
+
+    /// zlib compression level of PNG tiles (0 - 9)
+    #[arg(
+        long = "png-compression",
+        default_value_t = 6,
+        value_parser = clap::value_parser!(i32).range(0..=9)
+    )]
+    png_compression: i32,
+
+    /// Quantise PNG tiles to a palette of at most 256 colors
+    #[arg(long = "palette")]
+    palette: bool,
+
+    /// Bits per channel of PNG tiles
+    #[arg(
+        long = "png-bit-depth",
+        value_enum,
+        default_value = "8",
+        conflicts_with = "palette"
+    )]
+    png_bit_depth: BitDepth,
+
+    /// Compression of TIFF tiles
+    #[arg(long = "tiff-compression", value_enum, default_value = "none")]
+    tiff_compression: Compression,
+
+    /// Resampling filter used to scale the image down to the lower zoom
+    /// levels
+    #[arg(long = "filter", value_enum, default_value = "lanczos3")]
+    filter: Filter,
+
+    /// Maximum time in seconds a single GraphicsMagick or ImageMagick command
+    /// may run before it is killed, default=no limit
+    #[arg(
+        long = "timeout",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    timeout: Option<u64>,
+
+    /// Number of worker threads used for tiling
+    #[arg(
+        short = 't',
+        long = "threads",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    threads: u16,
+
+    /// Read the image row by row and compute the pyramid in-process, for
+    /// images larger than the available memory (tms, zoomify and iiif)
+    #[arg(long = "stream")]
+    stream: bool,
+
+    /// Memory budget for streaming, in MiB
+    #[arg(
+        long = "memory-budget",
+        default_value_t = 512,
+        requires = "stream",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    memory_budget: u32,
+
+    /// Background color as #rrggbb or a color name such as black, default=white.
+    /// With -v --deep, tiles of only this color are reported as blank.
+    #[arg(short = 'b', long = "color")]
+    background: Option<String>,
+
+    /// Generate an HTML preview file
+    #[arg(short = 'p')]
+    preview: bool,
+
+    /// Validate the input instead of generating a tileset
+    #[arg(short = 'v')]
+    validate: bool,
+
+    /// Print the metadata of the input tileset(s) instead of generating a
+    /// tileset
+    #[arg(short = 'n', long = "inspect", conflicts_with = "validate")]
+    inspect: bool,
+
+    /// Decode every tile and check its dimensions (with -v)
+    #[arg(long = "deep", requires = "validate")]
+    deep: bool,
+
+    /// Write the validation findings as JSON to this file (with -v)
+    #[arg(long = "report", requires = "validate")]
+    report: Option<PathBuf>,
+
+    /// Write reporting information to a log file (log.txt)
+    #[arg(short = 'l')]
+    log: bool,
+
+    /// Geographical bounding box of the image as north,south,east,west
+    /// (required for 'kml', recorded as the bounds of MBTiles and PMTiles
+    /// files)
+    #[arg(long = "bbox", value_delimiter = ',', allow_negative_numbers = true)]
+    bbox: Option<Vec<f64>>,
+
+    /// Package a KML super-overlay as a single KMZ file
+    #[arg(long = "kmz")]
+    kmz: bool,
+
+    /// Package a TMS tileset as a single MBTiles file
+    #[arg(long = "mbtiles")]
+    mbtiles: bool,
+
+    /// Store identical tiles only once (with --mbtiles)
+    #[arg(long = "dedup", requires = "mbtiles")]
+    dedup: bool,
+
+    /// Package a 'tms', 'gmap' or 'zoomify' tileset as a single PMTiles
+    /// archive
+    #[arg(long = "pmtiles", conflicts_with_all = ["mbtiles", "kmz"])]
+    pmtiles: bool,
+
+    /// Write a 'tms', 'zoomify' or 'gmap' tileset into a single archive
+    /// instead of a directory
+    #[arg(
+        long = "archive",
+        value_enum,
+        conflicts_with_all = ["mbtiles", "kmz", "pmtiles"]
+    )]
+    archive: Option<Archive>,
+
+    /// Upload a 'tms', 'zoomify' or 'gmap' tileset to this bucket of an
+    /// S3-compatible object storage instead of writing a directory; the
+    /// credentials are read from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+    /// and AWS_SESSION_TOKEN
+    #[arg(
+        long = "s3-bucket",
+        requires = "s3_endpoint",
+        conflicts_with_all = ["mbtiles", "kmz", "pmtiles", "archive"]
+    )]
+    s3_bucket: Option<String>,
+
+    /// URL of the object storage, e.g. http://localhost:9000 (plain HTTP only)
+    #[arg(long = "s3-endpoint", requires = "s3_bucket")]
+    s3_endpoint: Option<String>,
+
+    /// Region of the bucket
+    #[arg(long = "s3-region", default_value = "us-east-1")]
+    s3_region: String,
+
+    /// Cache-Control header of the uploaded files (with --s3-bucket)
+    #[arg(long = "cache-control", requires = "s3_bucket")]
+    cache_control: Option<String>,
+
+    /// Number of files uploaded at the same time (with --s3-bucket)
+    #[arg(
+        long = "upload-concurrency",
+        requires = "s3_bucket",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    upload_concurrency: u16,
+
+    /// Host the input tileset(s) over HTTP for previewing instead of
+    /// generating a tileset; with -s 'tms' or 'zoomify', the input image(s)
+    /// are tiled on demand into the output directory while they are viewed
+    #[arg(
+        long = "serve",
+        conflicts_with_all = ["validate", "inspect", "mbtiles", "kmz", "pmtiles", "archive", "s3_bucket"]
+    )]
+    serve: bool,
+
+    /// Port of the HTTP server (with --serve)
+    #[arg(long = "port", default_value_t = 8080, requires = "serve")]
+    port: u16,
+
+    /// Address the HTTP server listens on (with --serve)
+    #[arg(long = "bind", default_value = "127.0.0.1", requires = "serve")]
+    bind: String,
+
+    /// Origins allowed to load the tiles from other pages (with --serve)
+    #[arg(long = "cors-origin", default_value = "*", requires = "serve")]
+    cors_origin: String,
+
+    /// Number of connections the HTTP server handles at the same time (with
+    /// --serve)
+    #[arg(
+        long = "connections",
+        default_value_t = 16,
+        requires = "serve",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    connections: u16,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Scheme {
+    Tms,
+    Zoomify,
+    Gmap,
+    Ptif,
+    Dzi,
+    Iiif,
+    Kml,
+    Pmtiles,
+}
+
+impl Scheme {
+    fn description(&self) -> &'static str {
+        match self {
+            Scheme::Tms => "TMS tileset",
+            Scheme::Zoomify => "Zoomify tileset",
+            Scheme::Gmap => "Google Maps tileset",
+            Scheme::Ptif => "Pyramid TIFF",
+            Scheme::Dzi => "Deep Zoom tileset",
+            Scheme::Iiif => "IIIF tileset",
+            Scheme::Kml => "KML Super-Overlay",
+            Scheme::Pmtiles => "PMTiles archive",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Scheme::Tms => "tms",
+            Scheme::Zoomify => "zoomify",
+            Scheme::Gmap => "gmap",
+            Scheme::Ptif => "ptif",
+            Scheme::Dzi => "dzi",
+            Scheme::Iiif => "iiif",
+            Scheme::Kml => "kml",
+            Scheme::Pmtiles => "pmtiles",
+        }
+    }
+
+    fn tiling_scheme(&self) -> Option<TilingScheme> {
+        match self {
+            Scheme::Tms => Some(TilingScheme::Tms),
+            Scheme::Zoomify => Some(TilingScheme::Zoomify),
+            Scheme::Gmap => Some(TilingScheme::GoogleMaps),
+            Scheme::Dzi => Some(TilingScheme::DeepZoom),
+            Scheme::Iiif => Some(TilingScheme::IIIF),
+            Scheme::Pmtiles => Some(TilingScheme::PMTiles),
+            Scheme::Ptif | Scheme::Kml => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Jpeg,
+    Png,
+    Tiff,
+    Webp,
+    WebpLossless,
+    Avif,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Subsampling {
+    #[value(name = "444")]
+    Full,
+    #[value(name = "422")]
+    Horizontal,
+    #[value(name = "420")]
+    Both,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BitDepth {
+    #[value(name = "8")]
+    Eight,
+    #[value(name = "16")]
+    Sixteen,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Compression {
+    None,
+    Deflate,
+    Lzw,
+    Jpeg,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Archive {
+    Zip,
+    Tar,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Filter {
+    Box,
+    Triangle,
+    CatmullRom,
+    Lanczos3,
+    Mitchell,
+}
+
+/// Logs to the console and, optionally, everything down to debug level to
+/// a log file.
+struct CliLogger {
+    file: Option<Mutex<File>>,
+}
+
+impl Log for CliLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info || self.file.is_some()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= Level::Info {
+            println!("{}", record.args());
+        }
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = writeln!(file, "{} - {}", record.level(), record.args());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+fn init_logging(log_to_file: bool) -> Result<(), String> {
+    let file = if log_to_file {
+        let file = File::create(LOG_FILE)
+            .map_err(|e| format!("Failed to create log file {}: {}", LOG_FILE, e))?;
+        Some(Mutex::new(file))
+    } else {
+        None
+    };
+    let max_level = if file.is_some() {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    log::set_logger(Box::leak(Box::new(CliLogger { file })))
+        .map(|()| log::set_max_level(max_level))
+        .map_err(|e| e.to_string())
+}
+
+fn create_tiler(options: &Options, scheme: Scheme) -> Result<Box<dyn MagickTiler>, String> {
+    if !matches!(scheme, Scheme::Tms | Scheme::Zoomify | Scheme::Gmap) {
+        if options.archive.is_some() {
+            return Err(format!(
+                "--archive is not supported for tiling scheme: {}",
+                scheme.name()
+            ));
+        }
+        if options.s3_bucket.is_some() {
+            return Err(format!(
+                "--s3-bucket is not supported for tiling scheme: {}",
+                scheme.name()
+            ));
+        }
+    }
+    if let Some(endpoint) = &options.s3_endpoint {
+        if !DefaultHttpTransport::supports(endpoint) {
+            return Err(format!(
+                "--s3-endpoint must be an http:// or https:// URL: {}",
+                endpoint
+            ));
+        }
+    }
+    let mut tiler: Box<dyn MagickTiler> = match scheme {
+        _ if options.pmtiles => {
+            let mut tiler = scheme
+                .tiling_scheme()
+                .ok_or_else(|| format!("No PMTiles support for tiling scheme: {}", scheme.name()))
+                .and_then(|s| PMTilesTiler::new(s).map_err(|e| e.to_string()))?;
+            if let Some(bbox) = bounding_box(options)? {
+                tiler.set_bounding_box(bbox);
+            }
+            Box::new(tiler)
+        }
+        Scheme::Tms => {
+            let mut tiler = TMSTiler::new();
+            if let Some(bbox) = bounding_box(options)? {
+                tiler.set_bounding_box(bbox);
+            }
+            tiler.set_mbtiles(options.mbtiles);
+            tiler.set_dedup(options.dedup);
+            Box::new(tiler)
+        }
+        Scheme::Zoomify => Box::new(ZoomifyTiler::new()),
+        Scheme::Gmap => Box::new(GoogleMapsTiler::new()),
+        Scheme::Ptif => Box::new(PTIFConverter::new()),
+        Scheme::Dzi => Box::new(DeepZoomTiler::new()),
+        Scheme::Iiif => Box::new(IIIFTiler::new()),
+        Scheme::Kml => {
+            let bbox = bounding_box(options)?
+                .ok_or("The 'kml' scheme requires --bbox north,south,east,west")?;
+            let mut tiler = KMLSuperOverlayTiler::new();
+            tiler.set_bounding_box(bbox);
+            tiler.set_kmz(options.kmz);
+            Box::new(tiler)
+        }
+        Scheme::Pmtiles => return Err(
+            "Use --pmtiles with the 'tms', 'gmap' or 'zoomify' scheme to write PMTiles archives"
+                .into(),
+        ),
+    };
+
+    tiler.set_tile_format(match options.format {
+        Format::Jpeg => ImageFormat::JPEG,
+        Format::Png => ImageFormat::PNG,
+        Format::Tiff => ImageFormat::TIFF,
+        Format::Webp => ImageFormat::WebP,
+        Format::WebpLossless => ImageFormat::WebPLossless,
+        Format::Avif => ImageFormat::AVIF,
+    });
+    tiler.set_encoder_options(encoder_options(options)?);
+    tiler.set_resampling_filter(match options.filter {
+        Filter::Box => ResamplingFilter::Box,
+        Filter::Triangle => ResamplingFilter::Triangle,
+        Filter::CatmullRom => ResamplingFilter::CatmullRom,
+        Filter::Lanczos3 => ResamplingFilter::Lanczos3,
+        Filter::Mitchell => ResamplingFilter::Mitchell,
+    });
+    tiler.set_timeout(options.timeout.map(Duration::from_secs));
+    if let Some(background) = &options.background {
+        tiler.set_background_color(background.clone());
+    }
+    tiler.set_generate_preview_html(options.preview);
+    tiler.set_worker_threads(options.threads as usize);
+    tiler.set_streaming(options.stream);
+    tiler.set_memory_budget(options.memory_budget as usize * 1024 * 1024);
+    Ok(tiler)
+}
+
+/// Parses the --bbox option.
+fn bounding_box(options: &Options) -> Result<Option<BoundingBox>, String> {
+    match options.bbox.as_deref() {
+        None => Ok(None),
+        Some(&[north, south, east, west]) => BoundingBox::new(north, south, east, west)
+            .map(Some)
+            .map_err(|e| e.to_string()),
+        Some(_) => Err("--bbox expects north,south,east,west".into()),
+    }
+}
+
+/// Collects the encoder settings of the selected tile format. Fails if
+/// --effort is out of range for the format.
+fn encoder_options(options: &Options) -> Result<EncoderOptions, String> {
+    let mut encoder = EncoderOptions {
+        jpeg_progressive: options.progressive,
+        chroma_subsampling: match options.subsampling {
+            Subsampling::Full => ChromaSubsampling::YCbCr444,
+            Subsampling::Horizontal => ChromaSubsampling::YCbCr422,
+            Subsampling::Both => ChromaSubsampling::YCbCr420,
+        },
+        png_compression_level: options.png_compression,
+        png_palette: options.palette,
+        png_bit_depth: match options.png_bit_depth {
+            BitDepth::Eight => 8,
+            BitDepth::Sixteen => 16,
+        },
+        tiff_compression: match options.tiff_compression {
+            Compression::None => None,
+            Compression::Deflate => Some(TiffCompression::Deflate),
+            Compression::Lzw => Some(TiffCompression::LZW),
+            Compression::Jpeg => Some(TiffCompression::JPEG),
+        },
+        ..EncoderOptions::default()
+    };
+    if let Some(quality) = options.quality {
+        match options.format {
+            Format::Jpeg | Format::Tiff => encoder.jpeg_quality = quality,
+            Format::Webp => encoder.webp_quality = quality,
+            Format::Avif => encoder.avif_quality = quality,
+            Format::Png | Format::WebpLossless => {}
+        }
+    }
+    if let Some(effort) = options.effort {
+        match options.format {
+            Format::Webp | Format::WebpLossless if effort > 6 => {
+                return Err(format!(
+                    "--effort must be within 0 - 6 for WebP tiles, got {}",
+                    effort
+                ))
+            }
+            Format::Webp | Format::WebpLossless => encoder.webp_effort = effort,
+            Format::Avif => encoder.avif_effort = effort,
+            Format::Jpeg | Format::Png | Format::Tiff => {}
+        }
+    }
+    Ok(encoder)
+}
+
+/// With --archive, makes the tiler write the tileset for `target` into a new
+/// archive named after it; with --s3-bucket, makes it upload the tileset
+/// below the key prefix `target`.
+fn set_tile_sink(
+    options: &Options,
+    tiler: &mut dyn MagickTiler,
+    target: &Path,
+) -> Result<(), TilingError> {
+    if let Some(bucket) = &options.s3_bucket {
+        let credentials = S3Credentials::from_env().ok_or_else(|| {
+            TilingError::general(
+                "--s3-bucket requires AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY".to_string(),
+            )
+        })?;
+        let endpoint = options.s3_endpoint.as_deref().unwrap_or_default();
+        let mut config = S3Config::new(endpoint, bucket, credentials);
+        config.region = options.s3_region.clone();
+        config.prefix = target
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        config.cache_control = options.cache_control.clone();
+        config.concurrency = options.upload_concurrency as usize;
+        tiler.set_tile_sink(Arc::new(S3Sink::new(config)?))?;
+        return Ok(());
+    }
+    let Some(archive) = options.archive else {
+        return Ok(());
+    };
+    if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(TilingError::not_writable(parent))?;
+    }
+    let path = |ext: &str| PathBuf::from(format!("{}.{}", target.to_string_lossy(), ext));
+    let sink: Arc<dyn TileSink> = match archive {
+        Archive::Zip => Arc::new(ZipSink::create(&path("zip"))?),
+        Archive::Tar => Arc::new(TarSink::create(&path("tar"))?),
+    };
+    tiler.set_tile_sink(sink)
+}
+
+/// Returns the file name of `path` without extension.
+fn base_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Tiles a single file or every file in a directory. Returns false if any
+/// file could not be tiled.
+fn convert(options: &Options) -> Result<bool, String> {
+    let scheme = options.scheme.ok_or("No tiling scheme given")?;
+    let mut tiler = create_tiler(options, scheme)?;
+    let input = &options.input;
+
+    let start_time = Instant::now();
+    info!(
+        "Generating {} from file {} ({} tiles)",
+        scheme.description(),
+        input.display(),
+        match options.format {
+            Format::Jpeg => "JPEG",
+            Format::Png => "PNG",
+            Format::Tiff => "TIFF",
+            Format::Webp => "WebP",
+            Format::WebpLossless => "lossless WebP",
+            Format::Avif => "AVIF",
+        }
+    );
+    if let Some(output) = &options.output {
+        info!("Destination: {}", output.display());
+    }
+
+    if input.is_file() {
+        let target = options
+            .output
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(base_name(input)));
+        let result = set_tile_sink(options, tiler.as_mut(), &target)
+            .and_then(|_| tiler.convert_to(input, &target));
+        return match result {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                info!("[FAILED] {} - {}", input.display(), e);
+                Ok(false)
+            }
+        };
+    }
+
+    // Tile folder full of files. The output represents a folder in this case,
+    // each tileset is named after its source file.
+    let destination = options.output.clone().unwrap_or_else(|| PathBuf::from("."));
+    if options.s3_bucket.is_none() {
+        fs::create_dir_all(&destination)
+            .map_err(|e| format!("Failed to create {}: {}", destination.display(), e))?;
+    }
+
+    let mut files: Vec<PathBuf> = fs::read_dir(input)
+        .map_err(|e| format!("Failed to read {}: {}", input.display(), e))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .collect();
+    files.sort();
+
+    info!("{} files/subdirs in folder", files.len());
+    info!("--------------------------------------------------------------");
+    let mut ctr_files = 0;
+    let mut ctr_tilesets = 0;
+    for file in files.iter().filter(|f| f.is_file()) {
+        let tile_start_time = Instant::now();
+        ctr_files += 1;
+        let name = file.file_name().unwrap().to_string_lossy();
+        let target = destination.join(base_name(file));
+        let result = set_tile_sink(options, tiler.as_mut(), &target)
+            .and_then(|_| tiler.convert_to(file, &target));
+        match result {
+            Ok(_) => {
+                ctr_tilesets += 1;
+                info!(
+                    "[DONE] {} ({} ms)",
+                    name,
+                    tile_start_time.elapsed().as_millis()
+                );
+            }
+            Err(e) => info!("[SKIPPED] {} - {}", name, e),
+        }
+    }
+
+    info!("--------------------------------------------------------------");
+    info!("{} files processed", ctr_files);
+    info!(
+        "{} tilesets created ({} min)",
+        ctr_tilesets,
+        start_time.elapsed().as_secs() / 60
+    );
+    Ok(ctr_tilesets == ctr_files)
+}
+
+/// Logs the result and every finding of a validation report.
+fn log_report(scheme: TilingScheme, path: &Path, report: &ValidationReport) {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if report.is_valid() {
+        info!("[OK] ({}) {}", scheme, name);
+    } else {
+        info!(
+            "[CORRUPT] ({}) {}: {} errors",
+            scheme,
+            name,
+            report.errors().count()
+        );
+    }
+    for finding in report.findings() {
+        info!("  [{:?}] {}", finding.severity(), finding.message());
+    }
+}
+
+/// Returns the sorted children of a directory: its subdirectories and
+/// PMTiles archives, and all other files if `include_files` is set.
+fn children(dir: &Path, include_files: bool) -> Result<Vec<PathBuf>, String> {
+    let pmtiles = PMTilesValidator::new();
+    let mut children: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_dir() || include_files || pmtiles.is_tileset_dir(p))
+        .collect();
+    children.sort();
+    Ok(children)
+}
+
+/// Creates the validator of a scheme with the deep validation settings of
+/// the options.
+fn create_validator(scheme: TilingScheme, options: &Options) -> Result<Box<dyn Validator>, String> {
+    let mut validator = scheme.validator();
+    validator.set_deep(options.deep);
+    if let Some(color) = &options.background {
+        validator.set_background_color(
+            parse_color(color).ok_or_else(|| format!("Invalid background color: {}", color))?,
+        );
+    }
+    Ok(validator)
+}
+
+/// Validates a single tileset, or every tileset in a directory, and logs
+/// every finding. Returns the validation reports.
+fn validate_with(scheme: TilingScheme, options: &Options) -> Result<Vec<ValidationReport>, String> {
+    let input = options.input.as_path();
+    let validator = create_validator(scheme, options)?;
+    let validate_one = |path: &Path| -> ValidationReport {
+        let report = validator.report(path);
+        log_report(scheme, path, &report);
+        report
+    };
+
+    if validator.is_tileset_dir(input) {
+        return Ok(vec![validate_one(input)]);
+    }
+
+    // Try children. Only Google Maps tilesets are not directory-based.
+    let include_files = scheme == TilingScheme::GoogleMaps;
+    Ok(children(input, include_files)?
+        .iter()
+        .map(|child| validate_one(child))
+        .collect())
+}
+
+/// Detects the tiling scheme of the input, or of each of its subdirectories,
+/// and validates it. Returns the validation reports.
+fn validate_detected(options: &Options) -> Result<Vec<ValidationReport>, String> {
+    let validate_one = |path: &Path| -> Result<Option<ValidationReport>, String> {
+        let Some(detected) = detect_scheme(path) else {
+            return Ok(None);
+        };
+        let report = create_validator(detected.scheme(), options)?.report(path);
+        log_report(detected.scheme(), path, &report);
+        Ok(Some(report))
+    };
+
+    let input = options.input.as_path();
+    if let Some(report) = validate_one(input)? {
+        return Ok(vec![report]);
+    }
+
+    let mut reports = Vec::new();
+    for child in children(input, false)? {
+        match validate_one(&child)? {
+            Some(report) => reports.push(report),
+            None => info!(
+                "[SKIPPED] {} - no known tileset",
+                child.file_name().unwrap_or_default().to_string_lossy()
+            ),
+        }
+    }
+    Ok(reports)
+}
+
+/// Validates the input and optionally writes the findings to the JSON report
+/// file. Returns false if any tileset is corrupt.
+fn validate(options: &Options) -> Result<bool, String> {
+    let reports = match options.scheme {
+        None => validate_detected(options),
+        Some(scheme) => match scheme.tiling_scheme() {
+            Some(tiling_scheme) => validate_with(tiling_scheme, options),
+            None => Err(format!(
+                "No validation support for tiling scheme: {}",
+                scheme.name()
+            )),
+        },
+    }?;
+
+    if let Some(report_file) = &options.report {
+        let json = serde_json::to_string_pretty(&reports).map_err(|e| e.to_string())?;
+        fs::write(report_file, json)
+            .map_err(|e| format!("Failed to write {}: {}", report_file.display(), e))?;
+    }
+
+    Ok(reports.iter().all(|r| r.is_valid()))
+}
+
+/// Prints the metadata of a single tileset, or of every tileset in a
+/// directory. Returns false if no tileset was found.
+fn inspect(options: &Options) -> Result<bool, String> {
+    let read = |path: &Path| -> Option<(TilingScheme, TileSetInfo)> {
+        match options.scheme.and_then(|s| s.tiling_scheme()) {
+            Some(scheme) => scheme
+                .read_tileset_info(path)
+                .ok()
+                .map(|info| (scheme, info)),
+            None => detect_scheme(path).map(|d| (d.scheme(), d.tileset_info(path))),
+        }
+    };
+    let print = |path: &Path, scheme: TilingScheme, info: &TileSetInfo| {
+        info!(
+            "({}) {}: {}x{}, {}x{} {} tiles{}, {} zoom levels",
+            scheme,
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            info.image_width(),
+            info.image_height(),
+            info.tile_width(),
+            info.tile_height(),
+            info.tile_format().extension(),
+            info.encoder_options()
+                .map(|e| format!(" ({})", e.describe(info.tile_format())))
+                .unwrap_or_default(),
+            info.zoom_levels()
+        );
+    };
+
+    if let Some(scheme) = options.scheme {
+        if scheme.tiling_scheme().is_none() {
+            return Err(format!(
+                "No inspection support for tiling scheme: {}",
+                scheme.name()
+            ));
+        }
+    }
+
+    let input = options.input.as_path();
+    if let Some((scheme, info)) = read(input) {
+        print(input, scheme, &info);
+        return Ok(true);
+    }
+
+    let mut found = false;
+    for child in children(input, false)? {
+        if let Some((scheme, info)) = read(&child) {
+            print(&child, scheme, &info);
+            found = true;
+        }
+    }
+    if !found {
+        info!("No tileset found in {}", input.display());
+    }
+    Ok(found)
+}
+
+/// The name a tileset is served under: the name of its directory.
+fn tileset_name(dir: &Path) -> String {
+    fs::canonicalize(dir)
+        .ok()
+        .and_then(|dir| dir.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "tileset".to_string())
+}
+
+/// Adds an image to the server, tiled on demand into `target`.
+fn serve_on_demand(
+    options: &Options,
+    scheme: Scheme,
+    server: &mut TileServer,
+    image: &Path,
+    target: &Path,
+) -> Result<(), TilingError> {
+    let tiling_scheme = match scheme {
+        Scheme::Tms | Scheme::Zoomify => scheme.tiling_scheme().unwrap(),
+        _ => {
+            return Err(TilingError::general(format!(
+                "No on-demand tiling for tiling scheme: {}",
+                scheme.name()
+            )))
+        }
+    };
+    let mut tiler = create_tiler(options, scheme).map_err(TilingError::general)?;
+    // The server links the preview of every tileset
+    tiler.set_generate_preview_html(true);
+    let tiles = OnDemandTiles::new(tiler, tiling_scheme, image, target)?;
+    server.add_on_demand_tileset(&tileset_name(target), tiles)
+}
+
+/// Hosts the input tileset, or every tileset in the input directory, over
+/// HTTP until the process is stopped. With a tiling scheme, the input image,
+/// or every image in the input directory, is tiled on demand instead.
+fn serve(options: &Options) -> Result<bool, String> {
+    let mut server = TileServer::new();
+    server.set_cors_origin(&options.cors_origin);
+    server.set_worker_threads(options.connections as usize);
+    let input = options.input.as_path();
+
+    match options.scheme {
+        Some(scheme) if input.is_file() => {
+            let target = options
+                .output
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(base_name(input)));
+            serve_on_demand(options, scheme, &mut server, input, &target)
+                .map_err(|e| e.to_string())?;
+        }
+        Some(scheme) => {
+            let destination = options.output.clone().unwrap_or_else(|| PathBuf::from("."));
+            for file in children(input, true)?.iter().filter(|f| f.is_file()) {
+                let target = destination.join(base_name(file));
+                if let Err(e) = serve_on_demand(options, scheme, &mut server, file, &target) {
+                    info!(
+                        "[SKIPPED] {} - {}",
+                        file.file_name().unwrap_or_default().to_string_lossy(),
+                        e
+                    );
+                }
+            }
+        }
+        None if detect_scheme(input).is_some() => server
+            .add_tileset(&tileset_name(input), input)
+            .map_err(|e| e.to_string())?,
+        None => {
+            for child in children(input, false)? {
+                if let Err(e) = server.add_tileset(&tileset_name(&child), &child) {
+                    info!(
+                        "[SKIPPED] {} - {}",
+                        child.file_name().unwrap_or_default().to_string_lossy(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    let tilesets = server.tileset_urls();
+    if tilesets.is_empty() {
+        return Err(format!("No tileset to serve in {}", input.display()));
+    }
+    let listener = TcpListener::bind((options.bind.as_str(), options.port)).map_err(|e| {
+        format!(
+            "Failed to listen on {}:{}: {}",
+            options.bind, options.port, e
+        )
+    })?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    info!(
+        "Serving {} tileset(s) on http://{}/",
+        tilesets.len(),
+        address
+    );
+    for (name, url) in tilesets {
+        info!("  {} - http://{}{}", name, address, url);
+    }
+    log::logger().flush();
+    server.serve(listener).map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+fn main() -> ExitCode {
+    let options = Options::parse();
+
+    if let Err(e) = init_logging(options.log) {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+
+    if !options.input.exists() {
+        eprintln!("File not found: {}", options.input.display());
+        return ExitCode::FAILURE;
+    }
+
+    let result = if options.validate {
+        validate(&options)
+    } else if options.inspect {
+        inspect(&options)
+    } else if options.serve {
+        serve(&options)
+    } else {
+        convert(&options)
+    };
+
+    log::logger().flush();
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        Options::try_parse_from(
+            ["magicktiler", "-s", "tms", "-i", "image.tif"]
+                .iter()
+                .chain(args),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn effort_is_validated_per_format() {
+        let webp = encoder_options(&options(&["-f", "webp", "--effort", "6"])).unwrap();
+        assert_eq!(webp.webp_effort, 6);
+        assert!(encoder_options(&options(&["-f", "webp", "--effort", "7"])).is_err());
+        assert!(encoder_options(&options(&["-f", "webp-lossless", "--effort", "9"])).is_err());
+
+        let avif = encoder_options(&options(&["-f", "avif", "--effort", "9"])).unwrap();
+        assert_eq!(avif.avif_effort, 9);
+        assert!(
+            Options::try_parse_from(["magicktiler", "-s", "tms", "-i", "x", "--effort", "10"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn endpoints_must_be_http_urls() {
+        let s3 = |endpoint: &str| options(&["--s3-bucket", "tiles", "--s3-endpoint", endpoint]);
+        assert!(create_tiler(&s3("http://localhost:9000"), Scheme::Tms).is_ok());
+        assert!(create_tiler(&s3("https://s3.amazonaws.com"), Scheme::Tms).is_ok());
+        assert!(create_tiler(&s3("localhost:9000"), Scheme::Tms).is_err());
+    }
+
+    #[test]
+    fn upload_concurrency_requires_a_bucket() {
+        let args = [
+            "magicktiler",
+            "-s",
+            "tms",
+            "-i",
+            "x",
+            "--upload-concurrency",
+            "8",
+        ];
+        assert!(Options::try_parse_from(args).is_err());
+        let s3 = options(&[
+            "--s3-bucket",
+            "tiles",
+            "--s3-endpoint",
+            "http://localhost:9000",
+            "--upload-concurrency",
+            "8",
+        ]);
+        assert_eq!(s3.upload_concurrency, 8);
+    }
+
+    #[test]
+    fn timeout_is_passed_to_the_processor() {
+        let tiler = create_tiler(&options(&["--timeout", "30"]), Scheme::Tms).unwrap();
+        assert_eq!(
+            tiler.base().processor().get_timeout(),
+            Some(Duration::from_secs(30))
+        );
+        let tiler = create_tiler(&options(&[]), Scheme::Tms).unwrap();
+        assert_eq!(tiler.base().processor().get_timeout(), None);
+        assert!(
+            Options::try_parse_from(["magicktiler", "-s", "tms", "-i", "x", "--timeout", "0"])
+                .is_err()
+        );
+    }
+}
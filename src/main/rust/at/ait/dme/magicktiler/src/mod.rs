@@ -1,19 +0,0 @@
-pub mod image;
-pub mod magick_tiler;
-pub mod stripe;
-pub mod tile_set_info;
-pub mod validation_failed_exception;
-pub mod validator;
-
-// Tiler implementations
-pub mod gmaps;
-pub mod ptif;
-pub mod tms;
-pub mod zoomify;
-
-// Re-export commonly used types
-pub use magick_tiler::{BaseMagickTiler, MagickTiler, TilingError};
-pub use stripe::{Orientation, Stripe};
-pub use tile_set_info::TileSetInfo;
-pub use validation_failed_exception::ValidationFailedError;
-pub use validator::Validator;
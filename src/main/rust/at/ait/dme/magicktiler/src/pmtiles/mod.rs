@@ -0,0 +1,11 @@
+mod pmtiles_format;
+mod pmtiles_reader;
+mod pmtiles_tiler;
+mod pmtiles_validator;
+mod pmtiles_writer;
+
+pub use pmtiles_format::{tile_id, tile_position, Compression, Entry, Header, TileType};
+pub use pmtiles_reader::PMTilesReader;
+pub use pmtiles_tiler::PMTilesTiler;
+pub use pmtiles_validator::PMTilesValidator;
+pub use pmtiles_writer::PMTilesWriter;
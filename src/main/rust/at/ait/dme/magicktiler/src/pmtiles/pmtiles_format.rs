@@ -0,0 +1,547 @@
+use std::io::{self, Read, Write};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::image::ImageFormat;
+
+/// The magic number every PMTiles archive starts with
+pub const MAGIC: &[u8; 7] = b"PMTiles";
+
+/// The specification version this implementation reads and writes
+pub const VERSION: u8 = 3;
+
+/// Length of the fixed-size header
+pub const HEADER_LENGTH: usize = 127;
+
+/// The header and the root directory must fit into the first 16 KiB of an
+/// archive, so that clients can fetch both with a single request
+pub const ROOT_LIMIT: usize = 16384;
+
+/// Compression of the directories, the metadata or the tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Unknown,
+    None,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Compression {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Compression::None,
+            2 => Compression::Gzip,
+            3 => Compression::Brotli,
+            4 => Compression::Zstd,
+            _ => Compression::Unknown,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Compression::Unknown => 0,
+            Compression::None => 1,
+            Compression::Gzip => 2,
+            Compression::Brotli => 3,
+            Compression::Zstd => 4,
+        }
+    }
+
+    /// Compresses `data`. Only gzip is supported besides no compression.
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            _ => Err(unsupported(self)),
+        }
+    }
+
+    /// Decompresses `data`. Only gzip is supported besides no compression.
+    pub fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut decompressed = Vec::new();
+                GzDecoder::new(data).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+            _ => Err(unsupported(self)),
+        }
+    }
+}
+
+fn unsupported(compression: Compression) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Unsupported PMTiles compression: {:?}", compression),
+    )
+}
+
+/// The type of the tiles in an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileType {
+    Unknown,
+    /// Mapbox Vector Tiles
+    MVT,
+    PNG,
+    JPEG,
+    WebP,
+    AVIF,
+}
+
+impl TileType {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => TileType::MVT,
+            2 => TileType::PNG,
+            3 => TileType::JPEG,
+            4 => TileType::WebP,
+            5 => TileType::AVIF,
+            _ => TileType::Unknown,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            TileType::Unknown => 0,
+            TileType::MVT => 1,
+            TileType::PNG => 2,
+            TileType::JPEG => 3,
+            TileType::WebP => 4,
+            TileType::AVIF => 5,
+        }
+    }
+
+    /// The tile type of an image format. PMTiles has no type for TIFF tiles.
+    pub fn from_format(format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::JPEG => TileType::JPEG,
+            ImageFormat::PNG => TileType::PNG,
+            ImageFormat::WebP | ImageFormat::WebPLossless => TileType::WebP,
+            ImageFormat::AVIF => TileType::AVIF,
+            ImageFormat::TIFF => TileType::Unknown,
+        }
+    }
+
+    /// The image format of raster tiles of this type
+    pub fn image_format(&self) -> Option<ImageFormat> {
+        match self {
+            TileType::PNG => Some(ImageFormat::PNG),
+            TileType::JPEG => Some(ImageFormat::JPEG),
+            TileType::WebP => Some(ImageFormat::WebP),
+            TileType::AVIF => Some(ImageFormat::AVIF),
+            TileType::Unknown | TileType::MVT => None,
+        }
+    }
+}
+
+/// The fixed-size header of a PMTiles v3 archive. Offsets are absolute
+/// positions in the archive; coordinates are in degrees times 10^7.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub root_offset: u64,
+    pub root_length: u64,
+    pub metadata_offset: u64,
+    pub metadata_length: u64,
+    pub leaf_directories_offset: u64,
+    pub leaf_directories_length: u64,
+    pub tile_data_offset: u64,
+    pub tile_data_length: u64,
+
+    /// Number of tiles the directories address, counting runs of tiles
+    pub addressed_tiles: u64,
+
+    /// Number of directory entries pointing at tile data
+    pub tile_entries: u64,
+
+    /// Number of distinct tile contents
+    pub tile_contents: u64,
+
+    /// Whether the tile data is ordered by tile id
+    pub clustered: bool,
+    pub internal_compression: Compression,
+    pub tile_compression: Compression,
+    pub tile_type: TileType,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub min_lon_e7: i32,
+    pub min_lat_e7: i32,
+    pub max_lon_e7: i32,
+    pub max_lat_e7: i32,
+    pub center_zoom: u8,
+    pub center_lon_e7: i32,
+    pub center_lat_e7: i32,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_LENGTH] {
+        let mut bytes = [0u8; HEADER_LENGTH];
+        bytes[..7].copy_from_slice(MAGIC);
+        bytes[7] = VERSION;
+        let offsets = [
+            self.root_offset,
+            self.root_length,
+            self.metadata_offset,
+            self.metadata_length,
+            self.leaf_directories_offset,
+            self.leaf_directories_length,
+            self.tile_data_offset,
+            self.tile_data_length,
+            self.addressed_tiles,
+            self.tile_entries,
+            self.tile_contents,
+        ];
+        for (i, value) in offsets.iter().enumerate() {
+            bytes[8 + i * 8..16 + i * 8].copy_from_slice(&value.to_le_bytes());
+        }
+        bytes[96] = self.clustered as u8;
+        bytes[97] = self.internal_compression.to_u8();
+        bytes[98] = self.tile_compression.to_u8();
+        bytes[99] = self.tile_type.to_u8();
+        bytes[100] = self.min_zoom;
+        bytes[101] = self.max_zoom;
+        bytes[102..106].copy_from_slice(&self.min_lon_e7.to_le_bytes());
+        bytes[106..110].copy_from_slice(&self.min_lat_e7.to_le_bytes());
+        bytes[110..114].copy_from_slice(&self.max_lon_e7.to_le_bytes());
+        bytes[114..118].copy_from_slice(&self.max_lat_e7.to_le_bytes());
+        bytes[118] = self.center_zoom;
+        bytes[119..123].copy_from_slice(&self.center_lon_e7.to_le_bytes());
+        bytes[123..127].copy_from_slice(&self.center_lat_e7.to_le_bytes());
+        bytes
+    }
+
+    /// Parses a header, checking the magic number and the version
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < HEADER_LENGTH || &bytes[..7] != MAGIC {
+            return Err(invalid("Not a PMTiles archive"));
+        }
+        if bytes[7] != VERSION {
+            return Err(invalid(format!("Unsupported PMTiles version {}", bytes[7])));
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let i32_at = |i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            root_offset: u64_at(8),
+            root_length: u64_at(16),
+            metadata_offset: u64_at(24),
+            metadata_length: u64_at(32),
+            leaf_directories_offset: u64_at(40),
+            leaf_directories_length: u64_at(48),
+            tile_data_offset: u64_at(56),
+            tile_data_length: u64_at(64),
+            addressed_tiles: u64_at(72),
+            tile_entries: u64_at(80),
+            tile_contents: u64_at(88),
+            clustered: bytes[96] == 1,
+            internal_compression: Compression::from_u8(bytes[97]),
+            tile_compression: Compression::from_u8(bytes[98]),
+            tile_type: TileType::from_u8(bytes[99]),
+            min_zoom: bytes[100],
+            max_zoom: bytes[101],
+            min_lon_e7: i32_at(102),
+            min_lat_e7: i32_at(106),
+            max_lon_e7: i32_at(110),
+            max_lat_e7: i32_at(114),
+            center_zoom: bytes[118],
+            center_lon_e7: i32_at(119),
+            center_lat_e7: i32_at(123),
+        })
+    }
+}
+
+/// A directory entry. With a run length of 0, the entry points at a leaf
+/// directory; otherwise `run_length` consecutive tile ids starting at
+/// `tile_id` share the tile data at `offset`. Offsets are relative to the
+/// leaf directory or the tile data section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub tile_id: u64,
+    pub offset: u64,
+    pub length: u32,
+    pub run_length: u32,
+}
+
+impl Entry {
+    pub fn is_leaf(&self) -> bool {
+        self.run_length == 0
+    }
+}
+
+/// Serializes directory entries, sorted by tile id, column by column:
+/// delta-encoded tile ids, run lengths, lengths and offsets. An offset that
+/// directly follows the previous entry's data is stored as 0.
+pub fn serialize_directory(entries: &[Entry]) -> Vec<u8> {
+    let mut data = Vec::new();
+    write_varint(&mut data, entries.len() as u64);
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut data, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut data, entry.run_length as u64);
+    }
+    for entry in entries {
+        write_varint(&mut data, entry.length as u64);
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        let follows = i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length as u64;
+        write_varint(&mut data, if follows { 0 } else { entry.offset + 1 });
+    }
+    data
+}
+
+/// Parses (uncompressed) directory entries written by `serialize_directory`
+pub fn deserialize_directory(mut data: &[u8]) -> io::Result<Vec<Entry>> {
+    let count = read_varint(&mut data)? as usize;
+    // Every entry takes at least four bytes
+    if count > data.len() / 4 + 1 {
+        return Err(invalid("Directory entry count exceeds its length"));
+    }
+    let mut entries = vec![
+        Entry {
+            tile_id: 0,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        };
+        count
+    ];
+    let mut last_id = 0u64;
+    for entry in entries.iter_mut() {
+        last_id = last_id
+            .checked_add(read_varint(&mut data)?)
+            .ok_or_else(|| invalid("Tile id overflow"))?;
+        entry.tile_id = last_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(&mut data)? as u32;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(&mut data)? as u32;
+    }
+    for i in 0..count {
+        let value = read_varint(&mut data)?;
+        entries[i].offset = if value == 0 && i > 0 {
+            entries[i - 1].offset + entries[i - 1].length as u64
+        } else {
+            value.saturating_sub(1)
+        };
+    }
+    Ok(entries)
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data
+            .split_first()
+            .ok_or_else(|| invalid("Truncated directory"))?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("Varint too long"))
+}
+
+/// The tile id of a tile: the number of tiles on all lower zoom levels plus
+/// the position of the tile on the Hilbert curve through its zoom level.
+/// Rows count from the top (XYZ).
+pub fn tile_id(zoom_level: u8, x: u32, y: u32) -> u64 {
+    let n = 1u64 << zoom_level;
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u64;
+        let ry = (y & s > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        (x, y) = rotate(n, x, y, rx, ry);
+        s /= 2;
+    }
+    tiles_below(zoom_level) + d
+}
+
+/// The zoom level, column and row (counting from the top) of a tile id
+pub fn tile_position(tile_id: u64) -> (u8, u32, u32) {
+    let mut zoom_level = 0;
+    while zoom_level < 31 && tiles_below(zoom_level + 1) <= tile_id {
+        zoom_level += 1;
+    }
+    let n = 1u64 << zoom_level;
+    let mut t = tile_id - tiles_below(zoom_level);
+    let (mut x, mut y) = (0, 0);
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        (x, y) = rotate(s, x, y, rx, ry);
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (zoom_level, x as u32, y as u32)
+}
+
+/// The number of tiles on all zoom levels below `zoom_level`
+fn tiles_below(zoom_level: u8) -> u64 {
+    ((1u64 << (2 * zoom_level as u32)) - 1) / 3
+}
+
+/// Rotates and flips a quadrant of the Hilbert curve
+fn rotate(n: u64, x: u64, y: u64, rx: u64, ry: u64) -> (u64, u64) {
+    if ry != 0 {
+        return (x, y);
+    }
+    if rx == 1 {
+        (n - 1 - y, n - 1 - x)
+    } else {
+        (y, x)
+    }
+}
+
+pub(crate) fn invalid<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_ids_follow_the_specification() {
+        assert_eq!(tile_id(0, 0, 0), 0);
+        assert_eq!(tile_id(1, 0, 0), 1);
+        assert_eq!(tile_id(1, 0, 1), 2);
+        assert_eq!(tile_id(1, 1, 1), 3);
+        assert_eq!(tile_id(1, 1, 0), 4);
+        assert_eq!(tile_id(2, 0, 0), 5);
+        assert_eq!(tile_id(3, 0, 0), 21);
+    }
+
+    #[test]
+    fn tile_ids_round_trip() {
+        let mut next = 0;
+        for zoom_level in 0..=6 {
+            let n = 1u32 << zoom_level;
+            let mut ids: Vec<u64> = (0..n)
+                .flat_map(|x| (0..n).map(move |y| (x, y)))
+                .map(|(x, y)| {
+                    let id = tile_id(zoom_level, x, y);
+                    assert_eq!(tile_position(id), (zoom_level, x, y));
+                    id
+                })
+                .collect();
+            // Every zoom level fills the ids following the previous one
+            ids.sort();
+            assert_eq!(ids, (next..next + n as u64 * n as u64).collect::<Vec<_>>());
+            next += n as u64 * n as u64;
+        }
+
+        let max = (1u32 << 31) - 1;
+        for (zoom_level, x, y) in [
+            (20, 12345, 678910),
+            (31, 0, 0),
+            (31, max, max),
+            (31, max, 0),
+        ] {
+            assert_eq!(tile_position(tile_id(zoom_level, x, y)), (zoom_level, x, y));
+        }
+    }
+
+    #[test]
+    fn directories_round_trip() {
+        let entries = vec![
+            Entry {
+                tile_id: 0,
+                offset: 0,
+                length: 100,
+                run_length: 1,
+            },
+            // Directly follows the previous entry, stored as offset 0
+            Entry {
+                tile_id: 1,
+                offset: 100,
+                length: 20,
+                run_length: 300,
+            },
+            // Points back at earlier data
+            Entry {
+                tile_id: 5000,
+                offset: 0,
+                length: 100,
+                run_length: 1,
+            },
+            Entry {
+                tile_id: u64::MAX / 2,
+                offset: 1 << 40,
+                length: u32::MAX,
+                run_length: 0,
+            },
+        ];
+        let data = serialize_directory(&entries);
+        assert_eq!(deserialize_directory(&data).unwrap(), entries);
+        assert!(entries[3].is_leaf());
+
+        assert!(deserialize_directory(&data[..data.len() - 1]).is_err());
+        assert_eq!(
+            deserialize_directory(&serialize_directory(&[])).unwrap(),
+            []
+        );
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let header = Header {
+            root_offset: 127,
+            root_length: 25,
+            metadata_offset: 152,
+            metadata_length: 247,
+            leaf_directories_offset: 399,
+            leaf_directories_length: 0,
+            tile_data_offset: 399,
+            tile_data_length: 715,
+            addressed_tiles: 85,
+            tile_entries: 84,
+            tile_contents: 80,
+            clustered: true,
+            internal_compression: Compression::Gzip,
+            tile_compression: Compression::None,
+            tile_type: TileType::PNG,
+            min_zoom: 0,
+            max_zoom: 3,
+            min_lon_e7: -1_800_000_000,
+            min_lat_e7: -850_511_288,
+            max_lon_e7: 1_800_000_000,
+            max_lat_e7: 850_511_288,
+            center_zoom: 0,
+            center_lon_e7: 0,
+            center_lat_e7: 0,
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(&bytes[..7], MAGIC);
+        assert_eq!(Header::from_bytes(&bytes).unwrap(), header);
+
+        let mut version = bytes;
+        version[7] = 2;
+        assert!(Header::from_bytes(&version).is_err());
+        assert!(Header::from_bytes(&bytes[..HEADER_LENGTH - 1]).is_err());
+    }
+}
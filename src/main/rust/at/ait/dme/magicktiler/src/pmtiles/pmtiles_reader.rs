@@ -0,0 +1,158 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use super::pmtiles_format::{
+    deserialize_directory, invalid, tile_id, Entry, Header, HEADER_LENGTH,
+};
+
+/// Leaf directories may not point at further leaf directories more than
+/// this many times
+const MAX_DEPTH: usize = 3;
+
+/// Reads tiles and metadata from a PMTiles v3 archive. Only the header and
+/// the root directory are read on opening; leaf directories and tiles are
+/// read as they are requested.
+pub struct PMTilesReader {
+    path: PathBuf,
+    file: File,
+    header: Header,
+    root: Vec<Entry>,
+}
+
+impl PMTilesReader {
+    /// Opens an archive and reads its header and root directory.
+    ///
+    /// # Errors
+    /// Returns an error if the file is not a PMTiles v3 archive or its root
+    /// directory cannot be read
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut bytes = [0u8; HEADER_LENGTH];
+        file.read_exact(&mut bytes)
+            .map_err(|_| invalid("Not a PMTiles archive"))?;
+        let header = Header::from_bytes(&bytes)?;
+        let mut reader = Self {
+            path: path.to_path_buf(),
+            file,
+            header,
+            root: Vec::new(),
+        };
+        reader.root =
+            reader.read_directory(reader.header.root_offset, reader.header.root_length)?;
+        Ok(reader)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Reads the JSON metadata of the archive
+    pub fn metadata(&mut self) -> io::Result<serde_json::Value> {
+        let compressed = self.read_at(self.header.metadata_offset, self.header.metadata_length)?;
+        let metadata = self.header.internal_compression.decompress(&compressed)?;
+        Ok(serde_json::from_slice(&metadata)?)
+    }
+
+    /// Reads a tile, with rows counting from the top (XYZ). Returns `None`
+    /// if the archive holds no such tile.
+    pub fn tile(&mut self, zoom_level: u8, x: u32, y: u32) -> io::Result<Option<Vec<u8>>> {
+        if zoom_level > 31 || x >> zoom_level != 0 || y >> zoom_level != 0 {
+            return Ok(None);
+        }
+        let id = tile_id(zoom_level, x, y);
+        let mut entries = self.root.clone();
+        for _ in 0..=MAX_DEPTH {
+            let Some(entry) = find_entry(&entries, id) else {
+                return Ok(None);
+            };
+            if !entry.is_leaf() {
+                return self.tile_data(&entry).map(Some);
+            }
+            entries = self.read_directory(
+                self.header.leaf_directories_offset + entry.offset,
+                entry.length as u64,
+            )?;
+        }
+        Err(invalid("Leaf directories nested too deeply"))
+    }
+
+    /// All entries pointing at tile data, with the leaf directories resolved,
+    /// in the order of the directories
+    pub fn entries(&mut self) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let root = self.root.clone();
+        self.collect_entries(&root, 0, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn collect_entries(
+        &mut self,
+        directory: &[Entry],
+        depth: usize,
+        entries: &mut Vec<Entry>,
+    ) -> io::Result<()> {
+        for entry in directory {
+            if !entry.is_leaf() {
+                entries.push(*entry);
+            } else if depth == MAX_DEPTH {
+                return Err(invalid("Leaf directories nested too deeply"));
+            } else {
+                let leaf = self.read_directory(
+                    self.header.leaf_directories_offset + entry.offset,
+                    entry.length as u64,
+                )?;
+                self.collect_entries(&leaf, depth + 1, entries)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the tile data an entry points at, decompressing it if the
+    /// archive compresses its tiles.
+    pub fn tile_data(&mut self, entry: &Entry) -> io::Result<Vec<u8>> {
+        if entry.offset + entry.length as u64 > self.header.tile_data_length {
+            return Err(invalid(format!(
+                "Tile {} lies outside the tile data",
+                entry.tile_id
+            )));
+        }
+        let data = self.read_at(
+            self.header.tile_data_offset + entry.offset,
+            entry.length as u64,
+        )?;
+        self.header.tile_compression.decompress(&data)
+    }
+
+    fn read_directory(&mut self, offset: u64, length: u64) -> io::Result<Vec<Entry>> {
+        let compressed = self.read_at(offset, length)?;
+        deserialize_directory(&self.header.internal_compression.decompress(&compressed)?)
+    }
+
+    fn read_at(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(offset))?;
+        (&mut self.file).take(length).read_to_end(&mut data)?;
+        if data.len() as u64 != length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} is truncated", self.path.display()),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+/// The entry of a directory that covers a tile id: the last entry starting
+/// at or before it, if that is a leaf directory or its run includes the id
+pub(crate) fn find_entry(entries: &[Entry], id: u64) -> Option<Entry> {
+    let index = entries
+        .partition_point(|e| e.tile_id <= id)
+        .checked_sub(1)?;
+    let entry = entries[index];
+    (entry.is_leaf() || id < entry.tile_id + entry.run_length as u64).then_some(entry)
+}
@@ -0,0 +1,263 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{debug, error};
+use serde_json::json;
+
+use super::pmtiles_format::TileType;
+use super::pmtiles_writer::PMTilesWriter;
+use crate::geo::BoundingBox;
+use crate::gmaps::GoogleMapsTiler;
+use crate::image::ImageFormat;
+use crate::magick_tiler::{BaseMagickTiler, MagickTiler, TilingError};
+use crate::sink::TileSink;
+use crate::tile_set_info::TileSetInfo;
+use crate::tiling_scheme::{TilesetMetadata, TilingScheme};
+use crate::tms::TMSTiler;
+use crate::zoomify::{ZoomifyTiler, TILEGROUP};
+
+/// A tiler that packages the tiles of another tiling scheme into a single
+/// PMTiles v3 archive (see `PMTilesWriter`).
+///
+/// The wrapped tiler generates its tileset in the working directory. Its
+/// tiles are then added to the archive by zoom level, column and row, with
+/// TMS rows flipped to count from the top. Only schemes whose tiles form a
+/// regular grid per zoom level can be packaged: TMS, Google Maps and
+/// Zoomify. The background buffer rows TMS adds above the tile grid are left
+/// out.
+///
+/// Besides a name and the tile format, the metadata of the archive records
+/// the source scheme and the tileset parameters, so that `PMTilesValidator`
+/// can check the archive against them.
+pub struct PMTilesTiler {
+    tiler: Box<dyn MagickTiler>,
+
+    /// The tiling scheme of the wrapped tiler
+    scheme: TilingScheme,
+
+    /// Geographical bounding box of the image, recorded in the header
+    bbox: Option<BoundingBox>,
+}
+
+/// Metadata key of the tiling scheme the tiles were generated with
+pub(crate) const METADATA_SCHEME: &str = "scheme";
+
+/// Metadata key of the tileset parameters
+pub(crate) const METADATA_TILESET: &str = "tileset";
+
+/// The tile formats PMTiles has a tile type for
+const TILE_FORMATS: &[ImageFormat] = &[
+    ImageFormat::JPEG,
+    ImageFormat::PNG,
+    ImageFormat::WebP,
+    ImageFormat::WebPLossless,
+    ImageFormat::AVIF,
+];
+
+impl PMTilesTiler {
+    /// The tiling schemes whose tiles can be packaged
+    pub const SCHEMES: [TilingScheme; 3] = [
+        TilingScheme::Tms,
+        TilingScheme::GoogleMaps,
+        TilingScheme::Zoomify,
+    ];
+
+    /// Creates a tiler that packages tiles of the given scheme.
+    ///
+    /// # Errors
+    /// Returns an error if the tiles of the scheme do not form a regular
+    /// grid (see `SCHEMES`)
+    pub fn new(scheme: TilingScheme) -> Result<Self, TilingError> {
+        let mut tiler: Box<dyn MagickTiler> = match scheme {
+            TilingScheme::Tms => Box::new(TMSTiler::new()),
+            TilingScheme::GoogleMaps => Box::new(GoogleMapsTiler::new()),
+            TilingScheme::Zoomify => Box::new(ZoomifyTiler::new()),
+            TilingScheme::DeepZoom | TilingScheme::IIIF | TilingScheme::PMTiles => {
+                return Err(TilingError::general(format!(
+                    "{} tilesets cannot be packaged as PMTiles archives",
+                    scheme
+                )))
+            }
+        };
+        // The preview of the wrapped tiler would be deleted with its tileset
+        tiler.set_generate_preview_html(false);
+        Ok(Self {
+            tiler,
+            scheme,
+            bbox: None,
+        })
+    }
+
+    pub fn scheme(&self) -> TilingScheme {
+        self.scheme
+    }
+
+    pub fn bounding_box(&self) -> Option<&BoundingBox> {
+        self.bbox.as_ref()
+    }
+
+    /// Sets the geographical bounding box of the image, recorded as the
+    /// bounds of the archive. Without it, the bounds span the world.
+    pub fn set_bounding_box(&mut self, bbox: BoundingBox) {
+        self.bbox = Some(bbox);
+    }
+
+    /// Adds every tile of the tileset in `dir` to a new archive.
+    fn write_archive(
+        &self,
+        image: &Path,
+        info: &TileSetInfo,
+        dir: &Path,
+        archive: &Path,
+    ) -> Result<(), TilingError> {
+        let format = info.tile_format();
+        let mut writer = PMTilesWriter::create(archive, TileType::from_format(format))?;
+        if let Some(bbox) = &self.bbox {
+            writer.set_bounds(bbox);
+        }
+        let tileset = TilesetMetadata::new(
+            info.image_width(),
+            info.image_height(),
+            info.tile_width(),
+            info.tile_height(),
+            info.zoom_levels(),
+            format,
+        )
+        .with_encoder_options(info.encoder_options().cloned());
+        writer.set_metadata(json!({
+            "name": image.file_stem().unwrap_or_default().to_string_lossy(),
+            "format": format.extension(),
+            "type": "overlay",
+            METADATA_SCHEME: self.scheme,
+            METADATA_TILESET: tileset,
+        }));
+
+        let mut files = Vec::new();
+        tile_files(dir, format.extension(), &mut files)?;
+        for file in files {
+            let relative = file.strip_prefix(dir).unwrap_or(&file);
+            let Some((zoom_level, x, y)) = grid_position(self.scheme, relative) else {
+                continue;
+            };
+            let data = fs::read(&file).map_err(TilingError::not_readable(&file))?;
+            if self.scheme != TilingScheme::Tms {
+                writer.add_tile(zoom_level, x, y, &data)?;
+            } else if y >> zoom_level == 0 {
+                writer.add_tms_tile(zoom_level, x, y, &data)?;
+            } else {
+                debug!("Leaving out background tile {}", relative.display());
+            }
+        }
+        writer.finish()
+    }
+}
+
+/// Collects the files with the given extension in a directory tree.
+fn tile_files(dir: &Path, extension: &str, files: &mut Vec<PathBuf>) -> Result<(), TilingError> {
+    for entry in fs::read_dir(dir).map_err(TilingError::not_readable(dir))? {
+        let path = entry.map_err(TilingError::not_readable(dir))?.path();
+        if path.is_dir() {
+            tile_files(&path, extension, files)?;
+        } else if path.extension().is_some_and(|e| e == extension) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The zoom level, column and row of a tile from its path relative to the
+/// tileset root, in the layout of the tiling scheme. Other files, like the
+/// resized base image of Google Maps tilesets, have no position.
+fn grid_position(scheme: TilingScheme, tile: &Path) -> Option<(u8, u32, u32)> {
+    let tile = tile.with_extension("");
+    let names: Vec<&str> = tile
+        .iter()
+        .map(|name| name.to_str())
+        .collect::<Option<_>>()?;
+    let numbers: Vec<&str> = match (scheme, names.as_slice()) {
+        (TilingScheme::Tms, &[z, x, row]) => vec![z, x, row],
+        (TilingScheme::GoogleMaps, &[name]) => name.split('_').collect(),
+        (TilingScheme::Zoomify, &[group, name]) if group.starts_with(TILEGROUP) => {
+            name.split('-').collect()
+        }
+        _ => return None,
+    };
+    match numbers.as_slice() {
+        &[z, x, y] => Some((z.parse().ok()?, x.parse().ok()?, y.parse().ok()?)),
+        _ => None,
+    }
+}
+
+/// Returns the archive file name, adding a .pmtiles extension if necessary.
+fn pmtiles_target(target: &Path) -> PathBuf {
+    if target
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("pmtiles"))
+    {
+        target.to_path_buf()
+    } else {
+        PathBuf::from(format!("{}.pmtiles", target.to_string_lossy()))
+    }
+}
+
+impl MagickTiler for PMTilesTiler {
+    fn base(&self) -> &BaseMagickTiler {
+        self.tiler.base()
+    }
+
+    fn base_mut(&mut self) -> &mut BaseMagickTiler {
+        self.tiler.base_mut()
+    }
+
+    /// No preview is generated: it could not display the archive.
+    fn set_generate_preview_html(&mut self, _generate_preview: bool) {}
+
+    /// Not supported: the tiles are always packaged into the archive.
+    fn set_tile_sink(&mut self, _sink: Arc<dyn TileSink>) -> Result<(), TilingError> {
+        Err(TilingError::general(
+            "PMTiles archives cannot be written through a tile sink".to_string(),
+        ))
+    }
+
+    fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError> {
+        let target = self.base().default_target();
+        self.convert_to(image, &target)
+    }
+
+    fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
+        self.base()
+            .check_tile_format(image, "PMTiles", TILE_FORMATS)?;
+
+        // Generate the tileset in the working directory, then package it
+        let archive = pmtiles_target(target);
+        let temp_dir = self.base().working_directory().join(format!(
+            "{}-{}",
+            image.file_stem().unwrap().to_string_lossy(),
+            self.scheme.name()
+        ));
+        let result = self.tiler.convert_to(image, &temp_dir).and_then(|info| {
+            debug!("Packaging PMTiles");
+            if let Some(parent) = archive.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent).map_err(TilingError::not_writable(parent))?;
+            }
+            self.write_archive(image, &info, &temp_dir, &archive)?;
+            Ok(info)
+        });
+
+        if let Err(e) = fs::remove_dir_all(&temp_dir) {
+            error!("Could not delete {}: {}", temp_dir.display(), e);
+        }
+        result
+    }
+
+    /// Generates the tileset of the wrapped scheme in the tileset root
+    /// directory, without packaging it.
+    fn convert_internal(
+        &mut self,
+        image: &Path,
+        info: TileSetInfo,
+    ) -> Result<TileSetInfo, TilingError> {
+        self.tiler.convert_internal(image, info)
+    }
+}
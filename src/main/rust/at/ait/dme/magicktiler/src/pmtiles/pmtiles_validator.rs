@@ -0,0 +1,334 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use image::Rgba;
+
+use super::pmtiles_format::{tile_id, tile_position, Entry, TileType};
+use super::pmtiles_reader::{find_entry, PMTilesReader};
+use super::pmtiles_tiler::{METADATA_SCHEME, METADATA_TILESET};
+use crate::image::{avif_dimensions, ImageFormat};
+use crate::tile_set_info::TileSetInfo;
+use crate::tiling_scheme::{TilesetMetadata, TilingScheme};
+use crate::tms::tile_content;
+use crate::validation_failed_exception::ValidationFailedError;
+use crate::validation_report::{Finding, FindingCategory, ValidationReport};
+use crate::validator::{check_tile_data, Validator, DEFAULT_BACKGROUND};
+
+/// Validator for PMTiles v3 archives. Checks that the directories can be
+/// read, are sorted and point into the tile data, that the header agrees
+/// with them and with the metadata, and, for archives written by
+/// `PMTilesTiler`, that every tile of the source tiling scheme is present.
+pub struct PMTilesValidator {
+    /// Decode every tile and check its dimensions
+    deep: bool,
+
+    /// The background colour blank tiles are made of
+    background: Rgba<u8>,
+}
+
+impl PMTilesValidator {
+    pub fn new() -> Self {
+        Self {
+            deep: false,
+            background: DEFAULT_BACKGROUND,
+        }
+    }
+
+    /// Reads the tileset parameters recorded by `PMTilesTiler`, or derives
+    /// them from the header and the first tile of other archives
+    fn read_tileset_metadata(
+        &self,
+        reader: &mut PMTilesReader,
+    ) -> Result<(TilesetMetadata, Option<TilingScheme>), ValidationFailedError> {
+        let metadata = reader.metadata()?;
+        let scheme = metadata
+            .get(METADATA_SCHEME)
+            .and_then(|s| serde_json::from_value(s.clone()).ok());
+        if let Some(tileset) = metadata.get(METADATA_TILESET) {
+            return Ok((serde_json::from_value(tileset.clone())?, scheme));
+        }
+
+        let header = reader.header().clone();
+        let format = header.tile_type.image_format().ok_or_else(|| {
+            ValidationFailedError::new(format!("Unsupported tile type: {:?}", header.tile_type))
+        })?;
+        let first = reader
+            .entries()?
+            .first()
+            .copied()
+            .ok_or_else(|| ValidationFailedError::new("The archive holds no tiles"))?;
+        let data = reader.tile_data(&first)?;
+        let (tile_width, tile_height) = if format == ImageFormat::AVIF {
+            avif_dimensions(&data)?
+        } else {
+            image::load_from_memory(&data)
+                .map(|image| (image.width(), image.height()))
+                .map_err(|e| ValidationFailedError::new(format!("Could not decode tile: {}", e)))?
+        };
+        let (tile_width, tile_height) = (tile_width as i32, tile_height as i32);
+        Ok((
+            TilesetMetadata::new(
+                tile_width << header.max_zoom,
+                tile_height << header.max_zoom,
+                tile_width,
+                tile_height,
+                header.max_zoom as i32 + 1,
+                format,
+            ),
+            None,
+        ))
+    }
+
+    /// Checks the directory entries against each other and the header
+    fn check_entries(
+        &self,
+        reader: &PMTilesReader,
+        entries: &[Entry],
+        report: &mut ValidationReport,
+    ) {
+        let header = reader.header();
+        let mut addressed_tiles = 0;
+        let mut contents = HashSet::new();
+        let mut next_id = 0;
+        for entry in entries {
+            let (zoom, x, y) = tile_position(entry.tile_id);
+            if entry.tile_id < next_id {
+                report.add(
+                    Finding::error(
+                        FindingCategory::MetadataMismatch,
+                        format!("Directory entry for tile {} is out of order", entry.tile_id),
+                    )
+                    .at_tile(zoom as i32, x as i32, y as i32),
+                );
+            }
+            if entry.offset + entry.length as u64 > header.tile_data_length {
+                report.add(
+                    Finding::error(
+                        FindingCategory::MetadataMismatch,
+                        format!("Tile {} lies outside the tile data", entry.tile_id),
+                    )
+                    .at_tile(zoom as i32, x as i32, y as i32),
+                );
+            }
+            next_id = entry.tile_id + entry.run_length as u64;
+            addressed_tiles += entry.run_length as u64;
+            contents.insert(entry.offset);
+        }
+
+        let counts = [
+            ("addressed tiles", header.addressed_tiles, addressed_tiles),
+            ("tile entries", header.tile_entries, entries.len() as u64),
+            ("tile contents", header.tile_contents, contents.len() as u64),
+        ];
+        for (name, declared, actual) in counts {
+            // 0 means unknown
+            if declared != 0 && declared != actual {
+                report.add(Finding::error(
+                    FindingCategory::MetadataMismatch,
+                    format!(
+                        "The header declares {} {}, the directories hold {}",
+                        declared, name, actual
+                    ),
+                ));
+            }
+        }
+    }
+
+    /// Reports the tiles of the source tiling scheme missing from the
+    /// archive. TMS rows are flipped, since the archive counts rows from the
+    /// top.
+    fn check_coverage(
+        &self,
+        info: &TileSetInfo,
+        scheme: TilingScheme,
+        entries: &[Entry],
+        report: &mut ValidationReport,
+    ) {
+        for z in 0..info.zoom_levels() {
+            let level = info.zoom_levels() - 1 - z;
+            for x in 0..info.number_of_x_tiles(level) {
+                for row in 0..info.number_of_y_tiles(level) {
+                    let y = match scheme {
+                        TilingScheme::Tms => (1 << z) - 1 - row,
+                        _ => row,
+                    };
+                    if y < 0 || find_entry(entries, tile_id(z as u8, x as u32, y as u32)).is_none()
+                    {
+                        report.add(
+                            Finding::error(
+                                FindingCategory::MissingTile,
+                                format!("Missing tile {}/{}/{}", z, x, y),
+                            )
+                            .at_tile(z, x, y),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decodes the tile of every entry and checks its dimensions. Zoomify
+    /// tiles in the last column and row may be smaller.
+    fn check_tiles(
+        &self,
+        reader: &mut PMTilesReader,
+        info: &TileSetInfo,
+        scheme: Option<TilingScheme>,
+        entries: &[Entry],
+        report: &mut ValidationReport,
+    ) {
+        for entry in entries {
+            let (zoom, x, y) = tile_position(entry.tile_id);
+            let (zoom, x, y) = (zoom as i32, x as i32, y as i32);
+            let tile = format!("{}/{}/{}.{}", zoom, x, y, info.tile_format().extension());
+            let data = match reader.tile_data(entry) {
+                Ok(data) => data,
+                Err(e) => {
+                    report.add(
+                        Finding::error(
+                            FindingCategory::UndecodableTile,
+                            format!("Could not read tile {}: {}", tile, e),
+                        )
+                        .at_tile(zoom, x, y),
+                    );
+                    continue;
+                }
+            };
+
+            let level = info.zoom_levels() - 1 - zoom;
+            let content = match scheme {
+                Some(TilingScheme::Tms) => Some(tile_content(
+                    (info.image_width(), info.image_height()),
+                    (info.tile_width(), info.tile_height()),
+                    1 << level,
+                    x,
+                    (1 << zoom) - 1 - y,
+                )),
+                // The padding of Google Maps tiles is not recorded
+                Some(TilingScheme::GoogleMaps) => Some((0, 0, 0, 0)),
+                _ => None,
+            };
+            let (width, height, tolerance) = if scheme == Some(TilingScheme::Zoomify) {
+                let factor = 2_f64.powi(level);
+                let level_width = (info.image_width() as f64 / factor).ceil() as i32;
+                let level_height = (info.image_height() as f64 / factor).ceil() as i32;
+                let width = (level_width - x * info.tile_width()).clamp(1, info.tile_width());
+                let height = (level_height - y * info.tile_height()).clamp(1, info.tile_height());
+                let border = x == info.number_of_x_tiles(level) - 1
+                    || y == info.number_of_y_tiles(level) - 1;
+                (width, height, if border { 1 } else { 0 })
+            } else {
+                (info.tile_width(), info.tile_height(), 0)
+            };
+            check_tile_data(
+                Path::new(&tile),
+                &data,
+                width,
+                height,
+                tolerance,
+                content,
+                self.background,
+                Some((zoom, x, y)),
+                report,
+            );
+        }
+    }
+}
+
+impl Default for PMTilesValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Validator for PMTilesValidator {
+    /// PMTiles archives are single files with a .pmtiles extension
+    fn is_tileset_dir(&self, dir: &Path) -> bool {
+        dir.is_file()
+            && dir
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("pmtiles"))
+    }
+
+    fn read_metadata(&self, dir: &Path) -> Result<TilesetMetadata, ValidationFailedError> {
+        let mut reader = PMTilesReader::open(dir)?;
+        Ok(self.read_tileset_metadata(&mut reader)?.0)
+    }
+
+    fn set_deep(&mut self, deep: bool) {
+        self.deep = deep;
+    }
+
+    fn set_background_color(&mut self, color: Rgba<u8>) {
+        self.background = color;
+    }
+
+    fn report(&self, dir: &Path) -> ValidationReport {
+        let mut report = ValidationReport::new(dir);
+        if !self.is_tileset_dir(dir) {
+            report.add(Finding::error(
+                FindingCategory::InvalidTileset,
+                "Not a PMTiles archive, validation cannot be continued.",
+            ));
+            return report;
+        }
+
+        let opened = PMTilesReader::open(dir).and_then(|mut reader| {
+            let entries = reader.entries()?;
+            Ok((reader, entries))
+        });
+        let (mut reader, entries) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                report.add(Finding::error(
+                    FindingCategory::InvalidTileset,
+                    e.to_string(),
+                ));
+                return report;
+            }
+        };
+        let (metadata, scheme) = match self.read_tileset_metadata(&mut reader) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                report.add(Finding::error(
+                    FindingCategory::InvalidTileset,
+                    e.to_string(),
+                ));
+                return report;
+            }
+        };
+        let info = TileSetInfo::from_metadata(dir, &metadata);
+
+        self.check_entries(&reader, &entries, &mut report);
+
+        let header = reader.header();
+        if header.tile_type != TileType::from_format(metadata.format()) {
+            report.add(Finding::error(
+                FindingCategory::MetadataMismatch,
+                format!(
+                    "The header declares {:?} tiles, the metadata {:?}",
+                    header.tile_type,
+                    metadata.format()
+                ),
+            ));
+        }
+        if header.max_zoom as i32 != metadata.zoom_levels() - 1 {
+            report.add(Finding::error(
+                FindingCategory::MetadataMismatch,
+                format!(
+                    "The header declares zoom levels up to {}, the metadata {} zoom levels",
+                    header.max_zoom,
+                    metadata.zoom_levels()
+                ),
+            ));
+        }
+
+        if let Some(scheme) = scheme {
+            self.check_coverage(&info, scheme, &entries, &mut report);
+        }
+        if self.deep {
+            self.check_tiles(&mut reader, &info, scheme, &entries, &mut report);
+        }
+        report
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::error;
+
+use super::pmtiles_format::{
+    serialize_directory, tile_id, tile_position, Compression, Entry, Header, TileType,
+    HEADER_LENGTH, ROOT_LIMIT,
+};
+use crate::geo::BoundingBox;
+use crate::magick_tiler::TilingError;
+
+/// The number of entries per leaf directory the writer starts with. It grows
+/// until the root directory fits into the first 16 KiB.
+const LEAF_SIZE: usize = 4096;
+
+/// The extent of the Web Mercator projection, used as the bounds of archives
+/// without a geographical bounding box
+const WORLD: [f64; 4] = [-180.0, -85.05112878, 180.0, 85.05112878];
+
+/// Writes tiles into a single PMTiles v3 archive, which web clients read
+/// with HTTP range requests. Tiles are addressed by zoom level, column and
+/// row and ordered along a Hilbert curve per zoom level.
+///
+/// Tiles can be added in any order; their content is buffered in a
+/// temporary file next to the archive. Identical tiles (e.g. the blank tiles
+/// of the background buffer) are stored only once, and runs of consecutive
+/// identical tiles share a single directory entry. `finish` writes the
+/// header, the (gzip-compressed) root and leaf directories and metadata and
+/// the tile data, ordered by tile id.
+pub struct PMTilesWriter {
+    path: PathBuf,
+    tile_type: TileType,
+    bounds: [f64; 4],
+    metadata: serde_json::Value,
+
+    /// The tile contents in the order they were added
+    data_path: PathBuf,
+    data: File,
+    data_length: u64,
+
+    /// The offset and length of the content of every tile, by tile id
+    tiles: BTreeMap<u64, (u64, u32)>,
+
+    /// The offsets of the contents stored so far, by hash of their content
+    contents: HashMap<u64, Vec<(u64, u32)>>,
+}
+
+impl PMTilesWriter {
+    /// Creates the archive, replacing an existing one.
+    pub fn create(path: &Path, tile_type: TileType) -> Result<Self, TilingError> {
+        if path.exists() {
+            fs::remove_file(path).map_err(TilingError::not_writable(path))?;
+        }
+        let data_path = PathBuf::from(format!("{}.tmp", path.to_string_lossy()));
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&data_path)
+            .map_err(TilingError::not_writable(&data_path))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            tile_type,
+            bounds: WORLD,
+            metadata: serde_json::Value::Object(serde_json::Map::new()),
+            data_path,
+            data,
+            data_length: 0,
+            tiles: BTreeMap::new(),
+            contents: HashMap::new(),
+        })
+    }
+
+    /// Sets the geographical extent of the tiles. Without it, the bounds span
+    /// the world.
+    pub fn set_bounds(&mut self, bbox: &BoundingBox) {
+        self.bounds = [bbox.west(), bbox.south(), bbox.east(), bbox.north()];
+    }
+
+    /// Sets the JSON metadata of the archive
+    pub fn set_metadata(&mut self, metadata: serde_json::Value) {
+        self.metadata = metadata;
+    }
+
+    /// Adds a tile. Rows count from the top (XYZ); a tile added twice
+    /// replaces the first one.
+    pub fn add_tile(
+        &mut self,
+        zoom_level: u8,
+        x: u32,
+        y: u32,
+        data: &[u8],
+    ) -> Result<(), TilingError> {
+        if zoom_level > 31 || x >> zoom_level != 0 || y >> zoom_level != 0 {
+            return Err(TilingError::general(format!(
+                "Tile {}/{}/{} lies outside the tile grid",
+                zoom_level, x, y
+            )));
+        }
+        let content = self
+            .store(data)
+            .map_err(TilingError::not_writable(&self.data_path))?;
+        self.tiles.insert(tile_id(zoom_level, x, y), content);
+        Ok(())
+    }
+
+    /// Adds a tile whose row counts from the bottom (TMS).
+    pub fn add_tms_tile(
+        &mut self,
+        zoom_level: u8,
+        x: u32,
+        row: u32,
+        data: &[u8],
+    ) -> Result<(), TilingError> {
+        let rows = 1u32.checked_shl(zoom_level as u32).unwrap_or(0);
+        match rows.checked_sub(row + 1) {
+            Some(y) => self.add_tile(zoom_level, x, y, data),
+            None => Err(TilingError::general(format!(
+                "TMS tile {}/{}/{} lies outside the tile grid",
+                zoom_level, x, row
+            ))),
+        }
+    }
+
+    /// Returns the offset and length of the stored content, storing it first
+    /// if it is new. Contents with the same hash are compared byte by byte.
+    fn store(&mut self, data: &[u8]) -> io::Result<(u64, u32)> {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let candidates = self.contents.entry(hash).or_default();
+        for &(offset, length) in candidates.iter() {
+            if length as usize == data.len() {
+                let mut stored = vec![0; data.len()];
+                self.data.seek(SeekFrom::Start(offset))?;
+                self.data.read_exact(&mut stored)?;
+                if stored == data {
+                    return Ok((offset, length));
+                }
+            }
+        }
+
+        let content = (self.data_length, data.len() as u32);
+        self.data.seek(SeekFrom::Start(self.data_length))?;
+        self.data.write_all(data)?;
+        self.data_length += data.len() as u64;
+        candidates.push(content);
+        Ok(content)
+    }
+
+    /// Writes the archive and removes the temporary tile data.
+    pub fn finish(mut self) -> Result<(), TilingError> {
+        let result = self.write_archive();
+        if let Err(e) = fs::remove_file(&self.data_path) {
+            error!("Could not delete {}: {}", self.data_path.display(), e);
+        }
+        result.map_err(TilingError::not_writable(&self.path))
+    }
+
+    fn write_archive(&mut self) -> io::Result<()> {
+        // Lay the contents out in the order of their first tile, so that the
+        // tile data is clustered
+        let mut order: Vec<(u64, u32)> = Vec::new();
+        let mut new_offsets: HashMap<u64, u64> = HashMap::new();
+        let mut tile_data_length = 0;
+        let mut entries: Vec<Entry> = Vec::new();
+        for (&id, &(offset, length)) in &self.tiles {
+            let new_offset = *new_offsets.entry(offset).or_insert_with(|| {
+                order.push((offset, length));
+                tile_data_length += length as u64;
+                tile_data_length - length as u64
+            });
+            match entries.last_mut() {
+                Some(last)
+                    if last.offset == new_offset && last.tile_id + last.run_length as u64 == id =>
+                {
+                    last.run_length += 1
+                }
+                _ => entries.push(Entry {
+                    tile_id: id,
+                    offset: new_offset,
+                    length,
+                    run_length: 1,
+                }),
+            }
+        }
+
+        let (root, leaves) = build_directories(&entries)?;
+        let metadata = Compression::Gzip.compress(self.metadata.to_string().as_bytes())?;
+
+        let (min_zoom, max_zoom) = match (self.tiles.keys().next(), self.tiles.keys().last()) {
+            (Some(&first), Some(&last)) => (tile_position(first).0, tile_position(last).0),
+            _ => (0, 0),
+        };
+        let e7 = |degrees: f64| (degrees * 10_000_000.0).round() as i32;
+        let [west, south, east, north] = self.bounds;
+        let root_offset = HEADER_LENGTH as u64;
+        let metadata_offset = root_offset + root.len() as u64;
+        let leaf_directories_offset = metadata_offset + metadata.len() as u64;
+        let tile_data_offset = leaf_directories_offset + leaves.len() as u64;
+        let header = Header {
+            root_offset,
+            root_length: root.len() as u64,
+            metadata_offset,
+            metadata_length: metadata.len() as u64,
+            leaf_directories_offset,
+            leaf_directories_length: leaves.len() as u64,
+            tile_data_offset,
+            tile_data_length,
+            addressed_tiles: self.tiles.len() as u64,
+            tile_entries: entries.len() as u64,
+            tile_contents: order.len() as u64,
+            clustered: true,
+            internal_compression: Compression::Gzip,
+            tile_compression: Compression::None,
+            tile_type: self.tile_type,
+            min_zoom,
+            max_zoom,
+            min_lon_e7: e7(west),
+            min_lat_e7: e7(south),
+            max_lon_e7: e7(east),
+            max_lat_e7: e7(north),
+            center_zoom: min_zoom,
+            center_lon_e7: e7((west + east) / 2.0),
+            center_lat_e7: e7((south + north) / 2.0),
+        };
+
+        let mut archive = BufWriter::new(File::create(&self.path)?);
+        archive.write_all(&header.to_bytes())?;
+        archive.write_all(&root)?;
+        archive.write_all(&metadata)?;
+        archive.write_all(&leaves)?;
+        let mut content = Vec::new();
+        for (offset, length) in order {
+            content.resize(length as usize, 0);
+            self.data.seek(SeekFrom::Start(offset))?;
+            self.data.read_exact(&mut content)?;
+            archive.write_all(&content)?;
+        }
+        archive.flush()
+    }
+}
+
+/// Serializes the root directory and, if the root directory would not fit
+/// into the first 16 KiB with all entries, the leaf directories it points
+/// at. Both are gzip-compressed.
+fn build_directories(entries: &[Entry]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let root = Compression::Gzip.compress(&serialize_directory(entries))?;
+    if HEADER_LENGTH + root.len() <= ROOT_LIMIT {
+        return Ok((root, Vec::new()));
+    }
+
+    let mut leaf_size = LEAF_SIZE;
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = Compression::Gzip.compress(&serialize_directory(chunk))?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend_from_slice(&leaf);
+        }
+        let root = Compression::Gzip.compress(&serialize_directory(&root_entries))?;
+        if HEADER_LENGTH + root.len() <= ROOT_LIMIT {
+            return Ok((root, leaves));
+        }
+        leaf_size += leaf_size / 5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pmtiles::PMTilesReader;
+
+    /// A tile content whose length varies from tile to tile, so that the
+    /// directories do not compress too well
+    fn content(x: u32, y: u32) -> Vec<u8> {
+        let length = (x * 7919 + y * 104729) % 13 + 1;
+        (0..length).map(|i| (x ^ y ^ i) as u8).collect()
+    }
+
+    #[test]
+    fn tiles_round_trip_through_the_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.pmtiles");
+        let mut writer = PMTilesWriter::create(&path, TileType::PNG).unwrap();
+        writer.set_metadata(serde_json::json!({ "name": "test" }));
+        writer.add_tile(0, 0, 0, b"root").unwrap();
+        // TMS row 0 is the bottom row, i.e. XYZ row 1
+        writer.add_tms_tile(1, 0, 0, b"blank").unwrap();
+        writer.add_tile(1, 1, 0, b"blank").unwrap();
+        writer.add_tile(1, 0, 0, b"top").unwrap();
+        writer.add_tile(1, 1, 1, b"blank").unwrap();
+        assert!(writer.add_tile(1, 2, 0, b"outside").is_err());
+        assert!(writer.add_tms_tile(1, 0, 2, b"outside").is_err());
+        writer.finish().unwrap();
+        assert!(!dir.path().join("tiles.pmtiles.tmp").exists());
+
+        let mut reader = PMTilesReader::open(&path).unwrap();
+        let header = reader.header().clone();
+        assert_eq!(header.tile_type, TileType::PNG);
+        assert_eq!((header.min_zoom, header.max_zoom), (0, 1));
+        assert_eq!(header.addressed_tiles, 5);
+        assert_eq!(header.tile_contents, 3);
+        assert_eq!(reader.metadata().unwrap()["name"], "test");
+        assert_eq!(reader.tile(0, 0, 0).unwrap().unwrap(), b"root");
+        assert_eq!(reader.tile(1, 0, 0).unwrap().unwrap(), b"top");
+        assert_eq!(reader.tile(1, 0, 1).unwrap().unwrap(), b"blank");
+        assert_eq!(reader.tile(1, 1, 0).unwrap().unwrap(), b"blank");
+        assert_eq!(reader.tile(1, 1, 1).unwrap().unwrap(), b"blank");
+        assert_eq!(reader.tile(2, 0, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn large_archives_use_leaf_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.pmtiles");
+        let mut writer = PMTilesWriter::create(&path, TileType::JPEG).unwrap();
+        for x in 0..256 {
+            for y in 0..256 {
+                writer.add_tile(8, x, y, &content(x, y)).unwrap();
+            }
+        }
+        writer.finish().unwrap();
+
+        let mut reader = PMTilesReader::open(&path).unwrap();
+        assert!(reader.header().leaf_directories_length > 0);
+        assert!(HEADER_LENGTH as u64 + reader.header().root_length <= ROOT_LIMIT as u64);
+        for (x, y) in [(0, 0), (255, 255), (17, 200), (128, 3)] {
+            assert_eq!(reader.tile(8, x, y).unwrap().unwrap(), content(x, y));
+        }
+        assert_eq!(reader.tile(7, 0, 0).unwrap(), None);
+    }
+}
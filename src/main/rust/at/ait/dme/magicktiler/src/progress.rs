@@ -0,0 +1,152 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::magick_tiler::TilingError;
+
+/// A snapshot of the progress of a tiling run, as passed to a
+/// `ProgressListener`.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Number of tiles written so far
+    tiles_done: i32,
+
+    /// Total number of tiles in the tileset
+    total_tiles: i32,
+
+    /// The zoom level of the last finished tiles, in the numbering of the
+    /// tiling scheme
+    level: i32,
+
+    /// The stripe (or row) of the last finished tiles within their level
+    stripe: i32,
+
+    /// Time since the tiling run started
+    elapsed: Duration,
+}
+
+impl Progress {
+    pub fn tiles_done(&self) -> i32 {
+        self.tiles_done
+    }
+
+    pub fn total_tiles(&self) -> i32 {
+        self.total_tiles
+    }
+
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    pub fn stripe(&self) -> i32 {
+        self.stripe
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The finished part of the run, from 0.0 to 1.0
+    pub fn fraction(&self) -> f64 {
+        if self.total_tiles <= 0 {
+            return 0.0;
+        }
+        (self.tiles_done as f64 / self.total_tiles as f64).min(1.0)
+    }
+
+    /// Estimated time until the run is finished, extrapolated from the time
+    /// taken so far. `None` until the first tiles are done.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.tiles_done <= 0 {
+            return None;
+        }
+        let remaining = (self.total_tiles - self.tiles_done).max(0);
+        Some(
+            self.elapsed
+                .mul_f64(remaining as f64 / self.tiles_done as f64),
+        )
+    }
+}
+
+/// Receives progress updates from a tiler. Tilers may run on several worker
+/// threads, so updates can arrive from any thread.
+pub trait ProgressListener: Send + Sync {
+    fn progress(&self, progress: &Progress);
+}
+
+/// A handle for stopping a tiling run from another thread. Clones share the
+/// same state, so one clone can be handed to the tiler while another one is
+/// kept to cancel it.
+///
+/// A cancelled run stops before its next tiling step, removes its temporary
+/// stripes and fails with `TilingError::Cancelled`. Tiles written before the
+/// cancellation are left in place.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the tiling run to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Clears a previous cancellation, so the token can be used for another
+    /// run.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns `TilingError::Cancelled` if the run was cancelled.
+    pub fn check(&self) -> Result<(), TilingError> {
+        if self.is_cancelled() {
+            Err(TilingError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Counts the tiles written during one tiling run and reports them to the
+/// configured listener. Created by `BaseMagickTiler::progress_tracker`.
+pub struct ProgressTracker {
+    listener: Option<Arc<dyn ProgressListener>>,
+    total_tiles: i32,
+    tiles_done: AtomicI32,
+    start_time: Instant,
+}
+
+impl ProgressTracker {
+    pub fn new(listener: Option<Arc<dyn ProgressListener>>, total_tiles: i32) -> Self {
+        Self {
+            listener,
+            total_tiles,
+            tiles_done: AtomicI32::new(0),
+            start_time: Instant::now(),
+        }
+    }
+
+    /// Records that `tiles` more tiles of the given level and stripe have
+    /// been written.
+    pub fn tiles_done(&self, tiles: i32, level: i32, stripe: i32) {
+        let tiles_done = self.tiles_done.fetch_add(tiles, Ordering::SeqCst) + tiles;
+        if let Some(listener) = &self.listener {
+            listener.progress(&Progress {
+                tiles_done,
+                total_tiles: self.total_tiles,
+                level,
+                stripe,
+                elapsed: self.start_time.elapsed(),
+            });
+        }
+    }
+}
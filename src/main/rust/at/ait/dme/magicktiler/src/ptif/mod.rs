@@ -0,0 +1,3 @@
+mod ptif_converter;
+
+pub use ptif_converter::PTIFConverter;
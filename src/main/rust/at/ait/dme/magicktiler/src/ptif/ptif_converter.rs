@@ -0,0 +1,223 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info};
+
+use crate::image::TiffCompression;
+use crate::magick_tiler::{BaseMagickTiler, MagickTiler, TilingError};
+use crate::progress::ProgressTracker;
+use crate::stripe::half_size;
+use crate::tile_set_info::TileSetInfo;
+
+/// A converter that produces a Pyramid TIFF (PTIF), the multi-resolution
+/// image format served by IIPImage.
+///
+/// A PTIF is a single tiled TIFF file holding the image at several
+/// resolutions: the first image file directory holds the full-resolution
+/// image, each following one half the resolution of the previous, down to the
+/// first level that fits into a single tile.
+pub struct PTIFConverter {
+    base: BaseMagickTiler,
+
+    /// Compression applied to the TIFF tiles
+    compression: TiffCompression,
+}
+
+impl PTIFConverter {
+    pub fn new() -> Self {
+        Self {
+            base: BaseMagickTiler::new(),
+            compression: TiffCompression::JPEG,
+        }
+    }
+
+    pub fn compression(&self) -> TiffCompression {
+        self.compression
+    }
+
+    /// Sets the compression applied to the TIFF tiles (default: JPEG).
+    pub fn set_compression(&mut self, compression: TiffCompression) {
+        self.compression = compression;
+    }
+
+    /// Sets the TIFF tile size. TIFF requires a multiple of 16 (default: 256).
+    pub fn set_tile_size(&mut self, size: i32) {
+        self.base.set_tile_size(size);
+    }
+
+    /// Computes the reduced-resolution levels of the pyramid in the working
+    /// directory and appends them to `pyramid`, which starts with the original
+    /// image.
+    fn compute_pyramid(
+        &self,
+        info: &TileSetInfo,
+        pyramid: &mut Vec<PathBuf>,
+        progress: &ProgressTracker,
+    ) -> Result<(), TilingError> {
+        let name = info
+            .image_file()
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+
+        let mut w = info.image_width();
+        let mut h = info.image_height();
+        for i in 1..info.zoom_levels() {
+            self.base.check_cancelled()?;
+            w = half_size(w);
+            h = half_size(h);
+            let level = self
+                .base
+                .working_directory()
+                .join(format!("{}-{}.tif", name, i));
+
+            self.base
+                .processor()
+                .scale(pyramid.last().unwrap(), &level, w, h)?;
+            pyramid.push(level);
+            progress.tiles_done(info.number_of_x_tiles(i) * info.number_of_y_tiles(i), i, 0);
+        }
+
+        Ok(())
+    }
+
+    /// Computes the pyramid levels and combines them into `temp_file`.
+    /// `levels` collects the level images, so the temporary ones can be
+    /// removed even if the conversion fails.
+    fn generate_ptif(
+        &self,
+        info: &TileSetInfo,
+        levels: &mut Vec<PathBuf>,
+        temp_file: &Path,
+    ) -> Result<(), TilingError> {
+        let progress = self.base.progress_tracker(info.total_number_of_tiles());
+
+        // Step 1 - compute pyramid
+        debug!("Computing pyramid");
+        self.compute_pyramid(info, levels, &progress)?;
+
+        // Step 2 - merge
+        self.base.check_cancelled()?;
+        debug!("Merging");
+        let level_refs: Vec<&Path> = levels.iter().map(|l| l.as_path()).collect();
+        self.base.processor().pyramid_tiff(
+            &level_refs,
+            temp_file,
+            self.base.tile_width(),
+            self.compression,
+        )?;
+        progress.tiles_done(info.number_of_x_tiles(0) * info.number_of_y_tiles(0), 0, 0);
+        Ok(())
+    }
+}
+
+impl Default for PTIFConverter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the target file name, adding a .tif extension unless the target
+/// already ends with .tif or .ptif.
+fn target_file(target: &Path) -> PathBuf {
+    let name = target.to_string_lossy();
+    if name.ends_with(".tif") || name.ends_with(".ptif") {
+        target.to_path_buf()
+    } else {
+        PathBuf::from(format!("{}.tif", name))
+    }
+}
+
+impl MagickTiler for PTIFConverter {
+    fn base(&self) -> &BaseMagickTiler {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut BaseMagickTiler {
+        &mut self.base
+    }
+
+    fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError> {
+        let target = match self.base.tileset_root_dir() {
+            Some(target) => target.to_path_buf(),
+            None => image.with_extension("ptif"),
+        };
+        self.convert_to(image, &target)
+    }
+
+    fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
+        let target = target_file(target);
+        if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(TilingError::not_writable(parent))?;
+        }
+        let working_directory = self.base.working_directory();
+        if !working_directory.exists() {
+            fs::create_dir_all(working_directory)
+                .map_err(TilingError::not_writable(working_directory))?;
+        }
+        self.base.set_tileset_root_dir(&target);
+
+        let info = TileSetInfo::new(
+            image,
+            self.base.tile_width(),
+            self.base.tile_height(),
+            self.base.processor(),
+        )?;
+        self.convert_internal(image, info)
+    }
+
+    fn convert_internal(
+        &mut self,
+        image: &Path,
+        info: TileSetInfo,
+    ) -> Result<TileSetInfo, TilingError> {
+        let start_time = std::time::Instant::now();
+        info!(
+            "Generating PTIF for file {}: {}x{}, {} zoom levels, {:?} compression",
+            image.file_name().unwrap().to_string_lossy(),
+            info.image_width(),
+            info.image_height(),
+            info.zoom_levels(),
+            self.compression
+        );
+
+        let target = match self.base.tileset_root_dir() {
+            Some(target) => target_file(target),
+            None => image.with_extension("ptif"),
+        };
+
+        let temp_file = self.base.working_directory().join(format!(
+            "{}-ptif.tmp.tif",
+            image.file_stem().unwrap().to_string_lossy()
+        ));
+        let mut levels = vec![image.to_path_buf()];
+        let result = self.generate_ptif(&info, &mut levels, &temp_file);
+
+        // Step 3 - remove temporary files
+        for level in &levels[1..] {
+            if let Err(e) = fs::remove_file(level) {
+                error!("Failed to delete temp file {}: {}", level.display(), e);
+            }
+        }
+        if let Err(e) = result {
+            if temp_file.exists() {
+                fs::remove_file(&temp_file).map_err(TilingError::io(&temp_file))?;
+            }
+            return Err(e);
+        }
+
+        // Step 4 - rename
+        if target.exists() {
+            fs::remove_file(&target).map_err(TilingError::not_writable(&target))?;
+        }
+        if fs::rename(&temp_file, &target).is_err() {
+            // The working directory may be on a different file system
+            fs::copy(&temp_file, &target).map_err(TilingError::not_writable(&target))?;
+            fs::remove_file(&temp_file).map_err(TilingError::io(&temp_file))?;
+        }
+
+        info!("Took {} ms", start_time.elapsed().as_millis());
+        Ok(info)
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use log::error;
+
+use crate::magick_tiler::TilingError;
+use crate::progress::CancellationToken;
+use crate::stripe::Stripe;
+
+/// A unit of work in the stripe pyramid, addressed by (level, stripe index).
+/// Level 0 holds the base stripes.
+#[derive(Debug, Clone, Copy)]
+enum Task {
+    /// Cut a stripe into tiles
+    Tile(usize, usize),
+    /// Create a stripe from its two parent stripes on the level beneath
+    Merge(usize, usize),
+}
+
+enum Slot {
+    /// Not computed yet
+    Pending,
+    /// The merge did not produce a stripe, or the stripe file was deleted
+    Absent,
+    /// The stripe exists; `users` counts the tasks that still need its file
+    Present { stripe: Stripe, users: usize },
+}
+
+struct State {
+    slots: Vec<Vec<Slot>>,
+    queue: VecDeque<Task>,
+    running: usize,
+    error: Option<TilingError>,
+}
+
+impl State {
+    fn users(&self, level: usize) -> usize {
+        if level + 1 < self.slots.len() {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn is_resolved(&self, level: usize, idx: usize) -> bool {
+        !matches!(self.slots[level][idx], Slot::Pending)
+    }
+
+    /// Schedules the merge for the stripe above (level, idx) once both of
+    /// its parents are resolved.
+    fn resolved(&mut self, level: usize, idx: usize) {
+        let next = level + 1;
+        if next >= self.slots.len() {
+            return;
+        }
+
+        let target = idx / 2;
+        let first = target * 2;
+        let second = first + 1;
+        if !self.is_resolved(level, first)
+            || (second < self.slots[level].len() && !self.is_resolved(level, second))
+        {
+            return;
+        }
+
+        if matches!(self.slots[level][first], Slot::Absent) {
+            self.slots[next][target] = Slot::Absent;
+            self.release(level, second);
+            self.resolved(next, target);
+        } else {
+            self.queue.push_back(Task::Merge(next, target));
+        }
+    }
+
+    /// Called when a task no longer needs the stripe at (level, idx). Deletes
+    /// the stripe file after its last user is done.
+    fn release(&mut self, level: usize, idx: usize) {
+        let Some(Slot::Present { users, .. }) = self
+            .slots
+            .get_mut(level)
+            .and_then(|slots| slots.get_mut(idx))
+        else {
+            return;
+        };
+
+        *users -= 1;
+        if *users == 0 {
+            if let Slot::Present { stripe, .. } =
+                std::mem::replace(&mut self.slots[level][idx], Slot::Absent)
+            {
+                if let Err(e) = stripe.delete() {
+                    self.error.get_or_insert(e);
+                }
+            }
+        }
+    }
+
+    fn complete(&mut self, task: Task, result: Result<Option<Stripe>, TilingError>) {
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                self.error.get_or_insert(e);
+                return;
+            }
+        };
+
+        match task {
+            Task::Tile(level, idx) => self.release(level, idx),
+            Task::Merge(level, idx) => {
+                match result {
+                    Some(stripe) => {
+                        let users = self.users(level);
+                        self.slots[level][idx] = Slot::Present { stripe, users };
+                        self.queue.push_back(Task::Tile(level, idx));
+                    }
+                    None => self.slots[level][idx] = Slot::Absent,
+                }
+                self.release(level - 1, idx * 2);
+                self.release(level - 1, idx * 2 + 1);
+                self.resolved(level, idx);
+            }
+        }
+    }
+}
+
+struct StripePyramid<'a, M, T> {
+    state: Mutex<State>,
+    work_available: Condvar,
+    cancellation: &'a CancellationToken,
+    merge: M,
+    tile: T,
+}
+
+impl<M, T> StripePyramid<'_, M, T>
+where
+    M: Fn(i32, usize, &Stripe, Option<&Stripe>) -> Result<Option<Stripe>, TilingError> + Sync,
+    T: Fn(i32, usize, &Stripe) -> Result<(), TilingError> + Sync,
+{
+    fn next_task(&self) -> Option<(Task, Stripe, Option<Stripe>)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.error.is_some() {
+                return None;
+            }
+            if !state.queue.is_empty() && self.cancellation.is_cancelled() {
+                state.error = Some(TilingError::Cancelled);
+                return None;
+            }
+
+            if let Some(task) = state.queue.pop_front() {
+                state.running += 1;
+                let stripe = |level: usize, idx: usize| match state.slots[level].get(idx) {
+                    Some(Slot::Present { stripe, .. }) => Some(stripe.clone()),
+                    _ => None,
+                };
+                let (first, second) = match task {
+                    Task::Tile(level, idx) => (stripe(level, idx), None),
+                    Task::Merge(level, idx) => {
+                        (stripe(level - 1, idx * 2), stripe(level - 1, idx * 2 + 1))
+                    }
+                };
+                return Some((
+                    task,
+                    first.expect("stripe scheduled before it exists"),
+                    second,
+                ));
+            }
+
+            if state.running == 0 {
+                return None;
+            }
+            state = self.work_available.wait(state).unwrap();
+        }
+    }
+
+    fn run_worker(&self) {
+        while let Some((task, first, second)) = self.next_task() {
+            let result = match task {
+                Task::Tile(level, idx) => (self.tile)(level as i32, idx, &first).map(|_| None),
+                Task::Merge(level, idx) => (self.merge)(level as i32, idx, &first, second.as_ref()),
+            };
+
+            let mut state = self.state.lock().unwrap();
+            state.running -= 1;
+            state.complete(task, result);
+            self.work_available.notify_all();
+        }
+    }
+}
+
+/// Computes a tile pyramid from a list of base stripes. Every stripe is cut
+/// into tiles by `tile`, and each pair of neighbouring stripes is combined into
+/// a stripe of the next level by `merge` (which receives `None` as second
+/// stripe if the level has an odd number of stripes and may return `None` to
+/// skip a stripe). Levels 1 to `levels - 1` are computed this way.
+///
+/// Independent tasks run on up to `worker_threads` threads: all base stripes
+/// are tiled in parallel, and a merge starts as soon as both of its parent
+/// stripes exist. Stripe files are deleted once they are no longer needed.
+/// Since every task writes its own set of files, the result does not depend on
+/// the number of threads.
+///
+/// No new task is started after `cancellation` is cancelled; the remaining
+/// stripes are deleted and `TilingError::Cancelled` is returned.
+pub fn build_stripe_pyramid<M, T>(
+    worker_threads: usize,
+    cancellation: &CancellationToken,
+    base_stripes: Vec<Stripe>,
+    levels: i32,
+    merge: M,
+    tile: T,
+) -> Result<(), TilingError>
+where
+    M: Fn(i32, usize, &Stripe, Option<&Stripe>) -> Result<Option<Stripe>, TilingError> + Sync,
+    T: Fn(i32, usize, &Stripe) -> Result<(), TilingError> + Sync,
+{
+    let levels = levels.max(1) as usize;
+    let mut slots = Vec::with_capacity(levels);
+    let mut count = base_stripes.len();
+    slots.push(Vec::new());
+    for _ in 1..levels {
+        count = count.div_ceil(2);
+        slots.push((0..count).map(|_| Slot::Pending).collect());
+    }
+
+    let mut state = State {
+        slots,
+        queue: VecDeque::new(),
+        running: 0,
+        error: None,
+    };
+    let users = state.users(0);
+    for (idx, stripe) in base_stripes.into_iter().enumerate() {
+        state.slots[0].push(Slot::Present { stripe, users });
+        state.queue.push_back(Task::Tile(0, idx));
+    }
+    for idx in (0..state.slots[0].len()).step_by(2) {
+        state.resolved(0, idx);
+    }
+
+    let pyramid = StripePyramid {
+        state: Mutex::new(state),
+        work_available: Condvar::new(),
+        cancellation,
+        merge,
+        tile,
+    };
+
+    if worker_threads <= 1 {
+        pyramid.run_worker();
+    } else {
+        thread::scope(|scope| {
+            for _ in 0..worker_threads {
+                scope.spawn(|| pyramid.run_worker());
+            }
+        });
+    }
+
+    let mut state = pyramid.state.into_inner().unwrap();
+    match state.error.take() {
+        None => Ok(()),
+        Some(e) => {
+            // Remove the stripes left behind by the failed or cancelled run
+            for slot in state.slots.iter_mut().flatten() {
+                if let Slot::Present { stripe, .. } = std::mem::replace(slot, Slot::Absent) {
+                    if let Err(e) = stripe.delete() {
+                        error!(
+                            "Could not delete stripe {}: {}",
+                            stripe.image_file().display(),
+                            e
+                        );
+                    }
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
+
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+    use crate::tms::TMSTiler;
+    use crate::zoomify::ZoomifyTiler;
+    use crate::MagickTiler;
+
+    /// Reads all files below `dir`, keyed by their relative path.
+    fn read_tree(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in fs::read_dir(&current).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    let name = path
+                        .strip_prefix(dir)
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned();
+                    files.insert(name, fs::read(&path).unwrap());
+                }
+            }
+        }
+        files
+    }
+
+    /// Tiles the same image with 1 and with 4 worker threads and checks that
+    /// both tilesets are identical.
+    fn check_worker_threads<T: MagickTiler>(create: impl Fn() -> T) {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.png");
+        image::RgbImage::from_fn(500, 300, |x, y| {
+            image::Rgb([x as u8, y as u8, (x * y % 251) as u8])
+        })
+        .save(&image)
+        .unwrap();
+
+        let mut tilesets = Vec::new();
+        for worker_threads in [1, 4] {
+            let work = dir.path().join(format!("work-{}", worker_threads));
+            fs::create_dir(&work).unwrap();
+            let mut tiler = create();
+            tiler
+                .base_mut()
+                .set_image_processing_system(ImageProcessingSystem::Native);
+            tiler.base_mut().set_tile_format(ImageFormat::JPEG);
+            tiler.base_mut().set_tile_size(64);
+            tiler.base_mut().set_working_directory(&work);
+            tiler.base_mut().set_worker_threads(worker_threads);
+            tiler.set_generate_preview_html(false);
+            let target = dir.path().join(format!("tiles-{}", worker_threads));
+            tiler.convert_to(&image, &target).unwrap();
+
+            // All stripes have been cleaned up
+            assert_eq!(fs::read_dir(&work).unwrap().count(), 0);
+            tilesets.push(read_tree(&target));
+        }
+
+        assert!(tilesets[0].len() > 30);
+        assert_eq!(
+            tilesets[0].keys().collect::<Vec<_>>(),
+            tilesets[1].keys().collect::<Vec<_>>()
+        );
+        for (name, data) in &tilesets[0] {
+            assert!(&tilesets[1][name] == data, "{} differs", name);
+        }
+    }
+
+    #[test]
+    fn tms_output_does_not_depend_on_worker_threads() {
+        check_worker_threads(TMSTiler::new);
+    }
+
+    #[test]
+    fn zoomify_output_does_not_depend_on_worker_threads() {
+        check_worker_threads(ZoomifyTiler::new);
+    }
+}
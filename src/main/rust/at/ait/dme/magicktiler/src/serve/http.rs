@@ -0,0 +1,313 @@
+use std::io::{self, BufRead, Read, Write};
+
+/// The longest request line or header line that is accepted
+const MAX_LINE_LENGTH: u64 = 8192;
+
+/// A request received by the `TileServer`
+pub(crate) struct Request {
+    pub method: String,
+
+    /// The percent-decoded path, without query string
+    pub path: String,
+
+    /// Whether the connection stays open after the response
+    pub keep_alive: bool,
+}
+
+impl Request {
+    /// Reads the next request from a connection. Returns `None` if the client
+    /// closed the connection before sending one. Request bodies are skipped.
+    pub fn read<R: BufRead>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut line = String::new();
+        if read_line(reader, &mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target), Some(version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("Invalid request line"));
+        };
+        let method = method.to_string();
+        let target = target.split(['?', '#']).next().unwrap_or_default();
+        let path = decode_path(target).ok_or_else(|| invalid("Invalid request path"))?;
+        let mut keep_alive = version == "HTTP/1.1";
+
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            if read_line(reader, &mut line)? == 0 {
+                return Err(invalid("Incomplete request"));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let Some((name, value)) = header.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("Connection") {
+                keep_alive = match value.to_ascii_lowercase().as_str() {
+                    "close" => false,
+                    "keep-alive" => true,
+                    _ => keep_alive,
+                };
+            } else if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value
+                    .parse()
+                    .map_err(|_| invalid("Invalid Content-Length"))?;
+            }
+        }
+        io::copy(&mut reader.take(content_length), &mut io::sink())?;
+
+        Ok(Some(Self {
+            method,
+            path,
+            keep_alive,
+        }))
+    }
+}
+
+/// A response of the `TileServer`
+pub(crate) struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+
+    /// A plain text response with the status as body
+    pub fn status(status: u16) -> Self {
+        let body = format!("{} {}\n", status, reason(status));
+        Self::new(status, "text/plain; charset=utf-8", body.into_bytes())
+    }
+
+    pub fn redirect(location: &str) -> Self {
+        Self::status(302).with_header("Location", location)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Writes the response, without the body if `head_only` is set.
+    pub fn write<W: Write>(
+        &self,
+        writer: &mut W,
+        head_only: bool,
+        keep_alive: bool,
+    ) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: {}\r\n\r\n",
+            self.body.len(),
+            if keep_alive { "keep-alive" } else { "close" }
+        ));
+        writer.write_all(head.as_bytes())?;
+        if !head_only {
+            writer.write_all(&self.body)?;
+        }
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        302 => "Found",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+/// Reads a line of at most `MAX_LINE_LENGTH` bytes
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+    let read = reader.take(MAX_LINE_LENGTH).read_line(line)?;
+    if read as u64 == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Err(invalid("Request line too long"));
+    }
+    Ok(read)
+}
+
+/// Decodes the percent-encoded octets of a request path
+fn decode_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = path.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Percent-encodes a path segment for use in a URL
+pub(crate) fn encode_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Escapes text for use in HTML
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(raw: &str) -> io::Result<Option<Request>> {
+        Request::read(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn requests_are_parsed() {
+        let request = read("GET /tiles/0/0/0.png?v=2#top HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/tiles/0/0/0.png");
+        assert!(request.keep_alive);
+
+        let request = read("HEAD / HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.method, "HEAD");
+        assert!(!request.keep_alive);
+
+        let request = read("GET / HTTP/1.0\r\nconnection: Keep-Alive\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert!(request.keep_alive);
+        let request = read("GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert!(!request.keep_alive);
+    }
+
+    #[test]
+    fn bodies_are_skipped() {
+        let mut raw =
+            "POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\n\r\n".as_bytes();
+        assert_eq!(Request::read(&mut raw).unwrap().unwrap().path, "/a");
+        assert_eq!(Request::read(&mut raw).unwrap().unwrap().path, "/b");
+        assert!(Request::read(&mut raw).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        for raw in [
+            "GET /\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: localhost\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: five\r\n\r\n",
+            "GET /%zz HTTP/1.1\r\n\r\n",
+            "GET /%4 HTTP/1.1\r\n\r\n",
+            "GET /%+1 HTTP/1.1\r\n\r\n",
+            "GET /%ff HTTP/1.1\r\n\r\n",
+        ] {
+            let error = read(raw).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", raw);
+        }
+
+        let long = format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            "a".repeat(MAX_LINE_LENGTH as usize)
+        );
+        assert_eq!(
+            read(&long).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(read("").unwrap().is_none());
+    }
+
+    #[test]
+    fn paths_are_percent_decoded() {
+        assert_eq!(decode_path("/a%20b/%C3%A4.png").unwrap(), "/a b/ä.png");
+        assert_eq!(decode_path("/%2e%2E%2Fa").unwrap(), "/../a");
+        assert_eq!(decode_path("/100%25").unwrap(), "/100%");
+        assert_eq!(encode_segment("a b/ä~"), "a%20b%2F%C3%A4~");
+        assert_eq!(
+            decode_path(&format!("/{}", encode_segment("a b/ä~"))).unwrap(),
+            "/a b/ä~"
+        );
+    }
+
+    #[test]
+    fn responses_are_written() {
+        let response = Response::new(200, "image/png", b"tile".to_vec())
+            .with_header("Access-Control-Allow-Origin", "*");
+        let mut out = Vec::new();
+        response.write(&mut out, false, true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: image/png\r\n\
+             Access-Control-Allow-Origin: *\r\n\
+             Content-Length: 4\r\n\
+             Connection: keep-alive\r\n\
+             \r\n\
+             tile"
+        );
+
+        // HEAD responses announce the length of the body they omit
+        let mut out = Vec::new();
+        Response::redirect("/tiles/preview.html")
+            .write(&mut out, true, false)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 302 Found\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Location: /tiles/preview.html\r\n\
+             Content-Length: 10\r\n\
+             Connection: close\r\n\
+             \r\n"
+        );
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(
+            escape_html("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,6 @@
+mod http;
+mod on_demand_tiles;
+mod tile_server;
+
+pub use on_demand_tiles::OnDemandTiles;
+pub use tile_server::TileServer;
@@ -0,0 +1,347 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{debug, error};
+
+use crate::image::ImageProcessorImpl;
+use crate::magick_tiler::{MagickTiler, TilingError};
+use crate::sink::{FileSystemSink, TileKey, TileSink};
+use crate::tile_set_info::TileSetInfo;
+use crate::tiling_scheme::TilingScheme;
+use crate::tms::tile_rows;
+use crate::zoomify::{level_start_indices, MAX_TILES_PER_GROUP};
+
+/// A TMS or Zoomify tileset whose tiles are cut from the source image when
+/// they are first requested, instead of tiling the whole image up front.
+///
+/// Only the descriptor files are written when the tileset is created. Every
+/// tile is then computed with the image processor of the tiler: the region
+/// it covers is cropped from the source image, scaled down to its zoom level
+/// and, for TMS, placed on a canvas of the full tile size with the
+/// background color, like the padding of a regular TMS tileset; tiles of
+/// the background buffer above the image are filled with the background
+/// color. The tiles are written into the tileset directory, so each is cut
+/// only once; tiles that exist already are kept.
+pub struct OnDemandTiles {
+    tiler: Box<dyn MagickTiler>,
+    scheme: TilingScheme,
+    image: PathBuf,
+
+    /// The file name of the image without extension, naming the temporary
+    /// files of the tiles
+    base_name: String,
+
+    info: TileSetInfo,
+    sink: FileSystemSink,
+
+    /// The index of the first tile of each level, for Zoomify tile groups
+    level_start_idx: Vec<i32>,
+
+    /// Numbers the temporary files of tiles cut concurrently
+    counter: AtomicUsize,
+}
+
+impl OnDemandTiles {
+    /// Writes the descriptor files of the tileset for `image` to `target`
+    /// with a configured tiler of the given scheme. The tiles are always
+    /// written to `target`, even if the tiler has a tile sink.
+    ///
+    /// # Errors
+    /// Returns an error if the scheme cannot be tiled on demand or `image`
+    /// does not name a file.
+    pub fn new(
+        mut tiler: Box<dyn MagickTiler>,
+        scheme: TilingScheme,
+        image: &Path,
+        target: &Path,
+    ) -> Result<Self, TilingError> {
+        if !matches!(scheme, TilingScheme::Tms | TilingScheme::Zoomify) {
+            return Err(TilingError::general(format!(
+                "{} tiles cannot be cut on demand",
+                scheme
+            )));
+        }
+        let base_name = image
+            .file_stem()
+            .ok_or_else(|| {
+                TilingError::general_at(image, format!("Not an image file: {}", image.display()))
+            })?
+            .to_string_lossy()
+            .into_owned();
+        let info = tiler.write_descriptors(image, target)?;
+        let level_start_idx = level_start_indices(&info);
+        Ok(Self {
+            tiler,
+            scheme,
+            image: image.to_path_buf(),
+            base_name,
+            info,
+            sink: FileSystemSink::new(target),
+            level_start_idx,
+            counter: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn scheme(&self) -> TilingScheme {
+        self.scheme
+    }
+
+    pub fn tileset_dir(&self) -> &Path {
+        self.sink.root_dir()
+    }
+
+    pub fn tileset_info(&self) -> &TileSetInfo {
+        &self.info
+    }
+
+    /// The number of tile rows of a level, including the background buffer
+    /// rows of TMS tilesets
+    fn rows(&self, level: i32) -> i32 {
+        let info = &self.info;
+        match self.scheme {
+            TilingScheme::Tms => tile_rows(info.image_height(), info.tile_height(), 1 << level),
+            _ => info.number_of_y_tiles(level),
+        }
+    }
+
+    /// Whether the tileset has a tile at `key`
+    pub fn contains(&self, key: &TileKey) -> bool {
+        let info = &self.info;
+        let level = info.zoom_levels() - key.zoom_level - 1;
+        if key.scheme != self.scheme
+            || key.format != info.tile_format()
+            || !(0..info.zoom_levels()).contains(&level)
+            || !(0..info.number_of_x_tiles(level)).contains(&key.column)
+            || !(0..self.rows(level)).contains(&key.row)
+        {
+            return false;
+        }
+        match self.scheme {
+            TilingScheme::Zoomify => {
+                let idx = self.level_start_idx[level as usize]
+                    + key.row * info.number_of_x_tiles(level)
+                    + key.column;
+                key.tile_group == idx / MAX_TILES_PER_GROUP
+            }
+            _ => key.tile_group == 0,
+        }
+    }
+
+    /// Returns the file of the tile at `key`, cutting it first if it does not
+    /// exist yet, or `None` if the tileset has no such tile.
+    pub fn tile(&self, key: &TileKey) -> Result<Option<PathBuf>, TilingError> {
+        let Some(path) = key.path().filter(|_| self.contains(key)) else {
+            return Ok(None);
+        };
+        let file = self.tileset_dir().join(path);
+        if !file.exists() {
+            self.cut_tile(key)?;
+        }
+        Ok(Some(file))
+    }
+
+    /// Cuts a tile from the source image and puts it into the tileset.
+    fn cut_tile(&self, key: &TileKey) -> Result<(), TilingError> {
+        let base = self.tiler.base();
+        let processor = base.processor();
+        let info = &self.info;
+        let (width, height) = (info.image_width(), info.image_height());
+        let (tile_width, tile_height) = (info.tile_width(), info.tile_height());
+        let level = info.zoom_levels() - key.zoom_level - 1;
+        let factor = 2i32.pow(level as u32);
+
+        // The region of the source image the tile covers. TMS rows are
+        // counted from the bottom; tiles of the buffer cover no region.
+        let x = key.column * tile_width * factor;
+        let region_width = (tile_width * factor).min(width - x);
+        let (y, region_height) = match self.scheme {
+            TilingScheme::Tms => {
+                let bottom = key.row * tile_height * factor;
+                let top = (bottom + tile_height * factor).min(height);
+                (height - top, (top - bottom).max(0))
+            }
+            _ => {
+                let y = key.row * tile_height * factor;
+                (y, (tile_height * factor).min(height - y))
+            }
+        };
+        let scaled_width = (region_width + factor - 1) / factor;
+        let scaled_height = (region_height + factor - 1) / factor;
+        debug!(
+            "Cutting tile {:?} from {}x{}+{}+{}",
+            key, region_width, region_height, x, y
+        );
+
+        let ext = info.tile_format().extension();
+        let prefix = base.working_directory().join(format!(
+            "{}-ondemand-{}-",
+            self.base_name,
+            self.counter.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_file = |name: &str| PathBuf::from(format!("{}{}", prefix.display(), name));
+        let region = temp_file("region.tif");
+        let scaled = temp_file("scaled.tif");
+        let tile = temp_file(&format!("tile.{}", ext));
+
+        let result = (|| -> Result<(), TilingError> {
+            if region_height == 0 {
+                processor.montage_with_canvas(
+                    &[None],
+                    &tile,
+                    1,
+                    1,
+                    tile_width,
+                    tile_height,
+                    processor.get_background_color().unwrap_or("white"),
+                    ImageProcessorImpl::GRAVITY_SOUTHWEST,
+                )?;
+                return self.sink.put_tile_file(key, &tile);
+            }
+            processor.crop_region(&self.image, &region, x, y, region_width, region_height)?;
+            let scaled = if factor > 1 {
+                processor.scale(&region, &scaled, scaled_width, scaled_height)?;
+                &scaled
+            } else {
+                &region
+            };
+            match self.scheme {
+                TilingScheme::Tms => {
+                    // Cropping the whole image into a single tile places it on the canvas
+                    let pattern = temp_file(&format!("tile%d.{}", ext));
+                    processor.crop_with_canvas(
+                        scaled,
+                        &pattern,
+                        scaled_width,
+                        scaled_height,
+                        tile_width,
+                        tile_height,
+                        ImageProcessorImpl::GRAVITY_SOUTHWEST,
+                    )?;
+                    fs::rename(temp_file(&format!("tile0.{}", ext)), &tile)
+                        .map_err(TilingError::not_writable(&tile))?;
+                }
+                _ => processor.scale(scaled, &tile, scaled_width, scaled_height)?,
+            }
+            self.sink.put_tile_file(key, &tile)
+        })();
+
+        for file in [region, scaled, tile] {
+            match fs::remove_file(&file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    error!("Could not delete {}: {}", file.display(), e)
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+    use crate::tms::{TMSTiler, TMSValidator};
+    use crate::validator::Validator;
+
+    fn tiler(working_directory: &Path) -> TMSTiler {
+        let mut tiler = TMSTiler::new();
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_format(ImageFormat::PNG);
+        tiler.base_mut().set_tile_size(64);
+        tiler.base_mut().set_working_directory(working_directory);
+        tiler.set_generate_preview_html(false);
+        tiler
+    }
+
+    /// The mean absolute difference of the channels of two images
+    fn mean_difference(a: &image::RgbaImage, b: &image::RgbaImage) -> f64 {
+        let sum: u64 = a
+            .as_raw()
+            .iter()
+            .zip(b.as_raw())
+            .map(|(a, b)| a.abs_diff(*b) as u64)
+            .sum();
+        sum as f64 / a.as_raw().len() as f64
+    }
+
+    #[test]
+    fn tms_tiles_match_a_regular_tileset() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.png");
+        // A multiple of the tile height, so the buffer is a full row
+        image::RgbImage::from_fn(300, 256, |x, y| {
+            image::Rgb([(x * 255 / 299) as u8, y as u8, ((x + y) / 3) as u8])
+        })
+        .save(&image)
+        .unwrap();
+
+        let tiled = dir.path().join("tiled");
+        let info = tiler(dir.path()).convert_to(&image, &tiled).unwrap();
+        let on_demand = OnDemandTiles::new(
+            Box::new(tiler(dir.path())),
+            TilingScheme::Tms,
+            &image,
+            &dir.path().join("on-demand"),
+        )
+        .unwrap();
+
+        for zoom_level in 0..info.zoom_levels() + 1 {
+            for column in 0..8 {
+                for row in 0..8 {
+                    let key =
+                        TileKey::new(TilingScheme::Tms, zoom_level, column, row, ImageFormat::PNG);
+                    let path = key.path().unwrap();
+                    let expected = tiled.join(&path).exists();
+                    assert_eq!(on_demand.contains(&key), expected, "{}", path);
+                    let file = on_demand.tile(&key).unwrap();
+                    assert_eq!(file.is_some(), expected, "{}", path);
+                    if let Some(file) = file {
+                        let tile = image::open(file).unwrap().to_rgba8();
+                        let regular = image::open(tiled.join(&path)).unwrap().to_rgba8();
+                        assert_eq!(tile.dimensions(), regular.dimensions(), "{}", path);
+                        // The regular tileset halves the padded tiles of the level
+                        // below, the on-demand tiles are scaled from the image in
+                        // one step; this only differs by rounding and at the
+                        // edge to the background
+                        if zoom_level == info.zoom_levels() - 1 {
+                            assert!(tile == regular, "{} differs", path);
+                        } else {
+                            let difference = mean_difference(&tile, &regular);
+                            assert!(difference < 1.0, "{} differs by {}", path, difference);
+                        }
+                    }
+                }
+            }
+        }
+
+        // The top row of the base level lies entirely in the buffer
+        let buffer = TileKey::new(TilingScheme::Tms, 3, 0, 4, ImageFormat::PNG);
+        let tile = image::open(on_demand.tile(&buffer).unwrap().unwrap()).unwrap();
+        assert_eq!(tile.to_rgba8().get_pixel(0, 0).0, [255, 255, 255, 255]);
+
+        let report = TMSValidator::new().report(on_demand.tileset_dir());
+        assert!(report.is_valid(), "{:?}", report.findings());
+    }
+
+    #[test]
+    fn images_must_be_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = OnDemandTiles::new(
+            Box::new(tiler(dir.path())),
+            TilingScheme::Tms,
+            &dir.path().join(".."),
+            &dir.path().join("on-demand"),
+        );
+        assert!(result
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Not an image file"));
+        assert!(!dir.path().join("on-demand").exists());
+    }
+}
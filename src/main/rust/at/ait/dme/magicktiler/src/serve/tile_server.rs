@@ -0,0 +1,619 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error};
+
+use super::http::{encode_segment, escape_html, Request, Response};
+use super::on_demand_tiles::OnDemandTiles;
+use crate::magick_tiler::TilingError;
+use crate::sink::{content_type, TileKey};
+use crate::tile_set_info::TileSetInfo;
+use crate::tiling_scheme::{detect_scheme, TilingScheme};
+use crate::tms;
+
+/// How long an idle connection is kept open. An open connection occupies a
+/// worker thread, so this is short.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PREVIEW_FILE: &str = "preview.html";
+
+/// The tiles of a hosted tileset
+enum Tiles {
+    /// Only the existing tiles are served
+    Static(TileSetInfo),
+    /// Missing tiles are cut when they are requested
+    OnDemand(OnDemandTiles),
+}
+
+struct HostedTileset {
+    name: String,
+    dir: PathBuf,
+    scheme: TilingScheme,
+    tiles: Tiles,
+}
+
+impl HostedTileset {
+    fn info(&self) -> &TileSetInfo {
+        match &self.tiles {
+            Tiles::Static(info) => info,
+            Tiles::OnDemand(tiles) => tiles.tileset_info(),
+        }
+    }
+
+    /// The URL path of the tileset root, ending with '/'
+    fn url(&self) -> String {
+        format!("/{}/", encode_segment(&self.name))
+    }
+
+    /// The preview page. TMS previews are generated for the server, since
+    /// the one written by the tiler refers to the tiles by their file path.
+    fn preview(&self) -> Option<Vec<u8>> {
+        match self.scheme {
+            TilingScheme::Tms => Some(tms::preview_html(self.info(), &self.url()).into_bytes()),
+            _ => fs::read(self.dir.join(PREVIEW_FILE)).ok(),
+        }
+    }
+}
+
+/// A minimal HTTP server that hosts tilesets for previewing them in a
+/// browser. Each tileset is served below /[name]/ with the Content-Type of
+/// its files and CORS headers, so that viewers on other origins can load the
+/// tiles; the preview page of a tileset is at /[name]/preview.html, and an
+/// index of all tilesets at /.
+///
+/// Tilesets added with `add_on_demand_tileset` are tiled while they are
+/// viewed: a missing tile is cut from the source image when it is requested.
+///
+/// Connections are handled by a fixed number of worker threads; further
+/// connections wait until a worker is free. Only GET, HEAD and OPTIONS
+/// requests are supported.
+pub struct TileServer {
+    tilesets: Vec<HostedTileset>,
+
+    /// Value of the Access-Control-Allow-Origin header
+    cors_origin: String,
+
+    /// The number of connections handled at the same time
+    worker_threads: usize,
+}
+
+impl TileServer {
+    pub fn new() -> Self {
+        Self {
+            tilesets: Vec::new(),
+            cors_origin: "*".to_string(),
+            worker_threads: 16,
+        }
+    }
+
+    /// Sets the origins allowed to load the tiles, default="*" (any)
+    pub fn set_cors_origin(&mut self, origin: &str) {
+        self.cors_origin = origin.to_string();
+    }
+
+    /// Sets the number of threads handling connections, default=16. Values
+    /// below 1 are treated as 1.
+    pub fn set_worker_threads(&mut self, worker_threads: usize) {
+        self.worker_threads = worker_threads.max(1);
+    }
+
+    /// Hosts the tileset in a directory at /[name]/. The tiling scheme is
+    /// detected from its descriptor files.
+    pub fn add_tileset(&mut self, name: &str, dir: &Path) -> Result<(), TilingError> {
+        let detected = detect_scheme(dir).ok_or_else(|| {
+            TilingError::general_at(dir, format!("No known tileset in {}", dir.display()))
+        })?;
+        if detected.scheme() == TilingScheme::PMTiles {
+            return Err(TilingError::general_at(
+                dir,
+                format!("PMTiles archives cannot be served: {}", dir.display()),
+            ));
+        }
+        let tiles = Tiles::Static(detected.tileset_info(dir));
+        self.add(name, dir, detected.scheme(), tiles)
+    }
+
+    /// Hosts a tileset at /[name]/ that is tiled on demand.
+    pub fn add_on_demand_tileset(
+        &mut self,
+        name: &str,
+        tiles: OnDemandTiles,
+    ) -> Result<(), TilingError> {
+        let dir = tiles.tileset_dir().to_path_buf();
+        let scheme = tiles.scheme();
+        self.add(name, &dir, scheme, Tiles::OnDemand(tiles))
+    }
+
+    fn add(
+        &mut self,
+        name: &str,
+        dir: &Path,
+        scheme: TilingScheme,
+        tiles: Tiles,
+    ) -> Result<(), TilingError> {
+        if name.is_empty() || name.contains('/') {
+            return Err(TilingError::general(format!(
+                "Invalid tileset name: {}",
+                name
+            )));
+        }
+        if self.tilesets.iter().any(|t| t.name == name) {
+            return Err(TilingError::general(format!(
+                "A tileset named {} is served already",
+                name
+            )));
+        }
+        self.tilesets.push(HostedTileset {
+            name: name.to_string(),
+            dir: dir.to_path_buf(),
+            scheme,
+            tiles,
+        });
+        Ok(())
+    }
+
+    /// The URL paths of the hosted tilesets, by name. Each path leads to the
+    /// preview page of its tileset, if there is one.
+    pub fn tileset_urls(&self) -> Vec<(&str, String)> {
+        self.tilesets
+            .iter()
+            .map(|t| (t.name.as_str(), t.url()))
+            .collect()
+    }
+
+    /// Accepts connections on `worker_threads` threads, each handling one
+    /// connection at a time. Does not return.
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        thread::scope(|scope| {
+            for _ in 0..self.worker_threads {
+                scope.spawn(|| self.accept(&listener));
+            }
+        });
+        Ok(())
+    }
+
+    fn accept(&self, listener: &TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = self.handle_connection(stream) {
+                        debug!("Connection closed: {}", e);
+                    }
+                }
+                Err(e) => error!("Could not accept a connection: {}", e),
+            }
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        self.handle_requests(&mut BufReader::new(stream), &mut writer)
+    }
+
+    /// Answers the requests read from a connection until it is closed
+    fn handle_requests<R: BufRead, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        loop {
+            let request = match Request::read(reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    Response::status(400).write(writer, false, false)?;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+            let response = self
+                .respond(&request)
+                .with_header("Access-Control-Allow-Origin", &self.cors_origin);
+            debug!("{} {} {}", request.method, request.path, response.status);
+            response.write(writer, request.method == "HEAD", request.keep_alive)?;
+            if !request.keep_alive {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&self, request: &Request) -> Response {
+        match request.method.as_str() {
+            "GET" | "HEAD" => {}
+            "OPTIONS" => {
+                return Response::new(204, "text/plain", Vec::new())
+                    .with_header("Access-Control-Allow-Methods", "GET, HEAD, OPTIONS")
+                    .with_header("Access-Control-Allow-Headers", "*")
+                    .with_header("Access-Control-Max-Age", "86400")
+            }
+            _ => return Response::status(405).with_header("Allow", "GET, HEAD, OPTIONS"),
+        }
+
+        let path = request.path.trim_start_matches('/');
+        if path.is_empty() {
+            return Response::new(200, "text/html; charset=utf-8", self.index().into_bytes());
+        }
+        let (name, file) = path.split_once('/').unwrap_or((path, ""));
+        let Some(tileset) = self.tilesets.iter().find(|t| t.name == name) else {
+            return Response::status(404);
+        };
+        if file.is_empty() {
+            return match tileset.preview() {
+                Some(_) => Response::redirect(&format!("{}{}", tileset.url(), PREVIEW_FILE)),
+                None => Response::status(404),
+            };
+        }
+        if file == PREVIEW_FILE {
+            return match tileset.preview() {
+                Some(html) => Response::new(200, content_type(file), html),
+                None => Response::status(404),
+            };
+        }
+        self.serve_file(tileset, file)
+    }
+
+    /// Serves a file of a tileset, cutting it first if it is a missing tile
+    /// of an on-demand tileset.
+    fn serve_file(&self, tileset: &HostedTileset, file: &str) -> Response {
+        // Only plain relative paths stay inside the tileset directory
+        let relative = Path::new(file);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Response::status(404);
+        }
+
+        let mut path = tileset.dir.join(relative);
+        if !path.is_file() {
+            let Tiles::OnDemand(tiles) = &tileset.tiles else {
+                return Response::status(404);
+            };
+            let Some(key) = TileKey::from_path(tileset.scheme, file)
+                .filter(|key| key.path().as_deref() == Some(file))
+            else {
+                return Response::status(404);
+            };
+            path = match tiles.tile(&key) {
+                Ok(Some(path)) => path,
+                Ok(None) => return Response::status(404),
+                Err(e) => {
+                    error!("Could not cut tile {} of {}: {}", file, tileset.name, e);
+                    return Response::status(500);
+                }
+            };
+        }
+
+        match fs::read(&path) {
+            Ok(data) => Response::new(200, content_type(file), data),
+            Err(e) => {
+                error!("Could not read {}: {}", path.display(), e);
+                Response::status(404)
+            }
+        }
+    }
+
+    /// A page linking all tilesets
+    fn index(&self) -> String {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head><title>MagickTiler</title></head>\n<body>\n<ul>\n",
+        );
+        for tileset in &self.tilesets {
+            let info = tileset.info();
+            html.push_str(&format!(
+                "<li><a href=\"{}\">{}</a> ({}, {}x{}, {} zoom levels{})</li>\n",
+                tileset.url(),
+                escape_html(&tileset.name),
+                tileset.scheme,
+                info.image_width(),
+                info.image_height(),
+                info.zoom_levels(),
+                match tileset.tiles {
+                    Tiles::Static(_) => "",
+                    Tiles::OnDemand(_) => ", tiled on demand",
+                }
+            ));
+        }
+        html.push_str("</ul>\n</body>\n</html>\n");
+        html
+    }
+}
+
+impl Default for TileServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{Shutdown, TcpStream};
+
+    use super::*;
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+    use crate::zoomify::ZoomifyTiler;
+    use crate::MagickTiler;
+
+    type Headers = Vec<(String, String)>;
+
+    /// Hosts a TMS tileset at /tiles/ and a Zoomify tileset at /zoomify/,
+    /// next to a file that must not be served
+    fn server(dir: &Path) -> TileServer {
+        let image = dir.join("image.png");
+        image::RgbImage::from_fn(300, 200, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(&image)
+            .unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+
+        let mut server = TileServer::new();
+        let tilers: [(Box<dyn MagickTiler>, &str, ImageFormat); 2] = [
+            (Box::new(tms::TMSTiler::new()), "tiles", ImageFormat::PNG),
+            (Box::new(ZoomifyTiler::new()), "zoomify", ImageFormat::JPEG),
+        ];
+        for (mut tiler, name, format) in tilers {
+            tiler
+                .base_mut()
+                .set_image_processing_system(ImageProcessingSystem::Native);
+            tiler.base_mut().set_tile_format(format);
+            tiler.base_mut().set_tile_size(128);
+            tiler.base_mut().set_working_directory(dir);
+            tiler.convert_to(&image, &dir.join(name)).unwrap();
+            server.add_tileset(name, &dir.join(name)).unwrap();
+        }
+        fs::write(dir.join("tiles").join("notes a.json"), "{}").unwrap();
+        server
+    }
+
+    /// Feeds raw requests to the server and returns the raw responses
+    fn exchange(server: &TileServer, requests: &str) -> Vec<u8> {
+        let mut responses = Vec::new();
+        server
+            .handle_requests(&mut requests.as_bytes(), &mut responses)
+            .unwrap();
+        responses
+    }
+
+    /// Splits raw responses into their status, headers and body
+    fn parse(mut raw: &[u8]) -> Vec<(u16, Headers, Vec<u8>)> {
+        let mut responses = Vec::new();
+        while !raw.is_empty() {
+            let end = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            let head = std::str::from_utf8(&raw[..end]).unwrap();
+            let mut lines = head.split("\r\n");
+            let status = lines.next().unwrap().split(' ').nth(1).unwrap();
+            let headers: Headers = lines
+                .map(|line| {
+                    let (name, value) = line.split_once(": ").unwrap();
+                    (name.to_string(), value.to_string())
+                })
+                .collect();
+            let length: usize = header(&headers, "Content-Length").unwrap().parse().unwrap();
+            let body = raw[end + 4..end + 4 + length].to_vec();
+            raw = &raw[end + 4 + length..];
+            responses.push((status.parse().unwrap(), headers, body));
+        }
+        responses
+    }
+
+    fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn get(server: &TileServer, path: &str) -> (u16, Headers, Vec<u8>) {
+        let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+        parse(&exchange(server, &request)).remove(0)
+    }
+
+    #[test]
+    fn files_are_served_with_their_content_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path());
+        for (path, file, content_type) in [
+            ("/tiles/0/0/0.png", "tiles/0/0/0.png", "image/png"),
+            (
+                "/tiles/tilemapresource.xml",
+                "tiles/tilemapresource.xml",
+                "application/xml",
+            ),
+            (
+                "/zoomify/TileGroup0/0-0-0.jpg",
+                "zoomify/TileGroup0/0-0-0.jpg",
+                "image/jpeg",
+            ),
+            (
+                "/zoomify/ImageProperties.xml",
+                "zoomify/ImageProperties.xml",
+                "application/xml",
+            ),
+            (
+                "/tiles/notes%20a.json",
+                "tiles/notes a.json",
+                "application/json",
+            ),
+        ] {
+            let (status, headers, body) = get(&server, path);
+            assert_eq!(status, 200, "{}", path);
+            assert_eq!(header(&headers, "Content-Type"), Some(content_type));
+            assert_eq!(header(&headers, "Access-Control-Allow-Origin"), Some("*"));
+            assert_eq!(body, fs::read(dir.path().join(file)).unwrap(), "{}", path);
+        }
+        assert_eq!(get(&server, "/tiles/9/0/0.png").0, 404);
+        assert_eq!(get(&server, "/other/0/0/0.png").0, 404);
+    }
+
+    #[test]
+    fn head_requests_have_no_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path());
+        let response = exchange(&server, "HEAD /tiles/0/0/0.png HTTP/1.0\r\n\r\n");
+        let length = fs::read(dir.path().join("tiles/0/0/0.png")).unwrap().len();
+        let expected = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: image/png\r\n\
+             Access-Control-Allow-Origin: *\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\
+             \r\n",
+            length
+        );
+        assert_eq!(String::from_utf8(response).unwrap(), expected);
+    }
+
+    #[test]
+    fn files_outside_the_tileset_are_not_served() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path());
+        let secret = dir.path().join("secret.txt");
+        for path in [
+            "/tiles/../secret.txt",
+            "/tiles/0/../../secret.txt",
+            "/tiles/%2E%2E/secret.txt",
+            "/tiles/%2e%2e%2fsecret.txt",
+            "/tiles/0%2F..%2F..%2Fsecret.txt",
+            &format!("/tiles/{}", secret.display()),
+            "/../secret.txt",
+            "/%2E%2E/secret.txt",
+        ] {
+            let (status, _, body) = get(&server, path);
+            assert_eq!(status, 404, "{}", path);
+            assert_eq!(body, b"404 Not Found\n");
+        }
+    }
+
+    #[test]
+    fn cors_preflights_are_answered() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = server(dir.path());
+        server.set_cors_origin("https://example.org");
+
+        let request = "OPTIONS /tiles/0/0/0.png HTTP/1.1\r\n\
+                       Origin: https://example.org\r\n\
+                       Access-Control-Request-Method: GET\r\n\
+                       Connection: close\r\n\r\n";
+        let (status, headers, body) = parse(&exchange(&server, request)).remove(0);
+        assert_eq!(status, 204);
+        assert!(body.is_empty());
+        for (name, value) in [
+            ("Access-Control-Allow-Origin", "https://example.org"),
+            ("Access-Control-Allow-Methods", "GET, HEAD, OPTIONS"),
+            ("Access-Control-Allow-Headers", "*"),
+            ("Access-Control-Max-Age", "86400"),
+        ] {
+            assert_eq!(header(&headers, name), Some(value), "{}", name);
+        }
+
+        let request = "PUT /tiles/0/0/0.png HTTP/1.1\r\nContent-Length: 4\r\n\r\ntile";
+        let (status, headers, _) = parse(&exchange(&server, request)).remove(0);
+        assert_eq!(status, 405);
+        assert_eq!(header(&headers, "Allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Origin"),
+            Some("https://example.org")
+        );
+    }
+
+    #[test]
+    fn tilesets_are_indexed_and_previewed() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path());
+
+        let (status, headers, body) = get(&server, "/");
+        assert_eq!(status, 200);
+        assert_eq!(
+            header(&headers, "Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let index = String::from_utf8(body).unwrap();
+        assert!(index.contains("<a href=\"/tiles/\">tiles</a> (tms, 300x200"));
+        assert!(index.contains("<a href=\"/zoomify/\">zoomify</a>"));
+
+        for name in ["tiles", "zoomify"] {
+            let (status, headers, _) = get(&server, &format!("/{}/", name));
+            assert_eq!(status, 302);
+            let location = format!("/{}/preview.html", name);
+            assert_eq!(header(&headers, "Location"), Some(location.as_str()));
+
+            let (status, headers, body) = get(&server, &location);
+            assert_eq!(status, 200);
+            assert_eq!(
+                header(&headers, "Content-Type"),
+                Some("text/html; charset=utf-8")
+            );
+            assert!(String::from_utf8(body).unwrap().contains("<html"));
+        }
+    }
+
+    #[test]
+    fn connections_are_kept_alive() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path());
+        let responses = parse(&exchange(
+            &server,
+            "GET /tiles/0/0/0.png HTTP/1.1\r\n\r\n\
+             GET /tiles/tilemapresource.xml HTTP/1.1\r\nConnection: close\r\n\r\n\
+             GET /tiles/0/0/0.png HTTP/1.1\r\n\r\n",
+        ));
+        let connections: Vec<_> = responses
+            .iter()
+            .map(|(status, headers, _)| (*status, header(headers, "Connection").unwrap()))
+            .collect();
+        assert_eq!(connections, [(200, "keep-alive"), (200, "close")]);
+    }
+
+    #[test]
+    fn malformed_requests_are_bad_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path());
+        let mut responses = Vec::new();
+        let result =
+            server.handle_requests(&mut "GET /%zz HTTP/1.1\r\n\r\n".as_bytes(), &mut responses);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (status, headers, _) = parse(&responses).remove(0);
+        assert_eq!(status, 400);
+        assert_eq!(header(&headers, "Connection"), Some("close"));
+    }
+
+    #[test]
+    fn connections_wait_for_a_free_worker() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = server(dir.path());
+        server.set_worker_threads(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener));
+
+        let request = b"GET /tiles/tilemapresource.xml HTTP/1.1\r\n\r\n";
+        let mut first = TcpStream::connect(address).unwrap();
+        first.write_all(request).unwrap();
+        let mut response = [0; 15];
+        first.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK");
+
+        // The only worker is busy with the first connection
+        let mut second = TcpStream::connect(address).unwrap();
+        second.write_all(request).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let error = second.read_exact(&mut response).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+
+        first.shutdown(Shutdown::Both).unwrap();
+        second.set_read_timeout(Some(IDLE_TIMEOUT)).unwrap();
+        second.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK");
+    }
+}
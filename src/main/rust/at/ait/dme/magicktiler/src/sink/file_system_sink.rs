@@ -0,0 +1,58 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::tile_sink::{TileKey, TileSink};
+use crate::magick_tiler::TilingError;
+
+/// Writes a tileset as a directory tree below a root directory. This is the
+/// sink tilers use unless another one is configured.
+pub struct FileSystemSink {
+    root_dir: PathBuf,
+}
+
+impl FileSystemSink {
+    pub fn new<P: AsRef<Path>>(root_dir: P) -> Self {
+        Self {
+            root_dir: root_dir.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    /// The file a path relative to the root directory is written to. Its
+    /// parent directory is created if necessary.
+    fn target(&self, path: &str) -> Result<PathBuf, TilingError> {
+        let target = self.root_dir.join(path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(TilingError::not_writable(parent))?;
+        }
+        Ok(target)
+    }
+}
+
+impl TileSink for FileSystemSink {
+    fn put_tile(&self, tile: &TileKey, data: &[u8]) -> Result<(), TilingError> {
+        let target = self.target(&tile.required_path()?)?;
+        fs::write(&target, data).map_err(TilingError::not_writable(&target))
+    }
+
+    /// Moves the file into the tileset. Files on a different file system are
+    /// copied.
+    fn put_tile_file(&self, tile: &TileKey, file: &Path) -> Result<(), TilingError> {
+        let target = self.target(&tile.required_path()?)?;
+        fs::rename(file, &target)
+            .or_else(|_| fs::copy(file, &target).and_then(|_| fs::remove_file(file)))
+            .map_err(TilingError::not_writable(&target))
+    }
+
+    fn put_metadata(&self, name: &str, data: &[u8]) -> Result<(), TilingError> {
+        let target = self.target(name)?;
+        fs::write(&target, data).map_err(TilingError::not_writable(&target))
+    }
+
+    fn finalize(&self) -> Result<(), TilingError> {
+        Ok(())
+    }
+}
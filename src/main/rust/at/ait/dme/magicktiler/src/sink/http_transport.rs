@@ -0,0 +1,289 @@
+use std::io::{self, Read};
+use std::time::Duration;
+
+use ureq::{Agent, AgentBuilder};
+
+/// An HTTP request sent by `S3Sink`. The headers include `Host`;
+/// transports add `Content-Length` and connection handling themselves.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// The value of a header, compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Sends HTTP requests for `S3Sink`. Implement this to send requests through
+/// a client other than the built-in `DefaultHttpTransport`, e.g. through a
+/// proxy.
+pub trait HttpTransport: Send + Sync {
+    fn send(&self, request: &HttpRequest) -> io::Result<HttpResponse>;
+}
+
+/// An HTTP/1.1 client for `http://` and `https://` endpoints, such as AWS S3
+/// or a local MinIO server. TLS connections are verified against the Mozilla
+/// root certificates. Redirects are not followed, so that a request to the
+/// wrong region fails with its status.
+pub struct DefaultHttpTransport {
+    agent: Agent,
+}
+
+impl DefaultHttpTransport {
+    pub fn new() -> Self {
+        Self::with_timeout(Duration::from_secs(60))
+    }
+
+    /// Creates a transport with a timeout for connecting and for each read
+    /// or write, default=60s
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            agent: AgentBuilder::new()
+                .timeout_connect(timeout)
+                .timeout_read(timeout)
+                .timeout_write(timeout)
+                .redirects(0)
+                .build(),
+        }
+    }
+
+    /// Whether this transport can reach an endpoint, i.e. it is a valid
+    /// `http://` or `https://` URL
+    pub fn supports(url: &str) -> bool {
+        Url::parse(url).is_ok_and(|url| url.scheme == "http" || url.scheme == "https")
+    }
+}
+
+impl Default for DefaultHttpTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpTransport for DefaultHttpTransport {
+    fn send(&self, request: &HttpRequest) -> io::Result<HttpResponse> {
+        let mut call = self.agent.request(&request.method, &request.url);
+        for (name, value) in &request.headers {
+            call = call.set(name, value);
+        }
+        let result = match request.method.as_str() {
+            "GET" | "HEAD" => call.call(),
+            // With a Content-Length, even if the body is empty
+            _ => call.send_bytes(&request.body),
+        };
+        let response = match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(ureq::Error::Transport(e)) => return Err(io::Error::other(e)),
+        };
+
+        let headers = response
+            .headers_names()
+            .into_iter()
+            .flat_map(|name| {
+                response
+                    .all(&name)
+                    .into_iter()
+                    .map(|value| (name.clone(), value.to_string()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let status = response.status();
+        let mut body = Vec::new();
+        response.into_reader().read_to_end(&mut body)?;
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// The parts of an `http://` or `https://` URL a transport needs
+pub(crate) struct Url {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+
+    /// The path and query, starting with '/'
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid URL: {}", url));
+        let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+        let scheme = scheme.to_ascii_lowercase();
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let default_port = if scheme == "https" { 443 } else { 80 };
+        // The colons of an IPv6 address are inside the brackets
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            scheme,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    #[test]
+    fn urls_are_split_into_their_parts() {
+        let url = Url::parse("HTTP://localhost:9000/tiles/a.png?uploads=").unwrap();
+        assert_eq!(
+            (url.scheme.as_str(), url.host.as_str(), url.port),
+            ("http", "localhost", 9000)
+        );
+        assert_eq!(url.path, "/tiles/a.png?uploads=");
+
+        let url = Url::parse("https://[::1]").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("[::1]", 443));
+        assert_eq!(url.path, "/");
+
+        assert!(Url::parse("localhost:9000").is_err());
+        assert!(Url::parse("http://:9000/").is_err());
+        assert!(DefaultHttpTransport::supports("http://localhost:9000"));
+        assert!(DefaultHttpTransport::supports(
+            "https://s3.eu-west-1.amazonaws.com"
+        ));
+        assert!(!DefaultHttpTransport::supports("ftp://localhost"));
+    }
+
+    /// Answers a single request with `response` and returns the port and the
+    /// request head and body received
+    fn serve_once(response: &'static [u8]) -> (u16, JoinHandle<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                reader.read_line(&mut head).unwrap();
+            }
+            let length = head
+                .lines()
+                .find_map(|line| {
+                    line.to_ascii_lowercase()
+                        .strip_prefix("content-length: ")
+                        .map(String::from)
+                })
+                .map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(response).unwrap();
+            (head, body)
+        });
+        (port, server)
+    }
+
+    fn request(method: &str, port: u16, body: &[u8]) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            url: format!("http://127.0.0.1:{}/tiles/0.png", port),
+            headers: vec![("host".to_string(), format!("127.0.0.1:{}", port))],
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn requests_are_sent_over_http() {
+        let (port, server) = serve_once(
+            b"HTTP/1.1 200 OK\r\nETag: \"1\"\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+        );
+        let response = DefaultHttpTransport::new()
+            .send(&request("PUT", port, b"tile"))
+            .unwrap();
+        assert!(response.is_success());
+        assert_eq!(response.header("etag"), Some("\"1\""));
+        assert_eq!(response.body, b"abcde");
+
+        let (head, body) = server.join().unwrap();
+        assert!(head.starts_with("PUT /tiles/0.png HTTP/1.1\r\n"));
+        assert!(head
+            .to_ascii_lowercase()
+            .contains("\r\ncontent-length: 4\r\n"));
+        assert_eq!(head.matches("host: ").count(), 1, "{}", head);
+        assert_eq!(body, b"tile");
+    }
+
+    #[test]
+    fn empty_posts_have_a_content_length() {
+        let (port, server) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let response = DefaultHttpTransport::new()
+            .send(&request("POST", port, b""))
+            .unwrap();
+        assert_eq!(response.status, 200);
+        let (head, _) = server.join().unwrap();
+        assert!(head
+            .to_ascii_lowercase()
+            .contains("\r\ncontent-length: 0\r\n"));
+    }
+
+    #[test]
+    fn error_statuses_are_responses() {
+        let (port, server) = serve_once(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 37\r\n\r\n\
+              <Error><Code>NoSuchKey</Code></Error>",
+        );
+        let response = DefaultHttpTransport::new()
+            .send(&request("GET", port, b""))
+            .unwrap();
+        assert_eq!(response.status, 404);
+        assert!(!response.is_success());
+        assert_eq!(response.body, b"<Error><Code>NoSuchKey</Code></Error>");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn unreachable_endpoints_are_transport_errors() {
+        // Bind and drop a listener to find a port nobody listens on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let transport = DefaultHttpTransport::with_timeout(Duration::from_secs(5));
+        assert!(transport.send(&request("PUT", port, b"tile")).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use super::tile_sink::{finalized, TileKey, TileSink};
+use crate::magick_tiler::TilingError;
+
+/// Keeps a tileset in memory, by path relative to the tileset root, e.g. to
+/// inspect the output of a tiler without writing it to disk.
+#[derive(Default)]
+pub struct MemorySink {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    files: BTreeMap<String, Vec<u8>>,
+    finalized: bool,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The content of a tile, if it was put
+    pub fn tile(&self, tile: &TileKey) -> Option<Vec<u8>> {
+        self.get(&tile.path()?)
+    }
+
+    /// The content of a tile or metadata file by its path
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().files.get(path).cloned()
+    }
+
+    /// The paths of all files put so far, in lexicographic order
+    pub fn paths(&self) -> Vec<String> {
+        self.state.lock().unwrap().files.keys().cloned().collect()
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.state.lock().unwrap().finalized
+    }
+
+    fn insert(&self, path: String, data: &[u8]) -> Result<(), TilingError> {
+        let mut state = self.state.lock().unwrap();
+        if state.finalized {
+            return Err(finalized());
+        }
+        state.files.insert(path, data.to_vec());
+        Ok(())
+    }
+}
+
+impl TileSink for MemorySink {
+    fn put_tile(&self, tile: &TileKey, data: &[u8]) -> Result<(), TilingError> {
+        self.insert(tile.required_path()?, data)
+    }
+
+    fn put_metadata(&self, name: &str, data: &[u8]) -> Result<(), TilingError> {
+        self.insert(name.to_string(), data)
+    }
+
+    fn finalize(&self) -> Result<(), TilingError> {
+        let mut state = self.state.lock().unwrap();
+        if state.finalized {
+            return Err(finalized());
+        }
+        state.finalized = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::gmaps::GoogleMapsTiler;
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+    use crate::tiling_scheme::TilingScheme;
+    use crate::tms::TMSTiler;
+    use crate::zoomify::ZoomifyTiler;
+    use crate::MagickTiler;
+
+    #[test]
+    fn files_are_kept_until_finalized() {
+        let sink = MemorySink::new();
+        let key = TileKey::new(TilingScheme::Tms, 1, 0, 1, ImageFormat::PNG);
+        sink.put_tile(&key, b"first").unwrap();
+        sink.put_tile(&key, b"second").unwrap();
+        sink.put_metadata("tilemapresource.xml", b"<TileMap/>")
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("tile.png");
+        fs::write(&file, b"from file").unwrap();
+        let other = TileKey::new(TilingScheme::Tms, 0, 0, 0, ImageFormat::PNG);
+        sink.put_tile_file(&other, &file).unwrap();
+        assert!(!file.exists());
+
+        let deep_zoom = TileKey::new(TilingScheme::DeepZoom, 0, 0, 0, ImageFormat::PNG);
+        assert!(sink.put_tile(&deep_zoom, b"tile").is_err());
+
+        assert_eq!(sink.tile(&key).unwrap(), b"second");
+        assert_eq!(sink.get("0/0/0.png").unwrap(), b"from file");
+        assert_eq!(
+            sink.paths(),
+            ["0/0/0.png", "1/0/1.png", "tilemapresource.xml"]
+        );
+
+        assert!(!sink.is_finalized());
+        sink.finalize().unwrap();
+        assert!(sink.is_finalized());
+        assert!(sink.put_tile(&key, b"third").is_err());
+        assert!(sink.put_metadata("preview.html", b"").is_err());
+        assert!(sink.finalize().is_err());
+    }
+
+    /// Reads all files below `dir`, keyed by their relative path with '/' as
+    /// separator.
+    fn read_tree(dir: &Path, prefix: &str, files: &mut BTreeMap<String, Vec<u8>>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = format!("{}{}", prefix, path.file_name().unwrap().to_string_lossy());
+            if path.is_dir() {
+                read_tree(&path, &format!("{}/", name), files);
+            } else {
+                files.insert(name, fs::read(&path).unwrap());
+            }
+        }
+    }
+
+    /// Tiles the same image into a `MemorySink` and into the target directory
+    /// and checks that both hold the same files.
+    fn check_sink<T: MagickTiler>(create: impl Fn() -> T) {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.png");
+        image::RgbImage::from_fn(300, 200, |x, y| {
+            image::Rgb([x as u8, y as u8, (x * y % 251) as u8])
+        })
+        .save(&image)
+        .unwrap();
+        let target = dir.path().join("tiles");
+
+        let configure = |tiler: &mut T| {
+            tiler
+                .base_mut()
+                .set_image_processing_system(ImageProcessingSystem::Native);
+            tiler.base_mut().set_tile_format(ImageFormat::JPEG);
+            tiler.base_mut().set_tile_size(64);
+            tiler.base_mut().set_working_directory(dir.path());
+        };
+
+        let sink = Arc::new(MemorySink::new());
+        let mut tiler = create();
+        configure(&mut tiler);
+        tiler.set_tile_sink(sink.clone()).unwrap();
+        tiler.convert_to(&image, &target).unwrap();
+        assert!(sink.is_finalized());
+        assert!(!target.exists());
+
+        let mut tiler = create();
+        configure(&mut tiler);
+        tiler.convert_to(&image, &target).unwrap();
+        let mut files = BTreeMap::new();
+        read_tree(&target, "", &mut files);
+
+        assert!(files.len() > 10);
+        assert_eq!(sink.paths(), files.keys().cloned().collect::<Vec<_>>());
+        for (name, data) in &files {
+            assert!(&sink.get(name).unwrap() == data, "{} differs", name);
+        }
+    }
+
+    #[test]
+    fn tms_tilesets_match_the_file_system() {
+        check_sink(TMSTiler::new);
+    }
+
+    #[test]
+    fn zoomify_tilesets_match_the_file_system() {
+        check_sink(ZoomifyTiler::new);
+    }
+
+    #[test]
+    fn google_maps_tilesets_match_the_file_system() {
+        check_sink(GoogleMapsTiler::new);
+    }
+}
@@ -0,0 +1,18 @@
+mod file_system_sink;
+mod http_transport;
+mod memory_sink;
+mod s3_client;
+mod s3_sink;
+mod tar_sink;
+mod tile_sink;
+mod zip_sink;
+
+pub use file_system_sink::FileSystemSink;
+pub use http_transport::{DefaultHttpTransport, HttpRequest, HttpResponse, HttpTransport};
+pub use memory_sink::MemorySink;
+pub use s3_client::S3Credentials;
+pub use s3_sink::{S3Config, S3Sink};
+pub use tar_sink::TarSink;
+pub(crate) use tile_sink::content_type;
+pub use tile_sink::{TileKey, TileSink};
+pub use zip_sink::ZipSink;
@@ -0,0 +1,648 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::{debug, error};
+
+use super::http_transport::{DefaultHttpTransport, HttpTransport};
+use super::s3_client::{ObjectHeaders, S3Client, S3Credentials};
+use super::tile_sink::{content_type, finalized, TileKey, TileSink};
+use crate::magick_tiler::TilingError;
+
+/// The smallest part size S3 accepts for all but the last part
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Settings of an `S3Sink`
+#[derive(Clone)]
+pub struct S3Config {
+    /// URL of the service, e.g. "http://localhost:9000"
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+
+    /// Prepended to the path of every file in the tileset, e.g.
+    /// "tilesets/image"
+    pub prefix: String,
+    pub credentials: S3Credentials,
+
+    /// Cache-Control header stored with every object, if set
+    pub cache_control: Option<String>,
+
+    /// Number of uploads running at the same time, default=4
+    pub concurrency: usize,
+
+    /// Number of times a failed request is retried, default=3
+    pub max_retries: u32,
+
+    /// Files larger than this (in bytes) are uploaded in parts, default=8 MiB
+    pub multipart_threshold: usize,
+
+    /// Size of the parts of multipart uploads (at least 5 MiB), default=8 MiB
+    pub part_size: usize,
+}
+
+impl S3Config {
+    pub fn new(endpoint: &str, bucket: &str, credentials: S3Credentials) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+            region: "us-east-1".to_string(),
+            bucket: bucket.to_string(),
+            prefix: String::new(),
+            credentials,
+            cache_control: None,
+            concurrency: 4,
+            max_retries: 3,
+            multipart_threshold: 8 * 1024 * 1024,
+            part_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// A file waiting to be uploaded
+struct Upload {
+    key: String,
+    content_type: &'static str,
+    data: Vec<u8>,
+}
+
+/// Whether an upload failed, and its error until it is reported
+#[derive(Default)]
+struct Failure {
+    failed: bool,
+    error: Option<TilingError>,
+}
+
+/// Uploads a tileset to a bucket of an S3-compatible object storage (AWS
+/// S3, MinIO, ...), laid out like the directory tree a `FileSystemSink`
+/// would write below the configured prefix.
+///
+/// Files are uploaded in the background by a fixed number of threads; when
+/// all of them are busy, putting a file blocks until one is free. Files
+/// larger than the multipart threshold are uploaded in parts. Every object
+/// gets the Content-Type of its format and the configured Cache-Control.
+/// The first failed upload is reported by the next call to `put_tile`,
+/// `put_metadata` or `finalize`, which waits for all uploads to complete.
+///
+/// Requests are sent through an `HttpTransport`, by default a
+/// `DefaultHttpTransport`, which reaches `http://` and `https://` endpoints.
+pub struct S3Sink {
+    client: Arc<S3Client>,
+    config: S3Config,
+
+    /// The queue of the upload threads, `None` once the sink is finalized
+    queue: Mutex<Option<SyncSender<Upload>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+
+    failure: Arc<Mutex<Failure>>,
+}
+
+impl S3Sink {
+    /// Creates a sink that sends its requests with a `DefaultHttpTransport`.
+    ///
+    /// # Errors
+    /// Returns an error if the endpoint is not an `http://` or `https://`
+    /// URL
+    pub fn new(config: S3Config) -> Result<Self, TilingError> {
+        if !DefaultHttpTransport::supports(&config.endpoint) {
+            return Err(TilingError::general(format!(
+                "{} is not an http:// or https:// endpoint",
+                config.endpoint
+            )));
+        }
+        Self::with_transport(config, Arc::new(DefaultHttpTransport::new()))
+    }
+
+    pub fn with_transport(
+        mut config: S3Config,
+        transport: Arc<dyn HttpTransport>,
+    ) -> Result<Self, TilingError> {
+        config.concurrency = config.concurrency.max(1);
+        config.part_size = config.part_size.max(MIN_PART_SIZE);
+        config.prefix = config.prefix.trim_matches('/').to_string();
+        let client = S3Client::new(
+            &config.endpoint,
+            &config.region,
+            &config.bucket,
+            config.credentials.clone(),
+            transport,
+            config.max_retries,
+        )
+        .map_err(|e| TilingError::general(e.to_string()))?;
+
+        let mut sink = Self {
+            client: Arc::new(client),
+            config,
+            queue: Mutex::new(None),
+            workers: Mutex::new(Vec::new()),
+            failure: Arc::new(Mutex::new(Failure::default())),
+        };
+        sink.start_workers();
+        Ok(sink)
+    }
+
+    pub fn config(&self) -> &S3Config {
+        &self.config
+    }
+
+    fn start_workers(&mut self) {
+        let concurrency = self.config.concurrency;
+        let (sender, receiver) = mpsc::sync_channel::<Upload>(concurrency);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..concurrency)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let client = self.client.clone();
+                let config = self.config.clone();
+                let failure = self.failure.clone();
+                thread::spawn(move || upload_worker(&receiver, &client, &config, &failure))
+            })
+            .collect();
+        self.queue = Mutex::new(Some(sender));
+        self.workers = Mutex::new(workers);
+    }
+
+    /// The key of a file in the tileset
+    fn key(&self, path: &str) -> String {
+        if self.config.prefix.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.config.prefix, path)
+        }
+    }
+
+    /// Returns an error if an upload failed
+    fn check_failed(&self) -> Result<(), TilingError> {
+        let mut failure = self.failure.lock().unwrap();
+        match failure.error.take() {
+            Some(e) => Err(e),
+            None if failure.failed => Err(TilingError::general(
+                "An upload to the bucket failed".to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn enqueue(
+        &self,
+        path: &str,
+        content_type: &'static str,
+        data: &[u8],
+    ) -> Result<(), TilingError> {
+        self.check_failed()?;
+        let upload = Upload {
+            key: self.key(path),
+            content_type,
+            data: data.to_vec(),
+        };
+        // Clone the sender, so that waiting for a free thread does not block
+        // other callers
+        let sender = self.queue.lock().unwrap().clone().ok_or_else(finalized)?;
+        sender
+            .send(upload)
+            .map_err(|_| TilingError::general("The upload threads have stopped".to_string()))
+    }
+
+    /// Closes the queue and waits for the upload threads to finish
+    fn stop_workers(&self) {
+        self.queue.lock().unwrap().take();
+        for worker in self.workers.lock().unwrap().drain(..) {
+            if worker.join().is_err() {
+                error!("An upload thread panicked");
+            }
+        }
+    }
+}
+
+/// Uploads the files in the queue until it is closed. Once an upload failed,
+/// the remaining files are discarded.
+fn upload_worker(
+    receiver: &Mutex<Receiver<Upload>>,
+    client: &S3Client,
+    config: &S3Config,
+    failure: &Mutex<Failure>,
+) {
+    loop {
+        let upload = match receiver.lock().unwrap().recv() {
+            Ok(upload) => upload,
+            Err(_) => return,
+        };
+        if failure.lock().unwrap().failed {
+            continue;
+        }
+        if let Err(e) = upload_object(client, config, &upload) {
+            let target = PathBuf::from(format!("s3://{}/{}", config.bucket, upload.key));
+            error!("Could not upload {}: {}", target.display(), e);
+            let mut failure = failure.lock().unwrap();
+            if !failure.failed {
+                failure.failed = true;
+                failure.error = Some(TilingError::OutputNotWritable {
+                    path: target,
+                    source: e,
+                });
+            }
+        }
+    }
+}
+
+fn upload_object(client: &S3Client, config: &S3Config, upload: &Upload) -> io::Result<()> {
+    let headers = ObjectHeaders {
+        content_type: upload.content_type,
+        cache_control: config.cache_control.as_deref(),
+    };
+    if upload.data.len() <= config.multipart_threshold {
+        return client.put_object(&upload.key, &upload.data, &headers);
+    }
+
+    debug!(
+        "Uploading {} ({} bytes) in parts",
+        upload.key,
+        upload.data.len()
+    );
+    let upload_id = client.create_multipart_upload(&upload.key, &headers)?;
+    let etags: io::Result<Vec<String>> = upload
+        .data
+        .chunks(config.part_size)
+        .enumerate()
+        .map(|(i, part)| client.upload_part(&upload.key, &upload_id, i + 1, part))
+        .collect();
+    let result =
+        etags.and_then(|etags| client.complete_multipart_upload(&upload.key, &upload_id, &etags));
+    if result.is_err() {
+        if let Err(e) = client.abort_multipart_upload(&upload.key, &upload_id) {
+            error!("Could not abort the upload of {}: {}", upload.key, e);
+        }
+    }
+    result
+}
+
+impl TileSink for S3Sink {
+    fn put_tile(&self, tile: &TileKey, data: &[u8]) -> Result<(), TilingError> {
+        self.enqueue(&tile.required_path()?, tile.format.mime_type(), data)
+    }
+
+    fn put_metadata(&self, name: &str, data: &[u8]) -> Result<(), TilingError> {
+        self.enqueue(name, content_type(name), data)
+    }
+
+    /// Waits for all uploads to complete.
+    fn finalize(&self) -> Result<(), TilingError> {
+        if self.queue.lock().unwrap().is_none() {
+            return Err(finalized());
+        }
+        self.stop_workers();
+        self.check_failed()
+    }
+}
+
+impl Drop for S3Sink {
+    fn drop(&mut self) {
+        self.stop_workers();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::Path;
+
+    use super::super::http_transport::{HttpRequest, HttpResponse, Url};
+    use super::*;
+    use crate::image::ImageFormat;
+    use crate::tiling_scheme::TilingScheme;
+
+    /// A stored object with its Content-Type and Cache-Control
+    struct Object {
+        data: Vec<u8>,
+        content_type: String,
+        cache_control: Option<String>,
+    }
+
+    /// A multipart upload in progress: the key and headers of the object
+    /// and its parts, by part number
+    struct MultipartUpload {
+        key: String,
+        object: Object,
+        parts: BTreeMap<usize, Vec<u8>>,
+    }
+
+    #[derive(Default)]
+    struct Storage {
+        objects: HashMap<String, Object>,
+
+        /// The multipart uploads in progress, by upload id
+        uploads: HashMap<String, MultipartUpload>,
+
+        /// Every request received, as "METHOD path?query"
+        requests: Vec<String>,
+
+        /// Requests containing the pattern fail with the status (0 for a
+        /// connection error) as long as the count is positive
+        failures: Vec<(String, u16, usize)>,
+    }
+
+    /// A stand-in for an S3-compatible service like MinIO, keeping the
+    /// objects of a bucket in memory
+    #[derive(Default)]
+    struct MockStorage {
+        storage: Mutex<Storage>,
+    }
+
+    impl MockStorage {
+        fn fail(&self, pattern: &str, status: u16, times: usize) {
+            let mut storage = self.storage.lock().unwrap();
+            storage.failures.push((pattern.to_string(), status, times));
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.storage.lock().unwrap().requests.clone()
+        }
+
+        fn count(&self, pattern: &str) -> usize {
+            self.requests()
+                .iter()
+                .filter(|r| r.contains(pattern))
+                .count()
+        }
+    }
+
+    fn response(status: u16, headers: Vec<(String, String)>, body: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn header(request: &HttpRequest, name: &str) -> Option<String> {
+        request
+            .headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    impl HttpTransport for MockStorage {
+        fn send(&self, request: &HttpRequest) -> io::Result<HttpResponse> {
+            let url = Url::parse(&request.url)?;
+            let line = format!("{} {}", request.method, url.path);
+            let mut storage = self.storage.lock().unwrap();
+            storage.requests.push(line.clone());
+            assert!(header(request, "authorization")
+                .unwrap()
+                .starts_with("AWS4-HMAC-SHA256 Credential=minio/"));
+
+            let failure = storage
+                .failures
+                .iter_mut()
+                .find(|(pattern, _, times)| *times > 0 && line.contains(pattern.as_str()));
+            if let Some((_, status, times)) = failure {
+                *times -= 1;
+                return match *status {
+                    0 => Err(io::ErrorKind::ConnectionReset.into()),
+                    status => Ok(response(
+                        status,
+                        Vec::new(),
+                        "<Error><Code>Injected</Code></Error>",
+                    )),
+                };
+            }
+
+            let (path, query) = url.path.split_once('?').unwrap_or((&url.path, ""));
+            let key = path.strip_prefix("/tiles/").unwrap().to_string();
+            let params: HashMap<&str, &str> = query
+                .split('&')
+                .filter_map(|param| param.split_once('='))
+                .collect();
+            let upload_id = params.get("uploadId").map(|id| id.to_string());
+            let object = || Object {
+                data: request.body.clone(),
+                content_type: header(request, "content-type").unwrap(),
+                cache_control: header(request, "cache-control"),
+            };
+
+            Ok(match (request.method.as_str(), upload_id) {
+                ("PUT", None) => {
+                    storage.objects.insert(key, object());
+                    response(200, Vec::new(), "")
+                }
+                ("POST", None) => {
+                    let id = format!("upload-{}", storage.uploads.len());
+                    let upload = MultipartUpload {
+                        key,
+                        object: object(),
+                        parts: BTreeMap::new(),
+                    };
+                    storage.uploads.insert(id.clone(), upload);
+                    let body = format!(
+                        "<InitiateMultipartUploadResult><UploadId>{}</UploadId>\
+                         </InitiateMultipartUploadResult>",
+                        id
+                    );
+                    response(200, Vec::new(), &body)
+                }
+                ("PUT", Some(id)) => {
+                    let part = params["partNumber"].parse().unwrap();
+                    let upload = storage.uploads.get_mut(&id).unwrap();
+                    upload.parts.insert(part, request.body.clone());
+                    let etag = vec![("ETag".to_string(), format!("\"etag-{}\"", part))];
+                    response(200, etag, "")
+                }
+                ("POST", Some(id)) => {
+                    let mut upload = storage.uploads.remove(&id).unwrap();
+                    let body = String::from_utf8(request.body.clone()).unwrap();
+                    for part in upload.parts.keys() {
+                        let etag = format!("<ETag>\"etag-{}\"</ETag>", part);
+                        assert!(body.contains(&etag), "{} is missing", etag);
+                    }
+                    upload.object.data = upload.parts.into_values().flatten().collect();
+                    storage.objects.insert(upload.key, upload.object);
+                    response(200, Vec::new(), "<CompleteMultipartUploadResult/>")
+                }
+                ("DELETE", Some(id)) => {
+                    storage.uploads.remove(&id);
+                    response(204, Vec::new(), "")
+                }
+                _ => response(400, Vec::new(), "<Error><Code>BadRequest</Code></Error>"),
+            })
+        }
+    }
+
+    fn config() -> S3Config {
+        let mut config = S3Config::new(
+            "http://localhost:9000",
+            "tiles",
+            S3Credentials::new("minio", "minio123"),
+        );
+        config.prefix = "/tilesets/image/".to_string();
+        config.cache_control = Some("max-age=86400".to_string());
+        config.concurrency = 2;
+        config.max_retries = 2;
+        config
+    }
+
+    fn tile(column: i32) -> TileKey {
+        TileKey::new(TilingScheme::Tms, 1, column, 0, ImageFormat::PNG)
+    }
+
+    #[test]
+    fn tiles_and_metadata_are_uploaded_below_the_prefix() {
+        let storage = Arc::new(MockStorage::default());
+        let sink = S3Sink::with_transport(config(), storage.clone()).unwrap();
+        sink.put_tile(&tile(0), b"tile 0").unwrap();
+        sink.put_tile(&tile(1), b"tile 1").unwrap();
+        sink.put_metadata("tilemapresource.xml", b"<TileMap/>")
+            .unwrap();
+        sink.finalize().unwrap();
+        assert!(sink.put_tile(&tile(0), b"tile 0").is_err());
+
+        let storage = storage.storage.lock().unwrap();
+        let mut keys: Vec<&String> = storage.objects.keys().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "tilesets/image/1/0/0.png",
+                "tilesets/image/1/1/0.png",
+                "tilesets/image/tilemapresource.xml"
+            ]
+        );
+        let tile = &storage.objects["tilesets/image/1/1/0.png"];
+        assert_eq!(tile.data, b"tile 1");
+        assert_eq!(tile.content_type, "image/png");
+        assert_eq!(tile.cache_control.as_deref(), Some("max-age=86400"));
+        let xml = &storage.objects["tilesets/image/tilemapresource.xml"];
+        assert_eq!(xml.content_type, "application/xml");
+    }
+
+    #[test]
+    fn large_files_are_uploaded_in_parts() {
+        let storage = Arc::new(MockStorage::default());
+        let mut config = config();
+        config.multipart_threshold = 1024;
+        // Raised to the 5 MiB minimum
+        config.part_size = 1;
+        let sink = S3Sink::with_transport(config, storage.clone()).unwrap();
+        let data: Vec<u8> = (0..2 * MIN_PART_SIZE + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        sink.put_tile(&tile(0), &data).unwrap();
+        sink.put_tile(&tile(1), b"small").unwrap();
+        sink.finalize().unwrap();
+
+        assert_eq!(storage.count("?uploads"), 1);
+        assert_eq!(storage.count("partNumber="), 3);
+        assert_eq!(
+            storage.count("POST /tiles/tilesets/image/1/0/0.png?uploadId="),
+            1
+        );
+        assert_eq!(storage.count("PUT /tiles/tilesets/image/1/1/0.png"), 1);
+        let storage = storage.storage.lock().unwrap();
+        let object = &storage.objects["tilesets/image/1/0/0.png"];
+        assert!(object.data == data);
+        assert_eq!(object.content_type, "image/png");
+        assert_eq!(object.cache_control.as_deref(), Some("max-age=86400"));
+        assert!(storage.uploads.is_empty());
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let storage = Arc::new(MockStorage::default());
+        storage.fail("1/0/0.png", 503, 1);
+        storage.fail("1/0/0.png", 0, 1);
+        let sink = S3Sink::with_transport(config(), storage.clone()).unwrap();
+        sink.put_tile(&tile(0), b"tile 0").unwrap();
+        sink.finalize().unwrap();
+
+        assert_eq!(storage.count("PUT /tiles/tilesets/image/1/0/0.png"), 3);
+        let storage = storage.storage.lock().unwrap();
+        assert_eq!(storage.objects["tilesets/image/1/0/0.png"].data, b"tile 0");
+    }
+
+    #[test]
+    fn uploads_fail_once_the_retries_are_exhausted() {
+        let storage = Arc::new(MockStorage::default());
+        storage.fail("1/0/0.png", 500, 3);
+        let sink = S3Sink::with_transport(config(), storage.clone()).unwrap();
+        sink.put_tile(&tile(0), b"tile 0").unwrap();
+        let err = sink.finalize().unwrap_err();
+
+        assert_eq!(
+            err.path(),
+            Some(Path::new("s3://tiles/tilesets/image/1/0/0.png"))
+        );
+        assert_eq!(storage.count("1/0/0.png"), 3);
+    }
+
+    #[test]
+    fn failed_multipart_uploads_are_aborted() {
+        let storage = Arc::new(MockStorage::default());
+        storage.fail("partNumber=2", 403, 1);
+        let mut config = config();
+        config.multipart_threshold = 1024;
+        let sink = S3Sink::with_transport(config, storage.clone()).unwrap();
+        sink.put_tile(&tile(0), &vec![0; 2 * MIN_PART_SIZE])
+            .unwrap();
+        assert!(matches!(
+            sink.finalize(),
+            Err(TilingError::OutputNotWritable { .. })
+        ));
+
+        // Client errors are not retried
+        assert_eq!(storage.count("partNumber=2"), 1);
+        assert_eq!(
+            storage.count("DELETE /tiles/tilesets/image/1/0/0.png?uploadId="),
+            1
+        );
+        let storage = storage.storage.lock().unwrap();
+        assert!(storage.uploads.is_empty());
+        assert!(storage.objects.is_empty());
+    }
+
+    #[test]
+    fn multipart_uploads_are_not_repeated_after_connection_errors() {
+        let storage = Arc::new(MockStorage::default());
+        storage.fail("?uploads", 0, 1);
+        let mut config = config();
+        config.multipart_threshold = 1024;
+        let sink = S3Sink::with_transport(config, storage.clone()).unwrap();
+        sink.put_tile(&tile(0), &vec![0; 2 * MIN_PART_SIZE])
+            .unwrap();
+        assert!(sink.finalize().is_err());
+        assert_eq!(storage.count("?uploads"), 1);
+        assert!(storage.storage.lock().unwrap().objects.is_empty());
+    }
+
+    #[test]
+    fn multipart_uploads_are_not_completed_twice() {
+        let storage = Arc::new(MockStorage::default());
+        storage.fail("POST /tiles/tilesets/image/1/0/0.png?uploadId=", 0, 1);
+        let mut config = config();
+        config.multipart_threshold = 1024;
+        let sink = S3Sink::with_transport(config, storage.clone()).unwrap();
+        sink.put_tile(&tile(0), &vec![0; 2 * MIN_PART_SIZE])
+            .unwrap();
+        assert!(sink.finalize().is_err());
+
+        // The failed completion may have gone through, so it is not sent
+        // again; the upload is aborted instead
+        assert_eq!(
+            storage.count("POST /tiles/tilesets/image/1/0/0.png?uploadId="),
+            1
+        );
+        assert_eq!(
+            storage.count("DELETE /tiles/tilesets/image/1/0/0.png?uploadId="),
+            1
+        );
+    }
+
+    #[test]
+    fn http_and_https_endpoints_are_supported() {
+        let mut config = config();
+        config.endpoint = "https://s3.eu-west-1.amazonaws.com".to_string();
+        assert!(S3Sink::new(config.clone()).is_ok());
+        config.endpoint = "localhost:9000".to_string();
+        assert!(S3Sink::new(config).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tar::{Builder, EntryType, Header};
+
+use super::tile_sink::{finalized, TileKey, TileSink};
+use crate::magick_tiler::TilingError;
+
+/// Writes a tileset into an uncompressed tar archive, laid out like the
+/// directory tree a `FileSystemSink` would write. Entries are appended as
+/// they are put, so the archive can be streamed; a tile put twice replaces
+/// the first one when the archive is extracted.
+pub struct TarSink {
+    path: PathBuf,
+
+    /// The archive being written, `None` once it is finalized
+    tar: Mutex<Option<Builder<BufWriter<File>>>>,
+
+    /// Modification time recorded for every entry
+    mtime: u64,
+}
+
+impl TarSink {
+    /// Creates the archive, replacing an existing one.
+    pub fn create(path: &Path) -> Result<Self, TilingError> {
+        let file = File::create(path).map_err(TilingError::not_writable(path))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            tar: Mutex::new(Some(Builder::new(BufWriter::new(file)))),
+            mtime: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a regular file. Names that do not fit into the header are
+    /// written as a GNU long name entry first.
+    fn write_entry(&self, name: &str, data: &[u8]) -> Result<(), TilingError> {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.set_mtime(self.mtime);
+
+        let mut tar = self.tar.lock().unwrap();
+        let tar = tar.as_mut().ok_or_else(finalized)?;
+        tar.append_data(&mut header, name, data)
+            .map_err(TilingError::not_writable(&self.path))
+    }
+}
+
+impl TileSink for TarSink {
+    fn put_tile(&self, tile: &TileKey, data: &[u8]) -> Result<(), TilingError> {
+        self.write_entry(&tile.required_path()?, data)
+    }
+
+    fn put_metadata(&self, name: &str, data: &[u8]) -> Result<(), TilingError> {
+        self.write_entry(name, data)
+    }
+
+    /// Writes the two empty blocks that end a tar archive.
+    fn finalize(&self) -> Result<(), TilingError> {
+        let Some(tar) = self.tar.lock().unwrap().take() else {
+            return Err(finalized());
+        };
+        tar.into_inner()
+            .and_then(|mut file| file.flush())
+            .map_err(TilingError::not_writable(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::image::ImageFormat;
+    use crate::tiling_scheme::TilingScheme;
+
+    /// Size of the header and data blocks of a tar archive
+    const BLOCK_SIZE: usize = 512;
+
+    fn entries(path: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut archive = tar::Archive::new(File::open(path).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                assert!(entry.header().entry_type().is_file());
+                assert_eq!(entry.header().mode().unwrap(), 0o644);
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (name, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn archives_hold_the_tileset_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.tar");
+        let sink = TarSink::create(&path).unwrap();
+        let key = TileKey::new(TilingScheme::Tms, 2, 3, 1, ImageFormat::PNG);
+        sink.put_tile(&key, b"tile").unwrap();
+        sink.put_metadata("tilemapresource.xml", &[b'x'; BLOCK_SIZE])
+            .unwrap();
+        sink.put_metadata("empty.txt", b"").unwrap();
+        sink.finalize().unwrap();
+        assert!(sink.put_tile(&key, b"tile").is_err());
+        assert!(sink.finalize().is_err());
+
+        assert_eq!(
+            entries(&path),
+            vec![
+                ("2/3/1.png".to_string(), b"tile".to_vec()),
+                ("tilemapresource.xml".to_string(), vec![b'x'; BLOCK_SIZE]),
+                ("empty.txt".to_string(), Vec::new()),
+            ]
+        );
+        // Three headers, one padded data block each for the tile and the
+        // exactly block-sized XML, none for the empty file, two end blocks
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            7 * BLOCK_SIZE as u64
+        );
+    }
+
+    #[test]
+    fn long_names_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.tar");
+        let sink = TarSink::create(&path).unwrap();
+        // Longer than the 100 bytes of the name field, and too long to be
+        // split into the ustar prefix and name
+        let long_dir = format!("{}/{}.html", "d".repeat(120), "p".repeat(90));
+        let long_file = format!("{}.xml", "n".repeat(300));
+        sink.put_metadata(&long_dir, b"preview").unwrap();
+        sink.put_metadata(&long_file, b"").unwrap();
+        sink.finalize().unwrap();
+
+        assert_eq!(
+            entries(&path),
+            vec![(long_dir, b"preview".to_vec()), (long_file, Vec::new())]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use std::fs;
+use std::path::Path;
+
+use log::error;
+
+use crate::image::ImageFormat;
+use crate::magick_tiler::TilingError;
+use crate::tiling_scheme::TilingScheme;
+use crate::zoomify::TILEGROUP;
+
+/// The position of a tile in a tileset: its tiling scheme, zoom level,
+/// column and row, and the format it is encoded in. Zoom levels, columns and
+/// rows are numbered the way the scheme numbers them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileKey {
+    pub scheme: TilingScheme,
+    pub zoom_level: i32,
+    pub column: i32,
+    pub row: i32,
+
+    /// The Zoomify tile group the tile belongs to, 0 for other schemes
+    pub tile_group: i32,
+    pub format: ImageFormat,
+}
+
+impl TileKey {
+    pub fn new(
+        scheme: TilingScheme,
+        zoom_level: i32,
+        column: i32,
+        row: i32,
+        format: ImageFormat,
+    ) -> Self {
+        Self {
+            scheme,
+            zoom_level,
+            column,
+            row,
+            tile_group: 0,
+            format,
+        }
+    }
+
+    pub fn with_tile_group(mut self, tile_group: i32) -> Self {
+        self.tile_group = tile_group;
+        self
+    }
+
+    /// The path of the tile relative to the tileset root, with '/' as
+    /// separator, in the layout of its tiling scheme:
+    /// - TMS: [zoomlevel]/[column]/[row].jpg
+    /// - Google Maps: [zoomlevel]_[column]_[row].jpg
+    /// - Zoomify: TileGroup[group-no]/[zoomlevel]-[column]-[row].jpg
+    ///
+    /// Returns `None` for schemes whose tiles are not written through a
+    /// `TileSink`.
+    pub fn path(&self) -> Option<String> {
+        let (z, x, y, ext) = (
+            self.zoom_level,
+            self.column,
+            self.row,
+            self.format.extension(),
+        );
+        match self.scheme {
+            TilingScheme::Tms => Some(format!("{}/{}/{}.{}", z, x, y, ext)),
+            TilingScheme::GoogleMaps => Some(format!("{}_{}_{}.{}", z, x, y, ext)),
+            TilingScheme::Zoomify => Some(format!(
+                "{}{}/{}-{}-{}.{}",
+                TILEGROUP, self.tile_group, z, x, y, ext
+            )),
+            TilingScheme::DeepZoom | TilingScheme::IIIF | TilingScheme::PMTiles => None,
+        }
+    }
+
+    /// Parses a tile path in the layout of `scheme` (see `path`). Returns
+    /// `None` if the path does not name a tile of the scheme.
+    pub fn from_path(scheme: TilingScheme, path: &str) -> Option<Self> {
+        let (name, ext) = path.rsplit_once('.')?;
+        let format = ImageFormat::from_extension(ext)?;
+        let (tile_group, name) = match scheme {
+            TilingScheme::Zoomify => {
+                let (group, name) = name.strip_prefix(TILEGROUP)?.split_once('/')?;
+                (parse_number(group)?, name)
+            }
+            _ => (0, name),
+        };
+        let separator = match scheme {
+            TilingScheme::Tms => '/',
+            TilingScheme::GoogleMaps => '_',
+            TilingScheme::Zoomify => '-',
+            TilingScheme::DeepZoom | TilingScheme::IIIF | TilingScheme::PMTiles => return None,
+        };
+        let numbers = name
+            .split(separator)
+            .map(parse_number)
+            .collect::<Option<Vec<i32>>>()?;
+        match numbers[..] {
+            [z, x, y] => Some(Self::new(scheme, z, x, y, format).with_tile_group(tile_group)),
+            _ => None,
+        }
+    }
+
+    /// Like `path`, but fails for schemes without a tile layout.
+    pub(crate) fn required_path(&self) -> Result<String, TilingError> {
+        self.path().ok_or_else(|| {
+            TilingError::general(format!(
+                "{} tiles cannot be written to a tile sink",
+                self.scheme
+            ))
+        })
+    }
+}
+
+/// Parses a non-negative decimal number without sign or leading zeros
+fn parse_number(s: &str) -> Option<i32> {
+    let valid = s.bytes().all(|b| b.is_ascii_digit()) && (s == "0" || !s.starts_with('0'));
+    valid.then(|| s.parse().ok()).flatten()
+}
+
+/// The Content-Type of a file in a tileset, by its extension
+pub(crate) fn content_type(name: &str) -> &'static str {
+    let extension = Path::new(name)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if let Some(format) = ImageFormat::from_extension(&extension) {
+        return format.mime_type();
+    }
+    match extension.as_str() {
+        "xml" | "dzi" => "application/xml",
+        "html" | "htm" => "text/html; charset=utf-8",
+        // gmap_tileset.info holds JSON
+        "json" | "info" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The error sinks return when something is put after `finalize`
+pub(crate) fn finalized() -> TilingError {
+    TilingError::general("The tileset was already finalized".to_string())
+}
+
+/// The destination tilers write a tileset to. A sink receives the encoded
+/// tiles by tiling scheme, zoom level, column and row, and the metadata
+/// files (like tilemapresource.xml or preview.html) by name, and lays them
+/// out in the scheme's structure: as a directory tree (`FileSystemSink`),
+/// inside a ZIP or tar archive (`ZipSink`, `TarSink`) or in memory
+/// (`MemorySink`).
+///
+/// Tiles may be put concurrently by several worker threads and in any order.
+/// Tilers call `finalize` once the tileset is complete; nothing can be put
+/// afterwards.
+pub trait TileSink: Send + Sync {
+    /// Stores an encoded tile. A tile put twice replaces the first one
+    /// where the sink allows it.
+    fn put_tile(&self, tile: &TileKey, data: &[u8]) -> Result<(), TilingError>;
+
+    /// Stores a tile that was written to a file, e.g. by the image
+    /// processor, and removes the file, even if the tile could not be stored.
+    fn put_tile_file(&self, tile: &TileKey, file: &Path) -> Result<(), TilingError> {
+        let data = fs::read(file).map_err(|source| TilingError::InputNotReadable {
+            path: file.to_path_buf(),
+            source,
+        })?;
+        let result = self.put_tile(tile, &data);
+        if let Err(e) = fs::remove_file(file) {
+            error!("Could not delete {}: {}", file.display(), e);
+        }
+        result
+    }
+
+    /// Stores a metadata file at a path relative to the tileset root
+    fn put_metadata(&self, name: &str, data: &[u8]) -> Result<(), TilingError>;
+
+    /// Completes the tileset, e.g. by writing the index of an archive
+    fn finalize(&self) -> Result<(), TilingError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_paths_round_trip() {
+        let keys = [
+            (
+                TileKey::new(TilingScheme::Tms, 3, 5, 0, ImageFormat::JPEG),
+                "3/5/0.jpg",
+            ),
+            (
+                TileKey::new(TilingScheme::GoogleMaps, 10, 0, 1023, ImageFormat::PNG),
+                "10_0_1023.png",
+            ),
+            (
+                TileKey::new(TilingScheme::Zoomify, 2, 3, 1, ImageFormat::JPEG).with_tile_group(4),
+                "TileGroup4/2-3-1.jpg",
+            ),
+        ];
+        for (key, path) in keys {
+            assert_eq!(key.path().as_deref(), Some(path));
+            assert_eq!(TileKey::from_path(key.scheme, path), Some(key));
+        }
+
+        let key = TileKey::new(TilingScheme::DeepZoom, 0, 0, 0, ImageFormat::JPEG);
+        assert_eq!(key.path(), None);
+        assert!(key.required_path().is_err());
+    }
+
+    #[test]
+    fn other_paths_are_not_tiles() {
+        for path in [
+            "tilemapresource.xml",
+            "3/5.jpg",
+            "3/5/0/1.jpg",
+            "3/05/0.jpg",
+            "3/-5/0.jpg",
+            "3/5/0.txt",
+        ] {
+            assert_eq!(
+                TileKey::from_path(TilingScheme::Tms, path),
+                None,
+                "{}",
+                path
+            );
+        }
+        assert_eq!(TileKey::from_path(TilingScheme::Zoomify, "2-3-1.jpg"), None);
+        assert_eq!(TileKey::from_path(TilingScheme::IIIF, "0_0_0.jpg"), None);
+    }
+
+    #[test]
+    fn content_types_follow_the_extension() {
+        assert_eq!(content_type("0/0/0.jpg"), "image/jpeg");
+        assert_eq!(content_type("tilemapresource.xml"), "application/xml");
+        assert_eq!(content_type("preview.html"), "text/html; charset=utf-8");
+        assert_eq!(content_type("gmap_tileset.info"), "application/json");
+        assert_eq!(content_type("README"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use super::tile_sink::{finalized, TileKey, TileSink};
+use crate::magick_tiler::TilingError;
+
+/// Writes a tileset into a ZIP archive, laid out like the directory tree a
+/// `FileSystemSink` would write. Tiles are stored as they are, since tile
+/// images are compressed already; metadata files are deflated.
+pub struct ZipSink {
+    path: PathBuf,
+
+    /// The archive being written, `None` once it is finalized
+    zip: Mutex<Option<ZipWriter<BufWriter<File>>>>,
+}
+
+impl ZipSink {
+    /// Creates the archive, replacing an existing one.
+    pub fn create(path: &Path) -> Result<Self, TilingError> {
+        let file = File::create(path).map_err(TilingError::not_writable(path))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            zip: Mutex::new(Some(ZipWriter::new(BufWriter::new(file)))),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write_entry(
+        &self,
+        name: &str,
+        data: &[u8],
+        method: CompressionMethod,
+    ) -> Result<(), TilingError> {
+        let mut zip = self.zip.lock().unwrap();
+        let zip = zip.as_mut().ok_or_else(finalized)?;
+        zip.start_file(name, FileOptions::default().compression_method(method))
+            .map_err(io::Error::from)
+            .and_then(|_| zip.write_all(data))
+            .map_err(TilingError::not_writable(&self.path))
+    }
+}
+
+impl TileSink for ZipSink {
+    fn put_tile(&self, tile: &TileKey, data: &[u8]) -> Result<(), TilingError> {
+        self.write_entry(&tile.required_path()?, data, CompressionMethod::Stored)
+    }
+
+    fn put_metadata(&self, name: &str, data: &[u8]) -> Result<(), TilingError> {
+        self.write_entry(name, data, CompressionMethod::Deflated)
+    }
+
+    /// Writes the central directory of the archive.
+    fn finalize(&self) -> Result<(), TilingError> {
+        let Some(mut zip) = self.zip.lock().unwrap().take() else {
+            return Err(finalized());
+        };
+        zip.finish()
+            .map_err(io::Error::from)
+            .and_then(|mut file| file.flush())
+            .map_err(TilingError::not_writable(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::image::ImageFormat;
+    use crate::tiling_scheme::TilingScheme;
+
+    #[test]
+    fn archives_hold_the_tileset_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.zip");
+        let sink = ZipSink::create(&path).unwrap();
+        let key = TileKey::new(TilingScheme::Zoomify, 1, 2, 0, ImageFormat::JPEG);
+        sink.put_tile(&key, b"tile").unwrap();
+        sink.put_metadata("ImageProperties.xml", &[b'x'; 1000])
+            .unwrap();
+        sink.finalize().unwrap();
+        assert!(sink.put_tile(&key, b"tile").is_err());
+        assert!(sink.finalize().is_err());
+
+        let mut zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.len(), 2);
+        let mut entry = zip.by_name("TileGroup0/1-2-0.jpg").unwrap();
+        assert_eq!(entry.compression(), CompressionMethod::Stored);
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"tile");
+        drop(entry);
+
+        let mut entry = zip.by_name("ImageProperties.xml").unwrap();
+        assert_eq!(entry.compression(), CompressionMethod::Deflated);
+        assert!(entry.compressed_size() < 1000);
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [b'x'; 1000]);
+    }
+}
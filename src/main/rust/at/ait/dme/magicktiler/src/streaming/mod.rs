@@ -0,0 +1,5 @@
+mod row_source;
+mod streaming_pyramid;
+
+pub use row_source::{open_row_source, PngSource, RawSource, RowSource, TiffSource};
+pub use streaming_pyramid::{LevelLayout, StreamingPyramid, DEFAULT_MEMORY_BUDGET};
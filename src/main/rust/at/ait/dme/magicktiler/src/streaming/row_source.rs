@@ -0,0 +1,517 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+use png::Transformations;
+use tiff::decoder::{ChunkType, Decoder as TiffDecoder, DecodingResult, Limits};
+use tiff::tags::Tag;
+use tiff::{ColorType, TiffError};
+
+use crate::image::ImageProcessor;
+use crate::magick_tiler::TilingError;
+
+/// A source image that is read row by row, from top to bottom, so that it
+/// never has to be held in memory as a whole.
+pub trait RowSource {
+    /// Width and height of the image
+    fn dimensions(&self) -> (u32, u32);
+
+    /// (Re)starts reading at the top row. Subsequent reads only return the
+    /// columns `x` to `x + width` of each row.
+    fn start(&mut self, x: u32, width: u32) -> Result<(), TilingError>;
+
+    /// Reads the next row as 8-bit RGBA pixels into `row`, which holds
+    /// exactly `width` pixels of the range passed to `start`
+    fn read_row(&mut self, row: &mut [u8]) -> Result<(), TilingError>;
+}
+
+/// Opens an image for streaming. Strip- and tile-organised TIFFs and
+/// non-interlaced PNGs are decoded incrementally. Any other image is first
+/// converted into an uncompressed TIFF in the working directory by the image
+/// processor. GraphicsMagick and ImageMagick manage the memory of the
+/// conversion themselves; the native image processor decodes the whole image,
+/// so the conversion fails with an error if the decoded image exceeds
+/// `memory_budget` bytes.
+pub fn open_row_source(
+    image: &Path,
+    processor: &dyn ImageProcessor,
+    working_directory: &Path,
+    memory_budget: usize,
+) -> Result<Box<dyn RowSource>, TilingError> {
+    let mut magic = [0u8; 4];
+    File::open(image)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map_err(|source| TilingError::InputNotReadable {
+            path: image.to_path_buf(),
+            source,
+        })?;
+
+    let result = match &magic {
+        b"II*\0" | b"MM\0*" => TiffSource::open(image).map(|s| Box::new(s) as Box<dyn RowSource>),
+        [0x89, b'P', b'N', b'G'] => {
+            PngSource::open(image).map(|s| Box::new(s) as Box<dyn RowSource>)
+        }
+        _ => Err(TilingError::UnsupportedFormat {
+            path: image.to_path_buf(),
+            reason: "No streaming decoder for this format".to_string(),
+        }),
+    };
+
+    match result {
+        Err(TilingError::UnsupportedFormat { reason, .. }) => {
+            info!(
+                "Cannot stream {} ({}), converting it to TIFF first",
+                image.display(),
+                reason
+            );
+            let (width, height) = processor.get_dimensions(image)?;
+            let decoded = width as u64 * height as u64 * 4;
+            if processor.get_image_processing_system() == "Native" && decoded > memory_budget as u64
+            {
+                return Err(TilingError::general_at(
+                    image,
+                    format!(
+                        "Cannot stream {} ({}): converting it would decode {} bytes, \
+                         more than the memory budget of {} bytes. Convert it to a \
+                         TIFF or non-interlaced PNG first, or use GraphicsMagick.",
+                        image.display(),
+                        reason,
+                        decoded,
+                        memory_budget
+                    ),
+                ));
+            }
+            let stem = image
+                .file_stem()
+                .map_or("image".into(), |stem| stem.to_string_lossy());
+            let converted = working_directory.join(format!("{}-stream.tif", stem));
+            processor.crop_region(image, &converted, 0, 0, width, height)?;
+            let mut source = TiffSource::open(&converted)?;
+            source.temporary = true;
+            Ok(Box::new(source))
+        }
+        result => result,
+    }
+}
+
+fn read_error(path: &Path, e: TiffError) -> TilingError {
+    match e {
+        TiffError::IoError(source) => TilingError::InputNotReadable {
+            path: path.to_path_buf(),
+            source,
+        },
+        e => TilingError::UnsupportedFormat {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        },
+    }
+}
+
+fn png_read_error(path: &Path, e: png::DecodingError) -> TilingError {
+    match e {
+        png::DecodingError::IoError(source) => TilingError::InputNotReadable {
+            path: path.to_path_buf(),
+            source,
+        },
+        e => TilingError::UnsupportedFormat {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        },
+    }
+}
+
+/// Expands pixels of 1 (gray), 2 (gray + alpha), 3 (RGB) or 4 (RGBA) 8-bit
+/// samples into RGBA.
+fn expand_to_rgba(samples: &[u8], channels: usize, rgba: &mut [u8]) {
+    for (src, dst) in samples.chunks_exact(channels).zip(rgba.chunks_exact_mut(4)) {
+        let pixel = match *src {
+            [g] => [g, g, g, 255],
+            [g, a] => [g, g, g, a],
+            [r, g, b] => [r, g, b, 255],
+            [r, g, b, a] => [r, g, b, a],
+            _ => unreachable!(),
+        };
+        dst.copy_from_slice(&pixel);
+    }
+}
+
+/// Streams a strip- or tile-organised TIFF. Only one row of strips or tiles
+/// (restricted to the requested columns) is decoded at a time.
+pub struct TiffSource {
+    path: PathBuf,
+    decoder: TiffDecoder<BufReader<File>>,
+    channels: usize,
+    width: u32,
+    height: u32,
+    chunk_type: ChunkType,
+    chunk_width: u32,
+    chunk_height: u32,
+
+    /// The columns returned by `read_row`
+    x: u32,
+    window: u32,
+
+    /// The next row returned by `read_row`
+    y: u32,
+
+    /// RGBA pixels of the requested columns of the current row of chunks
+    band: Vec<u8>,
+    band_start: u32,
+    band_rows: u32,
+
+    /// Delete the file once the source is dropped
+    temporary: bool,
+}
+
+impl TiffSource {
+    pub fn open(path: &Path) -> Result<Self, TilingError> {
+        let file = File::open(path).map_err(|source| TilingError::InputNotReadable {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let mut decoder = TiffDecoder::new(BufReader::new(file))
+            .map_err(|e| read_error(path, e))?
+            .with_limits(Limits::unlimited());
+
+        let unsupported = |reason: String| TilingError::UnsupportedFormat {
+            path: path.to_path_buf(),
+            reason,
+        };
+        let channels = match decoder.colortype().map_err(|e| read_error(path, e))? {
+            ColorType::Gray(8 | 16) => 1,
+            ColorType::GrayA(8 | 16) => 2,
+            ColorType::RGB(8 | 16) => 3,
+            ColorType::RGBA(8 | 16) => 4,
+            color_type => return Err(unsupported(format!("Color type {:?}", color_type))),
+        };
+        let planar = decoder
+            .find_tag_unsigned::<u16>(Tag::PlanarConfiguration)
+            .map_err(|e| read_error(path, e))?;
+        if planar == Some(2) {
+            return Err(unsupported("Planar sample layout".to_string()));
+        }
+
+        let (width, height) = decoder.dimensions().map_err(|e| read_error(path, e))?;
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+        Ok(Self {
+            path: path.to_path_buf(),
+            chunk_type: decoder.get_chunk_type(),
+            decoder,
+            channels,
+            width,
+            height,
+            chunk_width,
+            chunk_height,
+            x: 0,
+            window: width,
+            y: 0,
+            band: Vec::new(),
+            band_start: 0,
+            band_rows: 0,
+            temporary: false,
+        })
+    }
+
+    /// Decodes a chunk into 8-bit samples
+    fn read_chunk(&mut self, index: u32) -> Result<Vec<u8>, TilingError> {
+        match self
+            .decoder
+            .read_chunk(index)
+            .map_err(|e| read_error(&self.path, e))?
+        {
+            DecodingResult::U8(samples) => Ok(samples),
+            DecodingResult::U16(samples) => Ok(samples.iter().map(|s| (s >> 8) as u8).collect()),
+            _ => Err(TilingError::UnsupportedFormat {
+                path: self.path.clone(),
+                reason: "Unsupported sample format".to_string(),
+            }),
+        }
+    }
+
+    /// Decodes the row of chunks containing row `y`
+    fn load_band(&mut self, y: u32) -> Result<(), TilingError> {
+        let chunk_row = y / self.chunk_height;
+        self.band_start = chunk_row * self.chunk_height;
+        self.band_rows = self.chunk_height.min(self.height - self.band_start);
+        let stride = self.window as usize * 4;
+        self.band.clear();
+        self.band.resize(stride * self.band_rows as usize, 0);
+
+        let chunks = match self.chunk_type {
+            ChunkType::Strip => chunk_row..chunk_row + 1,
+            ChunkType::Tile => {
+                let across = self.width.div_ceil(self.chunk_width);
+                let first = self.x / self.chunk_width;
+                let last = (self.x + self.window - 1) / self.chunk_width;
+                chunk_row * across + first..chunk_row * across + last + 1
+            }
+        };
+        for index in chunks {
+            let (data_width, data_height) = self.decoder.chunk_data_dimensions(index);
+            let chunk_x = match self.chunk_type {
+                ChunkType::Strip => 0,
+                ChunkType::Tile => {
+                    (index % self.width.div_ceil(self.chunk_width)) * self.chunk_width
+                }
+            };
+            let samples = self.read_chunk(index)?;
+
+            // The columns of the chunk that lie in the window
+            let from = self.x.max(chunk_x);
+            let to = (self.x + self.window).min(chunk_x + data_width);
+            if from >= to {
+                continue;
+            }
+            let count = (to - from) as usize;
+            for row in 0..data_height.min(self.band_rows) as usize {
+                let src = (row * data_width as usize + (from - chunk_x) as usize) * self.channels;
+                let dst = row * stride + (from - self.x) as usize * 4;
+                expand_to_rgba(
+                    &samples[src..src + count * self.channels],
+                    self.channels,
+                    &mut self.band[dst..dst + count * 4],
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RowSource for TiffSource {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn start(&mut self, x: u32, width: u32) -> Result<(), TilingError> {
+        self.x = x;
+        self.window = width;
+        self.y = 0;
+        self.band_rows = 0;
+        Ok(())
+    }
+
+    fn read_row(&mut self, row: &mut [u8]) -> Result<(), TilingError> {
+        if self.y < self.band_start || self.y >= self.band_start + self.band_rows {
+            self.load_band(self.y)?;
+        }
+        let stride = self.window as usize * 4;
+        let offset = (self.y - self.band_start) as usize * stride;
+        row.copy_from_slice(&self.band[offset..offset + stride]);
+        self.y += 1;
+        Ok(())
+    }
+}
+
+impl Drop for TiffSource {
+    fn drop(&mut self) {
+        if self.temporary {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("Could not delete {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Streams a non-interlaced PNG, one row at a time. Restarting the source
+/// decodes the file again from the beginning.
+pub struct PngSource {
+    path: PathBuf,
+    reader: png::Reader<BufReader<File>>,
+    channels: usize,
+    width: u32,
+    height: u32,
+    x: u32,
+    window: u32,
+
+    /// Whether rows have been read since the file was opened
+    started: bool,
+}
+
+impl PngSource {
+    pub fn open(path: &Path) -> Result<Self, TilingError> {
+        let reader = Self::reader(path)?;
+        let info = reader.info();
+        if info.interlaced {
+            return Err(TilingError::UnsupportedFormat {
+                path: path.to_path_buf(),
+                reason: "Interlaced PNG".to_string(),
+            });
+        }
+        let (width, height) = (info.width, info.height);
+        Ok(Self {
+            path: path.to_path_buf(),
+            channels: reader.output_color_type().0.samples(),
+            reader,
+            width,
+            height,
+            x: 0,
+            window: width,
+            started: false,
+        })
+    }
+
+    fn reader(path: &Path) -> Result<png::Reader<BufReader<File>>, TilingError> {
+        let file = File::open(path).map_err(|source| TilingError::InputNotReadable {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let mut decoder =
+            png::Decoder::new_with_limits(BufReader::new(file), png::Limits { bytes: usize::MAX });
+        decoder.set_transformations(Transformations::normalize_to_color8());
+        decoder.read_info().map_err(|e| png_read_error(path, e))
+    }
+}
+
+impl RowSource for PngSource {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn start(&mut self, x: u32, width: u32) -> Result<(), TilingError> {
+        if self.started {
+            self.reader = Self::reader(&self.path)?;
+        }
+        self.x = x;
+        self.window = width;
+        self.started = false;
+        Ok(())
+    }
+
+    fn read_row(&mut self, row: &mut [u8]) -> Result<(), TilingError> {
+        self.started = true;
+        let data = self
+            .reader
+            .next_row()
+            .map_err(|e| png_read_error(&self.path, e))?
+            .ok_or_else(|| TilingError::InputNotReadable {
+                path: self.path.clone(),
+                source: io::ErrorKind::UnexpectedEof.into(),
+            })?
+            .data();
+        let from = self.x as usize * self.channels;
+        let to = from + self.window as usize * self.channels;
+        expand_to_rgba(&data[from..to], self.channels, row);
+        Ok(())
+    }
+}
+
+/// Streams raw, uncompressed RGBA rows from a file, e.g. an intermediate
+/// pyramid level spilled to disk.
+pub struct RawSource {
+    path: PathBuf,
+    file: File,
+    width: u32,
+    height: u32,
+    x: u32,
+    window: u32,
+    y: u32,
+}
+
+impl RawSource {
+    pub fn open(path: &Path, width: u32, height: u32) -> Result<Self, TilingError> {
+        let file = File::open(path).map_err(|source| TilingError::InputNotReadable {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            width,
+            height,
+            x: 0,
+            window: width,
+            y: 0,
+        })
+    }
+}
+
+impl RowSource for RawSource {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn start(&mut self, x: u32, width: u32) -> Result<(), TilingError> {
+        self.x = x;
+        self.window = width;
+        self.y = 0;
+        Ok(())
+    }
+
+    fn read_row(&mut self, row: &mut [u8]) -> Result<(), TilingError> {
+        let offset = (self.y as u64 * self.width as u64 + self.x as u64) * 4;
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.read_exact(row))
+            .map_err(|source| TilingError::InputNotReadable {
+                path: self.path.clone(),
+                source,
+            })?;
+        self.y += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::NativeImageProcessor;
+    use image::{Rgb, RgbImage};
+
+    fn read_rows(source: &mut dyn RowSource) -> Vec<u8> {
+        let (width, height) = source.dimensions();
+        source.start(0, width).unwrap();
+        let mut pixels = vec![0u8; width as usize * height as usize * 4];
+        for row in pixels.chunks_exact_mut(width as usize * 4) {
+            source.read_row(row).unwrap();
+        }
+        pixels
+    }
+
+    #[test]
+    fn non_streamable_images_within_the_budget_are_converted() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.jpg");
+        RgbImage::from_pixel(40, 30, Rgb([200, 100, 50]))
+            .save(&image)
+            .unwrap();
+
+        let processor = NativeImageProcessor::new();
+        let mut source = open_row_source(&image, &processor, dir.path(), 1 << 20).unwrap();
+        assert_eq!(source.dimensions(), (40, 30));
+        assert_eq!(read_rows(source.as_mut()).len(), 40 * 30 * 4);
+
+        // The converted TIFF is deleted with the source
+        drop(source);
+        assert!(!dir.path().join("image-stream.tif").exists());
+    }
+
+    #[test]
+    fn non_streamable_images_over_the_budget_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.jpg");
+        RgbImage::new(40, 30).save(&image).unwrap();
+
+        let processor = NativeImageProcessor::new();
+        let err = open_row_source(&image, &processor, dir.path(), 1000)
+            .err()
+            .unwrap();
+        assert!(matches!(err, TilingError::General { .. }));
+        assert_eq!(err.path(), Some(image.as_path()));
+        assert!(!dir.path().join("image-stream.tif").exists());
+    }
+
+    #[test]
+    fn pngs_are_streamed_regardless_of_the_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.png");
+        let mut img = RgbImage::new(3, 2);
+        img.put_pixel(2, 1, Rgb([1, 2, 3]));
+        img.save(&image).unwrap();
+
+        let processor = NativeImageProcessor::new();
+        let mut source = open_row_source(&image, &processor, dir.path(), 0).unwrap();
+        let pixels = read_rows(source.as_mut());
+        assert_eq!(&pixels[20..24], &[1, 2, 3, 255]);
+    }
+}
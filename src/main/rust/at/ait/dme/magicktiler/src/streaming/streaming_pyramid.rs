@@ -0,0 +1,579 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use image::{Rgba, RgbaImage};
+use log::{debug, error, warn};
+
+use super::row_source::{RawSource, RowSource};
+use crate::image::{to_u8, ResamplingFilter, Taps};
+use crate::magick_tiler::TilingError;
+use crate::progress::CancellationToken;
+
+/// Default upper limit for the memory used by a streamed pyramid: 512 MiB
+pub const DEFAULT_MEMORY_BUDGET: usize = 512 * 1024 * 1024;
+
+/// Counter for unique names of spill files
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The canvas of one level of a streamed pyramid. Level 0 holds the source
+/// image; every further level holds the canvas of the level beneath scaled
+/// down by 50%, rounding up. The image content of a level is placed at the
+/// left edge, `top` rows below the top of the canvas. The rest of the canvas
+/// is filled with the background color, content beyond it is cut off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelLayout {
+    pub width: u32,
+    pub height: u32,
+    pub top: u32,
+}
+
+impl LevelLayout {
+    pub fn new(width: u32, height: u32, top: u32) -> Self {
+        Self { width, height, top }
+    }
+
+    /// The layouts of a pyramid without any padding, i.e. every level is
+    /// exactly as large as the scaled-down image.
+    pub fn unpadded(width: u32, height: u32, levels: usize) -> Vec<LevelLayout> {
+        (0..levels)
+            .map(|level| {
+                LevelLayout::new(
+                    width.div_ceil(1 << level).max(1),
+                    height.div_ceil(1 << level).max(1),
+                    0,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Computes a tile pyramid while reading the source image row by row. Each
+/// level keeps only the tile row that is currently being filled; as soon as
+/// it is complete, its tiles are handed to the tile callback. Rows are scaled
+/// down by 50% with the resampling filter, and each level keeps as many of
+/// them as the filter needs to compute the next row of the level above.
+///
+/// The buffers grow with the image width. If they do not fit into the memory
+/// budget, the lower levels are computed in vertical windows, each one tile
+/// wide on the highest level of the window, and that level is spilled to a
+/// raw file in the working directory. The remaining levels are then streamed
+/// from the spill file in the same way. Like the stripes of a striped
+/// pyramid, windows are scaled down independently of each other.
+pub struct StreamingPyramid {
+    layouts: Vec<LevelLayout>,
+    tile_width: u32,
+    tile_height: u32,
+    background: Rgba<u8>,
+    filter: ResamplingFilter,
+    memory_budget: usize,
+    worker_threads: usize,
+    working_directory: PathBuf,
+}
+
+/// The state of one level during a pass over a window
+struct Level {
+    /// The level number in the pyramid
+    level: usize,
+    layout: LevelLayout,
+
+    /// The columns of the canvas covered by the window
+    x: u32,
+    width: u32,
+
+    /// Whether tiles are generated for this level in this pass
+    emit: bool,
+
+    /// Canvas rows received so far
+    rows: u32,
+
+    /// The rows of the current tile row
+    band: Vec<u8>,
+    band_rows: u32,
+    tile_row: u32,
+
+    /// The taps of the columns of the next level, in the window
+    columns: Vec<Taps>,
+
+    /// The last rows received, scaled down horizontally to the width of the
+    /// next level, and the number of the first of them
+    history: VecDeque<Vec<f32>>,
+    history_start: u32,
+
+    /// Rows passed on to the next level so far
+    downsampled: u32,
+}
+
+/// A level written to disk as raw RGBA rows
+struct Spill {
+    path: PathBuf,
+    file: File,
+    width: u32,
+}
+
+impl StreamingPyramid {
+    pub fn new(layouts: Vec<LevelLayout>, tile_width: u32, tile_height: u32) -> Self {
+        Self {
+            layouts,
+            tile_width: tile_width.max(1),
+            tile_height: tile_height.max(1),
+            background: Rgba([255, 255, 255, 255]),
+            filter: ResamplingFilter::default(),
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            worker_threads: 1,
+            working_directory: PathBuf::from("."),
+        }
+    }
+
+    pub fn set_background(&mut self, background: Rgba<u8>) {
+        self.background = background;
+    }
+
+    /// Sets the filter used to scale each level down to the next one,
+    /// default=Lanczos3
+    pub fn set_filter(&mut self, filter: ResamplingFilter) {
+        self.filter = filter;
+    }
+
+    /// Sets the approximate upper limit (in bytes) for the pixel buffers,
+    /// default=512 MiB. Decoder buffers for one row of source strips or tiles
+    /// come on top of this.
+    pub fn set_memory_budget(&mut self, memory_budget: usize) {
+        self.memory_budget = memory_budget;
+    }
+
+    /// Sets the number of threads used to encode the tiles of a tile row
+    pub fn set_worker_threads(&mut self, worker_threads: usize) {
+        self.worker_threads = worker_threads.max(1);
+    }
+
+    /// Sets the directory for spill files
+    pub fn set_working_directory<P: AsRef<Path>>(&mut self, working_directory: P) {
+        self.working_directory = working_directory.as_ref().to_path_buf();
+    }
+
+    /// Streams the source through the pyramid, passing every tile with its
+    /// level, column and row (counted from the top left of the level canvas)
+    /// to `tile`. Tiles on the right and bottom border of a canvas may be
+    /// smaller than the tile size. Tiles of the same tile row may be passed
+    /// concurrently from several threads.
+    ///
+    /// # Errors
+    /// Returns the first error of the source or the tile callback, or
+    /// `TilingError::Cancelled` once `cancellation` is cancelled
+    pub fn run<F>(
+        &self,
+        source: &mut dyn RowSource,
+        cancellation: &CancellationToken,
+        tile: F,
+    ) -> Result<(), TilingError>
+    where
+        F: Fn(usize, u32, u32, RgbaImage) -> Result<(), TilingError> + Sync,
+    {
+        if self.layouts.is_empty() {
+            return Ok(());
+        }
+        self.run_stage(source, self.layouts.clone(), 0, true, cancellation, &tile)
+    }
+
+    /// Approximate memory needed for a pass over a window of the given width
+    fn pass_memory(&self, layouts: &[LevelLayout], window: u32, emit_first: bool) -> usize {
+        // The rows the filter reaches on the level beneath a downsampled row
+        let history_rows = (4.0 * self.filter.support()).ceil() as usize + 1;
+        layouts
+            .iter()
+            .enumerate()
+            .map(|(k, layout)| {
+                let width = (window >> k).min(layout.width) as usize;
+                let rows = if k > 0 || emit_first {
+                    // The tile row and a copy of its tiles for encoding
+                    width * 4 * 2 * self.tile_height as usize
+                } else {
+                    width * 4
+                };
+                let history = if k + 1 < layouts.len() {
+                    width.div_ceil(2) * 4 * std::mem::size_of::<f32>() * history_rows
+                } else {
+                    0
+                };
+                rows + history
+            })
+            .sum()
+    }
+
+    /// Computes `layouts` (levels `first` and up), where the first level is
+    /// read from `source`.
+    fn run_stage<F>(
+        &self,
+        source: &mut dyn RowSource,
+        layouts: Vec<LevelLayout>,
+        first: usize,
+        emit_first: bool,
+        cancellation: &CancellationToken,
+        tile: &F,
+    ) -> Result<(), TilingError>
+    where
+        F: Fn(usize, u32, u32, RgbaImage) -> Result<(), TilingError> + Sync,
+    {
+        let n = layouts.len();
+        let canvas_width = layouts[0].width;
+
+        // Pick the widest window that fits into the budget. A window for
+        // levels 0 to j is one tile wide on level j. Spilling the first level
+        // again would not make progress, so j >= 1 unless it is emitted.
+        let min_j = if emit_first || n == 1 { 0 } else { 1 };
+        let j = (min_j..n)
+            .rev()
+            .find(|&j| {
+                let window = self.window(j);
+                self.pass_memory(&layouts[..=j], window, emit_first) <= self.memory_budget
+            })
+            .unwrap_or_else(|| {
+                warn!(
+                    "Memory budget of {} bytes is too small for a single column of tiles",
+                    self.memory_budget
+                );
+                min_j
+            });
+        let window = self.window(j);
+        let windows = canvas_width.div_ceil(window);
+        debug!(
+            "Streaming levels {} to {} in {} window(s) of {} pixels",
+            first,
+            first + j,
+            windows,
+            window
+        );
+
+        if j == n - 1 {
+            for i in 0..windows {
+                self.run_pass(
+                    source,
+                    &layouts,
+                    first,
+                    emit_first,
+                    n,
+                    i * window,
+                    window,
+                    None,
+                    cancellation,
+                    tile,
+                )?;
+            }
+            return Ok(());
+        }
+
+        // Compute levels up to j in windows, then the rest from the spill file
+        let spill_layout = layouts[j];
+        let path = self.working_directory.join(format!(
+            "stream-{}-{}-{}.raw",
+            std::process::id(),
+            SPILL_COUNTER.fetch_add(1, Ordering::Relaxed),
+            first + j
+        ));
+        let result = (|| {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .map_err(TilingError::not_writable(&path))?;
+            let mut spill = Spill {
+                path: path.clone(),
+                file,
+                width: spill_layout.width,
+            };
+            for i in 0..windows {
+                self.run_pass(
+                    source,
+                    &layouts,
+                    first,
+                    emit_first,
+                    j + 1,
+                    i * window,
+                    window,
+                    Some(&mut spill),
+                    cancellation,
+                    tile,
+                )?;
+            }
+            drop(spill);
+
+            // The spill file holds the whole canvas of level j
+            let mut rest = layouts[j..].to_vec();
+            rest[0].top = 0;
+            let mut spilled = RawSource::open(&path, spill_layout.width, spill_layout.height)?;
+            self.run_stage(&mut spilled, rest, first + j, false, cancellation, tile)
+        })();
+
+        if let Err(e) = fs::remove_file(&path) {
+            error!("Could not delete spill file {}: {}", path.display(), e);
+        }
+        result
+    }
+
+    /// Width of a window that is one tile wide on level j, on the first level
+    fn window(&self, j: usize) -> u32 {
+        ((self.tile_width as u64) << j).min(u32::MAX as u64) as u32
+    }
+
+    /// Streams the source through levels 0 to `levels - 1` of `layouts` for
+    /// the window of the first level starting at column `x`. The last level
+    /// is written to `spill`, if any.
+    #[allow(clippy::too_many_arguments)]
+    fn run_pass<F>(
+        &self,
+        source: &mut dyn RowSource,
+        layouts: &[LevelLayout],
+        first: usize,
+        emit_first: bool,
+        levels: usize,
+        x: u32,
+        window: u32,
+        mut spill: Option<&mut Spill>,
+        cancellation: &CancellationToken,
+        tile: &F,
+    ) -> Result<(), TilingError>
+    where
+        F: Fn(usize, u32, u32, RgbaImage) -> Result<(), TilingError> + Sync,
+    {
+        cancellation.check()?;
+        let mut states: Vec<Level> = layouts[..levels]
+            .iter()
+            .enumerate()
+            .map(|(k, &layout)| {
+                let level_x = x >> k;
+                let emit = k > 0 || emit_first;
+                let width = (window >> k).min(layout.width.saturating_sub(level_x));
+                let columns = match layouts[..levels].get(k + 1) {
+                    Some(next) => {
+                        let next_width = (window >> (k + 1))
+                            .min(next.width.saturating_sub(x >> (k + 1)))
+                            .min(width.div_ceil(2));
+                        (0..next_width as usize)
+                            .map(|column| self.filter.taps(column, width as usize, 2.0))
+                            .collect()
+                    }
+                    None => Vec::new(),
+                };
+                Level {
+                    level: first + k,
+                    layout,
+                    x: level_x,
+                    width,
+                    emit,
+                    rows: 0,
+                    band: Vec::new(),
+                    band_rows: 0,
+                    tile_row: 0,
+                    columns,
+                    history: VecDeque::new(),
+                    history_start: 0,
+                    downsampled: 0,
+                }
+            })
+            .collect();
+
+        // Top padding, starting with the highest level so that the padding
+        // of a level is in place before rows arrive from the level beneath
+        for k in (0..levels).rev() {
+            for _ in 0..states[k].layout.top {
+                let row = self.background_row(states[k].width);
+                self.push_row(&mut states, k, row, &mut spill, cancellation, tile)?;
+            }
+        }
+
+        // Image content
+        let (source_width, source_height) = source.dimensions();
+        let read_width = source_width.min(x + states[0].width).saturating_sub(x);
+        if read_width > 0 {
+            source.start(x, read_width)?;
+        }
+        let visible = source_height.min(states[0].layout.height - states[0].layout.top);
+        for _ in 0..visible {
+            let mut row = self.background_row(states[0].width);
+            if read_width > 0 {
+                source.read_row(&mut row[..read_width as usize * 4])?;
+            }
+            self.push_row(&mut states, 0, row, &mut spill, cancellation, tile)?;
+        }
+
+        // Bottom padding. The last row of a level passes the remaining rows
+        // on to the next level.
+        for k in 0..levels {
+            while states[k].rows < states[k].layout.height {
+                let row = self.background_row(states[k].width);
+                self.push_row(&mut states, k, row, &mut spill, cancellation, tile)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn background_row(&self, width: u32) -> Vec<u8> {
+        self.background.0.repeat(width as usize)
+    }
+
+    /// Adds a canvas row to level k and emits its tile row once it is
+    /// complete. Passes the rows of the next level on as soon as all rows
+    /// that the filter reaches have been received.
+    fn push_row<F>(
+        &self,
+        states: &mut [Level],
+        k: usize,
+        row: Vec<u8>,
+        spill: &mut Option<&mut Spill>,
+        cancellation: &CancellationToken,
+        tile: &F,
+    ) -> Result<(), TilingError>
+    where
+        F: Fn(usize, u32, u32, RgbaImage) -> Result<(), TilingError> + Sync,
+    {
+        let next_width = states.get(k + 1).map(|next| next.width);
+        let state = &mut states[k];
+        if state.rows >= state.layout.height {
+            return Ok(());
+        }
+        let y = state.rows;
+        state.rows += 1;
+
+        if state.emit {
+            state.band.extend_from_slice(&row);
+            state.band_rows += 1;
+            if state.band_rows == self.tile_height || state.rows == state.layout.height {
+                cancellation.check()?;
+                self.emit_tile_row(state, tile)?;
+            }
+        }
+
+        let Some(next_width) = next_width else {
+            if let Some(spill) = spill {
+                let offset = (y as u64 * spill.width as u64 + state.x as u64) * 4;
+                spill
+                    .file
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| spill.file.write_all(&row))
+                    .map_err(TilingError::not_writable(&spill.path))?;
+            }
+            return Ok(());
+        };
+
+        let reduced = self.downsample_columns(&row, &state.columns, next_width);
+        state.history.push_back(reduced);
+
+        let height = state.layout.height as usize;
+        let mut rows = Vec::new();
+        let mut keep_from = state.rows;
+        while state.downsampled < state.layout.height.div_ceil(2) {
+            let taps = self.filter.taps(state.downsampled as usize, height, 2.0);
+            keep_from = taps.start as u32;
+            if taps.start + taps.weights.len() > state.rows as usize {
+                break;
+            }
+            rows.push(self.downsample_rows(state, &taps, next_width));
+            state.downsampled += 1;
+            keep_from = state.rows;
+        }
+        while state.history_start < keep_from && !state.history.is_empty() {
+            state.history.pop_front();
+            state.history_start += 1;
+        }
+
+        for row in rows {
+            self.push_row(states, k + 1, row, spill, cancellation, tile)?;
+        }
+        Ok(())
+    }
+
+    /// Scales a row of a level down horizontally to the width of the next
+    /// level. Columns beyond the taps are filled with the background color.
+    fn downsample_columns(&self, row: &[u8], columns: &[Taps], width: u32) -> Vec<f32> {
+        let mut reduced: Vec<f32> = self.background.0.map(f32::from).repeat(width as usize);
+        for (pixel, taps) in reduced.chunks_exact_mut(4).zip(columns) {
+            pixel.fill(0.0);
+            for (i, weight) in taps.weights.iter().enumerate() {
+                let offset = (taps.start + i) * 4;
+                for (c, value) in pixel.iter_mut().enumerate() {
+                    *value += row[offset + c] as f32 * weight;
+                }
+            }
+        }
+        reduced
+    }
+
+    /// Combines the horizontally scaled rows of a level into a row of the
+    /// next level.
+    fn downsample_rows(&self, state: &Level, taps: &Taps, width: u32) -> Vec<u8> {
+        let mut sums = vec![0f32; width as usize * 4];
+        for (i, weight) in taps.weights.iter().enumerate() {
+            let row = &state.history[taps.start + i - state.history_start as usize];
+            for (sum, value) in sums.iter_mut().zip(row) {
+                *sum += value * weight;
+            }
+        }
+        sums.into_iter().map(to_u8).collect()
+    }
+
+    /// Cuts the completed tile row of a level into tiles and passes them to
+    /// the tile callback, using up to `worker_threads` threads.
+    fn emit_tile_row<F>(&self, state: &mut Level, tile: &F) -> Result<(), TilingError>
+    where
+        F: Fn(usize, u32, u32, RgbaImage) -> Result<(), TilingError> + Sync,
+    {
+        let stride = state.width as usize * 4;
+        let first_column = state.x / self.tile_width;
+        let last_column = (state.x + state.width).div_ceil(self.tile_width);
+        let mut tiles = Vec::new();
+        for column in first_column..last_column {
+            let from = column * self.tile_width - state.x;
+            let to = ((column + 1) * self.tile_width).min(state.x + state.width) - state.x;
+            let width = to - from;
+            let mut pixels = Vec::with_capacity((width * state.band_rows) as usize * 4);
+            for y in 0..state.band_rows as usize {
+                let offset = y * stride;
+                pixels.extend_from_slice(
+                    &state.band[offset + from as usize * 4..offset + to as usize * 4],
+                );
+            }
+            let image = RgbaImage::from_raw(width, state.band_rows, pixels)
+                .expect("tile buffer matches its dimensions");
+            tiles.push((column, image));
+        }
+
+        let (level, row) = (state.level, state.tile_row);
+        state.band.clear();
+        state.band_rows = 0;
+        state.tile_row += 1;
+
+        if self.worker_threads <= 1 || tiles.len() <= 1 {
+            return tiles
+                .into_iter()
+                .try_for_each(|(column, image)| tile(level, column, row, image));
+        }
+
+        let per_thread = tiles.len().div_ceil(self.worker_threads);
+        let mut chunks = Vec::new();
+        while !tiles.is_empty() {
+            let rest = tiles.split_off(per_thread.min(tiles.len()));
+            chunks.push(std::mem::replace(&mut tiles, rest));
+        }
+        thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .into_iter()
+                            .try_for_each(|(column, image)| tile(level, column, row, image))
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().expect("tile worker panicked"))
+        })
+    }
+}
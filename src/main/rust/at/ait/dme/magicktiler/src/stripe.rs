@@ -1,9 +1,7 @@
-use std::collections::HashMap;
-use std::io;
 use std::path::{Path, PathBuf};
 
-use crate::image::ImageProcessingSystem;
 use crate::image::ImageProcessor;
+use crate::tiling_exception::TilingError;
 
 /// To speed up the MagickTiler tiling process, images are (for most tiling schemes)
 /// first split into a sequence of 'stripes'. Depending on the tiling scheme, striping
@@ -24,6 +22,25 @@ pub struct Stripe {
     orientation: Orientation,
 }
 
+/// Halves an image dimension for the next zoom level. Odd dimensions are
+/// rounded up, so that the last pixel column or row is not lost. All
+/// backends and the streaming pyramid scale down this way.
+pub fn half_size(size: i32) -> i32 {
+    (size + 1) / 2
+}
+
+/// An image dimension scaled down by `scale_factor` (a power of two), i.e.
+/// halved with `half_size` once per zoom level.
+pub fn scaled_size(size: i32, scale_factor: i32) -> i32 {
+    let mut size = size;
+    let mut factor = scale_factor;
+    while factor > 1 {
+        size = half_size(size);
+        factor /= 2;
+    }
+    size
+}
+
 /// Possible stripe orientations
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Orientation {
@@ -68,9 +85,9 @@ impl Stripe {
         &self,
         stripe: &Stripe,
         target_file: P,
-        system: ImageProcessingSystem,
-    ) -> io::Result<Stripe> {
-        self.merge_with_canvas(stripe, None, -1, -1, None, target_file, system)
+        processor: &dyn ImageProcessor,
+    ) -> Result<Stripe, TilingError> {
+        self.merge_with_canvas(stripe, None, -1, -1, None, target_file, processor)
     }
 
     /// Merges this stripe with another one into a single stripe, scaled according to
@@ -80,6 +97,7 @@ impl Stripe {
     /// This method allows to create a background color buffer around the stripe, in case
     /// the employed tiling scheme mandates certain image resolution constraints (e.g.
     /// width/height must be integer multiples of the tile-size).
+    #[allow(clippy::too_many_arguments)]
     pub fn merge_with_canvas<P: AsRef<Path>>(
         &self,
         stripe: &Stripe,
@@ -88,27 +106,19 @@ impl Stripe {
         y_extent: i32,
         background_color: Option<&str>,
         target_file: P,
-        system: ImageProcessingSystem,
-    ) -> io::Result<Stripe> {
+        processor: &dyn ImageProcessor,
+    ) -> Result<Stripe, TilingError> {
         if stripe.orientation != self.orientation {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                Self::DIFFERENT_ORIENTATION_ERROR,
+            return Err(TilingError::general(
+                Self::DIFFERENT_ORIENTATION_ERROR.to_string(),
             ));
         }
 
-        let mut srcs = vec![
-            self.file.to_string_lossy().into_owned(),
-            stripe.file.to_string_lossy().into_owned(),
-        ];
-
         let (x_tiles, y_tiles) = match self.orientation {
             Orientation::Horizontal => (1, 2),
             Orientation::Vertical => (2, 1),
         };
 
-        let processor = ImageProcessor::new(system);
-
         if x_extent > -1 && y_extent > -1 {
             let w = x_extent;
             let h = y_extent;
@@ -116,15 +126,15 @@ impl Stripe {
 
             if let Some(gravity) = gravity {
                 if let Some(bg_color) = background_color {
-                    processor.montage(
-                        &srcs,
-                        &target_file,
+                    processor.montage_with_canvas(
+                        &[Some(self.file.as_path()), Some(stripe.file.as_path())],
+                        target_file.as_ref(),
                         x_tiles,
                         y_tiles,
                         w,
                         h,
-                        Some(bg_color.to_string()),
-                        Some(gravity.to_string()),
+                        bg_color,
+                        gravity,
                     )?;
                 }
             }
@@ -132,19 +142,20 @@ impl Stripe {
             Ok(Stripe::new(target_file, w, h, self.orientation))
         } else {
             let w = match self.orientation {
-                Orientation::Horizontal => self.width / 2,
-                Orientation::Vertical => (self.width + stripe.width) / 2,
+                Orientation::Horizontal => half_size(self.width),
+                Orientation::Vertical => half_size(self.width + stripe.width),
             };
             let h = match self.orientation {
-                Orientation::Horizontal => (self.height + stripe.height) / 4,
-                Orientation::Vertical => self.height / 2,
+                Orientation::Horizontal => half_size(self.height + stripe.height),
+                Orientation::Vertical => half_size(self.height),
             };
 
-            let mut raw_args = HashMap::new();
-            raw_args.insert("-geometry".to_string(), "+0+0".to_string());
-            raw_args.insert("-resize".to_string(), "50%x50%".to_string());
-
-            processor.montage(&srcs, &target_file, x_tiles, y_tiles, Some(raw_args))?;
+            processor.montage(
+                &[self.file.as_path(), stripe.file.as_path()],
+                target_file.as_ref(),
+                x_tiles,
+                y_tiles,
+            )?;
 
             Ok(Stripe::new(target_file, w, h, self.orientation))
         }
@@ -154,9 +165,9 @@ impl Stripe {
     pub fn shrink<P: AsRef<Path>>(
         &self,
         target_file: P,
-        system: ImageProcessingSystem,
-    ) -> io::Result<Stripe> {
-        self.shrink_with_canvas(None, -1, -1, None, target_file, system)
+        processor: &dyn ImageProcessor,
+    ) -> Result<Stripe, TilingError> {
+        self.shrink_with_canvas(None, -1, -1, None, target_file, processor)
     }
 
     /// Shrinks this stripe 50% to the resolution of the next zoom level.
@@ -170,16 +181,9 @@ impl Stripe {
         y_extent: i32,
         background_color: Option<&str>,
         target_file: P,
-        system: ImageProcessingSystem,
-    ) -> io::Result<Stripe> {
-        let processor = ImageProcessor::new(system);
-
+        processor: &dyn ImageProcessor,
+    ) -> Result<Stripe, TilingError> {
         if x_extent > -1 && y_extent > -1 {
-            let mut srcs = vec![
-                self.file.to_string_lossy().into_owned(),
-                "null:".to_string(),
-            ];
-
             let (x_tiles, y_tiles) = match self.orientation {
                 Orientation::Horizontal => (1, 2),
                 Orientation::Vertical => (2, 1),
@@ -187,15 +191,15 @@ impl Stripe {
 
             if let Some(gravity) = gravity {
                 if let Some(bg_color) = background_color {
-                    processor.montage(
-                        &srcs,
-                        &target_file,
+                    processor.montage_with_canvas(
+                        &[Some(self.file.as_path()), None],
+                        target_file.as_ref(),
                         x_tiles,
                         y_tiles,
                         x_extent / 2,
                         y_extent,
-                        Some(bg_color.to_string()),
-                        Some(gravity.to_string()),
+                        bg_color,
+                        gravity,
                     )?;
                 }
             }
@@ -207,28 +211,16 @@ impl Stripe {
                 self.orientation,
             ))
         } else {
-            let mut raw_args = HashMap::new();
-            raw_args.insert("-scale".to_string(), "50%x50%".to_string());
+            let (w, h) = (half_size(self.width), half_size(self.height));
+            processor.scale(&self.file, target_file.as_ref(), w, h)?;
 
-            processor.convert(&self.file, &target_file, Some(raw_args))?;
-
-            Ok(Stripe::new(
-                target_file,
-                self.width / 2,
-                self.height / 2,
-                self.orientation,
-            ))
+            Ok(Stripe::new(target_file, w, h, self.orientation))
         }
     }
 
     /// Removes this stripe's image file from the file system.
     /// (Note that stripes are normally used as temporary files only!)
-    pub fn delete(&self) -> io::Result<()> {
-        std::fs::remove_file(&self.file).map_err(|e| {
-            io::Error::new(
-                e.kind(),
-                format!("Could not delete file: {}", self.file.display()),
-            )
-        })
+    pub fn delete(&self) -> Result<(), TilingError> {
+        std::fs::remove_file(&self.file).map_err(TilingError::io(&self.file))
     }
 }
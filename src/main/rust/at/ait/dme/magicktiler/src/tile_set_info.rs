@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
-use crate::image::{ImageFormat, ImageInfo, ImageProcessor};
+use crate::image::{EncoderOptions, ImageFormat, ImageInfo, ImageProcessor};
+use crate::tiling_exception::TilingError;
+use crate::tiling_scheme::TilesetMetadata;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TileSetInfo {
@@ -25,6 +27,15 @@ pub struct TileSetInfo {
 
     /// Image info
     img_info: ImageInfo,
+
+    /// Number of zoom levels declared by an existing tileset, if it differs
+    /// from the computed one (e.g. for Deep Zoom or IIIF)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    zoom_levels: Option<i32>,
+
+    /// The settings the tiles were encoded with, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoder: Option<EncoderOptions>,
 }
 
 impl TileSetInfo {
@@ -33,22 +44,54 @@ impl TileSetInfo {
         tile_width: i32,
         tile_height: i32,
         processor: &dyn ImageProcessor,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    ) -> Result<Self, TilingError> {
+        let img_info = ImageInfo::new(image, processor)?;
         Ok(Self {
             image_file: image.to_path_buf(),
-            width: 0,
-            height: 0,
+            width: img_info.width(),
+            height: img_info.height(),
             tile_width,
             tile_height,
             format: processor.get_image_format(),
-            img_info: ImageInfo::new(image, processor.get_image_processing_system())?,
+            img_info,
+            zoom_levels: None,
+            encoder: Some(processor.get_encoder_options().clone()),
         })
     }
 
+    /// Reconstructs the tileset information from the metadata of an existing
+    /// tileset. Most tiling schemes do not record the source image, so the
+    /// tileset directory takes its place as `image_file`.
+    pub fn from_metadata(tileset_dir: &Path, metadata: &TilesetMetadata) -> Self {
+        Self {
+            image_file: tileset_dir.to_path_buf(),
+            width: metadata.width(),
+            height: metadata.height(),
+            tile_width: metadata.tile_width(),
+            tile_height: metadata.tile_height(),
+            format: metadata.format(),
+            img_info: ImageInfo::from_dimensions(tileset_dir, metadata.width(), metadata.height()),
+            zoom_levels: Some(metadata.zoom_levels()),
+            encoder: metadata.encoder_options().cloned(),
+        }
+    }
+
     pub fn image_file(&self) -> &Path {
         &self.image_file
     }
 
+    /// Changes the recorded location of the source image, e.g. after it was
+    /// copied into the tileset
+    pub fn set_image_file(&mut self, image: &Path) {
+        self.image_file = image.to_path_buf();
+        self.img_info.set_file(image);
+    }
+
+    /// Information about the source image, as read from the file
+    pub fn image_info(&self) -> &ImageInfo {
+        &self.img_info
+    }
+
     pub fn image_width(&self) -> i32 {
         self.width
     }
@@ -74,7 +117,16 @@ impl TileSetInfo {
         self.format
     }
 
+    /// The settings the tiles were encoded with. Unknown for tilesets read
+    /// from metadata that does not record them.
+    pub fn encoder_options(&self) -> Option<&EncoderOptions> {
+        self.encoder.as_ref()
+    }
+
     pub fn zoom_levels(&self) -> i32 {
+        if let Some(zoom_levels) = self.zoom_levels {
+            return zoom_levels;
+        }
         let max_dim = self.width.max(self.height);
         let max_tiles = (max_dim as f64 / self.tile_width as f64).ceil() as i32;
         (max_tiles as f64).log2().ceil() as i32 + 1
@@ -0,0 +1,190 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors raised while generating a tileset, by the tilers as well as by the
+/// `ImageProcessor`s. Variants that concern a specific file carry its path, so
+/// callers can decide whether to retry, skip the file or abort.
+#[derive(Debug, Error)]
+pub enum TilingError {
+    /// A file operation other than reading the input or writing the output
+    /// failed, e.g. deleting a temporary file
+    #[error("IO error on {}: {source}", path.display())]
+    IO { path: PathBuf, source: io::Error },
+
+    /// The source image is in a format the image processor cannot read, or
+    /// the tile format cannot be written
+    #[error("Unsupported image format {}: {reason}", path.display())]
+    UnsupportedFormat { path: PathBuf, reason: String },
+
+    /// The source image could not be read
+    #[error("Could not read {}: {source}", path.display())]
+    InputNotReadable { path: PathBuf, source: io::Error },
+
+    /// A tile, stripe or metadata file could not be written
+    #[error("Could not write {}: {source}", path.display())]
+    OutputNotWritable { path: PathBuf, source: io::Error },
+
+    /// The image, or an intermediate stripe, has dimensions the tiling scheme
+    /// cannot handle
+    #[error("Invalid dimensions {width}x{height} for {}", path.display())]
+    InvalidDimensions {
+        path: PathBuf,
+        width: i32,
+        height: i32,
+    },
+
+    /// The background color is neither a `#rrggbb` value nor a known color
+    /// name
+    #[error("Unknown color {color} for {}", path.display())]
+    InvalidColor { path: PathBuf, color: String },
+
+    /// The GraphicsMagick or ImageMagick binary could not be found
+    #[error("Image processor {} not found", program.display())]
+    ProcessorNotFound { program: PathBuf },
+
+    /// The external image processor could not be run or exited with an
+    /// error. `status` is `None` if the process did not exit normally.
+    #[error("{} failed on {} (exit status {status:?}): {stderr}", program.display(), path.display())]
+    ProcessFailed {
+        program: PathBuf,
+        path: PathBuf,
+        status: Option<i32>,
+        stderr: String,
+    },
+
+    /// The external image processor did not finish within the configured
+    /// timeout and was killed
+    #[error("{} timed out after {timeout:?} on {}", program.display(), path.display())]
+    ProcessTimedOut {
+        program: PathBuf,
+        path: PathBuf,
+        timeout: Duration,
+    },
+
+    /// The tiling run was stopped through its `CancellationToken`
+    #[error("Tiling was cancelled")]
+    Cancelled,
+
+    /// Any other error, e.g. invalid settings. `path` is the file or tileset
+    /// the error concerns, if any.
+    #[error("{message}")]
+    General {
+        path: Option<PathBuf>,
+        message: String,
+    },
+}
+
+impl TilingError {
+    /// An error not covered by the other variants
+    pub fn general<S: Into<String>>(message: S) -> Self {
+        TilingError::General {
+            path: None,
+            message: message.into(),
+        }
+    }
+
+    /// An error not covered by the other variants, concerning `path`
+    pub fn general_at<P: AsRef<Path>, S: Into<String>>(path: P, message: S) -> Self {
+        TilingError::General {
+            path: Some(path.as_ref().to_path_buf()),
+            message: message.into(),
+        }
+    }
+
+    /// The file (or program) the error concerns, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            TilingError::IO { path, .. }
+            | TilingError::UnsupportedFormat { path, .. }
+            | TilingError::InputNotReadable { path, .. }
+            | TilingError::OutputNotWritable { path, .. }
+            | TilingError::InvalidDimensions { path, .. }
+            | TilingError::InvalidColor { path, .. }
+            | TilingError::ProcessFailed { path, .. }
+            | TilingError::ProcessTimedOut { path, .. } => Some(path),
+            TilingError::ProcessorNotFound { program } => Some(program),
+            TilingError::General { path, .. } => path.as_deref(),
+            TilingError::Cancelled => None,
+        }
+    }
+
+    /// Returns a function that wraps an IO error raised while writing `path`,
+    /// for use with `map_err`.
+    pub fn not_writable<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        move |source| TilingError::OutputNotWritable { path, source }
+    }
+
+    /// Returns a function that wraps an IO error raised while reading `path`,
+    /// for use with `map_err`.
+    pub fn not_readable<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        move |source| TilingError::InputNotReadable { path, source }
+    }
+
+    /// Returns a function that wraps any other IO error concerning `path`,
+    /// for use with `map_err`.
+    pub fn io<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        move |source| TilingError::IO { path, source }
+    }
+
+    /// Wraps an error encountered while decoding the image at `path`.
+    pub fn read(path: &Path, err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::IoError(source) => TilingError::InputNotReadable {
+                path: path.to_path_buf(),
+                source,
+            },
+            err => TilingError::UnsupportedFormat {
+                path: path.to_path_buf(),
+                reason: err.to_string(),
+            },
+        }
+    }
+
+    /// Wraps an error encountered while encoding the image at `path`.
+    pub fn write(path: &Path, err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::IoError(source) => TilingError::OutputNotWritable {
+                path: path.to_path_buf(),
+                source,
+            },
+            err => TilingError::UnsupportedFormat {
+                path: path.to_path_buf(),
+                reason: err.to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{ImageProcessor, NativeImageProcessor};
+
+    #[test]
+    fn processor_errors_carry_the_path() {
+        let processor = NativeImageProcessor::new();
+        let missing = Path::new("does-not-exist.png");
+        let err = processor.get_dimensions(missing).unwrap_err();
+        assert!(matches!(err, TilingError::InputNotReadable { .. }));
+        assert_eq!(err.path(), Some(missing));
+    }
+
+    #[test]
+    fn io_errors_carry_the_path() {
+        let err = std::fs::read("does-not-exist")
+            .map_err(TilingError::io("does-not-exist"))
+            .unwrap_err();
+        assert_eq!(err.path(), Some(Path::new("does-not-exist")));
+        assert!(err.to_string().contains("does-not-exist"));
+
+        assert_eq!(TilingError::general("Invalid tile size").path(), None);
+        let err = TilingError::general_at("tiles", "No known tileset");
+        assert_eq!(err.path(), Some(Path::new("tiles")));
+        assert_eq!(err.to_string(), "No known tileset");
+    }
+}
@@ -0,0 +1,374 @@
+use std::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dzi::DeepZoomValidator;
+use crate::gmaps::GoogleMapsValidator;
+use crate::iiif::IIIFValidator;
+use crate::image::{EncoderOptions, ImageFormat};
+use crate::pmtiles::PMTilesValidator;
+use crate::tile_set_info::TileSetInfo;
+use crate::tms::TMSValidator;
+use crate::validation_failed_exception::ValidationFailedError;
+use crate::validation_report::ValidationReport;
+use crate::validator::Validator;
+use crate::zoomify::ZoomifyValidator;
+
+/// The tiling schemes whose tilesets can be detected and validated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TilingScheme {
+    Tms,
+    Zoomify,
+    GoogleMaps,
+    DeepZoom,
+    IIIF,
+    /// A PMTiles archive, holding the tiles of one of the other schemes
+    #[serde(rename = "pmtiles")]
+    PMTiles,
+}
+
+impl TilingScheme {
+    /// All detectable schemes, in the order `detect_scheme` tries them. Schemes
+    /// with a distinctive descriptor file come first.
+    pub const ALL: [TilingScheme; 6] = [
+        TilingScheme::PMTiles,
+        TilingScheme::DeepZoom,
+        TilingScheme::IIIF,
+        TilingScheme::Zoomify,
+        TilingScheme::Tms,
+        TilingScheme::GoogleMaps,
+    ];
+
+    /// The short name of the scheme, as used on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            TilingScheme::Tms => "tms",
+            TilingScheme::Zoomify => "zoomify",
+            TilingScheme::GoogleMaps => "gmap",
+            TilingScheme::DeepZoom => "dzi",
+            TilingScheme::IIIF => "iiif",
+            TilingScheme::PMTiles => "pmtiles",
+        }
+    }
+
+    /// Creates a validator for tilesets of this scheme.
+    pub fn validator(&self) -> Box<dyn Validator> {
+        match self {
+            TilingScheme::Tms => Box::new(TMSValidator::new()),
+            TilingScheme::Zoomify => Box::new(ZoomifyValidator::new()),
+            TilingScheme::GoogleMaps => Box::new(GoogleMapsValidator::new()),
+            TilingScheme::DeepZoom => Box::new(DeepZoomValidator::new()),
+            TilingScheme::IIIF => Box::new(IIIFValidator::new()),
+            TilingScheme::PMTiles => Box::new(PMTilesValidator::new()),
+        }
+    }
+
+    /// Reads the metadata of an existing tileset of this scheme back into a
+    /// `TileSetInfo`, without checking the tiles.
+    ///
+    /// # Errors
+    /// Returns a ValidationFailedError if the metadata is missing or invalid
+    pub fn read_tileset_info(&self, dir: &Path) -> Result<TileSetInfo, ValidationFailedError> {
+        let metadata = self.validator().read_metadata(dir)?;
+        Ok(TileSetInfo::from_metadata(dir, &metadata))
+    }
+}
+
+impl fmt::Display for TilingScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The basic parameters of a tileset, as declared in its metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TilesetMetadata {
+    /// Width of the source image
+    width: i32,
+
+    /// Height of the source image
+    height: i32,
+
+    /// Width of a single tile
+    tile_width: i32,
+
+    /// Height of a single tile
+    tile_height: i32,
+
+    /// Number of zoom levels (scale factors for IIIF)
+    zoom_levels: i32,
+
+    /// Format of the tiles
+    format: ImageFormat,
+
+    /// The settings the tiles were encoded with, if the metadata records them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoder: Option<EncoderOptions>,
+}
+
+impl TilesetMetadata {
+    pub fn new(
+        width: i32,
+        height: i32,
+        tile_width: i32,
+        tile_height: i32,
+        zoom_levels: i32,
+        format: ImageFormat,
+    ) -> Self {
+        Self {
+            width,
+            height,
+            tile_width,
+            tile_height,
+            zoom_levels,
+            format,
+            encoder: None,
+        }
+    }
+
+    /// Adds the settings the tiles were encoded with
+    pub fn with_encoder_options(mut self, options: Option<EncoderOptions>) -> Self {
+        self.encoder = options;
+        self
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn tile_width(&self) -> i32 {
+        self.tile_width
+    }
+
+    pub fn tile_height(&self) -> i32 {
+        self.tile_height
+    }
+
+    pub fn zoom_levels(&self) -> i32 {
+        self.zoom_levels
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    pub fn encoder_options(&self) -> Option<&EncoderOptions> {
+        self.encoder.as_ref()
+    }
+}
+
+/// A tileset found by `detect_scheme`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetectedTileset {
+    scheme: TilingScheme,
+    metadata: TilesetMetadata,
+}
+
+impl DetectedTileset {
+    pub fn scheme(&self) -> TilingScheme {
+        self.scheme
+    }
+
+    pub fn metadata(&self) -> &TilesetMetadata {
+        &self.metadata
+    }
+
+    /// The tileset information reconstructed from the detected metadata
+    ///
+    /// # Arguments
+    /// * `dir` - The tileset directory the tileset was detected in
+    pub fn tileset_info(&self, dir: &Path) -> TileSetInfo {
+        TileSetInfo::from_metadata(dir, &self.metadata)
+    }
+
+    /// Validates the tileset with the validator of its scheme.
+    ///
+    /// # Arguments
+    /// * `dir` - The tileset directory the tileset was detected in
+    /// * `deep` - Whether to decode and check every tile
+    pub fn validate(&self, dir: &Path, deep: bool) -> ValidationReport {
+        let mut validator = self.scheme.validator();
+        validator.set_deep(deep);
+        validator.report(dir)
+    }
+}
+
+/// Detects the tiling scheme of a directory (or PMTiles archive) by running the detection of every
+/// known validator. Returns the first scheme whose descriptor is present and
+/// can be parsed, or `None` if the directory holds no known tileset.
+pub fn detect_scheme(dir: &Path) -> Option<DetectedTileset> {
+    TilingScheme::ALL.iter().find_map(|&scheme| {
+        let validator = scheme.validator();
+        if !validator.is_tileset_dir(dir) {
+            return None;
+        }
+        validator
+            .read_metadata(dir)
+            .ok()
+            .map(|metadata| DetectedTileset { scheme, metadata })
+    })
+}
+
+/// Parses the tile format extension declared in a tileset descriptor.
+pub(crate) fn tile_format(ext: &str) -> Result<ImageFormat, ValidationFailedError> {
+    ImageFormat::from_extension(ext)
+        .ok_or_else(|| ValidationFailedError::new(format!("Unsupported tile format: {}", ext)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::dzi::DeepZoomTiler;
+    use crate::gmaps::GoogleMapsTiler;
+    use crate::iiif::IIIFTiler;
+    use crate::image::ImageProcessingSystem;
+    use crate::pmtiles::PMTilesTiler;
+    use crate::tms::TMSTiler;
+    use crate::zoomify::ZoomifyTiler;
+    use crate::MagickTiler;
+
+    /// Tiles a 300x200 image into `dir/name` with 128 pixel JPEG tiles
+    fn tile(dir: &Path, mut tiler: Box<dyn MagickTiler>, name: &str) -> (PathBuf, TileSetInfo) {
+        let image = dir.join("image.png");
+        if !image.exists() {
+            image::RgbImage::from_fn(300, 200, |x, y| image::Rgb([x as u8, y as u8, 0]))
+                .save(&image)
+                .unwrap();
+        }
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_format(ImageFormat::JPEG);
+        tiler.base_mut().set_tile_size(128);
+        tiler.base_mut().set_working_directory(dir);
+        tiler.base_mut().set_generate_preview_html(false);
+        let target = dir.join(name);
+        let info = tiler.convert_to(&image, &target).unwrap();
+        (target, info)
+    }
+
+    /// One tileset of every scheme, with the path `detect_scheme` is given
+    fn tilesets(dir: &Path) -> Vec<(TilingScheme, PathBuf, TileSetInfo)> {
+        let tilers: [(TilingScheme, Box<dyn MagickTiler>); 6] = [
+            (TilingScheme::Tms, Box::new(TMSTiler::new())),
+            (TilingScheme::Zoomify, Box::new(ZoomifyTiler::new())),
+            (TilingScheme::GoogleMaps, Box::new(GoogleMapsTiler::new())),
+            (TilingScheme::DeepZoom, Box::new(DeepZoomTiler::new())),
+            (TilingScheme::IIIF, Box::new(IIIFTiler::new())),
+            (
+                TilingScheme::PMTiles,
+                Box::new(PMTilesTiler::new(TilingScheme::Tms).unwrap()),
+            ),
+        ];
+        tilers
+            .into_iter()
+            .map(|(scheme, tiler)| {
+                let (target, info) = tile(dir, tiler, scheme.name());
+                let target = match scheme {
+                    TilingScheme::PMTiles => target.with_extension("pmtiles"),
+                    _ => target,
+                };
+                (scheme, target, info)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_scheme_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        for (scheme, target, _) in tilesets(dir.path()) {
+            let detected = detect_scheme(&target)
+                .unwrap_or_else(|| panic!("No scheme detected for {}", scheme));
+            assert_eq!(detected.scheme(), scheme);
+            assert_eq!(
+                detected.metadata().format(),
+                ImageFormat::JPEG,
+                "{}",
+                scheme
+            );
+            assert!(detected.validate(&target, false).is_valid(), "{}", scheme);
+        }
+    }
+
+    #[test]
+    fn directories_without_a_tileset_are_not_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(detect_scheme(dir.path()).is_none());
+        assert!(detect_scheme(&dir.path().join("missing")).is_none());
+
+        // Tiles alone are not a tileset
+        fs::create_dir_all(dir.path().join("0/0")).unwrap();
+        fs::write(dir.path().join("0/0/0.png"), b"").unwrap();
+        assert!(detect_scheme(dir.path()).is_none());
+    }
+
+    #[test]
+    fn unparseable_descriptors_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (target, _) = tile(dir.path(), Box::new(DeepZoomTiler::new()), "dzi");
+        fs::write(target.join("image.dzi"), "<Image/>").unwrap();
+        assert!(detect_scheme(&target).is_none());
+    }
+
+    #[test]
+    fn metadata_is_read_back_into_tileset_info() {
+        let dir = tempfile::tempdir().unwrap();
+        for (scheme, target, info) in tilesets(dir.path()) {
+            let read = scheme.read_tileset_info(&target).unwrap();
+            assert_eq!(read.image_file(), target, "{}", scheme);
+            assert_eq!(
+                (read.image_width(), read.image_height()),
+                (info.image_width(), info.image_height()),
+                "{}",
+                scheme
+            );
+            assert_eq!(
+                (read.tile_width(), read.tile_height()),
+                (128, 128),
+                "{}",
+                scheme
+            );
+            assert_eq!(read.tile_format(), ImageFormat::JPEG, "{}", scheme);
+
+            // Deep Zoom halves its levels down to a single pixel
+            let zoom_levels = match scheme {
+                TilingScheme::DeepZoom => 10,
+                _ => info.zoom_levels(),
+            };
+            assert_eq!(read.zoom_levels(), zoom_levels, "{}", scheme);
+
+            // Only some schemes record the encoder settings
+            match scheme {
+                TilingScheme::GoogleMaps | TilingScheme::PMTiles => {
+                    assert_eq!(read.encoder_options(), info.encoder_options(), "{}", scheme)
+                }
+                _ => assert_eq!(read.encoder_options(), None, "{}", scheme),
+            }
+
+            let detected = detect_scheme(&target).unwrap().tileset_info(&target);
+            assert_eq!(
+                serde_json::to_value(detected).unwrap(),
+                serde_json::to_value(read).unwrap(),
+                "{}",
+                scheme
+            );
+        }
+    }
+
+    #[test]
+    fn missing_metadata_cannot_be_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        for scheme in TilingScheme::ALL {
+            assert!(scheme.read_tileset_info(dir.path()).is_err(), "{}", scheme);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::geo::BoundingBox;
+use crate::image::ImageFormat;
+use crate::magick_tiler::TilingError;
+use crate::tile_set_info::TileSetInfo;
+
+const SCHEMA: &str = "
+    CREATE TABLE metadata (name TEXT, value TEXT);
+    CREATE UNIQUE INDEX name ON metadata (name);
+    CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+    CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);
+";
+
+/// The deduplicating schema: each distinct tile image is stored once in
+/// `images` and referenced from `map`, `tiles` is a view joining both
+const DEDUP_SCHEMA: &str = "
+    CREATE TABLE metadata (name TEXT, value TEXT);
+    CREATE UNIQUE INDEX name ON metadata (name);
+    CREATE TABLE map (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_id TEXT);
+    CREATE UNIQUE INDEX map_index ON map (zoom_level, tile_column, tile_row);
+    CREATE TABLE images (tile_data BLOB, tile_id TEXT);
+    CREATE UNIQUE INDEX images_id ON images (tile_id);
+    CREATE VIEW tiles AS
+        SELECT map.zoom_level AS zoom_level, map.tile_column AS tile_column,
+               map.tile_row AS tile_row, images.tile_data AS tile_data
+        FROM map JOIN images ON images.tile_id = map.tile_id;
+";
+
+/// The bounds of a tileset without a geographical bounding box: the area
+/// covered by the Web Mercator projection
+const WORLD_BOUNDS: &str = "-180.0,-85.05112878,180.0,85.05112878";
+
+/// Writes a tileset into a single MBTiles 1.3 file, an SQLite database with
+/// a `metadata` table and a `tiles` table addressing each tile by zoom
+/// level, column and row. Rows count from the bottom, as in TMS.
+///
+/// With deduplication, tiles with identical content (e.g. the blank tiles
+/// of the background buffer) are stored only once.
+pub struct MBTilesWriter {
+    path: PathBuf,
+    connection: Connection,
+    dedup: bool,
+
+    /// The ids of the images stored so far, by hash of their content
+    images: HashMap<u64, Vec<String>>,
+}
+
+impl MBTilesWriter {
+    /// Creates the MBTiles file, replacing an existing one. Tiles are written
+    /// in a single transaction that `finish` commits.
+    pub fn create(path: &Path, dedup: bool) -> Result<Self, TilingError> {
+        if path.exists() {
+            fs::remove_file(path).map_err(TilingError::not_writable(path))?;
+        }
+        let connection = Connection::open(path).map_err(sql_error(path))?;
+        connection
+            .execute_batch(if dedup { DEDUP_SCHEMA } else { SCHEMA })
+            .and_then(|_| connection.execute_batch("BEGIN"))
+            .map_err(sql_error(path))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            connection,
+            dedup,
+            images: HashMap::new(),
+        })
+    }
+
+    /// Writes the metadata of a tileset: its name, tile format, bounds and
+    /// zoom level range. `bounds` is the geographical extent of the image;
+    /// without it, the bounds span the whole world.
+    pub fn write_metadata(
+        &self,
+        info: &TileSetInfo,
+        bounds: Option<&BoundingBox>,
+    ) -> Result<(), TilingError> {
+        let name = info
+            .image_file()
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let bounds = bounds.map_or(WORLD_BOUNDS.to_string(), |b| {
+            format!("{},{},{},{}", b.west(), b.south(), b.east(), b.north())
+        });
+        let metadata = [
+            ("name", name),
+            ("format", mbtiles_format(info.tile_format()).to_string()),
+            ("bounds", bounds),
+            ("minzoom", "0".to_string()),
+            ("maxzoom", (info.zoom_levels() - 1).to_string()),
+            ("type", "overlay".to_string()),
+        ];
+        for (name, value) in metadata {
+            self.connection
+                .execute(
+                    "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                    params![name, value],
+                )
+                .map_err(sql_error(&self.path))?;
+        }
+        Ok(())
+    }
+
+    /// Writes a single tile. `row` counts from the bottom.
+    pub fn write_tile(
+        &mut self,
+        zoom_level: i32,
+        column: i32,
+        row: i32,
+        data: &[u8],
+    ) -> Result<(), TilingError> {
+        if !self.dedup {
+            self.connection
+                .execute(
+                    "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![zoom_level, column, row, data],
+                )
+                .map_err(sql_error(&self.path))?;
+            return Ok(());
+        }
+
+        let tile_id = self.image_id(data)?;
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO map (zoom_level, tile_column, tile_row, tile_id) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![zoom_level, column, row, tile_id],
+            )
+            .map_err(sql_error(&self.path))?;
+        Ok(())
+    }
+
+    /// Returns the id of the stored image with the given content, storing it
+    /// first if it is new. Images with the same hash are compared byte by
+    /// byte.
+    fn image_id(&mut self, data: &[u8]) -> Result<String, TilingError> {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let candidates = self.images.entry(hash).or_default();
+        for id in candidates.iter() {
+            let stored: Option<Vec<u8>> = self
+                .connection
+                .query_row(
+                    "SELECT tile_data FROM images WHERE tile_id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(sql_error(&self.path))?;
+            if stored.as_deref() == Some(data) {
+                return Ok(id.clone());
+            }
+        }
+
+        let id = format!("{:016x}-{}", hash, candidates.len());
+        self.connection
+            .execute(
+                "INSERT INTO images (tile_data, tile_id) VALUES (?1, ?2)",
+                params![data, id],
+            )
+            .map_err(sql_error(&self.path))?;
+        candidates.push(id.clone());
+        Ok(id)
+    }
+
+    /// Adds every tile of a TMS directory tree, i.e.
+    /// [zoomlevel]/[column]/[row].[ext], with the extension of the tile
+    /// format. Other files, like tilemapresource.xml, are left out.
+    pub fn write_tms_tree(&mut self, dir: &Path, format: ImageFormat) -> Result<(), TilingError> {
+        for (zoom_level, zoom_dir) in numbered_entries(dir, None)? {
+            for (column, column_dir) in numbered_entries(&zoom_dir, None)? {
+                for (row, tile) in numbered_entries(&column_dir, Some(format.extension()))? {
+                    let data = fs::read(&tile).map_err(TilingError::not_readable(&tile))?;
+                    self.write_tile(zoom_level, column, row, &data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Commits the tiles and closes the file.
+    pub fn finish(self) -> Result<(), TilingError> {
+        self.connection
+            .execute_batch("COMMIT")
+            .map_err(sql_error(&self.path))?;
+        self.connection
+            .close()
+            .map_err(|(_, e)| sql_error(&self.path)(e))
+    }
+}
+
+/// The `format` metadata value: the extension for the formats named by the
+/// MBTiles specification, the MIME type for all others
+fn mbtiles_format(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::JPEG | ImageFormat::PNG | ImageFormat::WebP | ImageFormat::WebPLossless => {
+            format.extension()
+        }
+        ImageFormat::TIFF | ImageFormat::AVIF => format.mime_type(),
+    }
+}
+
+/// The entries of a directory whose name (without the extension, if one is
+/// given) is a number, with that number
+fn numbered_entries(
+    dir: &Path,
+    extension: Option<&str>,
+) -> Result<Vec<(i32, PathBuf)>, TilingError> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).map_err(TilingError::not_readable(dir))? {
+        let path = entry.map_err(TilingError::not_readable(dir))?.path();
+        let name = match extension {
+            Some(ext) if path.extension().is_some_and(|e| e == ext) => path.file_stem(),
+            Some(_) => None,
+            None if path.is_dir() => path.file_name(),
+            None => None,
+        };
+        if let Some(number) = name.and_then(|n| n.to_str()?.parse().ok()) {
+            entries.push((number, path));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Returns a function that wraps an SQLite error raised while writing `path`,
+/// for use with `map_err`.
+fn sql_error(path: &Path) -> impl FnOnce(rusqlite::Error) -> TilingError {
+    let path = path.to_path_buf();
+    move |e| TilingError::OutputNotWritable {
+        path,
+        source: io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::NativeImageProcessor;
+
+    fn tiles(path: &Path) -> Vec<(i32, i32, i32, Vec<u8>)> {
+        let connection = Connection::open(path).unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles \
+                 ORDER BY zoom_level, tile_column, tile_row",
+            )
+            .unwrap();
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    fn count(path: &Path, table: &str) -> i64 {
+        let connection = Connection::open(path).unwrap();
+        connection
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn deduplicated_tiles_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.mbtiles");
+        let mut writer = MBTilesWriter::create(&path, true).unwrap();
+        writer.write_tile(0, 0, 0, b"blank").unwrap();
+        writer.write_tile(1, 0, 0, b"tile").unwrap();
+        writer.write_tile(1, 1, 0, b"blank").unwrap();
+        writer.write_tile(1, 0, 1, b"blank").unwrap();
+        writer.write_tile(1, 1, 1, b"other").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(count(&path, "map"), 5);
+        assert_eq!(count(&path, "images"), 3);
+        assert_eq!(
+            tiles(&path),
+            vec![
+                (0, 0, 0, b"blank".to_vec()),
+                (1, 0, 0, b"tile".to_vec()),
+                (1, 0, 1, b"blank".to_vec()),
+                (1, 1, 0, b"blank".to_vec()),
+                (1, 1, 1, b"other".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tms_trees_round_trip_with_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.png");
+        image::RgbImage::new(512, 256).save(&image).unwrap();
+        let info = TileSetInfo::new(&image, 256, 256, &NativeImageProcessor::new()).unwrap();
+
+        let tree = dir.path().join("tms");
+        for (z, x, y) in [(0, 0, 0), (1, 0, 0), (1, 1, 0)] {
+            let column = tree.join(z.to_string()).join(x.to_string());
+            fs::create_dir_all(&column).unwrap();
+            fs::write(
+                column.join(format!("{}.jpg", y)),
+                format!("{}-{}-{}", z, x, y),
+            )
+            .unwrap();
+        }
+        fs::write(tree.join("tilemapresource.xml"), "<TileMap/>").unwrap();
+
+        let path = dir.path().join("tiles.mbtiles");
+        let mut writer = MBTilesWriter::create(&path, false).unwrap();
+        writer.write_metadata(&info, None).unwrap();
+        writer.write_tms_tree(&tree, ImageFormat::JPEG).unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            tiles(&path),
+            vec![
+                (0, 0, 0, b"0-0-0".to_vec()),
+                (1, 0, 0, b"1-0-0".to_vec()),
+                (1, 1, 0, b"1-1-0".to_vec()),
+            ]
+        );
+        let connection = Connection::open(&path).unwrap();
+        let value = |name: &str| -> String {
+            connection
+                .query_row(
+                    "SELECT value FROM metadata WHERE name = ?1",
+                    params![name],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(value("name"), "image");
+        assert_eq!(value("format"), "jpg");
+        assert_eq!(value("bounds"), WORLD_BOUNDS);
+        assert_eq!(value("minzoom"), "0");
+        assert_eq!(value("maxzoom"), (info.zoom_levels() - 1).to_string());
+    }
+}
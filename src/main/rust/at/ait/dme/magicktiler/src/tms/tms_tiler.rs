@@ -0,0 +1,530 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{debug, error, info};
+
+use super::mbtiles_writer::MBTilesWriter;
+use crate::geo::BoundingBox;
+use crate::image::ImageProcessorImpl;
+use crate::magick_tiler::{BaseMagickTiler, MagickTiler, TilingError};
+use crate::pyramid::build_stripe_pyramid;
+use crate::sink::{TileKey, TileSink};
+use crate::streaming::LevelLayout;
+use crate::stripe::{Orientation, Stripe};
+use crate::tile_set_info::TileSetInfo;
+use crate::tiling_scheme::TilingScheme;
+
+/// A tiler that implements the TMS tiling scheme.
+///
+/// The TMS tiling scheme arranges tiles in the following folder/file structure:
+/// /tileset-root/[zoomlevel]/[column]/[row].jpg (or .png, .webp, .avif)
+///
+/// The tileset is written through the configured `TileSink`, by default to
+/// the target directory.
+///
+/// The highest-resolution zoom level has the highest number. Column/row
+/// numbering of tiles starts left/bottom, counting direction is upwards/right.
+///
+/// TMS does NOT allow irregularly sized tiles on the border! Each tile must
+/// be rectangular. If the image width/height are not integer multiples of
+/// the tilesize, a background-color buffer must be added. TMS mandates this
+/// buffer to be added to the TOP and RIGHT of the image!
+///
+/// Alternatively, the tiles can be packaged into a single MBTiles file (see
+/// `MBTilesWriter`).
+pub struct TMSTiler {
+    base: BaseMagickTiler,
+
+    /// Whether to package the tiles into a single MBTiles file
+    mbtiles: bool,
+
+    /// Whether to store identical tiles only once in the MBTiles file
+    dedup: bool,
+
+    /// Geographical bounding box of the image, recorded in the MBTiles
+    /// metadata
+    bbox: Option<BoundingBox>,
+}
+
+/// File name of the TMS tilemap resource descriptor
+pub(crate) const METADATA_FILE: &str = "tilemapresource.xml";
+
+const METADATA_TEMPLATE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<TileMap version="1.0.0" tilemapservice="http://tms.osgeo.org/1.0.0">
+  <Title>@title@</Title>
+  <Abstract></Abstract>
+  <SRS></SRS>
+  <BoundingBox minx="-@height@.00000000000000" miny="0.00000000000000" maxx="0.00000000000000" maxy="@width@.00000000000000"/>
+  <Origin x="-@height@.00000000000000" y="0.00000000000000"/>
+  <TileFormat width="@tilewidth@" height="@tileheight@" mime-type="@mimetype@" extension="@ext@"/>
+  <TileSets profile="raster">
+@tilesets@  </TileSets>
+</TileMap>
+"#;
+
+const TILESET_TEMPLATE: &str = "    <TileSet href=\"@idx@\" units-per-pixel=\"@unitsPerPixel@.00000000000000\" order=\"@idx@\"/>\n";
+
+impl TMSTiler {
+    pub fn new() -> Self {
+        let mut base = BaseMagickTiler::new();
+        base.set_background_color("#ffffffff".to_string());
+        Self {
+            base,
+            mbtiles: false,
+            dedup: false,
+            bbox: None,
+        }
+    }
+
+    /// Sets whether `convert_to` packages the tiles into a single MBTiles
+    /// file instead of a directory (default: false). The file holds no
+    /// tilemapresource.xml and no preview is generated.
+    pub fn set_mbtiles(&mut self, mbtiles: bool) {
+        self.mbtiles = mbtiles;
+    }
+
+    /// Sets whether identical tiles are stored only once in the MBTiles file
+    /// (default: false).
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+
+    pub fn bounding_box(&self) -> Option<&BoundingBox> {
+        self.bbox.as_ref()
+    }
+
+    /// Sets the geographical bounding box of the image, recorded as the
+    /// bounds of an MBTiles file. Without it, the bounds span the world.
+    pub fn set_bounding_box(&mut self, bbox: BoundingBox) {
+        self.bbox = Some(bbox);
+    }
+
+    /// Tiles the image into a directory in the working directory and
+    /// packages the tiles into an MBTiles file. A configured tile sink is
+    /// not used.
+    fn convert_to_mbtiles(
+        &mut self,
+        image: &Path,
+        target: &Path,
+    ) -> Result<TileSetInfo, TilingError> {
+        let mbtiles_file = mbtiles_target(target);
+        let temp_dir = self.base.working_directory().join(format!(
+            "{}-tms",
+            image.file_stem().unwrap().to_string_lossy()
+        ));
+        let sink = self.base.tile_sink.take();
+        let result = self
+            .base
+            .prepare(image, &temp_dir)
+            .and_then(|info| self.convert_internal(image, info))
+            .and_then(|info| {
+                debug!("Packaging MBTiles");
+                if let Some(parent) = mbtiles_file.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    fs::create_dir_all(parent).map_err(TilingError::not_writable(parent))?;
+                }
+                let mut writer = MBTilesWriter::create(&mbtiles_file, self.dedup)?;
+                writer.write_metadata(&info, self.bbox.as_ref())?;
+                writer.write_tms_tree(&temp_dir, info.tile_format())?;
+                writer.finish()?;
+                Ok(info)
+            });
+
+        self.base.tile_sink = sink;
+        if let Err(e) = fs::remove_dir_all(&temp_dir) {
+            error!("Could not delete {}: {}", temp_dir.display(), e);
+        }
+        result
+    }
+
+    /// Tiles the stripe of a column into the working directory and puts the
+    /// tiles into the sink.
+    fn generate_tms_tiles(
+        &self,
+        stripe: &Stripe,
+        info: &TileSetInfo,
+        sink: &dyn TileSink,
+        zoom_level: i32,
+        column: i32,
+        base_name: &str,
+    ) -> Result<(), TilingError> {
+        // Stripes may be tiled concurrently, so the temporary files are named
+        // after the stripe
+        let ext = info.tile_format().extension();
+        let tmp_prefix = format!("{}-tmp-{}-{}-", base_name, zoom_level, column);
+        let filename_pattern = self
+            .base
+            .working_directory()
+            .join(format!("{}%d.{}", tmp_prefix, ext));
+
+        self.base.processor().crop(
+            stripe.image_file(),
+            &filename_pattern,
+            info.tile_width(),
+            info.tile_height(),
+        )?;
+
+        // Crops are numbered from the top, TMS rows from the bottom
+        let level = info.zoom_levels() - zoom_level - 1;
+        let rows = tile_rows(info.image_height(), info.tile_height(), 1 << level);
+        for i in 0..rows {
+            let tile = filename_pattern.with_file_name(format!("{}{}.{}", tmp_prefix, i, ext));
+            let key = TileKey::new(
+                TilingScheme::Tms,
+                zoom_level,
+                column,
+                rows - i - 1,
+                info.tile_format(),
+            );
+            sink.put_tile_file(&key, &tile)?;
+        }
+
+        Ok(())
+    }
+
+    fn merge_stripes(
+        &self,
+        stripe1: &Stripe,
+        stripe2: Option<&Stripe>,
+        target_file: &Path,
+    ) -> Result<Stripe, TilingError> {
+        let height = if (stripe1.height() / self.base.tile_height()) % 2 != 0 {
+            stripe1.height() / 2 + self.base.tile_height() / 2
+        } else {
+            stripe1.height() / 2
+        };
+
+        match stripe2 {
+            None => Ok(stripe1.shrink_with_canvas(
+                Some(ImageProcessorImpl::GRAVITY_SOUTHWEST),
+                self.base.tile_width(),
+                height,
+                self.base.processor().get_background_color(),
+                target_file,
+                self.base.processor(),
+            )?),
+            Some(s2) => Ok(stripe1.merge_with_canvas(
+                s2,
+                Some(ImageProcessorImpl::GRAVITY_SOUTHWEST),
+                self.base.tile_width(),
+                height,
+                self.base.processor().get_background_color(),
+                target_file,
+                self.base.processor(),
+            )?),
+        }
+    }
+
+    /// Computes the pyramid in-process while streaming the image. Every level
+    /// is padded at the top and right to full tiles, like the stripes merged
+    /// by `merge_stripes`.
+    fn stream_tms_tiles(
+        &self,
+        image: &Path,
+        info: &TileSetInfo,
+        sink: &dyn TileSink,
+        base_name: &str,
+    ) -> Result<(), TilingError> {
+        let tile_width = info.tile_width() as u32;
+        let tile_height = info.tile_height() as u32;
+        let mut width = info.number_of_x_tiles(0) as u32 * tile_width;
+        let mut height = canvas_height(info.image_height(), info.tile_height()) as u32;
+        let mut content_height = info.image_height() as u32;
+        let mut layouts = Vec::new();
+        for _ in 0..info.zoom_levels() {
+            layouts.push(LevelLayout::new(width, height, height - content_height));
+            width = width.div_ceil(2).div_ceil(tile_width) * tile_width;
+            content_height = height.div_ceil(2);
+            height = content_height.div_ceil(tile_height) * tile_height;
+        }
+
+        let progress = self.base.progress_tracker(info.total_number_of_tiles());
+        self.base
+            .stream_pyramid(image, layouts, |level, column, row, tile| {
+                // TMS counts rows from the bottom
+                let zoom_level = info.zoom_levels() - level as i32 - 1;
+                let rows = tile_rows(info.image_height(), info.tile_height(), 1 << level);
+                let key = TileKey::new(
+                    TilingScheme::Tms,
+                    zoom_level,
+                    column as i32,
+                    rows - row as i32 - 1,
+                    info.tile_format(),
+                );
+                self.base.put_tile(sink, &key, tile, base_name)?;
+                progress.tiles_done(1, zoom_level, column as i32);
+                Ok(())
+            })
+    }
+
+    fn generate_tilemap_resource_xml(
+        &self,
+        info: &TileSetInfo,
+        sink: &dyn TileSink,
+    ) -> Result<(), TilingError> {
+        let mut tilesets = String::new();
+        for i in 0..info.zoom_levels() {
+            tilesets.push_str(&TILESET_TEMPLATE.replace("@idx@", &i.to_string()).replace(
+                "@unitsPerPixel@",
+                &(2_i32.pow((info.zoom_levels() - i - 1) as u32)).to_string(),
+            ));
+        }
+
+        let metadata = METADATA_TEMPLATE
+            .replace(
+                "@title@",
+                &info.image_file().file_name().unwrap().to_string_lossy(),
+            )
+            .replace("@width@", &info.image_width().to_string())
+            .replace("@height@", &info.image_height().to_string())
+            .replace("@tilewidth@", &info.tile_width().to_string())
+            .replace("@tileheight@", &info.tile_height().to_string())
+            .replace("@mimetype@", info.tile_format().mime_type())
+            .replace("@ext@", info.tile_format().extension())
+            .replace("@tilesets@", &tilesets);
+
+        sink.put_metadata(METADATA_FILE, metadata.as_bytes())
+            .map_err(|e| {
+                error!("Error writing metadata XML: {}", e);
+                e
+            })
+    }
+
+    fn generate_preview(&self, info: &TileSetInfo, sink: &dyn TileSink) -> Result<(), TilingError> {
+        let tileset_url = format!(
+            "file:///{}/",
+            self.base
+                .tileset_root_dir()
+                .unwrap()
+                .to_string_lossy()
+                .replace('\\', "/")
+        );
+        sink.put_metadata("preview.html", preview_html(info, &tileset_url).as_bytes())
+    }
+}
+
+/// The OpenLayers preview of a tileset whose root is at `tileset_url` (ending
+/// with '/')
+pub(crate) fn preview_html(info: &TileSetInfo, tileset_url: &str) -> String {
+    let template = include_str!("tms-template.html");
+    template
+        .replace(
+            "@title@",
+            &info.image_file().file_name().unwrap().to_string_lossy(),
+        )
+        .replace("@width@", &info.image_width().to_string())
+        .replace("@height@", &info.image_height().to_string())
+        .replace("@maxZoom@", &(info.zoom_levels() - 1).to_string())
+        .replace(
+            "@maxResolution@",
+            &2_i32.pow((info.zoom_levels() - 1) as u32).to_string(),
+        )
+        .replace("@numZoomLevels@", &info.zoom_levels().to_string())
+        .replace("@tilesetpath@", tileset_url)
+        .replace("@ext@", info.tile_format().extension())
+}
+
+/// Height of the canvas the base image is placed on before striping. The
+/// buffer is added to the top of the image, the canvas is always an integer
+/// multiple of the tile height.
+pub(crate) fn canvas_height(image_height: i32, tile_height: i32) -> i32 {
+    image_height + tile_height - (image_height % tile_height)
+}
+
+/// Number of tile rows of the level whose tiles cover `factor` x `factor`
+/// tiles of the base level, including the rows of the background buffer.
+/// The tiler, `TMSValidator` and on-demand tilesets all count rows this way.
+pub(crate) fn tile_rows(image_height: i32, tile_height: i32, factor: i32) -> i32 {
+    let base_rows = canvas_height(image_height, tile_height) / tile_height;
+    (base_rows + factor - 1) / factor
+}
+
+/// The part of a tile covered by the image, as (x, y, width, height). Tiles
+/// hold the image in their bottom left corner and are padded with the
+/// background above and to the right; tiles of the background buffer have
+/// an empty image area. `row` counts from the bottom.
+pub(crate) fn tile_content(
+    (image_width, image_height): (i32, i32),
+    (tile_width, tile_height): (i32, i32),
+    factor: i32,
+    column: i32,
+    row: i32,
+) -> (u32, u32, u32, u32) {
+    // Rounded down, as the pixels on the edge of downscaled levels blend the
+    // image with the padding
+    let width = (image_width / factor - column * tile_width).clamp(0, tile_width);
+    let height = (image_height / factor - row * tile_height).clamp(0, tile_height);
+    (
+        0,
+        (tile_height - height) as u32,
+        width as u32,
+        height as u32,
+    )
+}
+
+/// Returns the MBTiles file name, adding a .mbtiles extension if necessary.
+fn mbtiles_target(target: &Path) -> PathBuf {
+    if target
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mbtiles"))
+    {
+        target.to_path_buf()
+    } else {
+        PathBuf::from(format!("{}.mbtiles", target.to_string_lossy()))
+    }
+}
+
+impl Default for TMSTiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MagickTiler for TMSTiler {
+    fn base(&self) -> &BaseMagickTiler {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut BaseMagickTiler {
+        &mut self.base
+    }
+
+    fn set_tile_sink(&mut self, sink: Arc<dyn TileSink>) -> Result<(), TilingError> {
+        self.base.set_tile_sink(sink);
+        Ok(())
+    }
+
+    /// Writes tilemapresource.xml and the preview. The tiles are not
+    /// packaged into an MBTiles file, even if enabled.
+    fn write_descriptors(
+        &mut self,
+        image: &Path,
+        target: &Path,
+    ) -> Result<TileSetInfo, TilingError> {
+        let info = self.base.prepare(image, target)?;
+        let sink = self.base.tile_sink();
+        self.generate_tilemap_resource_xml(&info, sink.as_ref())?;
+        if self.base.generate_preview() {
+            self.generate_preview(&info, sink.as_ref())?;
+        }
+        sink.finalize()?;
+        Ok(info)
+    }
+
+    fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError> {
+        let target = self.base.default_target();
+        self.convert_to(image, &target)
+    }
+
+    fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
+        if self.mbtiles {
+            return self.convert_to_mbtiles(image, target);
+        }
+        let info = self.base.prepare(image, target)?;
+        self.convert_internal(image, info)
+    }
+
+    fn convert_internal(
+        &mut self,
+        image: &Path,
+        info: TileSetInfo,
+    ) -> Result<TileSetInfo, TilingError> {
+        let start_time = std::time::Instant::now();
+        info!(
+            "Generating TMS tiles for file {}: {}x{}, {}x{} basetiles, {} zoom levels, {} tiles total",
+            image.file_name().unwrap().to_string_lossy(),
+            info.image_width(),
+            info.image_height(),
+            info.number_of_x_tiles(0),
+            info.number_of_y_tiles(0),
+            info.zoom_levels(),
+            info.total_number_of_tiles()
+        );
+
+        let base_name = image.file_stem().unwrap().to_string_lossy().into_owned();
+        let sink = self.base.tile_sink();
+
+        if self.base.streaming() {
+            // Steps 1 and 2 - stream the image through the pyramid
+            self.stream_tms_tiles(image, &info, sink.as_ref(), &base_name)?;
+        } else {
+            // Step 1 - stripe the base image
+            self.base.check_cancelled()?;
+            debug!("Striping base image");
+            let canvas_height = canvas_height(info.image_height(), self.base.tile_height());
+
+            let base_stripes = self.base.stripe_image_with_canvas(
+                image,
+                Orientation::Vertical,
+                info.number_of_x_tiles(0),
+                self.base.tile_width(),
+                info.image_height(),
+                self.base.tile_width(),
+                canvas_height,
+                ImageProcessorImpl::GRAVITY_SOUTHWEST,
+                &format!("{}-0-", base_name),
+            )?;
+
+            // Step 2 - tile base image stripes and compute the pyramid
+            let progress = self.base.progress_tracker(info.total_number_of_tiles());
+            build_stripe_pyramid(
+                self.base.worker_threads(),
+                self.base.cancellation_token(),
+                base_stripes,
+                info.zoom_levels(),
+                |level, j, stripe1, stripe2| {
+                    let target_file = self
+                        .base
+                        .working_directory()
+                        .join(format!("{}-{}-{}.tif", base_name, level, j));
+                    Ok(Some(self.merge_stripes(stripe1, stripe2, &target_file)?))
+                },
+                |level, j, stripe| {
+                    if j == 0 {
+                        debug!("Tiling level {}", level + 1);
+                    }
+                    let zoom_level = info.zoom_levels() - level - 1;
+                    self.generate_tms_tiles(
+                        stripe,
+                        &info,
+                        sink.as_ref(),
+                        zoom_level,
+                        j as i32,
+                        &base_name,
+                    )?;
+                    let rows = tile_rows(info.image_height(), info.tile_height(), 1 << level);
+                    progress.tiles_done(rows, zoom_level, j as i32);
+                    Ok(())
+                },
+            )?;
+        }
+
+        // Step 3 - generate tilemapresource.xml
+        self.generate_tilemap_resource_xml(&info, sink.as_ref())?;
+
+        // Step 4 (optional) - generate OpenLayers preview
+        if self.base.generate_preview() && !self.mbtiles {
+            self.generate_preview(&info, sink.as_ref())?;
+        }
+        sink.finalize()?;
+
+        info!("Took {} ms", start_time.elapsed().as_millis());
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_include_the_background_buffer() {
+        // 200 pixels are padded to 256, 256 pixels get a full buffer row
+        assert_eq!(tile_rows(200, 64, 1), 4);
+        assert_eq!(tile_rows(256, 64, 1), 5);
+        assert_eq!(tile_rows(256, 64, 2), 3);
+        assert_eq!(tile_rows(256, 64, 4), 2);
+        assert_eq!(tile_rows(256, 64, 8), 1);
+        assert_eq!(tile_rows(10, 256, 1), 1);
+    }
+}
@@ -0,0 +1,562 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::Rgba;
+
+use super::tms_tiler::{tile_content, tile_rows, METADATA_FILE};
+use crate::tiling_scheme::{tile_format, TilesetMetadata};
+use crate::validation_failed_exception::ValidationFailedError;
+use crate::validation_report::{Finding, FindingCategory, ValidationReport};
+use crate::validator::{check_tile_content, Validator, DEFAULT_BACKGROUND};
+
+/// Validator for the TMS tiling scheme. Recomputes the zoom/column/row tree
+/// from the TileSets and TileFormat declared in tilemapresource.xml and checks
+/// it against the files on disk: every tile must exist, have the declared tile
+/// size, and no other tiles may be present.
+pub struct TMSValidator {
+    /// Decode every tile instead of reading only its header
+    deep: bool,
+
+    /// The background colour blank tiles are made of
+    background: Rgba<u8>,
+}
+
+/// The tileset parameters declared in tilemapresource.xml
+struct TileMap {
+    width: i32,
+    height: i32,
+    tile_width: i32,
+    tile_height: i32,
+    extension: String,
+    tile_sets: Vec<TileSet>,
+}
+
+/// A single zoom level declared in tilemapresource.xml
+struct TileSet {
+    href: String,
+    units_per_pixel: i32,
+    order: i32,
+}
+
+/// Files next to the tile directories that are part of a TMS tileset
+const ALLOWED_FILES: [&str; 2] = [METADATA_FILE, "preview.html"];
+
+impl TMSValidator {
+    pub fn new() -> Self {
+        Self {
+            deep: false,
+            background: DEFAULT_BACKGROUND,
+        }
+    }
+
+    fn parse_tilemap_resource(&self, xml: &str) -> Result<TileMap, ValidationFailedError> {
+        // Helper function to extract an attribute value of an element
+        let extract_value = |element: &str, attr: &str| -> Result<String, ValidationFailedError> {
+            let start = element
+                .find(&format!(" {}=\"", attr))
+                .ok_or_else(|| ValidationFailedError::new(format!("Missing attribute {}", attr)))?
+                + attr.len()
+                + 3;
+            let end = element[start..]
+                .find('"')
+                .ok_or_else(|| ValidationFailedError::new("Missing closing quote"))?;
+            Ok(element[start..start + end].to_string())
+        };
+        let extract_number = |element: &str, attr: &str| -> Result<f64, ValidationFailedError> {
+            extract_value(element, attr)?.parse::<f64>().map_err(|e| {
+                ValidationFailedError::new(format!("Invalid number for {}: {}", attr, e))
+            })
+        };
+        // Helper function to find all elements with the given name
+        let elements = |name: &str| -> Vec<&str> {
+            xml.match_indices(&format!("<{} ", name))
+                .map(|(start, _)| {
+                    let end = xml[start..].find('>').map_or(xml.len(), |end| start + end);
+                    &xml[start..end]
+                })
+                .collect()
+        };
+
+        let bounding_box = *elements("BoundingBox")
+            .first()
+            .ok_or_else(|| ValidationFailedError::new("Missing BoundingBox"))?;
+        let tile_format = *elements("TileFormat")
+            .first()
+            .ok_or_else(|| ValidationFailedError::new("Missing TileFormat"))?;
+
+        let tile_sets = elements("TileSet")
+            .into_iter()
+            .map(|element| {
+                Ok(TileSet {
+                    href: extract_value(element, "href")?,
+                    units_per_pixel: extract_number(element, "units-per-pixel")? as i32,
+                    order: extract_number(element, "order")? as i32,
+                })
+            })
+            .collect::<Result<Vec<_>, ValidationFailedError>>()?;
+
+        // The tiler writes the image height as (negative) minx and the width as maxy
+        let tile_map = TileMap {
+            width: extract_number(bounding_box, "maxy")?.round() as i32,
+            height: -extract_number(bounding_box, "minx")?.round() as i32,
+            tile_width: extract_number(tile_format, "width")? as i32,
+            tile_height: extract_number(tile_format, "height")? as i32,
+            extension: extract_value(tile_format, "extension")?,
+            tile_sets,
+        };
+
+        if tile_map.tile_width <= 0
+            || tile_map.tile_height <= 0
+            || tile_map.width <= 0
+            || tile_map.height <= 0
+        {
+            return Err(ValidationFailedError::new(
+                "Invalid tile size or image dimensions in tilemapresource.xml",
+            ));
+        }
+        if tile_map.tile_sets.is_empty() {
+            return Err(ValidationFailedError::new(
+                "No TileSets declared in tilemapresource.xml",
+            ));
+        }
+        if let Some(tile_set) = tile_map.tile_sets.iter().find(|t| t.units_per_pixel <= 0) {
+            return Err(ValidationFailedError::new(format!(
+                "Invalid units-per-pixel for TileSet {}",
+                tile_set.href
+            )));
+        }
+
+        Ok(tile_map)
+    }
+
+    /// Checks that every expected tile exists and has the declared tile size.
+    /// Returns the relative paths of all expected directories and tiles.
+    fn check_tiles(
+        &self,
+        tile_map: &TileMap,
+        dir: &Path,
+        report: &mut ValidationReport,
+    ) -> HashSet<PathBuf> {
+        let base_columns = (tile_map.width as f64 / tile_map.tile_width as f64).ceil() as i32;
+
+        let mut expected = HashSet::new();
+        for tile_set in &tile_map.tile_sets {
+            let zoom = tile_set.order;
+            let factor = tile_set.units_per_pixel as f64;
+            let columns = (base_columns as f64 / factor).ceil() as i32;
+            let rows = tile_rows(
+                tile_map.height,
+                tile_map.tile_height,
+                tile_set.units_per_pixel,
+            );
+
+            let level_dir = PathBuf::from(&tile_set.href);
+            expected.insert(level_dir.clone());
+            for column in 0..columns {
+                let column_dir = level_dir.join(column.to_string());
+                expected.insert(column_dir.clone());
+                for row in 0..rows {
+                    let tile = column_dir.join(format!("{}.{}", row, tile_map.extension));
+                    let path = dir.join(&tile);
+                    if !path.is_file() {
+                        report.add(
+                            Finding::error(
+                                FindingCategory::MissingTile,
+                                format!("Missing tile {}", tile.display()),
+                            )
+                            .at_tile(zoom, column, row)
+                            .with_path(&tile),
+                        );
+                        continue;
+                    }
+
+                    if self.deep {
+                        check_tile_content(
+                            dir,
+                            &tile,
+                            tile_map.tile_width,
+                            tile_map.tile_height,
+                            0,
+                            Some(tile_content(
+                                (tile_map.width, tile_map.height),
+                                (tile_map.tile_width, tile_map.tile_height),
+                                tile_set.units_per_pixel,
+                                column,
+                                row,
+                            )),
+                            self.background,
+                            Some((zoom, column, row)),
+                            report,
+                        );
+                    } else {
+                        match image::image_dimensions(&path) {
+                            Ok((width, height))
+                                if width as i32 != tile_map.tile_width
+                                    || height as i32 != tile_map.tile_height =>
+                            {
+                                report.add(
+                                    Finding::error(
+                                        FindingCategory::WrongDimensions,
+                                        format!(
+                                            "Tile {} is {}x{} instead of {}x{}",
+                                            tile.display(),
+                                            width,
+                                            height,
+                                            tile_map.tile_width,
+                                            tile_map.tile_height
+                                        ),
+                                    )
+                                    .at_tile(zoom, column, row)
+                                    .with_path(&tile),
+                                );
+                            }
+                            Ok(_) => {}
+                            Err(e) => {
+                                report.add(
+                                    Finding::error(
+                                        FindingCategory::UndecodableTile,
+                                        format!("Unreadable tile {}: {}", tile.display(), e),
+                                    )
+                                    .at_tile(zoom, column, row)
+                                    .with_path(&tile),
+                                );
+                            }
+                        }
+                    }
+                    expected.insert(tile);
+                }
+            }
+        }
+
+        expected
+    }
+
+    /// Reports every file or directory below `dir` that is not part of the
+    /// tileset.
+    fn check_extra_files(
+        &self,
+        dir: &Path,
+        relative: &Path,
+        expected: &HashSet<PathBuf>,
+        report: &mut ValidationReport,
+    ) -> Result<(), ValidationFailedError> {
+        for entry in fs::read_dir(dir.join(relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            let allowed = relative.as_os_str().is_empty()
+                && ALLOWED_FILES.iter().any(|f| entry.file_name() == *f);
+            if entry.path().is_dir() && expected.contains(&path) {
+                self.check_extra_files(dir, &path, expected, report)?;
+            } else if !expected.contains(&path) && !allowed {
+                report.add(
+                    Finding::error(
+                        FindingCategory::ExtraFile,
+                        format!(
+                            "Unexpected file {} - not declared in tilemapresource.xml",
+                            path.display()
+                        ),
+                    )
+                    .with_path(path),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check(
+        &self,
+        dir: &Path,
+        report: &mut ValidationReport,
+    ) -> Result<(), ValidationFailedError> {
+        if !self.is_tileset_dir(dir) {
+            return Err(ValidationFailedError::new(
+                "Not a TMS tileset - missing tilemapresource.xml",
+            ));
+        }
+
+        let xml = fs::read_to_string(dir.join(METADATA_FILE))?;
+        let tile_map = self.parse_tilemap_resource(&xml)?;
+        let expected = self.check_tiles(&tile_map, dir, report);
+        self.check_extra_files(dir, Path::new(""), &expected, report)
+    }
+}
+
+impl Default for TMSValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Validator for TMSValidator {
+    fn is_tileset_dir(&self, dir: &Path) -> bool {
+        dir.is_dir() && dir.join(METADATA_FILE).is_file()
+    }
+
+    fn read_metadata(&self, dir: &Path) -> Result<TilesetMetadata, ValidationFailedError> {
+        let xml = fs::read_to_string(dir.join(METADATA_FILE))?;
+        let tile_map = self.parse_tilemap_resource(&xml)?;
+        Ok(TilesetMetadata::new(
+            tile_map.width,
+            tile_map.height,
+            tile_map.tile_width,
+            tile_map.tile_height,
+            tile_map.tile_sets.len() as i32,
+            tile_format(&tile_map.extension)?,
+        ))
+    }
+
+    fn set_deep(&mut self, deep: bool) {
+        self.deep = deep;
+    }
+
+    fn set_background_color(&mut self, color: Rgba<u8>) {
+        self.background = color;
+    }
+
+    fn report(&self, dir: &Path) -> ValidationReport {
+        let mut report = ValidationReport::new(dir);
+        if let Err(e) = self.check(dir, &mut report) {
+            report.add(Finding::error(
+                FindingCategory::InvalidTileset,
+                e.to_string(),
+            ));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+    use crate::tms::TMSTiler;
+    use crate::MagickTiler;
+
+    /// Tiles a 300x200 image with 128 pixel tiles: 3 zoom levels, with 3x2,
+    /// 2x1 and 1x1 tiles
+    fn tileset(dir: &Path) -> PathBuf {
+        let image = dir.join("image.png");
+        image::RgbImage::from_fn(300, 200, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(&image)
+            .unwrap();
+        let mut tiler = TMSTiler::new();
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_format(ImageFormat::PNG);
+        tiler.base_mut().set_tile_size(128);
+        tiler.base_mut().set_working_directory(dir);
+        tiler.set_generate_preview_html(false);
+        let target = dir.join("tiles");
+        tiler.convert_to(&image, &target).unwrap();
+        target
+    }
+
+    fn categories(report: &ValidationReport) -> Vec<FindingCategory> {
+        report.findings().iter().map(|f| f.category()).collect()
+    }
+
+    #[test]
+    fn tiled_images_are_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        let report = TMSValidator::new().report(&tiles);
+        assert!(report.is_valid(), "{:?}", report.findings());
+        assert!(report.findings().is_empty());
+    }
+
+    #[test]
+    fn missing_tiles_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        fs::remove_file(tiles.join("2/1/0.png")).unwrap();
+
+        let report = TMSValidator::new().report(&tiles);
+        assert_eq!(categories(&report), [FindingCategory::MissingTile]);
+        let finding = &report.findings()[0];
+        assert_eq!(
+            (finding.zoom(), finding.column(), finding.row()),
+            (Some(2), Some(1), Some(0))
+        );
+        assert_eq!(finding.path(), Some(Path::new("2/1/0.png")));
+    }
+
+    #[test]
+    fn extra_files_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        fs::copy(tiles.join("2/0/0.png"), tiles.join("2/0/9.png")).unwrap();
+        fs::create_dir(tiles.join("3")).unwrap();
+        fs::write(tiles.join("notes.txt"), "").unwrap();
+        // Files the tiler writes next to the tiles are part of the tileset
+        fs::write(tiles.join("preview.html"), "").unwrap();
+
+        let report = TMSValidator::new().report(&tiles);
+        assert_eq!(
+            categories(&report),
+            [FindingCategory::ExtraFile; 3],
+            "{:?}",
+            report.findings()
+        );
+        let mut paths: Vec<_> = report
+            .findings()
+            .iter()
+            .map(|f| f.path().unwrap())
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                Path::new("2/0/9.png"),
+                Path::new("3"),
+                Path::new("notes.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn wrongly_sized_tiles_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        image::RgbImage::new(100, 128)
+            .save(tiles.join("1/1/0.png"))
+            .unwrap();
+
+        for deep in [false, true] {
+            let mut validator = TMSValidator::new();
+            validator.set_deep(deep);
+            let report = validator.report(&tiles);
+            let errors: Vec<_> = report.errors().collect();
+            assert_eq!(errors.len(), 1, "{:?}", report.findings());
+            assert_eq!(errors[0].category(), FindingCategory::WrongDimensions);
+            assert_eq!(
+                (errors[0].zoom(), errors[0].column(), errors[0].row()),
+                (Some(1), Some(1), Some(0))
+            );
+            assert!(errors[0].message().contains("100x128 instead of 128x128"));
+        }
+    }
+
+    #[test]
+    fn all_problems_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        fs::remove_file(tiles.join("0/0/0.png")).unwrap();
+        fs::remove_file(tiles.join("2/2/1.png")).unwrap();
+        fs::write(tiles.join("1/1/0.png"), b"not an image").unwrap();
+
+        let report = TMSValidator::new().report(&tiles);
+        let mut found = categories(&report);
+        found.sort_by_key(|c| format!("{:?}", c));
+        assert_eq!(
+            found,
+            [
+                FindingCategory::MissingTile,
+                FindingCategory::MissingTile,
+                FindingCategory::UndecodableTile
+            ]
+        );
+    }
+
+    #[test]
+    fn the_bounding_box_holds_the_image_size() {
+        let xml = r#"<TileMap>
+  <BoundingBox minx="-200.00000000000000" miny="0.00000000000000" maxx="0.00000000000000" maxy="300.00000000000000"/>
+  <TileFormat width="128" height="64" mime-type="image/jpeg" extension="jpg"/>
+  <TileSets profile="raster">
+    <TileSet href="0" units-per-pixel="2.00000000000000" order="0"/>
+    <TileSet href="1" units-per-pixel="1.00000000000000" order="1"/>
+  </TileSets>
+</TileMap>"#;
+        let tile_map = TMSValidator::new().parse_tilemap_resource(xml).unwrap();
+        assert_eq!((tile_map.width, tile_map.height), (300, 200));
+        assert_eq!((tile_map.tile_width, tile_map.tile_height), (128, 64));
+        assert_eq!(tile_map.extension, "jpg");
+        let tile_sets: Vec<_> = tile_map
+            .tile_sets
+            .iter()
+            .map(|t| (t.href.as_str(), t.units_per_pixel, t.order))
+            .collect();
+        assert_eq!(tile_sets, [("0", 2, 0), ("1", 1, 1)]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        assert_eq!(
+            TMSValidator::new().read_metadata(&tiles).unwrap(),
+            TilesetMetadata::new(300, 200, 128, 128, 3, ImageFormat::PNG)
+        );
+    }
+
+    #[test]
+    fn malformed_tilemap_resources_are_rejected() {
+        let validator = TMSValidator::new();
+        let tile_format = r#"<TileFormat width="256" height="256" extension="png"/>"#;
+        let bounding_box = r#"<BoundingBox minx="-200.0" maxy="300.0"/>"#;
+        let tile_set = r#"<TileSet href="0" units-per-pixel="1.0" order="0"/>"#;
+        for (xml, error) in [
+            (
+                format!("{}{}", tile_format, tile_set),
+                "Missing BoundingBox",
+            ),
+            (
+                format!("{}{}", bounding_box, tile_set),
+                "Missing TileFormat",
+            ),
+            (
+                format!("{}{}", bounding_box, tile_format),
+                "No TileSets declared",
+            ),
+            (
+                format!(
+                    "{}{}{}",
+                    bounding_box,
+                    tile_format.replace("256\" height", "abc\" height"),
+                    tile_set
+                ),
+                "Invalid number for width",
+            ),
+            (
+                format!(
+                    "{}{}{}",
+                    bounding_box.replace("-200.0", "200.0"),
+                    tile_format,
+                    tile_set
+                ),
+                "Invalid tile size or image dimensions",
+            ),
+            (
+                format!(
+                    "{}{}{}",
+                    bounding_box,
+                    tile_format,
+                    tile_set.replace("1.0", "0.0")
+                ),
+                "Invalid units-per-pixel",
+            ),
+            (
+                format!(
+                    "{}{}{}",
+                    bounding_box,
+                    tile_format,
+                    tile_set.replace(" order=\"0\"", "")
+                ),
+                "Missing attribute order",
+            ),
+        ] {
+            let message = validator
+                .parse_tilemap_resource(&xml)
+                .err()
+                .map(|e| e.to_string())
+                .unwrap_or_default();
+            assert!(message.contains(error), "{}: {}", xml, message);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        fs::write(tiles.join(METADATA_FILE), "<TileMap>").unwrap();
+        let report = validator.report(&tiles);
+        assert_eq!(categories(&report), [FindingCategory::InvalidTileset]);
+        assert!(validator.read_metadata(&tiles).is_err());
+    }
+}
@@ -1,6 +1,8 @@
 use std::io;
 use thiserror::Error;
 
+use crate::tiling_exception::TilingError;
+
 #[derive(Debug, Error)]
 #[error("{message}")]
 pub struct ValidationFailedError {
@@ -27,8 +29,8 @@ impl From<serde_json::Error> for ValidationFailedError {
     }
 }
 
-impl From<Box<dyn std::error::Error>> for ValidationFailedError {
-    fn from(err: Box<dyn std::error::Error>) -> Self {
+impl From<TilingError> for ValidationFailedError {
+    fn from(err: TilingError) -> Self {
         Self::new(err.to_string())
     }
 }
@@ -0,0 +1,288 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::validation_failed_exception::ValidationFailedError;
+
+/// How serious a validation finding is. Only errors make a tileset invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// The kind of problem a validation finding describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FindingCategory {
+    /// A tile (or tile directory) the metadata declares is missing
+    MissingTile,
+
+    /// A file or directory that is not part of the tileset
+    ExtraFile,
+
+    /// A tile does not have the dimensions the tiling scheme prescribes
+    WrongDimensions,
+
+    /// A tile cannot be decoded
+    UndecodableTile,
+
+    /// The image area of a tile consists entirely of the background colour
+    BlankTile,
+
+    /// The metadata contradicts itself or the files on disk
+    MetadataMismatch,
+
+    /// The directory is not a tileset of this scheme, or its metadata is
+    /// missing, unreadable or malformed. Validation stops at this finding.
+    InvalidTileset,
+}
+
+/// A single problem found while validating a tileset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finding {
+    severity: Severity,
+    category: FindingCategory,
+
+    /// The zoom level of the affected tile, in the numbering of the tiling
+    /// scheme (the scale factor for IIIF)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    zoom: Option<i32>,
+
+    /// The column of the affected tile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<i32>,
+
+    /// The row of the affected tile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row: Option<i32>,
+
+    /// The affected file, relative to the tileset directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+
+    message: String,
+}
+
+impl Finding {
+    pub fn new<S: Into<String>>(severity: Severity, category: FindingCategory, message: S) -> Self {
+        Self {
+            severity,
+            category,
+            zoom: None,
+            column: None,
+            row: None,
+            path: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn error<S: Into<String>>(category: FindingCategory, message: S) -> Self {
+        Self::new(Severity::Error, category, message)
+    }
+
+    pub fn warning<S: Into<String>>(category: FindingCategory, message: S) -> Self {
+        Self::new(Severity::Warning, category, message)
+    }
+
+    /// Sets the zoom level the finding concerns.
+    pub fn at_zoom(mut self, zoom: i32) -> Self {
+        self.zoom = Some(zoom);
+        self
+    }
+
+    /// Sets the tile the finding concerns.
+    pub fn at_tile(mut self, zoom: i32, column: i32, row: i32) -> Self {
+        self.zoom = Some(zoom);
+        self.column = Some(column);
+        self.row = Some(row);
+        self
+    }
+
+    /// Sets the file the finding concerns, relative to the tileset directory.
+    pub fn with_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    pub fn category(&self) -> FindingCategory {
+        self.category
+    }
+
+    pub fn zoom(&self) -> Option<i32> {
+        self.zoom
+    }
+
+    pub fn column(&self) -> Option<i32> {
+        self.column
+    }
+
+    pub fn row(&self) -> Option<i32> {
+        self.row
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+/// All findings of a validation run over one tileset. Serialises to JSON,
+/// e.g. for feeding QA dashboards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidationReport {
+    /// The validated tileset directory
+    tileset: PathBuf,
+
+    findings: Vec<Finding>,
+}
+
+impl ValidationReport {
+    pub fn new<P: AsRef<Path>>(tileset: P) -> Self {
+        Self {
+            tileset: tileset.as_ref().to_path_buf(),
+            findings: Vec::new(),
+        }
+    }
+
+    pub fn tileset(&self) -> &Path {
+        &self.tileset
+    }
+
+    pub fn add(&mut self, finding: Finding) {
+        self.findings.push(finding);
+    }
+
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Finding> {
+        self.findings.iter().filter(|f| f.is_error())
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Finding> {
+        self.findings.iter().filter(|f| !f.is_error())
+    }
+
+    /// True if no errors were found. Warnings do not make a tileset invalid.
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    /// Fails with the first error of the report, if any.
+    pub fn into_result(self) -> Result<(), ValidationFailedError> {
+        match self.findings.into_iter().find(|f| f.is_error()) {
+            Some(finding) => Err(ValidationFailedError::new(finding.message)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> ValidationReport {
+        let mut report = ValidationReport::new("tiles");
+        report.add(
+            Finding::warning(FindingCategory::BlankTile, "Tile 0/0/0.png is blank")
+                .at_tile(0, 0, 0)
+                .with_path("0/0/0.png"),
+        );
+        report.add(
+            Finding::error(FindingCategory::MissingTile, "Missing tile 1/0/1.png")
+                .at_tile(1, 0, 1)
+                .with_path("1/0/1.png"),
+        );
+        report.add(Finding::error(FindingCategory::ExtraFile, "Unexpected level 2").at_zoom(2));
+        report
+    }
+
+    #[test]
+    fn every_finding_is_kept() {
+        let report = report();
+        assert_eq!(report.findings().len(), 3);
+        assert_eq!(report.errors().count(), 2);
+        assert_eq!(report.warnings().count(), 1);
+        assert!(!report.is_valid());
+
+        let errors: Vec<_> = report.errors().map(|f| f.category()).collect();
+        assert_eq!(
+            errors,
+            [FindingCategory::MissingTile, FindingCategory::ExtraFile]
+        );
+        // The first error, not the first finding
+        assert_eq!(
+            report.into_result().unwrap_err().to_string(),
+            "Missing tile 1/0/1.png"
+        );
+    }
+
+    #[test]
+    fn warnings_do_not_invalidate_a_tileset() {
+        let mut report = ValidationReport::new("tiles");
+        assert!(report.is_valid());
+        report.add(Finding::warning(FindingCategory::BlankTile, "blank"));
+        assert!(report.is_valid());
+        assert!(report.into_result().is_ok());
+    }
+
+    #[test]
+    fn reports_serialise_to_json() {
+        let json: serde_json::Value = serde_json::from_str(&report().to_json().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "tileset": "tiles",
+                "findings": [
+                    {
+                        "severity": "warning",
+                        "category": "blank_tile",
+                        "zoom": 0,
+                        "column": 0,
+                        "row": 0,
+                        "path": "0/0/0.png",
+                        "message": "Tile 0/0/0.png is blank"
+                    },
+                    {
+                        "severity": "error",
+                        "category": "missing_tile",
+                        "zoom": 1,
+                        "column": 0,
+                        "row": 1,
+                        "path": "1/0/1.png",
+                        "message": "Missing tile 1/0/1.png"
+                    },
+                    {
+                        "severity": "error",
+                        "category": "extra_file",
+                        "zoom": 2,
+                        "message": "Unexpected level 2"
+                    }
+                ]
+            })
+        );
+
+        let parsed: ValidationReport = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.findings().len(), 3);
+        assert_eq!(parsed.findings()[1].path(), Some(Path::new("1/0/1.png")));
+        assert_eq!(parsed.findings()[2].column(), None);
+    }
+}
@@ -1,4 +1,9 @@
+use image::{imageops, DynamicImage, GenericImageView, Rgba};
+
+use crate::image::{avif_dimensions, read_avif_dimensions};
+use crate::tiling_scheme::TilesetMetadata;
 use crate::validation_failed_exception::ValidationFailedError;
+use crate::validation_report::{Finding, FindingCategory, ValidationReport};
 use std::path::Path;
 
 /// Interface for file and tiling scheme validators.
@@ -8,7 +13,39 @@ pub trait Validator {
     ///
     /// # Arguments
     /// * `dir` - The directory to check
-    fn is_tileset_dir<P: AsRef<Path>>(&self, dir: P) -> bool;
+    fn is_tileset_dir(&self, dir: &Path) -> bool;
+
+    /// Reads the basic tileset parameters from the tileset's metadata,
+    /// without checking the tiles
+    ///
+    /// # Arguments
+    /// * `dir` - The tileset directory
+    ///
+    /// # Errors
+    /// Returns a ValidationFailedError if the metadata is missing or invalid
+    fn read_metadata(&self, dir: &Path) -> Result<TilesetMetadata, ValidationFailedError>;
+
+    /// Enables deep validation, which decodes every tile and checks its pixel
+    /// dimensions instead of only checking that the file exists. Tiles whose
+    /// image area consists entirely of the background colour are reported as
+    /// warnings. Default: disabled.
+    ///
+    /// # Arguments
+    /// * `deep` - Whether to decode and check every tile
+    fn set_deep(&mut self, deep: bool);
+
+    /// Sets the background colour the tileset was created with, which deep
+    /// validation looks for in blank tiles. Default: white, like the tilers.
+    ///
+    /// # Arguments
+    /// * `color` - The background colour
+    fn set_background_color(&mut self, color: Rgba<u8>);
+
+    /// Validate a tileset and collect every problem found
+    ///
+    /// # Arguments
+    /// * `dir` - The tileset directory to validate
+    fn report(&self, dir: &Path) -> ValidationReport;
 
     /// Validate a tileset
     ///
@@ -16,6 +53,314 @@ pub trait Validator {
     /// * `dir` - The tileset directory to validate
     ///
     /// # Errors
-    /// Returns a ValidationFailedError if the tileset fails validation
-    fn validate<P: AsRef<Path>>(&self, dir: P) -> Result<(), ValidationFailedError>;
+    /// Returns a ValidationFailedError with the first error of the
+    /// validation report if the tileset fails validation
+    fn validate(&self, dir: &Path) -> Result<(), ValidationFailedError> {
+        self.report(dir).into_result()
+    }
+}
+
+/// The default background colour of tilesets
+pub(crate) const DEFAULT_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// How far the channels of a background pixel may deviate from the
+/// background colour, as lossy formats do not reproduce it exactly
+const BACKGROUND_TOLERANCE: u8 = 2;
+
+/// The part of a tile covered by the image, as (x, y, width, height). The
+/// rest of the tile is padding the tiling scheme fills with the background.
+pub(crate) type ContentArea = (u32, u32, u32, u32);
+
+/// Decodes a tile for deep validation and reports it if it cannot be decoded,
+/// if its size differs from `width` x `height` by more than `tolerance` pixels,
+/// or if its image area consists entirely of the background colour. AVIF
+/// tiles cannot be decoded, so only their dimensions are checked.
+///
+/// # Arguments
+/// * `dir` - The tileset directory
+/// * `tile` - The tile path, relative to `dir`
+/// * `content` - The image area of the tile, `None` for the whole tile. Tiles
+///   with an empty image area are not checked for blankness.
+/// * `background` - The background colour of the tileset
+/// * `position` - The (zoom, column, row) position reported with the findings
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn check_tile_content(
+    dir: &Path,
+    tile: &Path,
+    width: i32,
+    height: i32,
+    tolerance: i32,
+    content: Option<ContentArea>,
+    background: Rgba<u8>,
+    position: Option<(i32, i32, i32)>,
+    report: &mut ValidationReport,
+) {
+    let decoded = if is_avif(tile) {
+        read_avif_dimensions(&dir.join(tile))
+            .map(|dimensions| (None, dimensions))
+            .map_err(|e| e.to_string())
+    } else {
+        image::open(dir.join(tile))
+            .map(|image| {
+                let dimensions = image.dimensions();
+                (Some(image), dimensions)
+            })
+            .map_err(|e| e.to_string())
+    };
+    let expected = (width, height, tolerance);
+    check_decoded_tile(
+        tile, decoded, expected, content, background, position, report,
+    );
+}
+
+/// Like `check_tile_content`, for a tile held in memory, e.g. read from an
+/// archive. `tile` names the tile in the findings; its extension gives the
+/// tile format.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn check_tile_data(
+    tile: &Path,
+    data: &[u8],
+    width: i32,
+    height: i32,
+    tolerance: i32,
+    content: Option<ContentArea>,
+    background: Rgba<u8>,
+    position: Option<(i32, i32, i32)>,
+    report: &mut ValidationReport,
+) {
+    let decoded = if is_avif(tile) {
+        avif_dimensions(data)
+            .map(|dimensions| (None, dimensions))
+            .map_err(|e| e.to_string())
+    } else {
+        image::load_from_memory(data)
+            .map(|image| {
+                let dimensions = image.dimensions();
+                (Some(image), dimensions)
+            })
+            .map_err(|e| e.to_string())
+    };
+    let expected = (width, height, tolerance);
+    check_decoded_tile(
+        tile, decoded, expected, content, background, position, report,
+    );
+}
+
+fn is_avif(tile: &Path) -> bool {
+    tile.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("avif"))
+}
+
+/// Reports a tile that could not be decoded, differs from the expected
+/// (width, height, tolerance) or shows only background in its image area.
+/// AVIF tiles come without the decoded image.
+fn check_decoded_tile(
+    tile: &Path,
+    decoded: Result<(Option<DynamicImage>, (u32, u32)), String>,
+    (width, height, tolerance): (i32, i32, i32),
+    content: Option<ContentArea>,
+    background: Rgba<u8>,
+    position: Option<(i32, i32, i32)>,
+    report: &mut ValidationReport,
+) {
+    let locate = |finding: Finding| {
+        match position {
+            Some((zoom, column, row)) => finding.at_tile(zoom, column, row),
+            None => finding,
+        }
+        .with_path(tile)
+    };
+
+    let (image, (tile_width, tile_height)) = match decoded {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            report.add(locate(Finding::error(
+                FindingCategory::UndecodableTile,
+                format!("Could not decode tile {}: {}", tile.display(), e),
+            )));
+            return;
+        }
+    };
+
+    if (tile_width as i32 - width).abs() > tolerance
+        || (tile_height as i32 - height).abs() > tolerance
+    {
+        report.add(locate(Finding::error(
+            FindingCategory::WrongDimensions,
+            format!(
+                "Tile {} is {}x{} instead of {}x{}",
+                tile.display(),
+                tile_width,
+                tile_height,
+                width,
+                height
+            ),
+        )));
+    }
+
+    let Some(image) = image else {
+        return;
+    };
+    let pixels = image.to_rgba8();
+    let (x, y, content_width, content_height) =
+        content.unwrap_or((0, 0, pixels.width(), pixels.height()));
+    // Clamped to the tile, in case it has the wrong dimensions
+    let area = imageops::crop_imm(&pixels, x, y, content_width, content_height);
+    if area.width() == 0 || area.height() == 0 {
+        return;
+    }
+    let is_background = |p: Rgba<u8>| {
+        p.0.iter()
+            .zip(background.0)
+            .all(|(&c, b)| c.abs_diff(b) <= BACKGROUND_TOLERANCE)
+    };
+    if area.pixels().all(|(_, _, p)| is_background(p)) {
+        let [r, g, b, a] = background.0;
+        report.add(locate(Finding::warning(
+            FindingCategory::BlankTile,
+            format!(
+                "Tile {} consists entirely of background colour #{:02x}{:02x}{:02x}{:02x}",
+                tile.display(),
+                r,
+                g,
+                b,
+                a
+            ),
+        )));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageOutputFormat, RgbaImage};
+
+    use super::*;
+    use crate::image::{ImageFormat, ImageProcessingSystem};
+    use crate::tms::{TMSTiler, TMSValidator};
+    use crate::MagickTiler;
+
+    const RED: Rgba<u8> = Rgba([200, 0, 0, 255]);
+
+    fn png(image: &RgbaImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        data
+    }
+
+    fn check(
+        image: &RgbaImage,
+        content: Option<ContentArea>,
+        background: Rgba<u8>,
+    ) -> Vec<FindingCategory> {
+        let mut report = ValidationReport::new("tiles");
+        let tile = Path::new("0/0/0.png");
+        let data = png(image);
+        check_tile_data(
+            tile,
+            &data,
+            64,
+            64,
+            0,
+            content,
+            background,
+            None,
+            &mut report,
+        );
+        report.findings().iter().map(|f| f.category()).collect()
+    }
+
+    #[test]
+    fn only_background_tiles_are_blank() {
+        let white = RgbaImage::from_pixel(64, 64, DEFAULT_BACKGROUND);
+        let red = RgbaImage::from_pixel(64, 64, RED);
+        assert_eq!(
+            check(&white, None, DEFAULT_BACKGROUND),
+            [FindingCategory::BlankTile]
+        );
+        // A flat area of the image is not blank
+        assert!(check(&red, None, DEFAULT_BACKGROUND).is_empty());
+        assert_eq!(check(&red, None, RED), [FindingCategory::BlankTile]);
+        assert!(check(&white, None, RED).is_empty());
+
+        // Lossy formats only approximate the background
+        let almost_white = RgbaImage::from_pixel(64, 64, Rgba([254, 255, 253, 255]));
+        assert_eq!(
+            check(&almost_white, None, DEFAULT_BACKGROUND),
+            [FindingCategory::BlankTile]
+        );
+    }
+
+    #[test]
+    fn padding_is_not_part_of_the_image_area() {
+        // The image fills the bottom left 40x24 pixels of the tile
+        let mut tile = RgbaImage::from_pixel(64, 64, DEFAULT_BACKGROUND);
+        for (x, y, pixel) in tile.enumerate_pixels_mut() {
+            if x < 40 && y >= 40 {
+                *pixel = RED;
+            }
+        }
+        assert!(check(&tile, Some((0, 40, 40, 24)), DEFAULT_BACKGROUND).is_empty());
+        assert_eq!(
+            check(&tile, Some((40, 0, 24, 64)), DEFAULT_BACKGROUND),
+            [FindingCategory::BlankTile]
+        );
+
+        // Tiles without an image area, e.g. the TMS buffer, are not checked
+        let white = RgbaImage::from_pixel(64, 64, DEFAULT_BACKGROUND);
+        assert!(check(&white, Some((0, 0, 0, 0)), DEFAULT_BACKGROUND).is_empty());
+    }
+
+    #[test]
+    fn wrongly_sized_tiles_are_checked_within_their_bounds() {
+        let white = RgbaImage::from_pixel(32, 32, DEFAULT_BACKGROUND);
+        assert_eq!(
+            check(&white, Some((0, 0, 64, 64)), DEFAULT_BACKGROUND),
+            [FindingCategory::WrongDimensions, FindingCategory::BlankTile]
+        );
+    }
+
+    #[test]
+    fn tms_padding_and_buffer_tiles_are_not_blank() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.png");
+        // A uniform image whose height is a multiple of the tile height, so
+        // the tileset has a full row of buffer tiles
+        image::RgbImage::from_pixel(300, 128, image::Rgb([200, 0, 0]))
+            .save(&image)
+            .unwrap();
+        let mut tiler = TMSTiler::new();
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_format(ImageFormat::PNG);
+        tiler.base_mut().set_tile_size(64);
+        tiler.base_mut().set_working_directory(dir.path());
+        tiler.set_generate_preview_html(false);
+        let tiles = dir.path().join("tiles");
+        tiler.convert_to(&image, &tiles).unwrap();
+        assert!(tiles.join("3/0/2.png").exists());
+
+        let mut validator = TMSValidator::new();
+        validator.set_deep(true);
+        let report = validator.report(&tiles);
+        assert!(report.findings().is_empty(), "{:?}", report.findings());
+
+        // With red as the background, every tile is blank
+        validator.set_background_color(RED);
+        let report = validator.report(&tiles);
+        assert!(report.is_valid());
+        assert!(report
+            .findings()
+            .iter()
+            .any(|f| f.category() == FindingCategory::BlankTile
+                && f.path() == Some(Path::new("3/0/0.png"))));
+        assert!(report
+            .findings()
+            .iter()
+            .all(|f| f.path() != Some(Path::new("3/0/2.png"))));
+    }
 }
@@ -2,4 +2,5 @@ mod zoomify_tiler;
 mod zoomify_validator;
 
 pub use zoomify_tiler::ZoomifyTiler;
+pub(crate) use zoomify_tiler::{level_start_indices, MAX_TILES_PER_GROUP, TILEGROUP};
 pub use zoomify_validator::ZoomifyValidator;
@@ -1,13 +1,16 @@
-use std::fs::{self, File};
-use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::sync::Arc;
 
 use log::{debug, error, info};
 
-use crate::image::ImageProcessor;
+use crate::image::ImageFormat;
 use crate::magick_tiler::{BaseMagickTiler, MagickTiler, TilingError};
+use crate::pyramid::build_stripe_pyramid;
+use crate::sink::{TileKey, TileSink};
+use crate::streaming::LevelLayout;
 use crate::stripe::{Orientation, Stripe};
 use crate::tile_set_info::TileSetInfo;
+use crate::tiling_scheme::TilingScheme;
 
 /// A tiler that implements the Zoomify tiling scheme.
 ///
@@ -21,12 +24,17 @@ use crate::tile_set_info::TileSetInfo;
 /// Zoomify allows irregularly sized tiles on the border: I.e. the tiles in the
 /// last (=right-most) column and in the last (=bottom-most) row do not need to
 /// be rectangular.
+///
+/// The tileset is written through the configured `TileSink`, by default to
+/// the target directory.
 pub struct ZoomifyTiler {
     base: BaseMagickTiler,
 }
 
 pub const MAX_TILES_PER_GROUP: i32 = 256;
 pub const TILEGROUP: &str = "TileGroup";
+/// Zoomify viewers only display JPEG tiles
+const TILE_FORMATS: &[ImageFormat] = &[ImageFormat::JPEG];
 const METADATA_TEMPLATE: &str = r#"<IMAGE_PROPERTIES WIDTH="@width@" HEIGHT="@height@" NUMTILES="@numtiles@" NUMIMAGES="1" VERSION="1.8" TILESIZE="@tilesize@" />"#;
 
 impl ZoomifyTiler {
@@ -36,15 +44,26 @@ impl ZoomifyTiler {
         }
     }
 
+    /// Tiles the stripe of a row into the working directory and puts the
+    /// tiles into the sink.
+    #[allow(clippy::too_many_arguments)]
     fn generate_zoomify_tiles(
         &self,
         stripe: &Stripe,
+        sink: &dyn TileSink,
         zoomlevel: i32,
         x_tiles: i32,
         start_idx: i32,
         row_number: i32,
+        base_name: &str,
     ) -> Result<(), TilingError> {
-        let filename_pattern = self.base.tileset_root_dir().unwrap().join("tmp-%d.jpg");
+        // Stripes may be tiled concurrently, so the temporary files are named
+        // after the stripe
+        let tmp_prefix = format!("{}-tmp-{}-{}-", base_name, zoomlevel, row_number);
+        let filename_pattern = self
+            .base
+            .working_directory()
+            .join(format!("{}%d.jpg", tmp_prefix));
 
         self.base.processor().crop(
             stripe.image_file(),
@@ -53,34 +72,17 @@ impl ZoomifyTiler {
             self.base.tile_height(),
         )?;
 
-        // Rename result files
         for idx in 0..x_tiles {
-            let tile_group = (start_idx + idx) / MAX_TILES_PER_GROUP;
-            let tile_group_dir = self
-                .base
-                .tileset_root_dir()
-                .unwrap()
-                .join(format!("{}{}", TILEGROUP, tile_group));
-
-            if !tile_group_dir.exists() {
-                fs::create_dir_all(&tile_group_dir)?;
-            }
-
-            let old_name = filename_pattern.with_file_name(format!("tmp-{}.jpg", idx));
-            let new_name = tile_group_dir.join(format!(
-                "{}-{}-{}.jpg",
+            let tile = filename_pattern.with_file_name(format!("{}{}.jpg", tmp_prefix, idx));
+            let key = TileKey::new(
+                TilingScheme::Zoomify,
                 zoomlevel,
-                idx % x_tiles,
-                row_number
-            ));
-
-            fs::rename(&old_name, &new_name).map_err(|e| {
-                TilingError::General(format!(
-                    "Failed to rename file {}: {}",
-                    old_name.display(),
-                    e
-                ))
-            })?;
+                idx,
+                row_number,
+                ImageFormat::JPEG,
+            )
+            .with_tile_group((start_idx + idx) / MAX_TILES_PER_GROUP);
+            sink.put_tile_file(&key, &tile)?;
         }
 
         Ok(())
@@ -93,52 +95,138 @@ impl ZoomifyTiler {
         target_file: &Path,
     ) -> Result<Stripe, TilingError> {
         match stripe2 {
-            None => Ok(stripe1.shrink(
-                target_file,
-                self.base.processor().get_image_processing_system(),
-            )?),
-            Some(s2) => Ok(stripe1.merge(
-                s2,
-                target_file,
-                self.base.processor().get_image_processing_system(),
-            )?),
+            None => Ok(stripe1.shrink(target_file, self.base.processor())?),
+            Some(s2) => Ok(stripe1.merge(s2, target_file, self.base.processor())?),
         }
     }
 
-    fn generate_image_properties_xml(&self, info: &TileSetInfo) -> Result<(), TilingError> {
+    /// Computes the pyramid in-process while streaming the image.
+    fn stream_zoomify_tiles(
+        &self,
+        image: &Path,
+        info: &TileSetInfo,
+        sink: &dyn TileSink,
+        base_name: &str,
+    ) -> Result<(), TilingError> {
+        let level_start_idx = level_start_indices(info);
+        let progress = self.base.progress_tracker(info.total_number_of_tiles());
+        let layouts = LevelLayout::unpadded(
+            info.image_width() as u32,
+            info.image_height() as u32,
+            info.zoom_levels() as usize,
+        );
+        self.base
+            .stream_pyramid(image, layouts, |level, column, row, tile| {
+                let zoom_level = info.zoom_levels() - level as i32 - 1;
+                let idx = level_start_idx[level]
+                    + row as i32 * info.number_of_x_tiles(level as i32)
+                    + column as i32;
+                let key = TileKey::new(
+                    TilingScheme::Zoomify,
+                    zoom_level,
+                    column as i32,
+                    row as i32,
+                    ImageFormat::JPEG,
+                )
+                .with_tile_group(idx / MAX_TILES_PER_GROUP);
+                self.base.put_tile(sink, &key, tile, base_name)?;
+                progress.tiles_done(1, zoom_level, row as i32);
+                Ok(())
+            })
+    }
+
+    fn generate_image_properties_xml(
+        &self,
+        info: &TileSetInfo,
+        sink: &dyn TileSink,
+    ) -> Result<(), TilingError> {
         let metadata = METADATA_TEMPLATE
             .replace("@width@", &info.image_width().to_string())
             .replace("@height@", &info.image_height().to_string())
             .replace("@numtiles@", &info.total_number_of_tiles().to_string())
             .replace("@tilesize@", &info.tile_height().to_string());
 
-        if let Some(root_dir) = self.base.tileset_root_dir() {
-            let metadata_path = root_dir.join("ImageProperties.xml");
-            let mut file = File::create(&metadata_path).map_err(|e| {
-                TilingError::General(format!(
-                    "Error creating metadata file {}: {}",
-                    metadata_path.display(),
-                    e
-                ))
-            })?;
-
-            file.write_all(metadata.as_bytes()).map_err(|e| {
+        sink.put_metadata("ImageProperties.xml", metadata.as_bytes())
+            .map_err(|e| {
                 error!("Error writing metadata XML: {}", e);
-                TilingError::from(e)
-            })?;
-        }
+                e
+            })
+    }
 
-        Ok(())
+    fn generate_preview(&self, info: &TileSetInfo, sink: &dyn TileSink) -> Result<(), TilingError> {
+        let template = include_str!("zoomify-template.html");
+        let html = template
+            .replace(
+                "@title@",
+                &info.image_file().file_name().unwrap().to_string_lossy(),
+            )
+            .replace("@tileset@", ".");
+
+        sink.put_metadata("preview.html", html.as_bytes())
+    }
+}
+
+/// The index of the first tile of each level. Zoomify numbers tiles starting
+/// at the lowest resolution, so the tiles of each level follow those of all
+/// smaller levels.
+pub(crate) fn level_start_indices(info: &TileSetInfo) -> Vec<i32> {
+    let mut level_start_idx = Vec::new();
+    let mut start_idx = info.total_number_of_tiles();
+    for i in 0..info.zoom_levels() {
+        start_idx -= info.number_of_x_tiles(i) * info.number_of_y_tiles(i);
+        level_start_idx.push(start_idx);
+    }
+    level_start_idx
+}
+
+impl Default for ZoomifyTiler {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl MagickTiler for ZoomifyTiler {
+    fn base(&self) -> &BaseMagickTiler {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut BaseMagickTiler {
+        &mut self.base
+    }
+
+    fn set_tile_sink(&mut self, sink: Arc<dyn TileSink>) -> Result<(), TilingError> {
+        self.base.set_tile_sink(sink);
+        Ok(())
+    }
+
+    /// Writes ImageProperties.xml and the preview.
+    fn write_descriptors(
+        &mut self,
+        image: &Path,
+        target: &Path,
+    ) -> Result<TileSetInfo, TilingError> {
+        self.base
+            .check_tile_format(image, "Zoomify", TILE_FORMATS)?;
+        let info = self.base.prepare(image, target)?;
+        let sink = self.base.tile_sink();
+        self.generate_image_properties_xml(&info, sink.as_ref())?;
+        if self.base.generate_preview() {
+            self.generate_preview(&info, sink.as_ref())?;
+        }
+        sink.finalize()?;
+        Ok(info)
+    }
+
     fn convert(&mut self, image: &Path) -> Result<TileSetInfo, TilingError> {
-        self.base.convert(image)
+        let target = self.base.default_target();
+        self.convert_to(image, &target)
     }
 
     fn convert_to(&mut self, image: &Path, target: &Path) -> Result<TileSetInfo, TilingError> {
-        self.base.convert_to(image, target)
+        self.base
+            .check_tile_format(image, "Zoomify", TILE_FORMATS)?;
+        let info = self.base.prepare(image, target)?;
+        self.convert_internal(image, info)
     }
 
     fn convert_internal(
@@ -159,93 +247,68 @@ impl MagickTiler for ZoomifyTiler {
         );
 
         let base_name = image.file_stem().unwrap().to_string_lossy().into_owned();
-
-        // Step 1 - stripe the base image
-        debug!("Striping base image");
-        let base_stripes = self.base.stripe_image(
-            image,
-            Orientation::Horizontal,
-            info.number_of_y_tiles(0),
-            info.image_width(),
-            self.base.tile_height(),
-            &format!("{}-0-", base_name),
-        )?;
-
-        // Step 2 - tile base image stripes
-        debug!("Tiling level 1");
-        let zoomlevel_start_idx =
-            info.total_number_of_tiles() - info.number_of_x_tiles(0) * info.number_of_y_tiles(0);
-        let mut offset = zoomlevel_start_idx;
-
-        for (i, stripe) in base_stripes.iter().enumerate() {
-            self.generate_zoomify_tiles(
-                stripe,
-                info.zoom_levels() as i32 - 1,
-                info.number_of_x_tiles(0),
-                offset,
-                i as i32,
+        let sink = self.base.tile_sink();
+
+        if self.base.streaming() {
+            // Steps 1 and 2 - stream the image through the pyramid
+            self.stream_zoomify_tiles(image, &info, sink.as_ref(), &base_name)?;
+        } else {
+            // Step 1 - stripe the base image
+            self.base.check_cancelled()?;
+            debug!("Striping base image");
+            let base_stripes = self.base.stripe_image(
+                image,
+                Orientation::Horizontal,
+                info.number_of_y_tiles(0),
+                info.image_width(),
+                self.base.tile_height(),
+                &format!("{}-0-", base_name),
             )?;
-            offset += info.number_of_x_tiles(0);
-        }
 
-        // Step 3 - compute the pyramid
-        let mut level_beneath = base_stripes;
-        let mut this_level = Vec::new();
-        let mut zoomlevel_start_idx = zoomlevel_start_idx;
-
-        for i in 1..info.zoom_levels() {
-            debug!("Tiling level {}", i + 1);
-            zoomlevel_start_idx -= info.number_of_x_tiles(i) * info.number_of_y_tiles(i);
-            let mut offset = zoomlevel_start_idx;
-
-            for j in 0..((level_beneath.len() as f64 / 2.0).ceil() as usize) {
-                // Step 3a - merge stripes from level beneath
-                let stripe1 = &level_beneath[j * 2];
-                let stripe2 = if j * 2 + 1 < level_beneath.len() {
-                    Some(&level_beneath[j * 2 + 1])
-                } else {
-                    None
-                };
-
-                let result = self.merge_stripes(
-                    stripe1,
-                    stripe2,
-                    &self
+            // Step 2 - tile base image stripes and compute the pyramid
+            let level_start_idx = level_start_indices(&info);
+            let progress = self.base.progress_tracker(info.total_number_of_tiles());
+            build_stripe_pyramid(
+                self.base.worker_threads(),
+                self.base.cancellation_token(),
+                base_stripes,
+                info.zoom_levels(),
+                |level, j, stripe1, stripe2| {
+                    let target_file = self
                         .base
                         .working_directory()
-                        .join(format!("{}-{}-{}.tif", base_name, i, j)),
-                )?;
-                this_level.push(result);
-
-                // Step 3b - tile result stripe
-                self.generate_zoomify_tiles(
-                    this_level.last().unwrap(),
-                    info.zoom_levels() as i32 - i as i32 - 1,
-                    info.number_of_x_tiles(i),
-                    offset,
-                    j as i32,
-                )?;
-                offset += info.number_of_x_tiles(i);
-            }
-
-            for s in &level_beneath {
-                s.delete()?;
-            }
-            level_beneath = this_level;
-            this_level = Vec::new();
-        }
-
-        for s in &level_beneath {
-            s.delete()?;
+                        .join(format!("{}-{}-{}.tif", base_name, level, j));
+                    Ok(Some(self.merge_stripes(stripe1, stripe2, &target_file)?))
+                },
+                |level, j, stripe| {
+                    if j == 0 {
+                        debug!("Tiling level {}", level + 1);
+                    }
+                    let zoom_level = info.zoom_levels() - level - 1;
+                    let x_tiles = info.number_of_x_tiles(level);
+                    self.generate_zoomify_tiles(
+                        stripe,
+                        sink.as_ref(),
+                        zoom_level,
+                        x_tiles,
+                        level_start_idx[level as usize] + j as i32 * x_tiles,
+                        j as i32,
+                        &base_name,
+                    )?;
+                    progress.tiles_done(x_tiles, zoom_level, j as i32);
+                    Ok(())
+                },
+            )?;
         }
 
-        // Step 4 - generate ImageProperties.xml
-        self.generate_image_properties_xml(&info)?;
+        // Step 3 - generate ImageProperties.xml
+        self.generate_image_properties_xml(&info, sink.as_ref())?;
 
-        // Step 5 (optional) - generate OpenLayers preview
+        // Step 4 (optional) - generate OpenLayers preview
         if self.base.generate_preview() {
-            self.base.generate_preview(&info)?;
+            self.generate_preview(&info, sink.as_ref())?;
         }
+        sink.finalize()?;
 
         info!("Took {} ms", start_time.elapsed().as_millis());
         Ok(info)
@@ -1,11 +1,16 @@
 use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader};
-use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use image::Rgba;
 
 use super::zoomify_tiler::{MAX_TILES_PER_GROUP, TILEGROUP};
+use crate::image::ImageFormat;
+use crate::tiling_scheme::TilesetMetadata;
 use crate::validation_failed_exception::ValidationFailedError;
-use crate::validator::Validator;
+use crate::validation_report::{Finding, FindingCategory, ValidationReport};
+use crate::validator::{check_tile_content, Validator, DEFAULT_BACKGROUND};
 
 /// Validation class for the Zoomify tiling scheme.
 pub struct ZoomifyValidator {
@@ -29,6 +34,16 @@ pub struct ZoomifyValidator {
 
     /// Number of zoomlevels in this tileset
     zoom_levels: i32,
+
+    /// Image width and height declared in the descriptor file
+    width: i32,
+    height: i32,
+
+    /// Decode every tile and check its dimensions
+    deep: bool,
+
+    /// The background colour blank tiles are made of
+    background: Rgba<u8>,
 }
 
 impl ZoomifyValidator {
@@ -41,6 +56,10 @@ impl ZoomifyValidator {
             x_tiles: Vec::new(),
             y_tiles: Vec::new(),
             zoom_levels: 0,
+            width: 0,
+            height: 0,
+            deep: false,
+            background: DEFAULT_BACKGROUND,
         }
     }
 
@@ -64,6 +83,8 @@ impl ZoomifyValidator {
 
         let width = extract_value("width")?;
         let height = extract_value("height")?;
+        self.width = width;
+        self.height = height;
         let numtiles = extract_value("numtiles")?;
         self.tile_size = extract_value("tilesize")?;
 
@@ -92,7 +113,11 @@ impl ZoomifyValidator {
         Ok(())
     }
 
-    fn check_tile_directories(&self, tileset_dir: &Path) -> Result<(), ValidationFailedError> {
+    fn check_tile_directories(
+        &self,
+        tileset_dir: &Path,
+        report: &mut ValidationReport,
+    ) -> Result<(), ValidationFailedError> {
         let mut all_tiles: HashMap<i32, HashSet<String>> = HashMap::new();
 
         for entry in fs::read_dir(tileset_dir)? {
@@ -100,105 +125,282 @@ impl ZoomifyValidator {
             let file_name = entry.file_name().to_string_lossy().into_owned();
 
             if file_name.contains(TILEGROUP) {
-                let tile_group: i32 = file_name[TILEGROUP.len()..]
-                    .parse()
-                    .map_err(|_| ValidationFailedError::new("Invalid TileGroup number"))?;
-
-                let tiles: HashSet<String> = fs::read_dir(&entry.path())?
+                let Ok(tile_group) = file_name[TILEGROUP.len()..].parse::<i32>() else {
+                    report.add(
+                        Finding::error(
+                            FindingCategory::ExtraFile,
+                            format!("Invalid TileGroup number: {}", file_name),
+                        )
+                        .with_path(&file_name),
+                    );
+                    continue;
+                };
+
+                let tiles: HashSet<String> = fs::read_dir(entry.path())?
                     .filter_map(|e| e.ok())
                     .map(|e| e.file_name().to_string_lossy().into_owned())
                     .collect();
 
-                if tile_group < self.tile_groups - 2 {
-                    if tiles.len() < MAX_TILES_PER_GROUP as usize {
-                        return Err(ValidationFailedError::new(format!(
-                            "Missing tiles in directory {} ({} instead of {})",
-                            file_name,
-                            tiles.len(),
-                            MAX_TILES_PER_GROUP
-                        )));
-                    }
-                } else if tiles.len() < self.tiles_in_last_group as usize {
-                    return Err(ValidationFailedError::new(format!(
-                        "Missing tiles in directory {} ({} instead of {})",
-                        file_name,
-                        tiles.len(),
-                        self.tiles_in_last_group
-                    )));
+                // The missing tiles themselves are reported by check_for_each_tile
+                let expected = if tile_group < self.tile_groups - 2 {
+                    MAX_TILES_PER_GROUP
+                } else {
+                    self.tiles_in_last_group
+                };
+                if tiles.len() < expected as usize {
+                    report.add(
+                        Finding::warning(
+                            FindingCategory::MissingTile,
+                            format!(
+                                "Missing tiles in directory {} ({} instead of {})",
+                                file_name,
+                                tiles.len(),
+                                expected
+                            ),
+                        )
+                        .with_path(&file_name),
+                    );
                 }
 
                 all_tiles.insert(tile_group, tiles);
             }
         }
 
-        self.check_for_each_tile(&all_tiles)?;
+        self.check_for_each_tile(tileset_dir, &all_tiles, report);
         Ok(())
     }
 
     fn check_for_each_tile(
         &self,
+        tileset_dir: &Path,
         all_tiles: &HashMap<i32, HashSet<String>>,
-    ) -> Result<(), ValidationFailedError> {
+        report: &mut ValidationReport,
+    ) {
         let mut tile = 0;
 
         for zoom_level in (0..self.zoom_levels).rev() {
             for row in 0..self.y_tiles[zoom_level as usize] {
                 for col in 0..self.x_tiles[zoom_level as usize] {
-                    let tile_name =
-                        format!("{}-{}-{}.jpg", self.zoom_levels - 1 - zoom_level, col, row);
+                    let zoom = self.zoom_levels - 1 - zoom_level;
+                    let tile_name = format!("{}-{}-{}.jpg", zoom, col, row);
                     let tile_group = tile / MAX_TILES_PER_GROUP;
 
+                    let tile_path = format!("{}{}/{}", TILEGROUP, tile_group, tile_name);
                     if !all_tiles
                         .get(&tile_group)
-                        .map_or(false, |tiles| tiles.contains(&tile_name))
+                        .is_some_and(|tiles| tiles.contains(&tile_name))
                     {
-                        return Err(ValidationFailedError::new(format!(
-                            "Missing tile: {}",
-                            tile_name
-                        )));
+                        report.add(
+                            Finding::error(
+                                FindingCategory::MissingTile,
+                                format!("Missing tile: {}", tile_name),
+                            )
+                            .at_tile(zoom, col, row)
+                            .with_path(tile_path),
+                        );
+                    } else if self.deep {
+                        let (width, height, tolerance) = self.tile_dimensions(zoom_level, col, row);
+                        check_tile_content(
+                            tileset_dir,
+                            Path::new(&tile_path),
+                            width,
+                            height,
+                            tolerance,
+                            None,
+                            self.background,
+                            Some((zoom, col, row)),
+                            report,
+                        );
                     }
                     tile += 1;
                 }
             }
         }
+    }
 
-        Ok(())
+    /// Returns the expected width and height of a tile, and the number of
+    /// pixels they may deviate. Tiles on the right and bottom border are
+    /// smaller than the tile size; since the stripes of each level are halved
+    /// with integer arithmetic, their size may be off by one pixel.
+    fn tile_dimensions(&self, zoom_level: i32, col: i32, row: i32) -> (i32, i32, i32) {
+        let factor = 2_f64.powi(zoom_level);
+        let level_width = (self.width as f64 / factor).ceil() as i32;
+        let level_height = (self.height as f64 / factor).ceil() as i32;
+        let width = (level_width - col * self.tile_size).clamp(1, self.tile_size);
+        let height = (level_height - row * self.tile_size).clamp(1, self.tile_size);
+
+        let border = col == self.x_tiles[zoom_level as usize] - 1
+            || row == self.y_tiles[zoom_level as usize] - 1;
+        (width, height, if border { 1 } else { 0 })
+    }
+
+    fn check(
+        &self,
+        dir: &Path,
+        report: &mut ValidationReport,
+    ) -> Result<(), ValidationFailedError> {
+        if !dir.is_dir() {
+            return Err(ValidationFailedError::new("Not a zoomify tileset"));
+        }
+
+        let properties_file = dir.join(self.image_properties);
+        if !properties_file.exists() {
+            return Err(ValidationFailedError::new(
+                "Not a Zoomify tileset - missing ImageProperties.xml",
+            ));
+        }
+
+        let file = File::open(properties_file)?;
+        let reader = BufReader::new(file);
+        let xml: String = reader.lines().collect::<Result<_, _>>()?;
+
+        let mut validator = ZoomifyValidator::new();
+        validator.deep = self.deep;
+        validator.background = self.background;
+        validator.parse_image_properties(&xml)?;
+        validator.check_tile_directories(dir, report)
+    }
+}
+
+impl Default for ZoomifyValidator {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Validator for ZoomifyValidator {
-    fn is_tileset_dir<P: AsRef<Path>>(&self, dir: P) -> bool {
-        if !dir.as_ref().is_dir() {
+    fn is_tileset_dir(&self, dir: &Path) -> bool {
+        if !dir.is_dir() {
             return false;
         }
 
-        fs::read_dir(dir.as_ref()).ok().map_or(false, |entries| {
+        fs::read_dir(dir).is_ok_and(|entries| {
             entries
                 .filter_map(|e| e.ok())
                 .any(|e| e.file_name() == self.image_properties)
         })
     }
 
-    fn validate<P: AsRef<Path>>(&self, dir: P) -> Result<(), ValidationFailedError> {
-        let dir = dir.as_ref();
-        if !dir.is_dir() {
-            return Err(ValidationFailedError::new("Not a zoomify tileset"));
-        }
+    fn read_metadata(&self, dir: &Path) -> Result<TilesetMetadata, ValidationFailedError> {
+        let xml = fs::read_to_string(dir.join(self.image_properties))?;
+        let mut validator = ZoomifyValidator::new();
+        validator.parse_image_properties(&xml)?;
+        Ok(TilesetMetadata::new(
+            validator.width,
+            validator.height,
+            validator.tile_size,
+            validator.tile_size,
+            validator.zoom_levels,
+            ImageFormat::JPEG,
+        ))
+    }
 
-        let properties_file = dir.join(self.image_properties);
-        if !properties_file.exists() {
-            return Err(ValidationFailedError::new(
-                "Not a Zoomify tileset - missing ImageProperties.xml",
+    fn set_deep(&mut self, deep: bool) {
+        self.deep = deep;
+    }
+
+    fn set_background_color(&mut self, color: Rgba<u8>) {
+        self.background = color;
+    }
+
+    fn report(&self, dir: &Path) -> ValidationReport {
+        let mut report = ValidationReport::new(dir);
+        if let Err(e) = self.check(dir, &mut report) {
+            report.add(Finding::error(
+                FindingCategory::InvalidTileset,
+                e.to_string(),
             ));
         }
+        report
+    }
+}
 
-        let file = File::open(properties_file)?;
-        let reader = BufReader::new(file);
-        let xml: String = reader.lines().filter_map(|line| line.ok()).collect();
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::image::ImageProcessingSystem;
+    use crate::zoomify::ZoomifyTiler;
+    use crate::MagickTiler;
+
+    /// Tiles a 300x200 image with 64 pixel tiles: 4 zoom levels with 5x4,
+    /// 3x2, 2x1 and 1x1 tiles, all in TileGroup0
+    fn tileset(dir: &Path) -> PathBuf {
+        let image = dir.join("image.png");
+        image::RgbImage::from_fn(300, 200, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(&image)
+            .unwrap();
+        let mut tiler = ZoomifyTiler::new();
+        tiler
+            .base_mut()
+            .set_image_processing_system(ImageProcessingSystem::Native);
+        tiler.base_mut().set_tile_size(64);
+        tiler.base_mut().set_working_directory(dir);
+        tiler.base_mut().set_generate_preview_html(false);
+        let target = dir.join("tiles");
+        tiler.convert_to(&image, &target).unwrap();
+        target
+    }
+
+    #[test]
+    fn tiled_images_are_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        let mut validator = ZoomifyValidator::new();
+        validator.set_deep(true);
+        let report = validator.report(&tiles);
+        assert!(report.findings().is_empty(), "{:?}", report.findings());
+        assert_eq!(
+            validator.read_metadata(&tiles).unwrap(),
+            TilesetMetadata::new(300, 200, 64, 64, 4, ImageFormat::JPEG)
+        );
+    }
 
-        self.parse_image_properties(&xml)?;
-        self.check_tile_directories(dir)?;
+    #[test]
+    fn all_missing_tiles_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        fs::remove_file(tiles.join("TileGroup0/0-0-0.jpg")).unwrap();
+        fs::remove_file(tiles.join("TileGroup0/3-4-3.jpg")).unwrap();
+        fs::create_dir(tiles.join("TileGroupX")).unwrap();
+
+        let report = ZoomifyValidator::new().report(&tiles);
+        let errors: Vec<_> = report
+            .errors()
+            .map(|f| (f.category(), f.path().unwrap().to_path_buf()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (FindingCategory::ExtraFile, PathBuf::from("TileGroupX")),
+                (
+                    FindingCategory::MissingTile,
+                    PathBuf::from("TileGroup0/0-0-0.jpg")
+                ),
+                (
+                    FindingCategory::MissingTile,
+                    PathBuf::from("TileGroup0/3-4-3.jpg")
+                ),
+            ]
+        );
+        // The tile group holds fewer tiles than declared
+        let warnings: Vec<_> = report.warnings().map(|f| f.category()).collect();
+        assert_eq!(warnings, [FindingCategory::MissingTile]);
+    }
 
-        Ok(())
+    #[test]
+    fn malformed_image_properties_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = tileset(dir.path());
+        fs::write(
+            tiles.join("ImageProperties.xml"),
+            r#"<IMAGE_PROPERTIES WIDTH="300" HEIGHT="abc" />"#,
+        )
+        .unwrap();
+        let report = ZoomifyValidator::new().report(&tiles);
+        let categories: Vec<_> = report.findings().iter().map(|f| f.category()).collect();
+        assert_eq!(categories, [FindingCategory::InvalidTileset]);
+        assert!(ZoomifyValidator::new().read_metadata(&tiles).is_err());
     }
 }